@@ -135,8 +135,27 @@ pub enum VmImage {
 #[derive(Clone, Debug, Deserialize, Serialize, FromKeyValues, PartialEq, Eq)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct Pstore {
-    pub path: PathBuf,
+    /// Backing file for the records. Without one the records are kept in anonymous memory and
+    /// discarded when the VM exits.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
     pub size: u32,
+    /// Size of each oops/panic record. Defaults to 1/4 of `size`.
+    #[serde(default)]
+    pub record_size: Option<u32>,
+    /// Size of the console log. Defaults to 1/4 of `size`.
+    #[serde(default)]
+    pub console_size: Option<u32>,
+    /// Size of the ftrace log. Disabled by default.
+    #[serde(default)]
+    pub ftrace_size: Option<u32>,
+    /// Size of the pmsg log. Disabled by default.
+    #[serde(default)]
+    pub pmsg_size: Option<u32>,
+    /// Bytes of ECC per ramoops block, so partially corrupted records can be recovered. Disabled
+    /// by default.
+    #[serde(default)]
+    pub ecc: Option<u32>,
 }
 
 /// Set of CPU cores.
@@ -719,7 +738,11 @@ pub fn configure_pci_device<V: VmArch, Vcpu: VcpuArch>(
     let intx_event = devices::IrqLevelEvent::new().map_err(DeviceRegistrationError::EventCreate)?;
 
     if let PreferredIrq::Fixed { pin, gsi } = device.preferred_irq() {
-        resources.reserve_irq(gsi);
+        // Functions of the same host device may share a GSI. Only the device that reserved it
+        // releases it when it is removed.
+        if resources.reserve_irq(gsi) {
+            linux.root_config.lock().claim_device_irq(pci_address, gsi);
+        }
 
         device.assign_irq(
             intx_event
@@ -1057,18 +1080,21 @@ pub fn generate_pci_root(
                 resources.reserve_irq(gsi);
                 Some((pin, gsi))
             }
-            PreferredIrq::Any => {
+            preferred @ (PreferredIrq::Any | PreferredIrq::AnyWithPin { .. }) => {
                 // The device did not provide a preferred IRQ but requested one, so allocate one.
 
-                // Choose a pin based on the slot's function number. Function 0 must always use
-                // INTA# for single-function devices per the PCI spec, and we choose to use INTA#
-                // for function 0 on multifunction devices and distribute the remaining functions
-                // evenly across the other pins.
-                let pin = match pci_address.func % 4 {
-                    0 => PciInterruptPin::IntA,
-                    1 => PciInterruptPin::IntB,
-                    2 => PciInterruptPin::IntC,
-                    _ => PciInterruptPin::IntD,
+                // Unless the device chose a pin, choose one based on the slot's function number.
+                // Function 0 must always use INTA# for single-function devices per the PCI spec,
+                // and we choose to use INTA# for function 0 on multifunction devices and
+                // distribute the remaining functions evenly across the other pins.
+                let pin = match preferred {
+                    PreferredIrq::AnyWithPin { pin } => pin,
+                    _ => match pci_address.func % 4 {
+                        0 => PciInterruptPin::IntA,
+                        1 => PciInterruptPin::IntB,
+                        2 => PciInterruptPin::IntC,
+                        _ => PciInterruptPin::IntD,
+                    },
                 };
 
                 // If an IRQ number has already been assigned for a different function with this
@@ -1369,16 +1395,42 @@ mod tests {
         assert_eq!(
             res,
             Pstore {
-                path: "/some/path".into(),
+                path: Some("/some/path".into()),
                 size: 16384,
+                record_size: None,
+                console_size: None,
+                ftrace_size: None,
+                pmsg_size: None,
+                ecc: None,
             }
         );
 
+        let res: Pstore = from_key_values(
+            "path=/some/path,size=16384,record-size=4096,console-size=8192,ftrace-size=2048,pmsg-size=1024",
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            Pstore {
+                path: Some("/some/path".into()),
+                size: 16384,
+                record_size: Some(4096),
+                console_size: Some(8192),
+                ftrace_size: Some(2048),
+                pmsg_size: Some(1024),
+                ecc: None,
+            }
+        );
+
+        let res: Pstore = from_key_values("size=16384,ecc=16").unwrap();
+        assert_eq!(res.ecc, Some(16));
+
         let res = from_key_values::<Pstore>("path=/some/path");
         assert!(res.is_err());
 
-        let res = from_key_values::<Pstore>("size=16384");
-        assert!(res.is_err());
+        let res: Pstore = from_key_values("size=16384").unwrap();
+        assert_eq!(res.path, None);
+        assert_eq!(res.size, 16384);
 
         let res = from_key_values::<Pstore>("");
         assert!(res.is_err());
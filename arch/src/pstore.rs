@@ -2,12 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
 
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use base::MemoryMappingBuilder;
+use base::SharedMemory;
+use base::VolatileSlice;
 use hypervisor::MemCacheType;
 use hypervisor::Vm;
 use resources::AddressRange;
@@ -17,36 +22,144 @@ use crate::Pstore;
 
 mod sys;
 
+/// Ramoops maps its region in pages, so the region base and size must be page multiples.
+const RAMOOPS_PAGE_SIZE: u64 = 0x1000;
+
 pub struct RamoopsRegion {
     pub address: u64,
     pub size: u32,
+    pub record_size: u32,
+    pub console_size: u32,
+    pub ftrace_size: u32,
+    pub pmsg_size: u32,
+    /// Bytes of ECC per block, or 0 if ECC is disabled.
+    pub ecc: u32,
+    /// Whether the pstore file already had the expected size, so the records of the previous
+    /// boot were kept.
+    pub preserved: bool,
 }
 
-/// Creates a mmio memory region for pstore.
-pub fn create_memory_region(
-    vm: &mut impl Vm,
-    region: AddressRange,
-    pstore: &Pstore,
-) -> Result<RamoopsRegion> {
-    let region_size = region.len().context("failed to get region len")?;
-    if region_size < pstore.size.into() {
-        bail!("insufficient space for pstore {} {}", region, pstore.size);
+impl RamoopsRegion {
+    /// Returns the guest address of the pmsg section, or `None` if pmsg is disabled. Ramoops
+    /// places the dump records, console, ftrace and pmsg sections in that order, so pmsg takes
+    /// the end of the region.
+    pub fn pmsg_address(&self) -> Option<u64> {
+        if self.pmsg_size == 0 {
+            return None;
+        }
+        Some(self.address + (self.size - self.pmsg_size) as u64)
     }
+}
 
+/// Opens the pstore backing file, creating it if needed. The file is only resized if its length
+/// does not already match `size`, so existing records survive a restart of the VMM.
+///
+/// Returns the file and whether its existing content was preserved.
+fn open_pstore_file(path: &Path, size: u32) -> Result<(File, bool)> {
     let mut open_opts = OpenOptions::new();
     open_opts.read(true).write(true).create(true);
     sys::set_extra_open_opts(&mut open_opts);
 
-    let file = open_opts
-        .open(&pstore.path)
-        .context("failed to open pstore")?;
-    file.set_len(pstore.size as u64)
+    let file = open_opts.open(path).context("failed to open pstore")?;
+    let len = file
+        .metadata()
+        .context("failed to get pstore metadata")?
+        .len();
+    if len == size as u64 {
+        return Ok((file, true));
+    }
+    file.set_len(size as u64)
         .context("failed to set pstore length")?;
+    Ok((file, false))
+}
 
-    let memory_mapping = MemoryMappingBuilder::new(pstore.size as usize)
-        .from_file(&file)
-        .build()
-        .context("failed to mmap pstore")?;
+/// Checks that `pstore` fits in `region` and is laid out the way ramoops expects.
+fn check_region(region: &AddressRange, pstore: &Pstore) -> Result<()> {
+    if pstore.size == 0 {
+        bail!("pstore size must not be zero");
+    }
+    if pstore.size as u64 % RAMOOPS_PAGE_SIZE != 0 {
+        bail!(
+            "pstore size {:#x} is not a multiple of {:#x}",
+            pstore.size,
+            RAMOOPS_PAGE_SIZE
+        );
+    }
+    if region.start % RAMOOPS_PAGE_SIZE != 0 {
+        bail!(
+            "pstore region {} is not aligned to {:#x}",
+            region,
+            RAMOOPS_PAGE_SIZE
+        );
+    }
+    let region_size = region.len().context("failed to get region len")?;
+    if region_size < pstore.size.into() {
+        bail!("insufficient space for pstore {} {}", region, pstore.size);
+    }
+    Ok(())
+}
+
+/// Creates a mmio memory region for pstore.
+pub fn create_memory_region(
+    vm: &mut impl Vm,
+    region: AddressRange,
+    pstore: &Pstore,
+) -> Result<RamoopsRegion> {
+    check_region(&region, pstore)?;
+
+    // It seems that default record_size is only 4096 byte even if crosvm allocates
+    // more memory. It means that one crash can only 4096 byte.
+    // Set record_size and console_size to 1/4 of allocated memory size.
+    // This configulation is same as the host.
+    let record_size = pstore.record_size.unwrap_or(pstore.size / 4);
+    let console_size = pstore.console_size.unwrap_or(pstore.size / 4);
+    let ftrace_size = pstore.ftrace_size.unwrap_or(0);
+    let pmsg_size = pstore.pmsg_size.unwrap_or(0);
+    let sections_size =
+        record_size as u64 + console_size as u64 + ftrace_size as u64 + pmsg_size as u64;
+    if sections_size > pstore.size as u64 {
+        bail!(
+            "pstore sections (record {}, console {}, ftrace {}, pmsg {}) need {} bytes, more than the pstore size {}",
+            record_size,
+            console_size,
+            ftrace_size,
+            pmsg_size,
+            sections_size,
+            pstore.size
+        );
+    }
+    // Ramoops rounds the pmsg size down to a power of two, which would leave the rest unused.
+    if pmsg_size != 0 && !pmsg_size.is_power_of_two() {
+        bail!("pstore pmsg size {:#x} is not a power of two", pmsg_size);
+    }
+    let ecc = pstore.ecc.unwrap_or(0);
+    if ecc != 0 && ecc >= record_size {
+        bail!(
+            "pstore ecc size {} leaves no room in the record size {}",
+            ecc,
+            record_size
+        );
+    }
+
+    let (memory_mapping, preserved) = match &pstore.path {
+        Some(path) => {
+            let (file, preserved) = open_pstore_file(path, pstore.size)?;
+            let memory_mapping = MemoryMappingBuilder::new(pstore.size as usize)
+                .from_file(&file)
+                .build()
+                .context("failed to mmap pstore")?;
+            (memory_mapping, preserved)
+        }
+        None => {
+            let shm = SharedMemory::new("pstore", pstore.size as u64)
+                .context("failed to create pstore shared memory")?;
+            let memory_mapping = MemoryMappingBuilder::new(pstore.size as usize)
+                .from_shared_memory(&shm)
+                .build()
+                .context("failed to mmap pstore")?;
+            (memory_mapping, false)
+        }
+    };
 
     vm.add_memory_region(
         GuestAddress(region.start),
@@ -60,17 +173,56 @@ pub fn create_memory_region(
     Ok(RamoopsRegion {
         address: region.start,
         size: pstore.size,
+        record_size,
+        console_size,
+        ftrace_size,
+        pmsg_size,
+        ecc,
+        preserved,
+    })
+}
+
+/// Writes the contents of the ramoops region, read back from the VM, to `out` as a zlib stream so
+/// that crash dumps can be collected compactly. If the region extends past the memory mapped at
+/// its address, only the mapped part is written.
+pub fn dump_compressed(region: &RamoopsRegion, vm: &impl Vm, out: &mut dyn Write) -> Result<()> {
+    let mut contents = None;
+    vm.for_each_memory_region(&mut |_, guest_addr, mem| {
+        if contents.is_some() {
+            return;
+        }
+        let offset = match region.address.checked_sub(guest_addr.offset()) {
+            Some(offset) if offset < mem.size() as u64 => offset as usize,
+            _ => return,
+        };
+        let len = std::cmp::min(region.size as usize, mem.size() - offset);
+        // SAFETY:
+        // Safe because the mapping stays alive while the memory regions are walked and
+        // `[offset, offset + len)` lies within it. The guest may still write to it, so it is
+        // only read through a volatile copy.
+        let slice = unsafe { VolatileSlice::from_raw_parts(mem.as_ptr().add(offset), len) };
+        let mut bytes = vec![0u8; len];
+        slice.copy_to(&mut bytes);
+        contents = Some(bytes);
     })
+    .context("failed to walk VM memory regions")?;
+    let contents = contents.context("pstore region is not mapped")?;
+    write_compressed(&contents, out)
+}
+
+fn write_compressed(contents: &[u8], out: &mut dyn Write) -> Result<()> {
+    let mut encoder = flate2::write::ZlibEncoder::new(out, flate2::Compression::default());
+    encoder
+        .write_all(contents)
+        .context("failed to compress pstore")?;
+    encoder.finish().context("failed to compress pstore")?;
+    Ok(())
 }
 
 pub fn add_ramoops_kernel_cmdline(
     cmdline: &mut kernel_cmdline::Cmdline,
     ramoops_region: &RamoopsRegion,
 ) -> std::result::Result<(), kernel_cmdline::Error> {
-    // It seems that default record_size is only 4096 byte even if crosvm allocates
-    // more memory. It means that one crash can only 4096 byte.
-    // Set record_size and console_size to 1/4 of allocated memory size.
-    // This configulation is same as the host.
     let ramoops_opts = [
         ("mem_address", ramoops_region.address),
         ("mem_size", ramoops_region.size as u64),
@@ -78,5 +230,318 @@ pub fn add_ramoops_kernel_cmdline(
     for (name, val) in &ramoops_opts {
         cmdline.insert_str(format!("ramoops.{}={:#x}", name, val))?;
     }
+    let section_sizes = [
+        ("record_size", ramoops_region.record_size),
+        ("console_size", ramoops_region.console_size),
+        ("ftrace_size", ramoops_region.ftrace_size),
+        ("pmsg_size", ramoops_region.pmsg_size),
+    ];
+    // A zero size leaves the section disabled, which is the ramoops default.
+    for (name, val) in section_sizes.iter().filter(|(_, val)| *val != 0) {
+        cmdline.insert_str(format!("ramoops.{}={:#x}", name, val))?;
+    }
+    if ramoops_region.ecc != 0 {
+        cmdline.insert_str(format!("ramoops.ecc={}", ramoops_region.ecc))?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn test_pstore(path: Option<std::path::PathBuf>, size: u32) -> Pstore {
+        Pstore {
+            path,
+            size,
+            record_size: None,
+            console_size: None,
+            ftrace_size: None,
+            pmsg_size: None,
+            ecc: None,
+        }
+    }
+
+    #[test]
+    fn open_pstore_file_preserves_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pstore");
+
+        let (mut file, preserved) = open_pstore_file(&path, 0x1000).unwrap();
+        assert!(!preserved);
+        file.seek(SeekFrom::Start(0x100)).unwrap();
+        file.write_all(b"oops").unwrap();
+        drop(file);
+
+        let (mut file, preserved) = open_pstore_file(&path, 0x1000).unwrap();
+        assert!(preserved);
+        let mut buf = [0u8; 4];
+        file.seek(SeekFrom::Start(0x100)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"oops");
+    }
+
+    #[test]
+    fn open_pstore_file_resizes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pstore");
+        drop(open_pstore_file(&path, 0x1000).unwrap());
+
+        let (file, preserved) = open_pstore_file(&path, 0x2000).unwrap();
+        assert!(!preserved);
+        assert_eq!(file.metadata().unwrap().len(), 0x2000);
+    }
+
+    #[test]
+    fn check_region_misaligned() {
+        let pstore = test_pstore(None, 0x4000);
+        let region = AddressRange {
+            start: 0x10_0800,
+            end: 0x10_8fff,
+        };
+        assert_eq!(
+            check_region(&region, &pstore).unwrap_err().to_string(),
+            format!("pstore region {} is not aligned to 0x1000", region)
+        );
+
+        let region = AddressRange {
+            start: 0x10_0000,
+            end: 0x10_3fff,
+        };
+        check_region(&region, &pstore).unwrap();
+        let pstore = test_pstore(None, 0x4800);
+        assert_eq!(
+            check_region(&region, &pstore).unwrap_err().to_string(),
+            "pstore size 0x4800 is not a multiple of 0x1000"
+        );
+    }
+
+    #[test]
+    fn check_region_zero_size() {
+        let pstore = test_pstore(None, 0);
+        let region = AddressRange {
+            start: 0x10_0000,
+            end: 0x10_3fff,
+        };
+        assert_eq!(
+            check_region(&region, &pstore).unwrap_err().to_string(),
+            "pstore size must not be zero"
+        );
+    }
+
+    fn test_ramoops_region(ecc: u32) -> RamoopsRegion {
+        RamoopsRegion {
+            address: 0x10_0000,
+            size: 0x4000,
+            record_size: 0x1000,
+            console_size: 0x1000,
+            ftrace_size: 0,
+            pmsg_size: 0,
+            ecc,
+            preserved: false,
+        }
+    }
+
+    #[test]
+    fn ramoops_kernel_cmdline_ecc() {
+        let mut cmdline = kernel_cmdline::Cmdline::new(512);
+        add_ramoops_kernel_cmdline(&mut cmdline, &test_ramoops_region(0)).unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            "ramoops.mem_address=0x100000 ramoops.mem_size=0x4000 \
+             ramoops.record_size=0x1000 ramoops.console_size=0x1000"
+        );
+
+        let mut cmdline = kernel_cmdline::Cmdline::new(512);
+        add_ramoops_kernel_cmdline(&mut cmdline, &test_ramoops_region(16)).unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            "ramoops.mem_address=0x100000 ramoops.mem_size=0x4000 \
+             ramoops.record_size=0x1000 ramoops.console_size=0x1000 ramoops.ecc=16"
+        );
+    }
+
+    #[test]
+    fn ramoops_pmsg() {
+        let mut region = test_ramoops_region(0);
+        assert_eq!(region.pmsg_address(), None);
+
+        region.pmsg_size = 0x1000;
+        assert_eq!(region.pmsg_address(), Some(0x10_3000));
+        let mut cmdline = kernel_cmdline::Cmdline::new(512);
+        add_ramoops_kernel_cmdline(&mut cmdline, &region).unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            "ramoops.mem_address=0x100000 ramoops.mem_size=0x4000 \
+             ramoops.record_size=0x1000 ramoops.console_size=0x1000 ramoops.pmsg_size=0x1000"
+        );
+    }
+
+    #[test]
+    fn write_compressed_round_trip() {
+        let mut contents = vec![0u8; 0x4000];
+        contents[0x100..0x104].copy_from_slice(b"oops");
+
+        let mut compressed = Vec::new();
+        write_compressed(&contents, &mut compressed).unwrap();
+        assert!(compressed.len() < contents.len());
+
+        assert_eq!(decompress(&compressed), contents);
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(compressed)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn dump_compressed_reads_vm_memory() {
+        use hypervisor::kvm::Kvm;
+        use hypervisor::kvm::KvmVm;
+        use vm_memory::GuestMemory;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pstore");
+        let (mut file, _) = open_pstore_file(&path, 0x4000).unwrap();
+        file.seek(SeekFrom::Start(0x100)).unwrap();
+        file.write_all(b"oops").unwrap();
+        drop(file);
+
+        let kvm = Kvm::new().unwrap();
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).unwrap();
+        let mut region = create_memory_region(
+            &mut vm,
+            AddressRange {
+                start: 0x10_0000,
+                end: 0x10_3fff,
+            },
+            &test_pstore(Some(path), 0x4000),
+        )
+        .unwrap();
+        assert!(region.preserved);
+
+        let mut expected = vec![0u8; 0x4000];
+        expected[0x100..0x104].copy_from_slice(b"oops");
+        let mut compressed = Vec::new();
+        dump_compressed(&region, &vm, &mut compressed).unwrap();
+        assert_eq!(decompress(&compressed), expected);
+
+        // A region reaching past the mapping is cut off at the end of the mapping.
+        region.size = 0x8000;
+        let mut compressed = Vec::new();
+        dump_compressed(&region, &vm, &mut compressed).unwrap();
+        assert_eq!(decompress(&compressed), expected);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn create_memory_region_anonymous() {
+        use hypervisor::kvm::Kvm;
+        use hypervisor::kvm::KvmVm;
+        use vm_memory::GuestMemory;
+
+        let kvm = Kvm::new().unwrap();
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).unwrap();
+        let pstore = test_pstore(None, 0x4000);
+
+        let region = create_memory_region(
+            &mut vm,
+            AddressRange {
+                start: 0x10_0000,
+                end: 0x10_3fff,
+            },
+            &pstore,
+        )
+        .unwrap();
+        assert_eq!(region.address, 0x10_0000);
+        assert_eq!(region.size, 0x4000);
+        assert!(!region.preserved);
+        assert_eq!(region.ecc, 0);
+
+        // The region takes the first slot after guest memory.
+        let mem = vm.remove_memory_region(1).unwrap();
+        assert_eq!(mem.size(), 0x4000);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn create_memory_region_ecc_too_large() {
+        use hypervisor::kvm::Kvm;
+        use hypervisor::kvm::KvmVm;
+        use vm_memory::GuestMemory;
+
+        let kvm = Kvm::new().unwrap();
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).unwrap();
+        let mut pstore = test_pstore(None, 0x4000);
+        pstore.record_size = Some(0x100);
+        pstore.ecc = Some(0x100);
+        let region = AddressRange {
+            start: 0x10_0000,
+            end: 0x10_3fff,
+        };
+
+        assert_eq!(
+            create_memory_region(&mut vm, region, &pstore)
+                .err()
+                .unwrap()
+                .to_string(),
+            "pstore ecc size 256 leaves no room in the record size 256"
+        );
+
+        pstore.ecc = Some(16);
+        let region = create_memory_region(&mut vm, region, &pstore).unwrap();
+        assert_eq!(region.ecc, 16);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn create_memory_region_pmsg() {
+        use hypervisor::kvm::Kvm;
+        use hypervisor::kvm::KvmVm;
+        use vm_memory::GuestMemory;
+
+        let kvm = Kvm::new().unwrap();
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).unwrap();
+        let mut pstore = test_pstore(None, 0x4000);
+        pstore.pmsg_size = Some(0x1800);
+        let region = AddressRange {
+            start: 0x10_0000,
+            end: 0x10_3fff,
+        };
+
+        assert_eq!(
+            create_memory_region(&mut vm, region, &pstore)
+                .err()
+                .unwrap()
+                .to_string(),
+            "pstore pmsg size 0x1800 is not a power of two"
+        );
+
+        // The record and console sections take half the region, so a larger pmsg doesn't fit.
+        pstore.pmsg_size = Some(0x4000);
+        assert!(create_memory_region(&mut vm, region, &pstore).is_err());
+
+        pstore.pmsg_size = Some(0x1000);
+        let ramoops = create_memory_region(&mut vm, region, &pstore).unwrap();
+        assert_eq!(ramoops.pmsg_size, 0x1000);
+        assert_eq!(ramoops.pmsg_address(), Some(0x10_3000));
+        let mut cmdline = kernel_cmdline::Cmdline::new(512);
+        add_ramoops_kernel_cmdline(&mut cmdline, &ramoops).unwrap();
+        assert!(cmdline.as_str().contains("ramoops.pmsg_size=0x1000"));
+    }
+}
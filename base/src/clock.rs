@@ -5,8 +5,13 @@
 // Utility file to provide a fake clock object representing current time, and a timer driven by
 // that time.
 
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use sync::Mutex;
 
 use crate::descriptor::AsRawDescriptor;
 use crate::Event;
@@ -30,6 +35,14 @@ pub struct FakeClock {
     epoch: Instant,
     ns_since_epoch: u64,
     deadlines: Vec<(u64, Event)>,
+    // Time spent suspended, which counts towards the boot time but not the monotonic time.
+    suspended_ns: u64,
+    boottime_deadlines: Vec<(u64, Event)>,
+    // Wall-clock time in ns since the unix epoch. It advances with `add_ns` and can also be
+    // stepped independently with `set_realtime`.
+    realtime_ns: u64,
+    realtime_steps: u64,
+    realtime_deadlines: Vec<(u64, Event)>,
 }
 
 impl FakeClock {
@@ -38,6 +51,14 @@ impl FakeClock {
             epoch: Instant::now(),
             ns_since_epoch: 0,
             deadlines: Vec::new(),
+            suspended_ns: 0,
+            boottime_deadlines: Vec::new(),
+            realtime_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            realtime_steps: 0,
+            realtime_deadlines: Vec::new(),
         }
     }
 
@@ -51,30 +72,161 @@ impl FakeClock {
         self.ns_since_epoch
     }
 
+    /// Get the time in ns including the time spent suspended, according to this clock.
+    pub fn boottime_nanos(&self) -> u64 {
+        self.ns_since_epoch + self.suspended_ns
+    }
+
+    /// Get the current wall-clock time, according to this clock.
+    pub fn realtime(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.realtime_ns)
+    }
+
+    /// Get the current wall-clock time in ns since the unix epoch, according to this clock.
+    pub fn realtime_nanos(&self) -> u64 {
+        self.realtime_ns
+    }
+
+    /// Get the number of times the wall-clock time has been stepped with `set_realtime`.
+    pub fn realtime_steps(&self) -> u64 {
+        self.realtime_steps
+    }
+
     /// Register the event descriptor for a notification when self's time is |deadline_ns|.
     /// Drop any existing events registered to the same raw descriptor.
     pub fn add_event(&mut self, deadline_ns: u64, descriptor: Event) {
-        self.deadlines.retain(|(_, old_descriptor)| {
-            descriptor.as_raw_descriptor() != old_descriptor.as_raw_descriptor()
-        });
+        self.remove_events(&descriptor);
         self.deadlines.push((deadline_ns, descriptor));
     }
 
+    /// Register the event descriptor for a notification when self's boot time is |deadline_ns|.
+    /// Drop any existing events registered to the same raw descriptor.
+    pub fn add_boottime_event(&mut self, deadline_ns: u64, descriptor: Event) {
+        self.remove_events(&descriptor);
+        self.boottime_deadlines.push((deadline_ns, descriptor));
+    }
+
+    /// Register the event descriptor for a notification when self's wall-clock time is
+    /// |deadline_ns| since the unix epoch, or when the wall-clock time is stepped.
+    /// Drop any existing events registered to the same raw descriptor.
+    pub fn add_realtime_event(&mut self, deadline_ns: u64, descriptor: Event) {
+        self.remove_events(&descriptor);
+        self.realtime_deadlines.push((deadline_ns, descriptor));
+    }
+
+    fn remove_events(&mut self, descriptor: &Event) {
+        let raw_descriptor = descriptor.as_raw_descriptor();
+        self.deadlines
+            .retain(|(_, old_descriptor)| raw_descriptor != old_descriptor.as_raw_descriptor());
+        self.boottime_deadlines
+            .retain(|(_, old_descriptor)| raw_descriptor != old_descriptor.as_raw_descriptor());
+        self.realtime_deadlines
+            .retain(|(_, old_descriptor)| raw_descriptor != old_descriptor.as_raw_descriptor());
+    }
+
+    /// Advance the time by `dur`, notifying every registered event whose deadline has been
+    /// reached. Events with later deadlines stay registered.
+    pub fn advance(&mut self, dur: Duration) {
+        self.add_ns(dur.as_nanos() as u64);
+    }
+
     pub fn add_ns(&mut self, ns: u64) {
         self.ns_since_epoch += ns;
-        let time = self.ns_since_epoch;
-        self.deadlines.retain(|(ns, descriptor)| {
-            let expired = *ns <= time;
-            if expired {
-                descriptor.signal().unwrap();
-            }
-            !expired
-        });
+        self.realtime_ns += ns;
+        signal_expired(&mut self.deadlines, self.ns_since_epoch);
+        signal_expired(&mut self.boottime_deadlines, self.boottime_nanos());
+        signal_expired(&mut self.realtime_deadlines, self.realtime_ns);
+    }
+
+    /// Simulate the host being suspended for `dur`: the boot time and the wall-clock time advance
+    /// but the monotonic time does not.
+    pub fn suspend(&mut self, dur: Duration) {
+        let ns = dur.as_nanos() as u64;
+        self.suspended_ns += ns;
+        self.realtime_ns += ns;
+        signal_expired(&mut self.boottime_deadlines, self.boottime_nanos());
+        signal_expired(&mut self.realtime_deadlines, self.realtime_ns);
+    }
+
+    /// Step the wall-clock time to `time` without advancing the monotonic time. Like a host
+    /// clock change, this notifies every registered wall-clock event whether or not its deadline
+    /// has been reached.
+    pub fn set_realtime(&mut self, time: SystemTime) {
+        self.realtime_ns = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        self.realtime_steps += 1;
+        for (_, descriptor) in self.realtime_deadlines.drain(..) {
+            descriptor.signal().unwrap();
+        }
     }
 }
 
+// Signals and drops the events in `deadlines` that are due by `time`.
+fn signal_expired(deadlines: &mut Vec<(u64, Event)>, time: u64) {
+    deadlines.retain(|(ns, descriptor)| {
+        let expired = *ns <= time;
+        if expired {
+            descriptor.signal().unwrap();
+        }
+        !expired
+    });
+}
+
 impl Default for FakeClock {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Measures elapsed time on the monotonic clock that `Timer` deadlines are based on.
+#[derive(Debug, Copy, Clone)]
+pub struct Stopwatch {
+    start: Instant,
+}
+
+impl Stopwatch {
+    /// Creates a stopwatch that starts measuring now.
+    pub fn start() -> Self {
+        Stopwatch {
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the time elapsed since the stopwatch was started or last reset.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Restarts the measurement from now.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+    }
+}
+
+/// A stopwatch that reads a `FakeClock`, for measuring elapsed time in code driven by a
+/// `FakeTimer` in tests.
+#[derive(Debug)]
+pub struct FakeStopwatch {
+    clock: Arc<Mutex<FakeClock>>,
+    start_ns: u64,
+}
+
+impl FakeStopwatch {
+    /// Creates a stopwatch that starts measuring at `clock`'s current time.
+    pub fn start(clock: Arc<Mutex<FakeClock>>) -> Self {
+        let start_ns = clock.lock().nanos();
+        FakeStopwatch { clock, start_ns }
+    }
+
+    /// Returns the time `clock` has advanced since the stopwatch was started or last reset.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.clock.lock().nanos() - self.start_ns)
+    }
+
+    /// Restarts the measurement from `clock`'s current time.
+    pub fn reset(&mut self) {
+        self.start_ns = self.clock.lock().nanos();
+    }
+}
@@ -170,6 +170,57 @@ impl FromRawDescriptor for Descriptor {
     }
 }
 
+/// Collects the raw descriptors of several objects, e.g. the set a device keeps open across
+/// sandboxing, dropping duplicates so that no descriptor is listed (and later closed) twice.
+///
+/// Descriptors are kept in the order they were first added.
+#[derive(Clone, Debug, Default)]
+pub struct RawDescriptorSet {
+    descriptors: Vec<RawDescriptor>,
+}
+
+impl RawDescriptorSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds all the descriptors of `rds` that are not already in the set.
+    pub fn add(mut self, rds: &(impl AsRawDescriptors + ?Sized)) -> Self {
+        for rd in rds.as_raw_descriptors() {
+            if !self.descriptors.contains(&rd) {
+                self.descriptors.push(rd);
+            }
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+
+    /// Removes all the descriptors from the set, returning them as an iterator.
+    pub fn drain(&mut self) -> std::vec::Drain<'_, RawDescriptor> {
+        self.descriptors.drain(..)
+    }
+
+    pub fn into_vec(self) -> Vec<RawDescriptor> {
+        self.descriptors
+    }
+}
+
+impl IntoIterator for RawDescriptorSet {
+    type Item = RawDescriptor;
+    type IntoIter = std::vec::IntoIter<RawDescriptor>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.descriptors.into_iter()
+    }
+}
+
 /// Implement token for implementations that wish to use this struct as such
 impl EventToken for Descriptor {
     fn as_raw_token(&self) -> u64 {
@@ -180,3 +231,34 @@ impl EventToken for Descriptor {
         Descriptor(data as RawDescriptor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Event;
+
+    #[test]
+    fn raw_descriptor_set_dedups() {
+        let a = Event::new().unwrap();
+        let b = Event::new().unwrap();
+        let rds = RawDescriptorSet::new()
+            .add(&a)
+            .add(&b)
+            .add(&a)
+            .add(&Descriptor(b.as_raw_descriptor()));
+        assert_eq!(rds.len(), 2);
+        assert_eq!(
+            rds.into_vec(),
+            vec![a.as_raw_descriptor(), b.as_raw_descriptor()]
+        );
+    }
+
+    #[test]
+    fn raw_descriptor_set_drain() {
+        let a = Event::new().unwrap();
+        let mut rds = RawDescriptorSet::new().add(&a);
+        assert_eq!(rds.drain().collect::<Vec<_>>(), vec![a.as_raw_descriptor()]);
+        assert!(rds.is_empty());
+        assert_eq!(rds.into_iter().count(), 0);
+    }
+}
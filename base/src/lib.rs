@@ -30,6 +30,8 @@ pub use alloc::LayoutAllocation;
 
 pub use clock::Clock;
 pub use clock::FakeClock;
+pub use clock::FakeStopwatch;
+pub use clock::Stopwatch;
 pub use errno::errno_result;
 pub use errno::Error;
 pub use errno::Result;
@@ -60,8 +62,19 @@ pub use platform::ioctl::IoctlNr;
 pub use shm::SharedMemory;
 use sys::platform;
 pub use timer::FakeTimer;
+pub use timer::InterruptHandle;
+pub use timer::RateLimiter;
+pub use timer::Schedule;
+pub use timer::ScheduleTimer;
+pub use timer::TimedEvent;
 pub use timer::Timer;
+pub use timer::TimerClock;
+pub use timer::TimerGroup;
+pub use timer::TimerId;
+pub use timer::TimerMetrics;
 pub use timer::TimerTrait;
+pub use timer::WaitResult;
+pub use timer::Watchdog;
 pub use tube::Error as TubeError;
 #[cfg(any(windows, feature = "proto_tube"))]
 pub use tube::ProtoTube;
@@ -198,6 +211,7 @@ pub use crate::descriptor::AsRawDescriptors;
 pub use crate::descriptor::Descriptor;
 pub use crate::descriptor::FromRawDescriptor;
 pub use crate::descriptor::IntoRawDescriptor;
+pub use crate::descriptor::RawDescriptorSet;
 pub use crate::descriptor::SafeDescriptor;
 
 /// An empty trait that helps reset timer resolution to its previous state.
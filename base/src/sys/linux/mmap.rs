@@ -54,7 +54,7 @@ fn validate_includes_range(mmap_size: usize, offset: usize, range_size: usize) -
     }
 }
 
-impl dyn MappedRegion {
+impl dyn MappedRegion + '_ {
     /// Calls msync with MS_SYNC on a mapping of `size` bytes starting at `offset` from the start of
     /// the region.  `offset`..`offset+size` must be contained within the `MappedRegion`.
     pub fn msync(&self, offset: usize, size: usize) -> Result<()> {
@@ -77,6 +77,34 @@ impl dyn MappedRegion {
             Err(Error::SystemCallFailed(ErrnoError::last()))
         }
     }
+
+    /// Locks the whole region in RAM with mlock so it is not paged out. Fails with ENOMEM or EPERM
+    /// if the region would exceed the process's `RLIMIT_MEMLOCK`.
+    pub fn lock(&self) -> Result<()> {
+        // SAFETY:
+        // Safe because mlock only affects the paging behavior of the kernel, and the pointer and
+        // size describe exactly the range owned by this `MappedRegion`.
+        let ret = unsafe { libc::mlock(self.as_ptr() as *const libc::c_void, self.size()) };
+        if ret != -1 {
+            Ok(())
+        } else {
+            Err(Error::SystemCallFailed(ErrnoError::last()))
+        }
+    }
+
+    /// Unlocks a region locked with `lock` so it can be paged out again. Unlocking a region that
+    /// is not locked does not fail.
+    pub fn unlock(&self) -> Result<()> {
+        // SAFETY:
+        // Safe because munlock only affects the paging behavior of the kernel, and the pointer and
+        // size describe exactly the range owned by this `MappedRegion`.
+        let ret = unsafe { libc::munlock(self.as_ptr() as *const libc::c_void, self.size()) };
+        if ret != -1 {
+            Ok(())
+        } else {
+            Err(Error::SystemCallFailed(ErrnoError::last()))
+        }
+    }
 }
 
 /// Wraps an anonymous shared memory mapping in the current process. Provides
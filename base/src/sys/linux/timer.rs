@@ -7,14 +7,24 @@ use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
 use std::ptr;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use libc::clock_getres;
 use libc::timerfd_create;
+use libc::timerfd_gettime;
 use libc::timerfd_settime;
+use libc::CLOCK_BOOTTIME;
 use libc::CLOCK_MONOTONIC;
+use libc::CLOCK_REALTIME;
 use libc::EAGAIN;
+use libc::ECANCELED;
+use libc::EINVAL;
 use libc::POLLIN;
 use libc::TFD_CLOEXEC;
+use libc::TFD_TIMER_ABSTIME;
+use once_cell::sync::OnceCell;
 
 use super::super::errno_result;
 use super::super::Error;
@@ -24,8 +34,11 @@ use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::FromRawDescriptor;
 use crate::descriptor::SafeDescriptor;
 use crate::handle_eintr_errno;
+use crate::timer::InterruptHandle;
 use crate::timer::Timer;
+use crate::timer::TimerClock;
 use crate::timer::TimerTrait;
+use crate::timer::WaitResult;
 
 impl AsRawFd for Timer {
     fn as_raw_fd(&self) -> RawFd {
@@ -33,13 +46,34 @@ impl AsRawFd for Timer {
     }
 }
 
+// Not exported by libc for all targets. Makes a CLOCK_REALTIME timer fail reads with ECANCELED
+// when the clock is changed discontinuously.
+const TFD_TIMER_CANCEL_ON_SET: libc::c_int = 1 << 1;
+
 impl Timer {
     /// Creates a new timerfd.  The timer is initally disarmed and must be armed by calling
     /// `reset`.
     pub fn new() -> Result<Timer> {
+        Self::new_with_clock(TimerClock::Monotonic)
+    }
+
+    /// Creates a new timerfd driven by the wall clock, which can also be armed with
+    /// `reset_realtime`. The timer is initally disarmed.
+    pub fn new_realtime() -> Result<Timer> {
+        Self::new_with_clock(TimerClock::Realtime)
+    }
+
+    /// Creates a new timerfd driven by `clock`. The timer is initally disarmed and must be armed
+    /// by calling `reset`.
+    pub fn new_with_clock(clock: TimerClock) -> Result<Timer> {
+        let clock_id = match clock {
+            TimerClock::Monotonic => CLOCK_MONOTONIC,
+            TimerClock::Boottime => CLOCK_BOOTTIME,
+            TimerClock::Realtime => CLOCK_REALTIME,
+        };
         // SAFETY:
         // Safe because this doesn't modify any memory and we check the return value.
-        let ret = unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_CLOEXEC) };
+        let ret = unsafe { timerfd_create(clock_id, TFD_CLOEXEC) };
         if ret < 0 {
             return errno_result();
         }
@@ -49,11 +83,23 @@ impl Timer {
             // Safe because we uniquely own the file descriptor.
             handle: unsafe { SafeDescriptor::from_raw_descriptor(ret) },
             interval: None,
+            realtime: clock == TimerClock::Realtime,
+            deadline: None,
+            metrics: Default::default(),
         })
     }
 
     // Calls `timerfd_settime()` and stores the new value of `interval`.
     fn set_time(&mut self, dur: Option<Duration>, interval: Option<Duration>) -> Result<()> {
+        self.set_time_with_flags(0, dur, interval)
+    }
+
+    fn set_time_with_flags(
+        &mut self,
+        flags: libc::c_int,
+        dur: Option<Duration>,
+        interval: Option<Duration>,
+    ) -> Result<()> {
         // The posix implementation of timer does not need self.interval, but we
         // save it anyways to keep a consistent interface.
         self.interval = interval;
@@ -65,30 +111,138 @@ impl Timer {
 
         // SAFETY:
         // Safe because this doesn't modify any memory and we check the return value.
-        let ret = unsafe { timerfd_settime(self.as_raw_descriptor(), 0, &spec, ptr::null_mut()) };
+        let ret =
+            unsafe { timerfd_settime(self.as_raw_descriptor(), flags, &spec, ptr::null_mut()) };
         if ret < 0 {
             return errno_result();
         }
 
         Ok(())
     }
+
+    // Reads the number of expirations since the last read. Returns `None` if the timer has not
+    // expired, and zero if the wall clock was changed under a `reset_realtime` timer.
+    fn read_expirations(&mut self) -> Result<Option<u64>> {
+        let mut count = 0u64;
+
+        // SAFETY:
+        // The timerfd is in non-blocking mode, so this should return immediately.
+        let ret = unsafe {
+            libc::read(
+                self.as_raw_descriptor(),
+                &mut count as *mut _ as *mut libc::c_void,
+                mem::size_of_val(&count),
+            )
+        };
+
+        if ret < 0 {
+            match Error::last().errno() {
+                EAGAIN => Ok(None),
+                // The wall clock was changed while a `reset_realtime` timer was armed. Report it as
+                // a wakeup without expirations so the caller can check the time and re-arm.
+                ECANCELED => Ok(Some(0)),
+                _ => errno_result(),
+            }
+        } else {
+            Ok(Some(count))
+        }
+    }
 }
 
 impl TimerTrait for Timer {
     fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()> {
-        self.set_time(Some(dur), interval)
+        self.set_time(Some(dur), interval)?;
+        self.deadline = Some(Instant::now() + dur);
+        Ok(())
+    }
+
+    fn reset_realtime(&mut self, deadline: SystemTime, interval: Option<Duration>) -> Result<()> {
+        if !self.realtime {
+            return Err(Error::new(EINVAL));
+        }
+        let since_epoch = deadline
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::new(EINVAL))?;
+        // A zero expiration would disarm the timer instead of firing it.
+        let since_epoch = std::cmp::max(since_epoch, Duration::from_nanos(1));
+        self.set_time_with_flags(
+            TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET,
+            Some(since_epoch),
+            interval,
+        )?;
+
+        let dur = deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        self.deadline = Some(Instant::now() + dur);
+        Ok(())
     }
 
     fn clear(&mut self) -> Result<()> {
-        self.set_time(None, None)
+        self.set_time(None, None)?;
+        self.deadline = None;
+        Ok(())
+    }
+
+    fn disarm_and_drain(&mut self) -> Result<u64> {
+        // Disarming a timerfd resets its expiration count, so read the count first. An expiration
+        // that lands between the read and the disarm is discarded along with it.
+        let pending = self.read_expirations()?.unwrap_or(0);
+        self.clear()?;
+        Ok(pending)
+    }
+
+    fn wait_interruptible(&mut self, interrupt: &InterruptHandle) -> Result<WaitResult> {
+        let mut pfds = [
+            libc::pollfd {
+                fd: self.as_raw_descriptor(),
+                events: POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: interrupt.as_raw_descriptor(),
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            // SAFETY:
+            // Safe because this only modifies |pfds| and we check the return value
+            let ret = handle_eintr_errno!(unsafe {
+                libc::ppoll(
+                    pfds.as_mut_ptr(),
+                    pfds.len() as libc::nfds_t,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            });
+
+            if ret < 0 {
+                return errno_result();
+            }
+
+            // Check for an interrupt first and leave any expiration unread, so that it is reported
+            // by the next wait.
+            if pfds[1].revents & POLLIN != 0 && interrupt.take_interrupt()? {
+                return Ok(WaitResult::Interrupted);
+            }
+            if pfds[0].revents & POLLIN != 0 {
+                return match self.read_expirations()? {
+                    Some(0) => Ok(WaitResult::Spurious),
+                    _ => Ok(WaitResult::Expired),
+                };
+            }
+        }
     }
 
-    fn wait(&mut self) -> Result<()> {
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<WaitResult> {
         let mut pfd = libc::pollfd {
             fd: self.as_raw_descriptor(),
             events: POLLIN,
             revents: 0,
         };
+        let timeoutspec = duration_to_timespec(timeout);
 
         // SAFETY:
         // Safe because this only modifies |pfd| and we check the return value
@@ -96,7 +250,7 @@ impl TimerTrait for Timer {
             libc::ppoll(
                 &mut pfd as *mut libc::pollfd,
                 1,
-                ptr::null_mut(),
+                &timeoutspec,
                 ptr::null_mut(),
             )
         });
@@ -104,53 +258,95 @@ impl TimerTrait for Timer {
         if ret < 0 {
             return errno_result();
         }
+        if ret == 0 {
+            return Ok(WaitResult::Timeout);
+        }
 
-        // EAGAIN is a valid error in the case where another thread has called timerfd_settime
-        // in between this thread calling ppoll and read. Since the ppoll returned originally
-        // without any revents it means the timer did expire, so we treat this as a
-        // WaitResult::Expired.
-        let _ = self.mark_waited()?;
-
-        Ok(())
+        // As in `wait_count`, EAGAIN means another thread re-armed the timer after ppoll saw it
+        // expire, which still counts as an expiration.
+        match self.read_expirations()? {
+            Some(0) => Ok(WaitResult::Spurious),
+            _ => Ok(WaitResult::Expired),
+        }
     }
 
-    fn mark_waited(&mut self) -> Result<bool> {
-        let mut count = 0u64;
+    fn wait_count(&mut self) -> Result<u64> {
+        let mut pfd = libc::pollfd {
+            fd: self.as_raw_descriptor(),
+            events: POLLIN,
+            revents: 0,
+        };
 
         // SAFETY:
-        // The timerfd is in non-blocking mode, so this should return immediately.
-        let ret = unsafe {
-            libc::read(
-                self.as_raw_descriptor(),
-                &mut count as *mut _ as *mut libc::c_void,
-                mem::size_of_val(&count),
+        // Safe because this only modifies |pfd| and we check the return value
+        let ret = handle_eintr_errno!(unsafe {
+            libc::ppoll(
+                &mut pfd as *mut libc::pollfd,
+                1,
+                ptr::null_mut(),
+                ptr::null_mut(),
             )
-        };
+        });
 
         if ret < 0 {
-            if Error::last().errno() == EAGAIN {
-                Ok(true)
-            } else {
-                errno_result()
-            }
-        } else {
-            Ok(false)
+            return errno_result();
         }
+
+        // EAGAIN is a valid error in the case where another thread has called timerfd_settime
+        // in between this thread calling ppoll and read. Since the ppoll returned originally
+        // without any revents it means the timer did expire, so we treat this as a
+        // single expiration.
+        let count = self.read_expirations()?.unwrap_or(1);
+        self.record_expirations(count, Instant::now());
+        Ok(count)
+    }
+
+    fn mark_waited(&mut self) -> Result<bool> {
+        Ok(self.read_expirations()?.is_none())
     }
 
     fn resolution(&self) -> Result<Duration> {
+        // The resolution of CLOCK_MONOTONIC does not change while the process runs, so only ask
+        // the kernel once.
+        static RESOLUTION: OnceCell<Duration> = OnceCell::new();
+
+        RESOLUTION
+            .get_or_try_init(|| {
+                // SAFETY:
+                // Safe because we are zero-initializing a struct with only primitive member fields.
+                let mut res: libc::timespec = unsafe { mem::zeroed() };
+
+                // SAFETY:
+                // Safe because it only modifies a local struct and we check the return value.
+                let ret = unsafe { clock_getres(CLOCK_MONOTONIC, &mut res) };
+
+                if ret != 0 {
+                    return errno_result();
+                }
+
+                Ok(Duration::new(res.tv_sec as u64, res.tv_nsec as u32))
+            })
+            .copied()
+    }
+
+    fn remaining(&self) -> Result<Option<Duration>> {
         // SAFETY:
         // Safe because we are zero-initializing a struct with only primitive member fields.
-        let mut res: libc::timespec = unsafe { mem::zeroed() };
+        let mut spec: libc::itimerspec = unsafe { mem::zeroed() };
 
         // SAFETY:
         // Safe because it only modifies a local struct and we check the return value.
-        let ret = unsafe { clock_getres(CLOCK_MONOTONIC, &mut res) };
-
-        if ret != 0 {
+        let ret = unsafe { timerfd_gettime(self.as_raw_descriptor(), &mut spec) };
+        if ret < 0 {
             return errno_result();
         }
 
-        Ok(Duration::new(res.tv_sec as u64, res.tv_nsec as u32))
+        let value = Duration::new(spec.it_value.tv_sec as u64, spec.it_value.tv_nsec as u32);
+        // A zero value means the timer is disarmed.
+        if value.is_zero() {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
     }
 }
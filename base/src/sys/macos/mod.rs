@@ -327,7 +327,20 @@ impl crate::TimerTrait for crate::Timer {
     ) -> crate::errno::Result<()> {
         todo!();
     }
-    fn wait(&mut self) -> crate::errno::Result<()> {
+    fn reset_realtime(
+        &mut self,
+        _deadline: std::time::SystemTime,
+        _interval: Option<std::time::Duration>,
+    ) -> crate::errno::Result<()> {
+        todo!();
+    }
+    fn wait_interruptible(
+        &mut self,
+        _interrupt: &crate::InterruptHandle,
+    ) -> crate::errno::Result<crate::WaitResult> {
+        todo!();
+    }
+    fn wait_count(&mut self) -> crate::errno::Result<u64> {
         todo!();
     }
     fn mark_waited(&mut self) -> crate::errno::Result<bool> {
@@ -336,9 +349,15 @@ impl crate::TimerTrait for crate::Timer {
     fn clear(&mut self) -> crate::errno::Result<()> {
         todo!();
     }
+    fn disarm_and_drain(&mut self) -> crate::errno::Result<u64> {
+        todo!();
+    }
     fn resolution(&self) -> crate::errno::Result<std::time::Duration> {
         todo!();
     }
+    fn remaining(&self) -> crate::errno::Result<Option<std::time::Duration>> {
+        todo!();
+    }
 }
 
 pub(crate) use libc::off_t;
@@ -35,7 +35,7 @@ fn validate_includes_range(mmap_size: usize, offset: usize, range_size: usize) -
     }
 }
 
-impl dyn MappedRegion {
+impl dyn MappedRegion + '_ {
     /// Calls msync with MS_SYNC on a mapping of `size` bytes starting at `offset` from the start of
     /// the region.  `offset`..`offset+size` must be contained within the `MappedRegion`.
     pub fn msync(&self, offset: usize, size: usize) -> Result<()> {
@@ -59,6 +59,38 @@ impl dyn MappedRegion {
             Err(Error::SystemCallFailed(super::Error::last()))
         }
     }
+
+    /// Locks the whole region in RAM with VirtualLock so it is not paged out. Fails if the region
+    /// doesn't fit in the process's minimum working set size.
+    pub fn lock(&self) -> Result<()> {
+        // SAFETY:
+        // Safe because VirtualLock only affects the paging behavior of the system, and the pointer
+        // and size describe exactly the range owned by this `MappedRegion`.
+        let ret = unsafe {
+            winapi::um::memoryapi::VirtualLock(self.as_ptr() as *mut libc::c_void, self.size())
+        };
+        if ret != 0 {
+            Ok(())
+        } else {
+            Err(Error::SystemCallFailed(super::Error::last()))
+        }
+    }
+
+    /// Unlocks a region locked with `lock` so it can be paged out again. Unlike munlock, this
+    /// fails if no page of the region is locked.
+    pub fn unlock(&self) -> Result<()> {
+        // SAFETY:
+        // Safe because VirtualUnlock only affects the paging behavior of the system, and the
+        // pointer and size describe exactly the range owned by this `MappedRegion`.
+        let ret = unsafe {
+            winapi::um::memoryapi::VirtualUnlock(self.as_ptr() as *mut libc::c_void, self.size())
+        };
+        if ret != 0 {
+            Ok(())
+        } else {
+            Err(Error::SystemCallFailed(super::Error::last()))
+        }
+    }
 }
 
 /// Wraps an anonymous shared memory mapping in the current process. Provides
@@ -6,13 +6,19 @@ use std::os::windows::io::AsRawHandle;
 use std::os::windows::io::RawHandle;
 use std::ptr;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use libc::EINVAL;
 use win_util::LargeInteger;
 use win_util::SecurityAttributes;
 use win_util::SelfRelativeSecurityDescriptor;
 use winapi::shared::minwindef::FALSE;
+use winapi::shared::winerror::WAIT_TIMEOUT;
 use winapi::um::synchapi::CancelWaitableTimer;
 use winapi::um::synchapi::SetWaitableTimer;
+use winapi::um::synchapi::WaitForMultipleObjects;
 use winapi::um::synchapi::WaitForSingleObject;
 use winapi::um::winbase::CreateWaitableTimerA;
 use winapi::um::winbase::INFINITE;
@@ -20,12 +26,16 @@ use winapi::um::winbase::WAIT_OBJECT_0;
 
 use super::errno_result;
 use super::platform_timer_utils::nt_query_timer_resolution;
+use super::Error;
 use super::Result;
 use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::FromRawDescriptor;
 use crate::descriptor::SafeDescriptor;
+use crate::timer::InterruptHandle;
 use crate::timer::Timer;
+use crate::timer::TimerClock;
 use crate::timer::TimerTrait;
+use crate::timer::WaitResult;
 
 impl AsRawHandle for Timer {
     fn as_raw_handle(&self) -> RawHandle {
@@ -65,12 +75,25 @@ impl Timer {
             // Safe because we uniquely own the file descriptor.
             handle: unsafe { SafeDescriptor::from_raw_descriptor(handle) },
             interval: None,
+            deadline: None,
+            metrics: Default::default(),
         })
     }
-}
 
-impl TimerTrait for Timer {
-    fn reset(&mut self, dur: Duration, mut interval: Option<Duration>) -> Result<()> {
+    /// Creates a new timer for `clock`. Waitable timers have no choice of clock, so this creates
+    /// the same timer as `new` for every clock: relative due times are measured on the interrupt
+    /// time, which keeps advancing while the host is suspended like `Boottime`, and
+    /// `reset_realtime` arms any timer with an absolute wall-clock due time.
+    pub fn new_with_clock(_clock: TimerClock) -> Result<Timer> {
+        Self::new()
+    }
+
+    // Arms the timer with a `SetWaitableTimer` due time and stores the new value of `interval`.
+    fn set_waitable_timer(
+        &mut self,
+        due_time: &LargeInteger,
+        mut interval: Option<Duration>,
+    ) -> Result<()> {
         // If interval is 0 or None it means that this timer does not repeat. We
         // set self.interval to None in this case so it can easily be checked
         // in self.wait.
@@ -78,15 +101,6 @@ impl TimerTrait for Timer {
             interval = None;
         }
         self.interval = interval;
-        // Windows timers use negative values for relative times, and positive
-        // values for absolute times, so we'll use negative times.
-
-        // Windows timers also use a 64 number of 100 nanosecond intervals,
-        // which we get like so: (dur.as_secs()*1e7 + dur.subsec_nanos()/100)
-
-        let due_time = LargeInteger::new(
-            -((dur.as_secs() * 10_000_000 + (dur.subsec_nanos() as u64) / 100) as i64),
-        );
         let period: i32 = match interval {
             Some(int) => {
                 if int.is_zero() {
@@ -107,7 +121,7 @@ impl TimerTrait for Timer {
         let ret = unsafe {
             SetWaitableTimer(
                 self.as_raw_descriptor(),
-                &*due_time,
+                &**due_time,
                 period,
                 None,            // no completion routine
                 ptr::null_mut(), // or routine argument
@@ -117,19 +131,101 @@ impl TimerTrait for Timer {
         if ret == 0 {
             return errno_result();
         }
+        Ok(())
+    }
+}
+
+impl TimerTrait for Timer {
+    fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()> {
+        // Windows timers use negative values for relative times, and positive
+        // values for absolute times, so we'll use negative times.
+
+        // Windows timers also use a 64 number of 100 nanosecond intervals,
+        // which we get like so: (dur.as_secs()*1e7 + dur.subsec_nanos()/100)
+
+        let due_time = LargeInteger::new(
+            -((dur.as_secs() * 10_000_000 + (dur.subsec_nanos() as u64) / 100) as i64),
+        );
+        self.set_waitable_timer(&due_time, interval)?;
+
+        self.deadline = Some(Instant::now() + dur);
+        Ok(())
+    }
+
+    fn reset_realtime(&mut self, deadline: SystemTime, interval: Option<Duration>) -> Result<()> {
+        // Positive due times are absolute, in 100 nanosecond intervals since January 1, 1601 (UTC).
+        // Windows adjusts absolute timers when the system time changes, so unlike Linux a clock
+        // step does not fire the timer early.
+        const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+        let since_epoch = deadline
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::new(EINVAL))?;
+        let due_time = LargeInteger::new(
+            (FILETIME_UNIX_EPOCH
+                + since_epoch.as_secs() * 10_000_000
+                + (since_epoch.subsec_nanos() as u64) / 100) as i64,
+        );
+        self.set_waitable_timer(&due_time, interval)?;
 
+        let now = SystemTime::now();
+        let dur = deadline.duration_since(now).unwrap_or_default();
+        self.deadline = Some(Instant::now() + dur);
         Ok(())
     }
 
-    fn wait(&mut self) -> Result<()> {
+    fn wait_interruptible(&mut self, interrupt: &InterruptHandle) -> Result<WaitResult> {
+        let handles = [self.as_raw_descriptor(), interrupt.as_raw_descriptor()];
+        loop {
+            // SAFETY:
+            // Safe because this doesn't modify any memory and we check the return value.
+            let ret = unsafe {
+                WaitForMultipleObjects(handles.len() as u32, handles.as_ptr(), FALSE, INFINITE)
+            };
+
+            // The timer is checked first, so an expiration that raced with an interrupt is
+            // reported and the interrupt stays pending for the next wait.
+            match ret {
+                WAIT_OBJECT_0 => return Ok(WaitResult::Expired),
+                r if r == WAIT_OBJECT_0 + 1 => {
+                    if interrupt.take_interrupt()? {
+                        return Ok(WaitResult::Interrupted);
+                    }
+                }
+                _ => return errno_result(),
+            }
+        }
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<WaitResult> {
+        // Round up so that a sub-millisecond timeout still waits, and stay below INFINITE.
+        let milliseconds = std::cmp::min(
+            timeout.as_nanos().div_ceil(1_000_000),
+            (INFINITE - 1) as u128,
+        ) as u32;
+
+        // SAFETY:
+        // Safe because this doesn't modify any memory and we check the return value.
+        let ret = unsafe { WaitForSingleObject(self.as_raw_descriptor(), milliseconds) };
+        match ret {
+            WAIT_OBJECT_0 => Ok(WaitResult::Expired),
+            WAIT_TIMEOUT => Ok(WaitResult::Timeout),
+            _ => errno_result(),
+        }
+    }
+
+    fn wait_count(&mut self) -> Result<u64> {
         // SAFETY:
         // Safe because this doesn't modify any memory and we check the return value.
         let ret = unsafe { WaitForSingleObject(self.as_raw_descriptor(), INFINITE) };
 
         // Should return WAIT_OBJECT_0, otherwise it's some sort of error or
-        // timeout (which shouldn't happen in this case).
+        // timeout (which shouldn't happen in this case). Waitable timers don't count missed
+        // periods, so each wait is a single expiration.
         match ret {
-            WAIT_OBJECT_0 => Ok(()),
+            WAIT_OBJECT_0 => {
+                self.record_expirations(1, Instant::now());
+                Ok(1)
+            }
             _ => errno_result(),
         }
     }
@@ -150,10 +246,47 @@ impl TimerTrait for Timer {
         }
 
         self.interval = None;
+        self.deadline = None;
         Ok(())
     }
 
+    fn disarm_and_drain(&mut self) -> Result<u64> {
+        self.clear()?;
+        // Cancelling leaves an expired timer signaled. It is a synchronization timer, so a zero
+        // timeout wait resets it.
+        // SAFETY:
+        // Safe because this doesn't modify any memory and we check the return value.
+        let ret = unsafe { WaitForSingleObject(self.as_raw_descriptor(), 0) };
+        match ret {
+            WAIT_OBJECT_0 => Ok(1),
+            WAIT_TIMEOUT => Ok(0),
+            _ => errno_result(),
+        }
+    }
+
     fn resolution(&self) -> Result<Duration> {
         nt_query_timer_resolution().map(|(current_res, _)| current_res)
     }
+
+    fn remaining(&self) -> Result<Option<Duration>> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(None),
+        };
+        let now = Instant::now();
+        if let Some(remaining) = deadline.checked_duration_since(now) {
+            return Ok(Some(remaining));
+        }
+        match self.interval {
+            Some(interval) => {
+                let interval_ns = interval.as_nanos();
+                let since_last = (now - deadline).as_nanos() % interval_ns;
+                Ok(Some(Duration::from_nanos(
+                    (interval_ns - since_last) as u64,
+                )))
+            }
+            // A one-shot timer that has already fired is disarmed.
+            None => Ok(None),
+        }
+    }
 }
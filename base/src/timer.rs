@@ -2,32 +2,135 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use sync::Mutex;
 
+use super::Error;
 use super::Event;
 use super::EventWaitResult;
 use super::FakeClock;
 use super::RawDescriptor;
 use super::Result;
+use crate::debug;
 use crate::descriptor::AsRawDescriptor;
 use crate::descriptor::FromRawDescriptor;
 use crate::descriptor::IntoRawDescriptor;
 use crate::descriptor::SafeDescriptor;
+use crate::error;
 
 /// A trait for timer objects that delivers timer expiration
 /// notifications to an underlying descriptor.
+///
+/// The descriptor returned by `as_raw_descriptor` becomes readable (signaled on Windows) when the
+/// timer expires, so a timer can be added to a `WaitContext` or another reactor like any other
+/// descriptor. For a `FakeTimer` this happens when its `FakeClock` is advanced past the deadline.
+/// Once the descriptor is reported ready, `mark_waited` drains it without blocking; otherwise it
+/// stays ready.
 pub trait TimerTrait: AsRawDescriptor + IntoRawDescriptor + Send {
     /// Sets the timer to expire after `dur`.  If `interval` is not `None` and non-zero it
     /// represents the period for repeated expirations after the initial expiration.  Otherwise
     /// the timer will expire just once.  Cancels any existing duration and repeating interval.
     fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()>;
 
+    /// Like `reset`, but first raises a non-zero `dur` or `interval` that is shorter than
+    /// `resolution()` to one resolution tick, so that asking for an expiration "soon" cannot be
+    /// rounded down to zero and disarm the timer.
+    fn reset_clamped(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()> {
+        let resolution = self.resolution()?;
+        let dur = clamp_to_resolution(dur, resolution);
+        let interval = interval.map(|interval| clamp_to_resolution(interval, resolution));
+        self.reset(dur, interval)
+    }
+
+    /// Sets the timer to expire once at `deadline`. If `deadline` has already passed, the timer
+    /// expires as soon as possible. Cancels any existing duration and repeating interval.
+    fn set_deadline(&mut self, deadline: Instant) -> Result<()> {
+        self.reset(duration_until(deadline, Instant::now()), None)
+    }
+
+    /// Makes the timer expire as soon as possible, as if it had been armed with a deadline that
+    /// just passed. Cancels any existing duration and repeating interval.
+    fn expire_now(&mut self) -> Result<()> {
+        // The smallest non-zero duration, since a zero duration disarms the timer.
+        self.reset(Duration::from_nanos(1), None)
+    }
+
+    /// Sets the timer to expire at the wall-clock time `deadline`. If `interval` is not `None` and
+    /// non-zero it represents the period for repeated expirations after the initial expiration.
+    /// Cancels any existing duration and repeating interval.
+    ///
+    /// Unlike `reset`, the deadline follows changes to the host's wall clock. On Linux the host
+    /// clock being stepped (including backwards) fires the timer early, so callers should check
+    /// the current time after each expiration and re-arm the timer if the deadline has not been
+    /// reached. On Linux the timer must have been created with `Timer::new_realtime`.
+    fn reset_realtime(&mut self, deadline: SystemTime, interval: Option<Duration>) -> Result<()>;
+
     /// Waits until the timer expires.
-    fn wait(&mut self) -> Result<()>;
+    fn wait(&mut self) -> Result<()> {
+        self.wait_count().map(|_| ())
+    }
+
+    /// Returns a handle that interrupts `wait_interruptible` calls on this timer. The handle may be
+    /// moved to another thread, for example to wake a worker blocked on the timer at shutdown.
+    fn arm_interrupt(&self) -> Result<InterruptHandle> {
+        Ok(InterruptHandle::new(Event::new()?))
+    }
+
+    /// Waits until the timer expires or `interrupt`, which must have been returned by this timer's
+    /// `arm_interrupt`, is signaled. An interrupted wait leaves the timer's state unchanged: it
+    /// stays armed and an expiration that raced with the interrupt is reported by the next wait.
+    ///
+    /// # Returns
+    ///
+    /// - `WaitResult::Expired` if the timer expired.
+    /// - `WaitResult::Interrupted` if `interrupt` was signaled.
+    /// - `WaitResult::Spurious` if the timer woke up without expiring.
+    fn wait_interruptible(&mut self, interrupt: &InterruptHandle) -> Result<WaitResult>;
+
+    /// Waits until the timer expires or `timeout` passes, whichever comes first, so a thread
+    /// servicing a timer that may never fire can still wake up, for example to check for shutdown.
+    ///
+    /// # Returns
+    ///
+    /// - `WaitResult::Expired` if the timer expired.
+    /// - `WaitResult::Timeout` if the timer did not expire within `timeout`.
+    /// - `WaitResult::Spurious` if the timer woke up without expiring.
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<WaitResult>;
+
+    /// Waits until the timer expires and returns the number of expirations since the timer was
+    /// last waited on, which is greater than one if a repeating timer fell behind. Zero means the
+    /// wait ended without an expiration, such as when the wall clock is stepped under a
+    /// `reset_realtime` timer. Windows coalesces missed expirations, so it always reports one.
+    fn wait_count(&mut self) -> Result<u64>;
+
+    /// Waits for the next tick of a periodic task that runs every `interval`, arming the timer to
+    /// repeat with that period first if it is disarmed. Returns the number of ticks since the last
+    /// wait. Ticks missed because the task ran longer than `interval` are dropped, not delivered as
+    /// a burst, and the timer keeps the phase it was armed with so the cadence does not drift.
+    fn wait_rearm(&mut self, interval: Duration) -> Result<u64> {
+        if !self.is_armed() {
+            self.reset(interval, Some(interval))?;
+        }
+        let count = self.wait_count()?;
+        if count > 1 {
+            debug!(
+                "periodic timer fell behind, dropped {} of {} ticks",
+                count - 1,
+                count
+            );
+        }
+        Ok(count)
+    }
 
     /// After a timer is triggered from an EventContext, mark the timer as having been waited for.
     /// If a timer is not marked waited, it will immediately trigger the event context again. This
@@ -40,16 +143,78 @@ pub trait TimerTrait: AsRawDescriptor + IntoRawDescriptor + Send {
     /// Disarms the timer.
     fn clear(&mut self) -> Result<()>;
 
+    /// Disarms the timer and discards any expirations that have not been waited on yet, so that
+    /// stopping a repeating timer can't leave a stale tick that makes the next wait return at
+    /// once. Returns the number of discarded expirations, which like `wait_count` is at most one
+    /// on Windows.
+    fn disarm_and_drain(&mut self) -> Result<u64>;
+
     /// Returns the resolution of timers on the host.
     fn resolution(&self) -> Result<Duration>;
+
+    /// Returns the time left until the timer next expires, or `None` if the timer is disarmed.
+    fn remaining(&self) -> Result<Option<Duration>>;
+
+    /// Returns true if the timer will expire in the future. A one-shot timer is no longer armed
+    /// once it has expired, even if the expiration has not been waited on yet.
+    fn is_armed(&self) -> bool {
+        matches!(self.remaining(), Ok(Some(_)))
+    }
 }
 
 pub struct Timer {
     pub(crate) handle: SafeDescriptor,
     pub(crate) interval: Option<Duration>,
+    // Whether the timerfd was created against CLOCK_REALTIME and so can be armed with
+    // `reset_realtime`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(crate) realtime: bool,
+    // The next expiration, recorded when the timer is armed and moved on by `wait_count`. Windows
+    // cannot query a waitable timer for its due time, so `remaining` is computed from it there.
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) metrics: TimerMetrics,
+}
+
+/// Counters describing how promptly a `Timer` has been delivering its expirations, for diagnosing
+/// jitter in timer-driven work. Only expirations returned by `TimerTrait::wait` and
+/// `TimerTrait::wait_count` are counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerMetrics {
+    /// Number of expirations.
+    pub fires: u64,
+    /// Sum over the waits of how long after its scheduled time the latest expiration was seen.
+    pub total_lateness: Duration,
+}
+
+/// Raises a non-zero `dur` shorter than `resolution` to `resolution`.
+fn clamp_to_resolution(dur: Duration, resolution: Duration) -> Duration {
+    if dur.is_zero() || dur >= resolution {
+        return dur;
+    }
+    debug!(
+        "clamping timer duration {:?} to resolution {:?}",
+        dur, resolution
+    );
+    resolution
+}
+
+/// Returns the time from `now` until `deadline`, saturating to the smallest non-zero duration if
+/// `deadline` is not after `now`. A zero duration would disarm the timer instead of firing it.
+fn duration_until(deadline: Instant, now: Instant) -> Duration {
+    deadline
+        .checked_duration_since(now)
+        .filter(|dur| !dur.is_zero())
+        .unwrap_or(Duration::from_nanos(1))
 }
 
 impl Timer {
+    /// Creates a new `Timer` that expires once after `dur`.
+    pub fn oneshot(dur: Duration) -> Result<Timer> {
+        let mut timer = Timer::new()?;
+        timer.reset(dur, None)?;
+        Ok(timer)
+    }
+
     /// Creates a new `Timer` instance that shares the same underlying `SafeDescriptor` as the
     /// existing `Timer` instance.
     pub fn try_clone(&self) -> std::result::Result<Timer, std::io::Error> {
@@ -58,19 +223,129 @@ impl Timer {
             .map(|handle| Timer {
                 handle,
                 interval: self.interval,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                realtime: self.realtime,
+                deadline: self.deadline,
+                metrics: self.metrics,
             })
             .map_err(|err| std::io::Error::from_raw_os_error(err.errno()))
     }
+
+    /// Returns true if `self` and `other` are handles to the same underlying timer, for example
+    /// because one was made from the other with `try_clone`. Handles are compared by the OS
+    /// object they refer to (kcmp on Linux, CompareObjectHandles on Windows), not by descriptor
+    /// number. On a Linux kernel without kcmp, a clone only matches its own descriptor.
+    pub fn same_timer(&self, other: &Timer) -> bool {
+        self.handle == other.handle
+    }
+
+    /// Returns the timer's expiration count and accumulated lateness.
+    pub fn metrics(&self) -> TimerMetrics {
+        self.metrics
+    }
+
+    // Records `count` expirations seen by a wait that returned at `now`, adding how late the
+    // latest of them was to the metrics, and moves `deadline` on to the next expiration.
+    pub(crate) fn record_expirations(&mut self, count: u64, now: Instant) {
+        if count == 0 {
+            return;
+        }
+        self.metrics.fires += count;
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let interval = self.interval.filter(|interval| !interval.is_zero());
+        let due = match interval {
+            Some(interval) => {
+                let periods =
+                    now.saturating_duration_since(deadline).as_nanos() / interval.as_nanos();
+                deadline + Duration::from_nanos((periods * interval.as_nanos()) as u64)
+            }
+            None => deadline,
+        };
+        self.metrics.total_lateness += now.saturating_duration_since(due);
+        self.deadline = interval.map(|interval| due + interval);
+    }
 }
 
-// This enum represents those two different retrun values from a "wait" call. Either the
-// timer will "expire", meaning it has reached it's duration, or the caller will time out
-// waiting for the timer to expire. If no timeout option is provieded to the wait call
-// then it can only return WaitResult::Expired or an error.
+/// The clock a `Timer` measures its durations against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimerClock {
+    /// A clock that does not advance while the host is suspended.
+    #[default]
+    Monotonic,
+    /// Like `Monotonic`, but keeps advancing while the host is suspended.
+    Boottime,
+    /// The host's wall clock. Required for `TimerTrait::reset_realtime` on Linux.
+    Realtime,
+}
+
+/// The different return values from a "wait" call. Either the timer will "expire", meaning it
+/// has reached its duration, the caller will time out waiting for the timer to expire, or the
+/// wait is interrupted through an `InterruptHandle`. If no timeout option is provided to the wait
+/// call then it can not return `WaitResult::Timeout`.
 #[derive(PartialEq, Eq, Debug)]
-enum WaitResult {
+pub enum WaitResult {
     Expired,
     Timeout,
+    Interrupted,
+    /// The timer woke the waiter without expiring, so the caller should check whether its
+    /// deadline has been reached and wait again if not. On Linux this happens to a timer armed
+    /// with `reset_realtime` when the wall clock is stepped. Windows timers never wake spuriously,
+    /// and a `FakeTimer` only does after `FakeClock::set_realtime` or
+    /// `FakeTimer::inject_spurious_wakeup`.
+    Spurious,
+}
+
+/// Interrupts a thread blocked in `TimerTrait::wait_interruptible`. An interrupt sent while no
+/// thread is waiting makes the next `wait_interruptible` call return immediately.
+pub struct InterruptHandle {
+    event: Event,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    fn new(event: Event) -> Self {
+        InterruptHandle {
+            event,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Interrupts the current or next `wait_interruptible` call on the timer.
+    pub fn interrupt(&self) -> Result<()> {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.event.signal()
+    }
+
+    /// Creates a new handle that interrupts the same timer.
+    pub fn try_clone(&self) -> Result<InterruptHandle> {
+        Ok(InterruptHandle {
+            event: self.event.try_clone()?,
+            interrupted: self.interrupted.clone(),
+        })
+    }
+
+    // Clears a pending interrupt, returning whether there was one. `interrupt` sets the flag
+    // before signaling, so resetting the event before taking the flag never discards the signal
+    // of an interrupt that is left pending, and a stale signal is cleared rather than waking every
+    // following wait.
+    pub(crate) fn take_interrupt(&self) -> Result<bool> {
+        self.event.reset()?;
+        let interrupted = self.interrupted.swap(false, Ordering::SeqCst);
+        // An interrupt that set the flag after the swap must still wake the next wait.
+        if self.interrupted.load(Ordering::SeqCst) {
+            self.event.signal()?;
+        }
+        Ok(interrupted)
+    }
+}
+
+impl AsRawDescriptor for InterruptHandle {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.event.as_raw_descriptor()
+    }
 }
 
 impl AsRawDescriptor for Timer {
@@ -84,6 +359,10 @@ impl FromRawDescriptor for Timer {
         Timer {
             handle: SafeDescriptor::from_raw_descriptor(handle),
             interval: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            realtime: false,
+            deadline: None,
+            metrics: TimerMetrics::default(),
         }
     }
 }
@@ -97,23 +376,88 @@ impl IntoRawDescriptor for Timer {
 /// FakeTimer: For use in tests.
 pub struct FakeTimer {
     clock: Arc<Mutex<FakeClock>>,
+    // The clock that durations passed to `reset` are measured against.
+    timer_clock: TimerClock,
     deadline_ns: Option<u64>,
+    // Wall-clock deadline in ns since the unix epoch, and the number of wall-clock steps seen
+    // when it was armed.
+    realtime_deadline_ns: Option<u64>,
+    realtime_steps: u64,
     interval: Option<Duration>,
+    resolution: Duration,
     event: Event,
+    fires: u64,
+    // Whether the next wait reports a spurious wakeup.
+    spurious: bool,
 }
 
 impl FakeTimer {
     /// Creates a new fake Timer.  The timer is initally disarmed and must be armed by calling
     /// `reset`.
     pub fn new(clock: Arc<Mutex<FakeClock>>) -> Self {
+        Self::new_with_clock(clock, TimerClock::Monotonic)
+    }
+
+    /// Creates a new fake Timer whose `reset` durations are measured against `timer_clock`. A
+    /// `Boottime` timer keeps counting through `FakeClock::suspend`. As with a timerfd, relative
+    /// durations on a `Realtime` timer are not affected by `FakeClock::set_realtime`. The timer is
+    /// initally disarmed.
+    pub fn new_with_clock(clock: Arc<Mutex<FakeClock>>, timer_clock: TimerClock) -> Self {
         FakeTimer {
             clock,
+            timer_clock,
             deadline_ns: None,
+            realtime_deadline_ns: None,
+            realtime_steps: 0,
             interval: None,
+            resolution: Duration::from_nanos(1),
             event: Event::new().unwrap(),
+            fires: 0,
+            spurious: false,
+        }
+    }
+
+    /// Creates a new `FakeTimer` driven by the same `FakeClock` and sharing the same event as the
+    /// existing one, so an expiry consumed through one of them is not seen by the other, as with
+    /// a cloned `Timer`. The clone starts out armed for a copy of the timer's current deadline;
+    /// re-arming either one afterwards doesn't affect the other.
+    pub fn try_clone(&self) -> Result<FakeTimer> {
+        Ok(FakeTimer {
+            clock: self.clock.clone(),
+            timer_clock: self.timer_clock,
+            deadline_ns: self.deadline_ns,
+            realtime_deadline_ns: self.realtime_deadline_ns,
+            realtime_steps: self.realtime_steps,
+            interval: self.interval,
+            resolution: self.resolution,
+            event: self.event.try_clone()?,
+            fires: 0,
+            spurious: self.spurious,
+        })
+    }
+
+    /// Makes the next wait on the timer return `WaitResult::Spurious` (or a count of zero from
+    /// `wait_count`) without affecting its deadline, to test how callers handle spurious wakeups.
+    pub fn inject_spurious_wakeup(&mut self) -> Result<()> {
+        self.spurious = true;
+        self.event.signal()
+    }
+
+    /// Returns the timer's metrics like `Timer::metrics`. Fake time only moves when the clock is
+    /// advanced, so expirations are never late.
+    pub fn metrics(&self) -> TimerMetrics {
+        TimerMetrics {
+            fires: self.fires,
+            total_lateness: Duration::ZERO,
         }
     }
 
+    /// Sets the resolution the timer reports, which is 1ns by default, to simulate a host with
+    /// coarser timers.
+    pub fn set_resolution(&mut self, resolution: Duration) {
+        self.resolution = resolution;
+    }
+
     /// Waits until the timer expires or an optional wait timeout expires, whichever happens first.
     ///
     /// # Returns
@@ -122,53 +466,165 @@ impl FakeTimer {
     /// - `WaitResult::Timeout` if `timeout` was not `None` and the timer did not expire within the
     ///   specified timeout period.
     fn wait_for(&mut self, timeout: Option<Duration>) -> Result<WaitResult> {
+        self.wait_count_for(timeout, None).map(|(result, _)| result)
+    }
+
+    /// Like `wait_for`, but also returns the number of expirations that elapsed, and returns
+    /// `WaitResult::Interrupted` once `interrupt` is signaled.
+    fn wait_count_for(
+        &mut self,
+        timeout: Option<Duration>,
+        interrupt: Option<&InterruptHandle>,
+    ) -> Result<(WaitResult, u64)> {
         let wait_start = Instant::now();
         loop {
             if let Some(timeout) = timeout {
-                let elapsed = Instant::now() - wait_start;
-                if let Some(remaining) = elapsed.checked_sub(timeout) {
-                    if let EventWaitResult::TimedOut = self.event.wait_timeout(remaining)? {
-                        return Ok(WaitResult::Timeout);
-                    }
-                } else {
-                    return Ok(WaitResult::Timeout);
+                // Once the timeout has passed this still polls the event, so a timer that already
+                // fired is reported as expired rather than timed out.
+                let remaining = timeout.saturating_sub(wait_start.elapsed());
+                if let EventWaitResult::TimedOut = self.event.wait_timeout(remaining)? {
+                    return Ok((WaitResult::Timeout, 0));
                 }
             } else {
                 self.event.wait()?;
             }
 
-            if let Some(deadline_ns) = &mut self.deadline_ns {
+            if let Some(interrupt) = interrupt {
+                if interrupt.take_interrupt()? {
+                    // The interrupt shares the timer's event, so the wakeup may also have come from
+                    // the clock. Signal the event again so that the next wait checks the deadline.
+                    self.event.signal()?;
+                    return Ok((WaitResult::Interrupted, 0));
+                }
+            }
+
+            if self.spurious {
+                self.spurious = false;
+                return Ok((WaitResult::Spurious, 0));
+            }
+
+            if let Some(deadline_ns) = self.deadline_ns {
+                let mut guard = self.clock.lock();
+                let now = self.clock_nanos(&guard);
+                if now >= deadline_ns {
+                    let (expirys, next_ns) = fake_expirations(deadline_ns, now, self.interval);
+                    self.deadline_ns = next_ns;
+                    if let Some(next_ns) = next_ns {
+                        self.add_clock_event(&mut guard, next_ns)?;
+                    }
+                    return Ok((WaitResult::Expired, expirys));
+                }
+            } else if let Some(deadline_ns) = self.realtime_deadline_ns {
                 let mut guard = self.clock.lock();
-                let now = guard.nanos();
-                if now >= *deadline_ns {
-                    let mut expirys = 0;
-                    if let Some(interval) = self.interval {
-                        let interval_ns = interval.as_nanos() as u64;
-                        if interval_ns > 0 {
-                            expirys += (now - *deadline_ns) / interval_ns;
-                            *deadline_ns += (expirys + 1) * interval_ns;
-                            guard.add_event(*deadline_ns, self.event.try_clone()?);
-                        }
+                let now = guard.realtime_nanos();
+                let stepped = guard.realtime_steps() != self.realtime_steps;
+                self.realtime_steps = guard.realtime_steps();
+                if now >= deadline_ns {
+                    let (expirys, next_ns) = fake_expirations(deadline_ns, now, self.interval);
+                    self.realtime_deadline_ns = next_ns;
+                    if let Some(next_ns) = next_ns {
+                        guard.add_realtime_event(next_ns, self.event.try_clone()?);
                     }
-                    return Ok(WaitResult::Expired);
+                    return Ok((WaitResult::Expired, expirys));
+                } else if stepped {
+                    // A clock step wakes the timer but leaves it armed for the same deadline.
+                    guard.add_realtime_event(deadline_ns, self.event.try_clone()?);
+                    return Ok((WaitResult::Spurious, 0));
                 }
             }
         }
     }
+
+    // Returns the current time in ns of the clock that `reset` deadlines are measured against.
+    fn clock_nanos(&self, clock: &FakeClock) -> u64 {
+        match self.timer_clock {
+            TimerClock::Monotonic | TimerClock::Realtime => clock.nanos(),
+            TimerClock::Boottime => clock.boottime_nanos(),
+        }
+    }
+
+    // Registers the timer's event for a notification when the `reset` clock reaches `deadline_ns`.
+    fn add_clock_event(&self, clock: &mut FakeClock, deadline_ns: u64) -> Result<()> {
+        let event = self.event.try_clone()?;
+        match self.timer_clock {
+            TimerClock::Monotonic | TimerClock::Realtime => clock.add_event(deadline_ns, event),
+            TimerClock::Boottime => clock.add_boottime_event(deadline_ns, event),
+        }
+        Ok(())
+    }
+}
+
+/// Returns how many times a timer due at `deadline_ns` has expired by `now`, and the deadline it is
+/// re-armed for. Like a timerfd, a repeating timer is re-armed for the next interval boundary after
+/// `now`, keeping its phase, and a one-shot timer (or a zero interval) is disarmed once it fires.
+fn fake_expirations(deadline_ns: u64, now: u64, interval: Option<Duration>) -> (u64, Option<u64>) {
+    match interval.map(|interval| interval.as_nanos() as u64) {
+        Some(interval_ns) if interval_ns > 0 => {
+            let expirys = (now - deadline_ns) / interval_ns + 1;
+            (expirys, Some(deadline_ns + expirys * interval_ns))
+        }
+        _ => (1, None),
+    }
 }
 
 impl TimerTrait for FakeTimer {
     fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> Result<()> {
         let mut guard = self.clock.lock();
-        let deadline = guard.nanos() + dur.as_nanos() as u64;
+        let deadline = self.clock_nanos(&guard) + dur.as_nanos() as u64;
         self.deadline_ns = Some(deadline);
+        self.realtime_deadline_ns = None;
+        self.interval = interval;
+        self.add_clock_event(&mut guard, deadline)
+    }
+
+    fn reset_realtime(&mut self, deadline: SystemTime, interval: Option<Duration>) -> Result<()> {
+        let deadline = deadline
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::new(libc::EINVAL))?
+            .as_nanos() as u64;
+        let mut guard = self.clock.lock();
+        self.deadline_ns = None;
+        self.realtime_deadline_ns = Some(deadline);
+        self.realtime_steps = guard.realtime_steps();
         self.interval = interval;
-        guard.add_event(deadline, self.event.try_clone()?);
+        guard.add_realtime_event(deadline, self.event.try_clone()?);
         Ok(())
     }
 
-    fn wait(&mut self) -> Result<()> {
-        self.wait_for(None).map(|_| ())
+    fn set_deadline(&mut self, deadline: Instant) -> Result<()> {
+        let now = self.clock.lock().now();
+        self.reset(duration_until(deadline, now), None)
+    }
+
+    /// Expires at the clock's current time, without waiting for the clock to be advanced.
+    fn expire_now(&mut self) -> Result<()> {
+        self.deadline_ns = Some(self.clock_nanos(&self.clock.lock()));
+        self.realtime_deadline_ns = None;
+        self.interval = None;
+        self.event.signal()
+    }
+
+    fn arm_interrupt(&self) -> Result<InterruptHandle> {
+        // Interrupts wake the timer's own event, which is the only thing the fake waits on.
+        Ok(InterruptHandle::new(self.event.try_clone()?))
+    }
+
+    fn wait_interruptible(&mut self, interrupt: &InterruptHandle) -> Result<WaitResult> {
+        self.wait_count_for(None, Some(interrupt))
+            .map(|(result, _)| result)
+    }
+
+    /// `timeout` is real time: the wait times out unless the `FakeClock` is advanced past the
+    /// deadline, by another thread or beforehand, within it.
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<WaitResult> {
+        self.wait_for(Some(timeout))
+    }
+
+    fn wait_count(&mut self) -> Result<u64> {
+        // Without a timeout the wait only returns once the timer has fired.
+        let count = self.wait_count_for(None, None).map(|(_, count)| count)?;
+        self.fires += count;
+        Ok(count)
     }
 
     fn mark_waited(&mut self) -> Result<bool> {
@@ -183,12 +639,53 @@ impl TimerTrait for FakeTimer {
 
     fn clear(&mut self) -> Result<()> {
         self.deadline_ns = None;
+        self.realtime_deadline_ns = None;
         self.interval = None;
         Ok(())
     }
 
+    fn disarm_and_drain(&mut self) -> Result<u64> {
+        let pending = {
+            let guard = self.clock.lock();
+            match (self.deadline_ns, self.realtime_deadline_ns) {
+                (Some(deadline_ns), _) => Some((deadline_ns, self.clock_nanos(&guard))),
+                (None, Some(deadline_ns)) => Some((deadline_ns, guard.realtime_nanos())),
+                (None, None) => None,
+            }
+            .filter(|(deadline_ns, now)| now >= deadline_ns)
+            .map_or(0, |(deadline_ns, now)| {
+                fake_expirations(deadline_ns, now, self.interval).0
+            })
+        };
+        self.clear()?;
+        self.spurious = false;
+        // Consume the signal left by an expiration that was not waited on.
+        self.event.wait_timeout(Duration::ZERO)?;
+        Ok(pending)
+    }
+
     fn resolution(&self) -> Result<Duration> {
-        Ok(Duration::from_nanos(1))
+        Ok(self.resolution)
+    }
+
+    fn remaining(&self) -> Result<Option<Duration>> {
+        let (deadline_ns, now) = match (self.deadline_ns, self.realtime_deadline_ns) {
+            (Some(deadline_ns), _) => (deadline_ns, self.clock_nanos(&self.clock.lock())),
+            (None, Some(deadline_ns)) => (deadline_ns, self.clock.lock().realtime_nanos()),
+            (None, None) => return Ok(None),
+        };
+        if now < deadline_ns {
+            return Ok(Some(Duration::from_nanos(deadline_ns - now)));
+        }
+        // The deadline is only advanced when the timer is waited on, so work out the next
+        // expiration of a repeating timer here.
+        match self.interval.map(|interval| interval.as_nanos() as u64) {
+            Some(interval_ns) if interval_ns > 0 => {
+                let since_last = (now - deadline_ns) % interval_ns;
+                Ok(Some(Duration::from_nanos(interval_ns - since_last)))
+            }
+            _ => Ok(None),
+        }
     }
 }
 
@@ -203,115 +700,699 @@ impl IntoRawDescriptor for FakeTimer {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
-    use std::time::Instant;
+/// Identifies a logical timer in a `TimerGroup`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
 
-    use super::*;
+/// Multiplexes any number of logical timers onto a single underlying timer, so that one thread
+/// can service all of them. The underlying timer is always armed for the nearest deadline.
+pub struct TimerGroup<T: TimerTrait = Timer> {
+    timer: T,
+    now: Box<dyn Fn() -> Instant + Send>,
+    next_id: u64,
+    // Min-heap of deadlines. Entries of timers that were cancelled or re-armed are left in place
+    // and skipped once they reach the top.
+    deadlines: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    // The current deadline and repeat interval of each armed timer.
+    armed: BTreeMap<TimerId, (Instant, Option<Duration>)>,
+}
 
-    // clock error is 2*clock_resolution + 100 microseconds to handle
-    // time change from calling now() to arming timer
-    fn get_clock_error() -> Duration {
-        Timer::new()
-            .unwrap()
-            .resolution()
-            .expect("expected to be able to read timer resolution")
-            .checked_mul(2)
-            .expect("timer resolution x 2 should not overflow")
-            .checked_add(Duration::from_micros(100))
-            .expect("timer resolution x 2 + 100 microsecond should not overflow")
+impl TimerGroup<Timer> {
+    /// Creates a new, empty `TimerGroup` backed by a `Timer`.
+    pub fn new() -> Result<TimerGroup<Timer>> {
+        Ok(TimerGroup::with_timer(
+            Timer::new()?,
+            Box::new(Instant::now),
+        ))
     }
+}
 
-    #[test]
-    #[ignore]
-    fn one_shot() {
-        // This test relies on the host having a reliable clock and not being
-        // overloaded, so it's marked as "ignore".  You can run by running
-        // cargo test -p base timer -- --ignored
+impl TimerGroup<FakeTimer> {
+    /// Creates a new, empty `TimerGroup` driven by `clock`, for use in tests.
+    pub fn new_fake(clock: Arc<Mutex<FakeClock>>) -> TimerGroup<FakeTimer> {
+        let timer = FakeTimer::new(clock.clone());
+        TimerGroup::with_timer(timer, Box::new(move || clock.lock().now()))
+    }
+}
 
-        let mut tfd = Timer::new().expect("failed to create Timer");
+impl<T: TimerTrait> TimerGroup<T> {
+    fn with_timer(timer: T, now: Box<dyn Fn() -> Instant + Send>) -> Self {
+        TimerGroup {
+            timer,
+            now,
+            next_id: 0,
+            deadlines: BinaryHeap::new(),
+            armed: BTreeMap::new(),
+        }
+    }
 
-        let dur = Duration::from_millis(1000);
-        let clock_error = get_clock_error();
+    /// Adds a timer that expires after `dur`. If `interval` is not `None` and non-zero the timer
+    /// then repeats with that period, otherwise it expires once and is removed from the group.
+    pub fn add(&mut self, dur: Duration, interval: Option<Duration>) -> Result<TimerId> {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let deadline = (self.now)() + dur;
+        let interval = interval.filter(|interval| !interval.is_zero());
+        self.armed.insert(id, (deadline, interval));
+        self.deadlines.push(Reverse((deadline, id)));
+        self.rearm()?;
+        Ok(id)
+    }
 
-        let now = Instant::now();
-        tfd.reset(dur, None).expect("failed to arm timer");
+    /// Removes the timer `id` from the group. Returns false if it was not armed, such as a one-shot
+    /// timer that already expired.
+    pub fn cancel(&mut self, id: TimerId) -> Result<bool> {
+        if self.armed.remove(&id).is_none() {
+            return Ok(false);
+        }
+        self.rearm()?;
+        Ok(true)
+    }
 
-        tfd.wait().expect("unable to wait for timer");
-        let elapsed = now.elapsed();
-        // elapsed is within +-clock_error from expected duration
-        assert!(elapsed - clock_error <= dur);
-        assert!(elapsed + clock_error >= dur);
+    /// Returns the number of armed timers in the group.
+    pub fn len(&self) -> usize {
+        self.armed.len()
     }
 
-    /// Similar to one_shot, except this one waits for a clone of the timer.
-    #[test]
-    #[ignore]
-    fn one_shot_cloned() {
-        let mut tfd = Timer::new().expect("failed to create Timer");
-        let dur = Duration::from_millis(1000);
-        let mut cloned_tfd = tfd.try_clone().expect("failed to clone timer");
+    /// Returns true if no timers are armed.
+    pub fn is_empty(&self) -> bool {
+        self.armed.is_empty()
+    }
 
-        // clock error is 2*clock_resolution + 100 microseconds to handle
-        // time change from calling now() to arming timer
-        let clock_error = get_clock_error();
+    /// Waits until at least one timer in the group expires and returns the expired timers,
+    /// ordered by deadline. Returns an empty list right away if no timers are armed.
+    pub fn wait(&mut self) -> Result<Vec<TimerId>> {
+        loop {
+            if self.armed.is_empty() {
+                return Ok(Vec::new());
+            }
+            self.timer.wait()?;
+            // The underlying timer may fire slightly early, so keep waiting until a deadline has
+            // actually passed.
+            let expired = self.collect_expired()?;
+            if !expired.is_empty() {
+                return Ok(expired);
+            }
+        }
+    }
 
-        let now = Instant::now();
-        tfd.reset(dur, None).expect("failed to arm timer");
-        cloned_tfd.wait().expect("unable to wait for timer");
-        let elapsed = now.elapsed();
+    /// Returns the timers that have expired without blocking, after the group's descriptor was
+    /// signaled in a `WaitContext`.
+    pub fn take_expired(&mut self) -> Result<Vec<TimerId>> {
+        self.timer.mark_waited()?;
+        self.collect_expired()
+    }
 
-        // elapsed is within +-clock_error from expected duration
-        assert!(elapsed - clock_error <= dur);
-        assert!(elapsed + clock_error >= dur);
+    // Removes expired deadlines from the heap, schedules the next expiration of repeating timers
+    // and re-arms the underlying timer.
+    fn collect_expired(&mut self) -> Result<Vec<TimerId>> {
+        let now = (self.now)();
+        let mut expired = Vec::new();
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            let interval = match self.armed.get(&id) {
+                Some(&(armed_deadline, interval)) if armed_deadline == deadline => interval,
+                // Cancelled or re-armed since this deadline was pushed.
+                _ => continue,
+            };
+            expired.push(id);
+            // A period that is past the range of `Instant` never comes, so the timer is removed
+            // like a one-shot timer.
+            match interval.and_then(|interval| next_period(deadline, interval, now)) {
+                Some(next) => {
+                    self.armed.insert(id, (next, interval));
+                    self.deadlines.push(Reverse((next, id)));
+                }
+                None => {
+                    self.armed.remove(&id);
+                }
+            }
+        }
+        self.rearm()?;
+        Ok(expired)
     }
 
-    #[test]
-    #[ignore]
-    fn repeating() {
-        // This test relies on the host having a reliable clock and not being
-        // overloaded, so it's marked as "ignore".  You can run by running
-        // cargo test -p base timer -- --ignored
+    // Arms the underlying timer for the nearest live deadline, or disarms it if there is none.
+    fn rearm(&mut self) -> Result<()> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if matches!(self.armed.get(&id), Some(&(armed_deadline, _)) if armed_deadline == deadline)
+            {
+                return self.timer.set_deadline(deadline);
+            }
+            self.deadlines.pop();
+        }
+        self.timer.clear()
+    }
+}
 
-        let mut tfd = Timer::new().expect("failed to create Timer");
+// Returns the first time after `now` in the series `start + n * period`, skipping the periods that
+// were missed while nobody was waiting. If the missed periods can't be skipped without
+// overflowing, such as after a long stall, the series restarts from `now`. Returns `None` if even
+// that is past the range of `Instant`.
+fn next_period(start: Instant, period: Duration, now: Instant) -> Option<Instant> {
+    let missed = (now - start).as_nanos() / period.as_nanos();
+    let missed = u32::try_from(missed).unwrap_or(u32::MAX);
+    period
+        .checked_mul(missed.saturating_add(1))
+        .and_then(|skip| start.checked_add(skip))
+        .filter(|next| *next > now)
+        .or_else(|| now.checked_add(period))
+}
 
-        let dur = Duration::from_millis(200);
-        // clock error is 2*clock_resolution + 100 microseconds to handle
-        // time change from calling now() to arming timer
-        let clock_error = Timer::new()
-            .unwrap()
-            .resolution()
-            .expect("expected to be able to read timer resolution")
-            .checked_mul(2)
-            .expect("timer resolution x 2 should not overflow")
-            .checked_add(Duration::from_micros(100))
-            .expect("timer resolution x 2 + 100 microsecond should not overflow");
-        let interval = Duration::from_millis(100);
-        let now = Instant::now();
-        tfd.reset(dur, Some(interval)).expect("failed to arm timer");
+impl<T: TimerTrait> AsRawDescriptor for TimerGroup<T> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.timer.as_raw_descriptor()
+    }
+}
 
-        tfd.wait().expect("unable to wait for timer");
-        // should take "dur" duration for the first wait
-        assert!(now.elapsed() + clock_error >= dur);
-        tfd.wait().expect("unable to wait for timer");
-        // subsequent waits should take "interval" duration
-        assert!(now.elapsed() + clock_error >= dur + interval);
-        tfd.wait().expect("unable to wait for timer");
-        assert!(now.elapsed() + clock_error >= dur + interval * 2);
+/// Permits up to `burst` events in each window of a fixed length, for throttling things like log
+/// messages or guest-triggered work. The permits are refilled when the window ends.
+///
+/// Once the permits run out, the limiter's descriptor is signaled at the end of the window so a
+/// throttled caller in an event loop knows when to try again.
+pub struct RateLimiter<T: TimerTrait = Timer> {
+    timer: T,
+    now: Box<dyn Fn() -> Instant + Send>,
+    burst: u64,
+    window: Duration,
+    tokens: u64,
+    window_end: Instant,
+}
+
+impl RateLimiter<Timer> {
+    /// Creates a `RateLimiter` backed by a `Timer` that permits `burst` events per `window`.
+    pub fn new(burst: u64, window: Duration) -> Result<RateLimiter<Timer>> {
+        RateLimiter::with_timer(Timer::new()?, Box::new(Instant::now), burst, window)
     }
+}
 
-    #[test]
-    fn fake_one_shot() {
-        let clock = Arc::new(Mutex::new(FakeClock::new()));
-        let mut tfd = FakeTimer::new(clock.clone());
+impl RateLimiter<FakeTimer> {
+    /// Creates a `RateLimiter` driven by `clock` that permits `burst` events per `window`, for use
+    /// in tests.
+    pub fn new_fake(
+        clock: Arc<Mutex<FakeClock>>,
+        burst: u64,
+        window: Duration,
+    ) -> Result<RateLimiter<FakeTimer>> {
+        let timer = FakeTimer::new(clock.clone());
+        RateLimiter::with_timer(timer, Box::new(move || clock.lock().now()), burst, window)
+    }
+}
 
-        let dur = Duration::from_nanos(200);
-        tfd.reset(dur, None).expect("failed to arm timer");
+impl<T: TimerTrait> RateLimiter<T> {
+    fn with_timer(
+        timer: T,
+        now: Box<dyn Fn() -> Instant + Send>,
+        burst: u64,
+        window: Duration,
+    ) -> Result<Self> {
+        if window.is_zero() {
+            return Err(Error::new(libc::EINVAL));
+        }
+        let window_end = now() + window;
+        Ok(RateLimiter {
+            timer,
+            now,
+            burst,
+            window,
+            tokens: burst,
+            window_end,
+        })
+    }
 
-        clock.lock().add_ns(200);
+    /// Takes a permit for one event. Returns false if the current window's permits are used up,
+    /// in which case the event should be dropped or deferred.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        if self.tokens == 0 {
+            if let Err(e) = self.timer.set_deadline(self.window_end) {
+                error!("failed to arm rate limiter timer: {}", e);
+            }
+        }
+        true
+    }
 
-        assert_eq!(tfd.wait().is_ok(), true);
+    /// Marks the limiter's descriptor as handled after it was signaled in a `WaitContext`. The
+    /// permits themselves are refilled by the next `try_acquire`.
+    pub fn mark_waited(&mut self) -> Result<()> {
+        self.timer.mark_waited()?;
+        Ok(())
+    }
+
+    // Starts a new window with a full set of permits if the current one has ended.
+    fn refill(&mut self) {
+        let now = (self.now)();
+        if now < self.window_end {
+            return;
+        }
+        // Skip any windows that passed without an event. If even a window starting now would end
+        // past the range of `Instant`, every call starts a new window.
+        self.window_end = next_period(self.window_end, self.window, now).unwrap_or(now);
+        self.tokens = self.burst;
+    }
+}
+
+impl<T: TimerTrait> AsRawDescriptor for RateLimiter<T> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.timer.as_raw_descriptor()
+    }
+}
+
+/// A wall-clock recurrence for a `ScheduleTimer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every `interval`, at `anchor` and at whole multiples of `interval` before and after it.
+    Every {
+        anchor: SystemTime,
+        interval: Duration,
+    },
+    /// Once a day at `hour`:`minute` UTC.
+    DailyAt { hour: u8, minute: u8 },
+}
+
+impl Schedule {
+    /// Returns the first time after `now` that the schedule fires, or `None` if the schedule is
+    /// invalid: a zero interval or a time of day past 23:59.
+    pub fn next_after(&self, now: SystemTime) -> Option<SystemTime> {
+        let (anchor, interval) = match *self {
+            Schedule::Every { anchor, interval } => (anchor, interval),
+            Schedule::DailyAt { hour, minute } => {
+                if hour > 23 || minute > 59 {
+                    return None;
+                }
+                let secs = u64::from(hour) * 3600 + u64::from(minute) * 60;
+                (
+                    UNIX_EPOCH + Duration::from_secs(secs),
+                    Duration::from_secs(24 * 3600),
+                )
+            }
+        };
+        if interval.is_zero() {
+            return None;
+        }
+        match now.duration_since(anchor) {
+            Ok(since) => {
+                let periods = since.as_nanos() / interval.as_nanos() + 1;
+                Some(anchor + Duration::from_nanos((periods * interval.as_nanos()) as u64))
+            }
+            Err(e) => {
+                let periods = e.duration().as_nanos() / interval.as_nanos();
+                let next = anchor - Duration::from_nanos((periods * interval.as_nanos()) as u64);
+                // `now` may fall exactly on an earlier occurrence, which has already fired.
+                if next > now {
+                    Some(next)
+                } else {
+                    Some(next + interval)
+                }
+            }
+        }
+    }
+}
+
+/// Fires at the wall-clock times given by a `Schedule`, for periodic maintenance such as log
+/// rotation that should happen at a time of day rather than at a fixed interval from startup.
+///
+/// The timer is re-armed for the next occurrence each time it fires. If the host clock is changed,
+/// the next occurrence is worked out again from the new time, and occurrences that were skipped
+/// over by the change are not reported.
+pub struct ScheduleTimer<T: TimerTrait = Timer> {
+    timer: T,
+    now: Box<dyn Fn() -> SystemTime + Send>,
+    schedule: Schedule,
+    next: SystemTime,
+}
+
+impl ScheduleTimer<Timer> {
+    /// Creates a `ScheduleTimer` backed by a wall-clock `Timer`. Returns EINVAL if `schedule` is
+    /// invalid.
+    pub fn new(schedule: Schedule) -> Result<ScheduleTimer<Timer>> {
+        let timer = Timer::new_with_clock(TimerClock::Realtime)?;
+        ScheduleTimer::with_timer(timer, Box::new(SystemTime::now), schedule)
+    }
+}
+
+impl ScheduleTimer<FakeTimer> {
+    /// Creates a `ScheduleTimer` that follows `clock`'s wall-clock time, for use in tests.
+    pub fn new_fake(
+        clock: Arc<Mutex<FakeClock>>,
+        schedule: Schedule,
+    ) -> Result<ScheduleTimer<FakeTimer>> {
+        let timer = FakeTimer::new_with_clock(clock.clone(), TimerClock::Realtime);
+        ScheduleTimer::with_timer(timer, Box::new(move || clock.lock().realtime()), schedule)
+    }
+}
+
+impl<T: TimerTrait> ScheduleTimer<T> {
+    fn with_timer(
+        timer: T,
+        now: Box<dyn Fn() -> SystemTime + Send>,
+        schedule: Schedule,
+    ) -> Result<Self> {
+        let next = schedule.next_after(now()).ok_or(Error::new(libc::EINVAL))?;
+        let mut schedule_timer = ScheduleTimer {
+            timer,
+            now,
+            schedule,
+            next,
+        };
+        schedule_timer.timer.reset_realtime(next, None)?;
+        Ok(schedule_timer)
+    }
+
+    /// Returns the time the timer fires next.
+    pub fn next_deadline(&self) -> SystemTime {
+        self.next
+    }
+
+    /// Waits until the next occurrence and returns its scheduled time.
+    pub fn wait(&mut self) -> Result<SystemTime> {
+        loop {
+            self.timer.wait()?;
+            if let Some(fired) = self.rearm()? {
+                return Ok(fired);
+            }
+        }
+    }
+
+    /// Handles the timer's descriptor being signaled in a `WaitContext`. Returns the scheduled time
+    /// of the occurrence that fired, or `None` if the wakeup was caused by a change to the host
+    /// clock instead.
+    pub fn mark_waited(&mut self) -> Result<Option<SystemTime>> {
+        if self.timer.mark_waited()? {
+            return Ok(None);
+        }
+        self.rearm()
+    }
+
+    // Arms the timer for the first occurrence after the current time, returning the occurrence
+    // that was due if it has been reached.
+    fn rearm(&mut self) -> Result<Option<SystemTime>> {
+        let now = (self.now)();
+        let fired = if now >= self.next {
+            Some(self.next)
+        } else {
+            None
+        };
+        // The schedule was checked when the timer was created.
+        self.next = self.schedule.next_after(now).unwrap();
+        self.timer.reset_realtime(self.next, None)?;
+        Ok(fired)
+    }
+}
+
+impl<T: TimerTrait> AsRawDescriptor for ScheduleTimer<T> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.timer.as_raw_descriptor()
+    }
+}
+
+/// Calls a function if it is not fed within an interval, for detecting threads that have stopped
+/// making progress, such as a stuck vcpu thread.
+///
+/// The watchdog's descriptor is signaled once the interval passes without a `feed`. The owner's
+/// event loop then calls `mark_waited`, which runs the callback. After expiring, the watchdog stays
+/// idle until it is fed again.
+pub struct Watchdog<T: TimerTrait = Timer> {
+    timer: T,
+    interval: Duration,
+    on_expire: Box<dyn FnMut() + Send>,
+}
+
+impl Watchdog<Timer> {
+    /// Creates a `Watchdog` backed by a `Timer` that calls `on_expire` if it isn't fed for
+    /// `interval`. The countdown starts immediately. Returns EINVAL if `interval` is zero.
+    pub fn new(
+        interval: Duration,
+        on_expire: impl FnMut() + Send + 'static,
+    ) -> Result<Watchdog<Timer>> {
+        Watchdog::with_timer(Timer::new()?, interval, Box::new(on_expire))
+    }
+}
+
+impl Watchdog<FakeTimer> {
+    /// Creates a `Watchdog` driven by `clock` that calls `on_expire` if it isn't fed for
+    /// `interval`, for use in tests.
+    pub fn new_fake(
+        clock: Arc<Mutex<FakeClock>>,
+        interval: Duration,
+        on_expire: impl FnMut() + Send + 'static,
+    ) -> Result<Watchdog<FakeTimer>> {
+        Watchdog::with_timer(FakeTimer::new(clock), interval, Box::new(on_expire))
+    }
+}
+
+impl<T: TimerTrait> Watchdog<T> {
+    fn with_timer(
+        timer: T,
+        interval: Duration,
+        on_expire: Box<dyn FnMut() + Send>,
+    ) -> Result<Self> {
+        if interval.is_zero() {
+            return Err(Error::new(libc::EINVAL));
+        }
+        let mut watchdog = Watchdog {
+            timer,
+            interval,
+            on_expire,
+        };
+        watchdog.feed()?;
+        Ok(watchdog)
+    }
+
+    /// Restarts the countdown, so the watchdog expires `interval` from now. Also re-arms a
+    /// watchdog that has already expired.
+    pub fn feed(&mut self) -> Result<()> {
+        self.timer.reset(self.interval, None)
+    }
+
+    /// Handles the watchdog's descriptor being signaled in a `WaitContext`. Runs the callback and
+    /// returns true if the watchdog expired, or returns false if it was fed after the descriptor
+    /// was signaled.
+    pub fn mark_waited(&mut self) -> Result<bool> {
+        if self.timer.mark_waited()? {
+            return Ok(false);
+        }
+        (self.on_expire)();
+        Ok(true)
+    }
+}
+
+impl<T: TimerTrait> AsRawDescriptor for Watchdog<T> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.timer.as_raw_descriptor()
+    }
+}
+
+/// An event that is either signaled directly or signals itself once a timeout passes, for devices
+/// that complete a request early or give up on it after a deadline.
+///
+/// Both ways of signaling go through the same timer, so there is a single descriptor to wait on,
+/// and signaling early replaces the pending timeout instead of racing with it.
+pub struct TimedEvent<T: TimerTrait = Timer> {
+    timer: T,
+    // Whether the event was signaled and has not been waited on yet.
+    signaled: bool,
+}
+
+impl TimedEvent<Timer> {
+    /// Creates an unsignaled `TimedEvent` backed by a `Timer`.
+    pub fn new() -> Result<TimedEvent<Timer>> {
+        Ok(TimedEvent::with_timer(Timer::new()?))
+    }
+}
+
+impl TimedEvent<FakeTimer> {
+    /// Creates an unsignaled `TimedEvent` whose timeouts follow `clock`, for use in tests.
+    pub fn new_fake(clock: Arc<Mutex<FakeClock>>) -> TimedEvent<FakeTimer> {
+        TimedEvent::with_timer(FakeTimer::new(clock))
+    }
+}
+
+impl<T: TimerTrait> TimedEvent<T> {
+    fn with_timer(timer: T) -> Self {
+        TimedEvent {
+            timer,
+            signaled: false,
+        }
+    }
+
+    /// Signals the event now, cancelling any pending timeout.
+    pub fn signal(&mut self) -> Result<()> {
+        self.timer.expire_now()?;
+        self.signaled = true;
+        Ok(())
+    }
+
+    /// Signals the event automatically once `dur` passes, replacing any pending timeout. Does
+    /// nothing if the event is already signaled, so the signal is not lost.
+    pub fn arm(&mut self, dur: Duration) -> Result<()> {
+        if self.signaled {
+            return Ok(());
+        }
+        self.timer.reset_clamped(dur, None)
+    }
+
+    /// Cancels a pending timeout. A signal that was already delivered is kept.
+    pub fn cancel(&mut self) -> Result<()> {
+        if self.signaled {
+            return Ok(());
+        }
+        self.timer.clear()
+    }
+
+    /// Waits until the event is signaled, by `signal` or by the timeout set with `arm`.
+    pub fn wait(&mut self) -> Result<()> {
+        self.timer.wait()?;
+        self.signaled = false;
+        Ok(())
+    }
+
+    /// Resets the event after its descriptor was signaled in a `WaitContext`. Returns false if
+    /// the wakeup came from a timeout that was since replaced or cancelled, in which case the event
+    /// was not signaled.
+    pub fn mark_waited(&mut self) -> Result<bool> {
+        if self.timer.mark_waited()? {
+            return Ok(false);
+        }
+        self.signaled = false;
+        Ok(true)
+    }
+}
+
+impl<T: TimerTrait> AsRawDescriptor for TimedEvent<T> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.timer.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::FakeStopwatch;
+    use crate::WaitContext;
+
+    // clock error is 2*clock_resolution + 100 microseconds to handle
+    // time change from calling now() to arming timer
+    fn get_clock_error() -> Duration {
+        Timer::new()
+            .unwrap()
+            .resolution()
+            .expect("expected to be able to read timer resolution")
+            .checked_mul(2)
+            .expect("timer resolution x 2 should not overflow")
+            .checked_add(Duration::from_micros(100))
+            .expect("timer resolution x 2 + 100 microsecond should not overflow")
+    }
+
+    #[test]
+    fn same_timer() {
+        let timer = Timer::new().expect("failed to create timer");
+        let clone = timer.try_clone().expect("failed to clone timer");
+        let other = Timer::new().expect("failed to create timer");
+        assert!(timer.same_timer(&timer));
+        assert!(timer.same_timer(&clone));
+        assert!(!timer.same_timer(&other));
+    }
+
+    #[test]
+    #[ignore]
+    fn one_shot() {
+        // This test relies on the host having a reliable clock and not being
+        // overloaded, so it's marked as "ignore".  You can run by running
+        // cargo test -p base timer -- --ignored
+
+        let mut tfd = Timer::new().expect("failed to create Timer");
+
+        let dur = Duration::from_millis(1000);
+        let clock_error = get_clock_error();
+
+        let now = Instant::now();
+        tfd.reset(dur, None).expect("failed to arm timer");
+
+        tfd.wait().expect("unable to wait for timer");
+        let elapsed = now.elapsed();
+        // elapsed is within +-clock_error from expected duration
+        assert!(elapsed - clock_error <= dur);
+        assert!(elapsed + clock_error >= dur);
+    }
+
+    /// Similar to one_shot, except this one waits for a clone of the timer.
+    #[test]
+    #[ignore]
+    fn one_shot_cloned() {
+        let mut tfd = Timer::new().expect("failed to create Timer");
+        let dur = Duration::from_millis(1000);
+        let mut cloned_tfd = tfd.try_clone().expect("failed to clone timer");
+
+        // clock error is 2*clock_resolution + 100 microseconds to handle
+        // time change from calling now() to arming timer
+        let clock_error = get_clock_error();
+
+        let now = Instant::now();
+        tfd.reset(dur, None).expect("failed to arm timer");
+        cloned_tfd.wait().expect("unable to wait for timer");
+        let elapsed = now.elapsed();
+
+        // elapsed is within +-clock_error from expected duration
+        assert!(elapsed - clock_error <= dur);
+        assert!(elapsed + clock_error >= dur);
+    }
+
+    #[test]
+    #[ignore]
+    fn repeating() {
+        // This test relies on the host having a reliable clock and not being
+        // overloaded, so it's marked as "ignore".  You can run by running
+        // cargo test -p base timer -- --ignored
+
+        let mut tfd = Timer::new().expect("failed to create Timer");
+
+        let dur = Duration::from_millis(200);
+        // clock error is 2*clock_resolution + 100 microseconds to handle
+        // time change from calling now() to arming timer
+        let clock_error = Timer::new()
+            .unwrap()
+            .resolution()
+            .expect("expected to be able to read timer resolution")
+            .checked_mul(2)
+            .expect("timer resolution x 2 should not overflow")
+            .checked_add(Duration::from_micros(100))
+            .expect("timer resolution x 2 + 100 microsecond should not overflow");
+        let interval = Duration::from_millis(100);
+        let now = Instant::now();
+        tfd.reset(dur, Some(interval)).expect("failed to arm timer");
+
+        tfd.wait().expect("unable to wait for timer");
+        // should take "dur" duration for the first wait
+        assert!(now.elapsed() + clock_error >= dur);
+        tfd.wait().expect("unable to wait for timer");
+        // subsequent waits should take "interval" duration
+        assert!(now.elapsed() + clock_error >= dur + interval);
+        tfd.wait().expect("unable to wait for timer");
+        assert!(now.elapsed() + clock_error >= dur + interval * 2);
+    }
+
+    #[test]
+    fn fake_one_shot() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        let dur = Duration::from_nanos(200);
+        tfd.reset(dur, None).expect("failed to arm timer");
+
+        clock.lock().add_ns(200);
+
+        assert_eq!(tfd.wait().is_ok(), true);
     }
 
     #[test]
@@ -339,6 +1420,808 @@ mod tests {
         assert_eq!(result, WaitResult::Expired);
     }
 
+    #[test]
+    fn fake_set_deadline() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        let deadline = clock.lock().now() + Duration::from_nanos(200);
+        tfd.set_deadline(deadline).expect("failed to arm timer");
+
+        clock.lock().add_ns(100);
+        let result = tfd
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Timeout);
+
+        clock.lock().add_ns(100);
+        let result = tfd
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Expired);
+    }
+
+    #[test]
+    fn fake_set_deadline_past() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        let deadline = clock.lock().now();
+        clock.lock().add_ns(100);
+        tfd.set_deadline(deadline).expect("failed to arm timer");
+
+        // A deadline in the past still arms the timer for the next tick of the clock.
+        clock.lock().add_ns(1);
+        assert_eq!(tfd.wait().is_ok(), true);
+    }
+
+    #[test]
+    fn fake_remaining() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        assert_eq!(tfd.remaining().unwrap(), None);
+
+        tfd.reset(Duration::from_nanos(200), None)
+            .expect("failed to arm timer");
+        clock.lock().add_ns(50);
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_nanos(150)));
+
+        clock.lock().add_ns(150);
+        assert_eq!(tfd.remaining().unwrap(), None);
+
+        tfd.reset(Duration::from_nanos(200), Some(Duration::from_nanos(100)))
+            .expect("failed to arm timer");
+        clock.lock().add_ns(230);
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_nanos(70)));
+
+        tfd.clear().expect("failed to clear timer");
+        assert_eq!(tfd.remaining().unwrap(), None);
+    }
+
+    #[test]
+    fn fake_reset_realtime() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        let deadline = clock.lock().realtime() + Duration::from_nanos(200);
+        tfd.reset_realtime(deadline, None)
+            .expect("failed to arm timer");
+
+        clock.lock().add_ns(100);
+        let result = tfd
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Timeout);
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_nanos(100)));
+
+        clock.lock().add_ns(100);
+        let result = tfd
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Expired);
+    }
+
+    #[test]
+    fn fake_reset_realtime_step() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        let start = clock.lock().realtime();
+        let deadline = start + Duration::from_nanos(200);
+        tfd.reset_realtime(deadline, None)
+            .expect("failed to arm timer");
+
+        // Stepping the wall clock backwards fires the timer without reaching the deadline.
+        clock.lock().set_realtime(start - Duration::from_secs(1));
+        assert_eq!(tfd.wait().is_ok(), true);
+        assert!(clock.lock().realtime() < deadline);
+
+        // The timer stays armed for the original deadline.
+        clock.lock().set_realtime(deadline);
+        assert_eq!(tfd.wait().is_ok(), true);
+        assert_eq!(tfd.remaining().unwrap(), None);
+    }
+
+    #[test]
+    fn fake_try_clone() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+        let mut cloned_tfd = tfd.try_clone().expect("failed to clone timer");
+        assert!(cloned_tfd.is_armed());
+
+        clock.lock().add_ns(100);
+        assert_eq!(
+            cloned_tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Expired
+        );
+
+        // The original measures its next deadline from the clock the clone saw advance.
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+        clock.lock().add_ns(50);
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Timeout
+        );
+        clock.lock().add_ns(50);
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Expired
+        );
+    }
+
+    #[test]
+    fn fake_spurious_wakeup() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+
+        tfd.inject_spurious_wakeup().unwrap();
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Spurious
+        );
+        assert!(tfd.is_armed());
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Timeout
+        );
+
+        clock.lock().add_ns(100);
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Expired
+        );
+
+        // Stepping the wall clock under a wall-clock deadline is spurious too.
+        let deadline = clock.lock().realtime() + Duration::from_nanos(200);
+        tfd.reset_realtime(deadline, None)
+            .expect("failed to arm timer");
+        clock
+            .lock()
+            .set_realtime(deadline - Duration::from_nanos(100));
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Spurious
+        );
+    }
+
+    #[test]
+    fn fake_wait_count() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+
+        tfd.reset(Duration::from_nanos(200), None)
+            .expect("failed to arm timer");
+        clock.lock().add_ns(500);
+        assert_eq!(tfd.wait_count().unwrap(), 1);
+
+        tfd.reset(Duration::from_nanos(200), Some(Duration::from_nanos(100)))
+            .expect("failed to arm timer");
+        clock.lock().add_ns(450);
+        // The initial expiry plus two repeats.
+        assert_eq!(tfd.wait_count().unwrap(), 3);
+
+        clock.lock().add_ns(50);
+        assert_eq!(tfd.wait_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let mut tfd = Timer::new().expect("failed to create Timer");
+        tfd.reset(Duration::from_secs(10), None)
+            .expect("failed to arm timer");
+        assert_eq!(
+            tfd.wait_timeout(Duration::from_millis(10)).unwrap(),
+            WaitResult::Timeout
+        );
+
+        tfd.reset(Duration::from_millis(1), None)
+            .expect("failed to arm timer");
+        assert_eq!(
+            tfd.wait_timeout(Duration::from_secs(10)).unwrap(),
+            WaitResult::Expired
+        );
+    }
+
+    #[test]
+    fn disarm_and_drain() {
+        let mut tfd = Timer::new().expect("failed to create Timer");
+        tfd.reset(Duration::from_millis(1), Some(Duration::from_millis(1)))
+            .expect("failed to arm timer");
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(tfd.disarm_and_drain().unwrap() >= 1);
+        assert!(!tfd.is_armed());
+        assert_eq!(
+            tfd.wait_timeout(Duration::from_millis(20)).unwrap(),
+            WaitResult::Timeout
+        );
+        assert_eq!(tfd.disarm_and_drain().unwrap(), 0);
+    }
+
+    #[test]
+    fn fake_disarm_and_drain() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), Some(Duration::from_nanos(100)))
+            .expect("failed to arm timer");
+
+        clock.lock().add_ns(350);
+        assert_eq!(tfd.disarm_and_drain().unwrap(), 3);
+        assert!(!tfd.is_armed());
+        assert_eq!(
+            tfd.wait_timeout(Duration::ZERO).unwrap(),
+            WaitResult::Timeout
+        );
+
+        // Nothing is pending before the deadline.
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+        assert_eq!(tfd.disarm_and_drain().unwrap(), 0);
+    }
+
+    #[test]
+    fn fake_wait_timeout() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+
+        // The fake clock doesn't move on its own, so the deadline is never reached.
+        assert_eq!(
+            tfd.wait_timeout(Duration::from_millis(1)).unwrap(),
+            WaitResult::Timeout
+        );
+
+        clock.lock().add_ns(100);
+        assert_eq!(
+            tfd.wait_timeout(Duration::from_millis(1)).unwrap(),
+            WaitResult::Expired
+        );
+    }
+
+    #[test]
+    fn fake_boottime() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut monotonic = FakeTimer::new(clock.clone());
+        let mut boottime = FakeTimer::new_with_clock(clock.clone(), TimerClock::Boottime);
+        monotonic
+            .reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+        boottime
+            .reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+
+        // Only the boot time advances while suspended.
+        clock.lock().suspend(Duration::from_nanos(60));
+        clock.lock().add_ns(50);
+        assert!(!boottime.mark_waited().unwrap());
+        assert!(monotonic.mark_waited().unwrap());
+        assert_eq!(
+            monotonic.remaining().unwrap(),
+            Some(Duration::from_nanos(50))
+        );
+
+        clock.lock().add_ns(50);
+        assert!(!monotonic.mark_waited().unwrap());
+    }
+
+    #[test]
+    fn schedule_next_after() {
+        let day = Duration::from_secs(24 * 3600);
+        let hour = Duration::from_secs(3600);
+        let daily = Schedule::DailyAt { hour: 3, minute: 0 };
+        assert_eq!(
+            daily.next_after(UNIX_EPOCH + day + hour),
+            Some(UNIX_EPOCH + day + 3 * hour)
+        );
+        // An occurrence that is due now has already fired.
+        assert_eq!(
+            daily.next_after(UNIX_EPOCH + day + 3 * hour),
+            Some(UNIX_EPOCH + 2 * day + 3 * hour)
+        );
+        let invalid = Schedule::DailyAt {
+            hour: 24,
+            minute: 0,
+        };
+        assert_eq!(invalid.next_after(UNIX_EPOCH), None);
+
+        let every = Schedule::Every {
+            anchor: UNIX_EPOCH + day,
+            interval: 10 * hour,
+        };
+        assert_eq!(every.next_after(UNIX_EPOCH), Some(UNIX_EPOCH + 4 * hour));
+        assert_eq!(
+            every.next_after(UNIX_EPOCH + day),
+            Some(UNIX_EPOCH + day + 10 * hour)
+        );
+        let never = Schedule::Every {
+            anchor: UNIX_EPOCH,
+            interval: Duration::ZERO,
+        };
+        assert_eq!(never.next_after(UNIX_EPOCH), None);
+    }
+
+    #[test]
+    fn fake_schedule_timer() {
+        let day = Duration::from_secs(24 * 3600);
+        let hour = Duration::from_secs(3600);
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        clock.lock().set_realtime(UNIX_EPOCH + day + 2 * hour);
+        let mut timer =
+            ScheduleTimer::new_fake(clock.clone(), Schedule::DailyAt { hour: 3, minute: 0 })
+                .expect("failed to create schedule timer");
+        assert_eq!(timer.next_deadline(), UNIX_EPOCH + day + 3 * hour);
+
+        clock
+            .lock()
+            .add_ns((hour + Duration::from_secs(1)).as_nanos() as u64);
+        assert_eq!(timer.wait().unwrap(), UNIX_EPOCH + day + 3 * hour);
+        assert_eq!(timer.next_deadline(), UNIX_EPOCH + 2 * day + 3 * hour);
+
+        // Stepping the clock back wakes the timer without an occurrence and reschedules it.
+        clock.lock().set_realtime(UNIX_EPOCH + day);
+        assert_eq!(timer.mark_waited().unwrap(), None);
+        assert_eq!(timer.next_deadline(), UNIX_EPOCH + day + 3 * hour);
+    }
+
+    #[test]
+    fn fake_watchdog() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let expired = Arc::new(Mutex::new(0));
+        let on_expire = {
+            let expired = expired.clone();
+            move || *expired.lock() += 1
+        };
+        let mut watchdog = Watchdog::new_fake(clock.clone(), Duration::from_nanos(100), on_expire)
+            .expect("failed to create watchdog");
+        let ctx: WaitContext<u32> = WaitContext::build_with(&[(&watchdog, 0)]).unwrap();
+
+        // Feeding restarts the countdown, so crossing the original deadline doesn't expire it.
+        clock.lock().add_ns(60);
+        watchdog.feed().unwrap();
+        clock.lock().add_ns(60);
+        if !ctx.wait_timeout(Duration::ZERO).unwrap().is_empty() {
+            assert!(!watchdog.mark_waited().unwrap());
+        }
+        assert_eq!(*expired.lock(), 0);
+
+        clock.lock().add_ns(40);
+        assert_eq!(ctx.wait_timeout(Duration::ZERO).unwrap().len(), 1);
+        assert!(watchdog.mark_waited().unwrap());
+        assert_eq!(*expired.lock(), 1);
+
+        // The watchdog doesn't fire again until it is fed.
+        clock.lock().add_ns(1000);
+        assert!(ctx.wait_timeout(Duration::ZERO).unwrap().is_empty());
+        assert_eq!(*expired.lock(), 1);
+
+        assert!(Watchdog::new_fake(clock, Duration::ZERO, || {}).is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let mut tfd = Timer::new().expect("failed to create timer");
+        assert_eq!(tfd.metrics(), TimerMetrics::default());
+
+        let dur = Duration::from_millis(1);
+        tfd.reset(dur, Some(dur)).expect("failed to arm timer");
+        std::thread::sleep(dur * 5);
+        let count = tfd.wait_count().expect("unable to wait for timer");
+        tfd.wait().expect("unable to wait for timer");
+        // How late the expirations were depends on the host's load, so it isn't checked.
+        assert_eq!(tfd.metrics().fires, count + 1);
+    }
+
+    #[test]
+    fn fake_metrics() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), Some(Duration::from_nanos(100)))
+            .expect("failed to arm timer");
+
+        clock.lock().add_ns(250);
+        assert_eq!(tfd.wait_count().unwrap(), 2);
+        assert_eq!(
+            tfd.metrics(),
+            TimerMetrics {
+                fires: 2,
+                total_lateness: Duration::ZERO,
+            }
+        );
+    }
+
+    #[test]
+    fn fake_timed_event() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut event = TimedEvent::new_fake(clock.clone());
+        let ctx: WaitContext<u32> = WaitContext::build_with(&[(&event, 0)]).unwrap();
+        assert!(ctx.wait_timeout(Duration::ZERO).unwrap().is_empty());
+
+        // The timeout signals the event.
+        event.arm(Duration::from_nanos(100)).unwrap();
+        clock.lock().add_ns(100);
+        assert_eq!(ctx.wait_timeout(Duration::ZERO).unwrap().len(), 1);
+        assert!(event.mark_waited().unwrap());
+        assert!(ctx.wait_timeout(Duration::ZERO).unwrap().is_empty());
+
+        // Signaling early replaces the timeout, and arming again doesn't drop the signal.
+        event.arm(Duration::from_nanos(100)).unwrap();
+        event.signal().unwrap();
+        event.arm(Duration::from_nanos(100)).unwrap();
+        assert_eq!(ctx.wait_timeout(Duration::ZERO).unwrap().len(), 1);
+        assert!(event.mark_waited().unwrap());
+        assert!(!event.timer.is_armed());
+
+        // Neither a replaced nor a cancelled timeout signals the event.
+        event.arm(Duration::from_nanos(100)).unwrap();
+        event.cancel().unwrap();
+        assert!(!event.timer.is_armed());
+        clock.lock().add_ns(200);
+        if !ctx.wait_timeout(Duration::ZERO).unwrap().is_empty() {
+            assert!(!event.mark_waited().unwrap());
+        }
+    }
+
+    #[test]
+    fn timed_event_signal() {
+        let mut event = TimedEvent::new().expect("failed to create timed event");
+        event.arm(Duration::from_secs(1000)).unwrap();
+        event.signal().unwrap();
+        // Doesn't block for the timeout.
+        event.wait().expect("failed to wait for timed event");
+    }
+
+    #[test]
+    fn fake_stopwatch() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut stopwatch = FakeStopwatch::start(clock.clone());
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+
+        clock.lock().add_ns(150);
+        tfd.wait().expect("unable to wait for timer");
+        assert_eq!(stopwatch.elapsed(), Duration::from_nanos(150));
+
+        stopwatch.reset();
+        assert_eq!(stopwatch.elapsed(), Duration::ZERO);
+        clock.lock().add_ns(25);
+        assert_eq!(stopwatch.elapsed(), Duration::from_nanos(25));
+    }
+
+    #[test]
+    fn fake_interval_boundaries() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        let interval = Duration::from_millis(10);
+
+        tfd.reset(interval, Some(interval))
+            .expect("failed to arm timer");
+        clock
+            .lock()
+            .add_ns(Duration::from_millis(35).as_nanos() as u64);
+        // Expirations at 10, 20 and 30ms, re-armed for 40ms.
+        assert_eq!(tfd.wait_count().unwrap(), 3);
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_millis(5)));
+
+        clock
+            .lock()
+            .add_ns(Duration::from_millis(5).as_nanos() as u64);
+        assert_eq!(tfd.wait_count().unwrap(), 1);
+        assert_eq!(tfd.remaining().unwrap(), Some(interval));
+
+        // A one-shot timer disarms once it fires, so it doesn't fire again.
+        tfd.reset(interval, None).expect("failed to arm timer");
+        clock
+            .lock()
+            .add_ns(Duration::from_millis(20).as_nanos() as u64);
+        assert_eq!(tfd.wait_count().unwrap(), 1);
+        assert_eq!(tfd.remaining().unwrap(), None);
+        let result = tfd
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Timeout);
+    }
+
+    #[test]
+    fn interrupt_wait() {
+        let mut tfd = Timer::new().expect("failed to create Timer");
+        tfd.reset(Duration::from_secs(100), None)
+            .expect("failed to arm timer");
+
+        let interrupt = tfd.arm_interrupt().expect("failed to arm interrupt");
+        let remote = interrupt.try_clone().expect("failed to clone interrupt");
+        let thread = std::thread::spawn(move || remote.interrupt());
+        assert_eq!(
+            tfd.wait_interruptible(&interrupt).unwrap(),
+            WaitResult::Interrupted
+        );
+        thread.join().unwrap().expect("failed to interrupt");
+
+        // The timer is still armed after the interrupted wait.
+        assert!(tfd.remaining().unwrap().is_some());
+    }
+
+    #[test]
+    fn take_interrupt_clears_stale_signal() {
+        let interrupt = InterruptHandle::new(Event::new().unwrap());
+
+        // A signal left over from an interrupt that was already taken is cleared.
+        interrupt.event.signal().unwrap();
+        assert!(!interrupt.take_interrupt().unwrap());
+        assert_eq!(
+            interrupt.event.wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::TimedOut
+        );
+
+        interrupt.interrupt().unwrap();
+        assert!(interrupt.take_interrupt().unwrap());
+        assert!(!interrupt.take_interrupt().unwrap());
+        assert_eq!(
+            interrupt.event.wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn fake_interrupt_wait() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        tfd.reset(Duration::from_nanos(200), None)
+            .expect("failed to arm timer");
+
+        let interrupt = tfd.arm_interrupt().expect("failed to arm interrupt");
+        interrupt.interrupt().expect("failed to interrupt");
+        assert_eq!(
+            tfd.wait_interruptible(&interrupt).unwrap(),
+            WaitResult::Interrupted
+        );
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_nanos(200)));
+
+        // An expiration that races with an interrupt is reported by the next wait.
+        clock.lock().add_ns(200);
+        interrupt.interrupt().expect("failed to interrupt");
+        assert_eq!(
+            tfd.wait_interruptible(&interrupt).unwrap(),
+            WaitResult::Interrupted
+        );
+        assert_eq!(
+            tfd.wait_interruptible(&interrupt).unwrap(),
+            WaitResult::Expired
+        );
+    }
+
+    #[test]
+    fn fake_timer_group() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut group = TimerGroup::new_fake(clock.clone());
+        assert_eq!(group.wait().unwrap(), Vec::new());
+
+        let late = group.add(Duration::from_nanos(300), None).unwrap();
+        let early = group.add(Duration::from_nanos(100), None).unwrap();
+        let repeating = group
+            .add(Duration::from_nanos(100), Some(Duration::from_nanos(100)))
+            .unwrap();
+        let cancelled = group.add(Duration::from_nanos(200), None).unwrap();
+        assert_eq!(group.len(), 4);
+        assert!(group.cancel(cancelled).unwrap());
+        assert!(!group.cancel(cancelled).unwrap());
+
+        clock.lock().add_ns(100);
+        assert_eq!(group.wait().unwrap(), vec![early, repeating]);
+        assert_eq!(group.len(), 2);
+
+        // The cancelled timer's deadline passes without it being reported.
+        clock.lock().add_ns(100);
+        assert_eq!(group.wait().unwrap(), vec![repeating]);
+
+        // Missed periods of the repeating timer are coalesced.
+        clock.lock().add_ns(250);
+        assert_eq!(group.wait().unwrap(), vec![late, repeating]);
+        assert_eq!(group.len(), 1);
+
+        clock.lock().add_ns(50);
+        assert_eq!(group.wait().unwrap(), vec![repeating]);
+    }
+
+    #[test]
+    fn fake_timer_group_long_stall() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut group = TimerGroup::new_fake(clock.clone());
+        let repeating = group
+            .add(Duration::from_nanos(1), Some(Duration::from_nanos(1)))
+            .unwrap();
+        let huge = group
+            .add(Duration::from_nanos(1), Some(Duration::MAX))
+            .unwrap();
+
+        // More periods are missed than fit in a u32, so the series restarts from now. The huge
+        // interval can't be added to the deadline, so that timer is removed.
+        clock.lock().add_ns(u32::MAX as u64 + 10);
+        assert_eq!(group.wait().unwrap(), vec![repeating, huge]);
+        assert_eq!(group.len(), 1);
+        assert_eq!(
+            group.armed.get(&repeating),
+            Some(&(
+                clock.lock().now() + Duration::from_nanos(1),
+                Some(Duration::from_nanos(1))
+            ))
+        );
+
+        clock.lock().add_ns(1);
+        assert_eq!(group.wait().unwrap(), vec![repeating]);
+    }
+
+    #[test]
+    fn fake_resolution() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock);
+        assert_eq!(tfd.resolution().unwrap(), Duration::from_nanos(1));
+        tfd.set_resolution(Duration::from_millis(15));
+        assert_eq!(tfd.resolution().unwrap(), Duration::from_millis(15));
+    }
+
+    #[test]
+    fn fake_rate_limiter() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut limiter = RateLimiter::new_fake(clock.clone(), 2, Duration::from_nanos(100))
+            .expect("failed to create rate limiter");
+
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // Running out of permits arms the timer for the end of the window.
+        clock.lock().add_ns(50);
+        assert!(!limiter.try_acquire());
+        clock.lock().add_ns(50);
+        limiter.timer.wait().expect("failed to wait for refill");
+        limiter.mark_waited().unwrap();
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // Windows that pass without events don't accumulate permits.
+        clock.lock().add_ns(350);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        assert!(RateLimiter::new_fake(clock, 1, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn fake_rate_limiter_long_stall() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut limiter = RateLimiter::new_fake(clock.clone(), 1, Duration::from_nanos(1))
+            .expect("failed to create rate limiter");
+        assert!(limiter.try_acquire());
+
+        // More windows pass than fit in a u32, so the next window starts now.
+        clock.lock().add_ns(u32::MAX as u64 + 10);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+        assert_eq!(
+            limiter.window_end,
+            clock.lock().now() + Duration::from_nanos(1)
+        );
+    }
+
+    #[test]
+    fn fake_readiness() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        let ctx: WaitContext<u32> = WaitContext::build_with(&[(&tfd, 0)]).unwrap();
+        assert!(!tfd.is_armed());
+
+        tfd.reset(Duration::from_nanos(100), None)
+            .expect("failed to reset timer");
+        assert!(tfd.is_armed());
+        assert!(ctx.wait_timeout(Duration::ZERO).unwrap().is_empty());
+
+        // Crossing the deadline makes the descriptor readable until the expiration is drained.
+        clock.lock().add_ns(100);
+        assert!(!tfd.is_armed());
+        let events = ctx.wait_timeout(Duration::ZERO).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_readable);
+        tfd.mark_waited().unwrap();
+        assert!(ctx.wait_timeout(Duration::ZERO).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fake_wait_rearm() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock.clone());
+        let interval = Duration::from_nanos(100);
+        tfd.reset(interval, Some(interval))
+            .expect("failed to reset timer");
+
+        // Falling behind by several intervals reports them once and keeps the original phase.
+        clock.lock().add_ns(350);
+        assert_eq!(tfd.wait_rearm(interval).unwrap(), 3);
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_nanos(50)));
+
+        clock.lock().add_ns(50);
+        assert_eq!(tfd.wait_rearm(interval).unwrap(), 1);
+        assert_eq!(tfd.remaining().unwrap(), Some(interval));
+    }
+
+    #[test]
+    fn fake_reset_clamped() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut tfd = FakeTimer::new(clock);
+        tfd.set_resolution(Duration::from_millis(15));
+
+        tfd.reset_clamped(Duration::from_millis(1), Some(Duration::from_millis(2)))
+            .expect("failed to reset timer");
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_millis(15)));
+        assert_eq!(tfd.interval, Some(Duration::from_millis(15)));
+
+        tfd.reset_clamped(Duration::from_millis(20), None)
+            .expect("failed to reset timer");
+        assert_eq!(tfd.remaining().unwrap(), Some(Duration::from_millis(20)));
+
+        // A zero duration is left alone.
+        assert_eq!(
+            clamp_to_resolution(Duration::ZERO, Duration::from_millis(15)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn fake_advance_shared_clock() {
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut early = FakeTimer::new(clock.clone());
+        let mut late = FakeTimer::new(clock.clone());
+
+        early
+            .reset(Duration::from_nanos(100), None)
+            .expect("failed to arm timer");
+        late.reset(Duration::from_nanos(300), None)
+            .expect("failed to arm timer");
+
+        // A single advance only trips the timer whose deadline has passed.
+        clock.lock().advance(Duration::from_nanos(200));
+        let result = early
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Expired);
+        let result = late
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Timeout);
+
+        clock.lock().advance(Duration::from_nanos(100));
+        let result = late
+            .wait_for(Some(Duration::from_millis(0)))
+            .expect("unable to wait for timer");
+        assert_eq!(result, WaitResult::Expired);
+    }
+
+    #[test]
+    fn fake_boxed_trait_object() {
+        // Production code holds a `Box<dyn TimerTrait>`, so tests can inject a `FakeTimer`.
+        let clock = Arc::new(Mutex::new(FakeClock::new()));
+        let mut timer: Box<dyn TimerTrait> = Box::new(FakeTimer::new(clock.clone()));
+
+        timer
+            .reset(Duration::from_nanos(200), None)
+            .expect("failed to arm timer");
+        assert_eq!(timer.remaining().unwrap(), Some(Duration::from_nanos(200)));
+
+        clock.lock().add_ns(200);
+        assert_eq!(timer.wait().is_ok(), true);
+
+        timer.clear().expect("failed to clear timer");
+        assert_eq!(timer.remaining().unwrap(), None);
+    }
+
     #[test]
     fn fake_repeating() {
         let clock = Arc::new(Mutex::new(FakeClock::new()));
@@ -151,6 +151,9 @@ pub trait BusDevice: Send + Suspendable {
     /// Invoked when the device is destroyed
     fn destroy_device(&mut self) {}
 
+    /// Tears down the interrupt state of a device that is being hot-removed
+    fn release_irq(&mut self) {}
+
     /// Returns the secondary bus number if this bus device is pci bridge
     fn is_bridge(&self) -> Option<u8> {
         None
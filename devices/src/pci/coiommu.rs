@@ -1477,6 +1477,7 @@ impl PciDevice for CoIommuDev {
             addr: mmio_addr,
             size: COIOMMU_MMIO_BAR_SIZE,
             prefetchable: false,
+            io: false,
         });
 
         Ok(ranges)
@@ -1504,6 +1505,7 @@ impl PciDevice for CoIommuDev {
             addr: topologymap_addr,
             size: COIOMMU_TOPOLOGYMAP_SIZE as u64,
             prefetchable: false,
+            io: false,
         });
 
         let notifymap_addr = self.allocate_bar_address(
@@ -1518,6 +1520,7 @@ impl PciDevice for CoIommuDev {
             addr: notifymap_addr,
             size: COIOMMU_NOTIFYMAP_SIZE as u64,
             prefetchable: false,
+            io: false,
         });
 
         Ok(ranges)
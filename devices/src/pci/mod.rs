@@ -79,6 +79,8 @@ pub use self::pci_root::PciRoot;
 pub use self::pci_root::PciRootCommand;
 pub use self::pci_root::PciVirtualConfigMmio;
 #[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::pcie::BridgeWindows;
+#[cfg(any(target_os = "android", target_os = "linux"))]
 pub use self::pcie::PciBridge;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub use self::pcie::PcieDownstreamPort;
@@ -108,6 +110,18 @@ impl PciInterruptPin {
     pub fn to_mask(self) -> u32 {
         self as u32
     }
+
+    /// Returns the pin for the device at `address` rotated by its device and function numbers,
+    /// following the swizzle a PCI-to-PCI bridge applies to the INTx# pins of its slots, so that
+    /// devices in neighbouring slots don't all share INTA#.
+    pub fn from_address(address: PciAddress) -> Self {
+        match (address.dev + address.func) % 4 {
+            0 => PciInterruptPin::IntA,
+            1 => PciInterruptPin::IntB,
+            2 => PciInterruptPin::IntC,
+            _ => PciInterruptPin::IntD,
+        }
+    }
 }
 
 // VCFG
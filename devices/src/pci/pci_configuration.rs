@@ -27,7 +27,7 @@ use thiserror::Error;
 use crate::pci::PciInterruptPin;
 
 // The number of 32bit registers in the config space, 256 bytes.
-const NUM_CONFIGURATION_REGISTERS: usize = 64;
+pub const NUM_CONFIGURATION_REGISTERS: usize = 64;
 
 pub const PCI_ID_REG: usize = 0;
 pub const COMMAND_REG: usize = 1;
@@ -233,6 +233,27 @@ pub trait PciProgrammingInterface {
     fn get_register_value(&self) -> u8;
 }
 
+/// Walks the capability list of the configuration space read through `read_reg`, starting at the
+/// capabilities pointer, and returns the byte offset of the first capability with ID `cap_id`.
+pub fn find_capability(read_reg: impl Fn(usize) -> u32, cap_id: u8) -> Option<usize> {
+    if read_reg(STATUS_REG) & STATUS_REG_CAPABILITIES_USED_MASK == 0 {
+        return None;
+    }
+    let mut offset = (read_reg(CAPABILITY_LIST_HEAD_OFFSET / 4) & 0xfc) as usize;
+    // Every capability takes at least a dword, which bounds the walk even if the list loops.
+    for _ in FIRST_CAPABILITY_OFFSET / 4..NUM_CONFIGURATION_REGISTERS {
+        if offset < FIRST_CAPABILITY_OFFSET {
+            return None;
+        }
+        let header = read_reg(offset / 4);
+        if header & 0xff == cap_id as u32 {
+            return Some(offset);
+        }
+        offset = ((header >> 8) & 0xfc) as usize;
+    }
+    None
+}
+
 /// Types of PCI capabilities.
 pub enum PciCapabilityID {
     ListID = 0,
@@ -481,6 +502,16 @@ impl PciConfiguration {
         data
     }
 
+    /// Returns the byte offset of the first capability with ID `cap_id`, or None if there is none.
+    pub fn find_capability(&self, cap_id: u8) -> Option<usize> {
+        find_capability(|reg_idx| self.read_reg(reg_idx), cap_id)
+    }
+
+    /// Returns the bits of the 32bit register at `reg_idx` that guest writes may modify.
+    pub fn get_writable_bits(&self, reg_idx: usize) -> u32 {
+        self.writable_bits.get(reg_idx).copied().unwrap_or(0)
+    }
+
     /// Writes data to PciConfiguration.registers.
     /// `reg_idx` - index into PciConfiguration.registers.
     /// `offset`  - PciConfiguration.registers is in unit of DWord, offset define byte
@@ -1100,6 +1131,16 @@ mod tests {
         assert_eq!((cap2_data >> 8) & 0xFF, 0x00); // next capability pointer
         assert_eq!((cap2_data >> 16) & 0xFF, 0x04); // cap2.len
         assert_eq!((cap2_data >> 24) & 0xFF, 0x55); // cap2.foo
+
+        // Lookups by ID find the first matching capability.
+        assert_eq!(
+            cfg.find_capability(PciCapabilityID::VendorSpecific as u8),
+            Some(cap1_offset)
+        );
+        assert_eq!(
+            cfg.find_capability(PciCapabilityID::MessageSignalledInterrupts as u8),
+            None
+        );
     }
 
     #[derive(Copy, Clone)]
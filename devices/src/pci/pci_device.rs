@@ -66,6 +66,15 @@ pub enum Error {
     /// Invalid alignment encountered.
     #[error("Alignment must be a power of 2")]
     BadAlignment,
+    /// Two BARs behind a PCI bridge that share a bridge window overlap.
+    #[error("PCI bridge child BARs {0:#x}+{1:#x} and {2:#x}+{3:#x} overlap")]
+    BridgeChildBarsOverlap(u64, u64, u64, u64),
+    /// PCI bridge non-prefetchable memory window can't be encoded in 32 bits.
+    #[error("PCI bridge memory window {0:#x}+{1:#x} does not fit below 4GB")]
+    BridgeWindowAbove4G(u64, u64),
+    /// PCI bridge memory window is not aligned to 1MB.
+    #[error("PCI bridge memory window {0:#x}+{1:#x} is not aligned to 1MB")]
+    BridgeWindowUnaligned(u64, u64),
     /// The new bus has already been added to this bus
     #[error("Added bus {0} already existed on bus {1}")]
     BusAlreadyExist(u8, u8),
@@ -89,6 +98,9 @@ pub enum Error {
     /// Device not exist on this bus
     #[error("pci device {0} does not located on bus {1}")]
     DeviceNotExist(PciAddress, u8),
+    /// PCI bridge bus numbers are not ordered primary < secondary <= subordinate.
+    #[error("invalid PCI bridge bus range: primary {0}, secondary {1}, subordinate {2}")]
+    InvalidBusRange(u8, u8, u8),
     /// Allocating space for an IO BAR failed.
     #[error("failed to allocate space for an IO BAR, size={0}: {1}")]
     IoAllocationFailed(u64, SystemAllocatorFaliure),
@@ -101,6 +113,9 @@ pub enum Error {
     /// Registering an IO BAR failed.
     #[error("failed to register an IO BAR, addr={0} err={1}")]
     IoRegistrationFailed(u64, pci_configuration::Error),
+    /// PCI bridge backend device did not report a bus range.
+    #[error("PCI bridge backend device has no bus range")]
+    MissingBusRange,
     /// Setting up MMIO mapping
     #[error("failed to set up MMIO mapping: {0}")]
     MmioSetup(anyhow::Error),
@@ -158,6 +173,8 @@ pub struct BarRange {
     pub size: u64,
     /// pci bar is prefetchable or not, it used to set parent's bridge window
     pub prefetchable: bool,
+    /// pci bar is port I/O or not, it used to set parent's bridge I/O window
+    pub io: bool,
 }
 
 /// Pci Bus information
@@ -192,6 +209,15 @@ impl PciBus {
         self.bus_num
     }
 
+    // Renumbers this pci bus, e.g. after the guest reprogrammed the bridge in front of it.
+    pub fn set_bus_num(&mut self, bus_num: u8, parent_bus_num: u8) {
+        self.bus_num = bus_num;
+        self.parent_bus_num = parent_bus_num;
+        for child_bus in self.child_buses.values() {
+            child_bus.lock().parent_bus_num = bus_num;
+        }
+    }
+
     // Find all PCI buses from this PCI bus to a given PCI bus
     pub fn path_to(&self, bus_num: u8) -> Vec<u8> {
         if self.bus_num == bus_num {
@@ -339,6 +365,9 @@ impl PciBus {
 pub enum PreferredIrq {
     None,
     Any,
+    // Like `Any`, but the IRQ is routed through `pin` rather than a pin chosen from the function
+    // number.
+    AnyWithPin { pin: PciInterruptPin },
     Fixed { pin: PciInterruptPin, gsi: u32 },
 }
 
@@ -361,7 +390,8 @@ pub trait PciDevice: Send + Suspendable {
     fn keep_rds(&self) -> Vec<RawDescriptor>;
 
     /// Preferred IRQ for this device.
-    /// The device may request a specific pin and IRQ number by returning a `Fixed` value.
+    /// The device may request a specific pin and IRQ number by returning a `Fixed` value, or only
+    /// a specific pin by returning `AnyWithPin`.
     /// If a device does not support INTx# interrupts at all, it should return `None`.
     /// Otherwise, an appropriate IRQ will be allocated automatically.
     /// The device's `assign_irq` function will be called with its assigned IRQ either way.
@@ -374,6 +404,11 @@ pub trait PciDevice: Send + Suspendable {
     /// When `irq_resample_evt` is signaled, the device should re-assert `irq_evt` if necessary.
     fn assign_irq(&mut self, _irq_evt: IrqLevelEvent, _pin: PciInterruptPin, _irq_num: u32) {}
 
+    /// Tears down the interrupt state of a device that is being hot-removed, such as its MSI
+    /// routes. The GSI assigned with `assign_irq` is released by `PciRoot::release_device_irq`,
+    /// which knows whether the device owns it or shares it with other devices.
+    fn release_irq(&mut self) {}
+
     /// Allocates the needed IO BAR space using the `allocate` function which takes a size and
     /// returns an address. Returns a Vec of BarRange{addr, size, prefetchable}.
     fn allocate_io_bars(&mut self, _resources: &mut SystemAllocator) -> Result<Vec<BarRange>> {
@@ -405,12 +440,34 @@ pub trait PciDevice: Send + Suspendable {
     /// * `reg_idx` - PCI register index (in units of 4 bytes).
     fn read_config_register(&self, reg_idx: usize) -> u32;
 
+    /// Reads `count` consecutive PCI configuration registers.
+    /// * `start` - PCI register index of the first register (in units of 4 bytes).
+    /// * `count` - Number of registers to read.
+    fn read_config_registers(&self, start: usize, count: usize) -> Vec<u32> {
+        (start..start + count)
+            .map(|reg_idx| self.read_config_register(reg_idx))
+            .collect()
+    }
+
     /// Writes to a PCI configuration register.
     /// * `reg_idx` - PCI register index (in units of 4 bytes).
     /// * `offset`  - byte offset within 4-byte register.
     /// * `data`    - The data to write.
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]);
 
+    /// Returns the bits of a PCI configuration register that the guest is allowed to modify.
+    /// Defaults to all bits, leaving any masking to `write_config_register`.
+    /// * `reg_idx` - PCI register index (in units of 4 bytes).
+    fn config_register_write_mask(&self, _reg_idx: usize) -> u32 {
+        0xffff_ffff
+    }
+
+    /// Returns the byte offset in configuration space of the first capability with ID `cap_id`,
+    /// found by walking the capability list, or None if the device doesn't have one.
+    fn find_capability(&self, cap_id: u8) -> Option<usize> {
+        pci_configuration::find_capability(|reg_idx| self.read_config_register(reg_idx), cap_id)
+    }
+
     /// Provides a memory region to back MMIO access to the configuration
     /// space. If the device can keep the memory region up to date, then it
     /// should return Ok(true), after which no more calls to read_config_register
@@ -752,6 +809,10 @@ impl<T: PciDevice> BusDevice for T {
         self.destroy_device()
     }
 
+    fn release_irq(&mut self) {
+        self.release_irq()
+    }
+
     fn is_bridge(&self) -> Option<u8> {
         self.get_new_pci_bus().map(|bus| bus.lock().get_bus_num())
     }
@@ -777,6 +838,9 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn assign_irq(&mut self, irq_evt: IrqLevelEvent, pin: PciInterruptPin, irq_num: u32) {
         (**self).assign_irq(irq_evt, pin, irq_num)
     }
+    fn release_irq(&mut self) {
+        (**self).release_irq()
+    }
     fn allocate_io_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<BarRange>> {
         (**self).allocate_io_bars(resources)
     }
@@ -801,9 +865,18 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn read_config_register(&self, reg_idx: usize) -> u32 {
         (**self).read_config_register(reg_idx)
     }
+    fn read_config_registers(&self, start: usize, count: usize) -> Vec<u32> {
+        (**self).read_config_registers(start, count)
+    }
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
         (**self).write_config_register(reg_idx, offset, data)
     }
+    fn config_register_write_mask(&self, reg_idx: usize) -> u32 {
+        (**self).config_register_write_mask(reg_idx)
+    }
+    fn find_capability(&self, cap_id: u8) -> Option<usize> {
+        (**self).find_capability(cap_id)
+    }
     fn setup_pci_config_mapping(
         &mut self,
         shmem: &SharedMemory,
@@ -904,6 +977,8 @@ mod tests {
 
     struct TestDev {
         pub config_regs: PciConfiguration,
+        // BAR index, offset and size of each BAR access, in order.
+        pub bar_accesses: Vec<(PciBarIndex, u64, usize)>,
     }
 
     impl PciDevice for TestDev {
@@ -923,9 +998,13 @@ mod tests {
             self.config_regs.write_reg(reg_idx, offset, data);
         }
 
-        fn read_bar(&mut self, _bar_index: PciBarIndex, _offset: u64, _data: &mut [u8]) {}
+        fn read_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &mut [u8]) {
+            self.bar_accesses.push((bar_index, offset, data.len()));
+        }
 
-        fn write_bar(&mut self, _bar_index: PciBarIndex, _offset: u64, _data: &[u8]) {}
+        fn write_bar(&mut self, bar_index: PciBarIndex, offset: u64, data: &[u8]) {
+            self.bar_accesses.push((bar_index, offset, data.len()));
+        }
 
         fn allocate_address(&mut self, _resources: &mut SystemAllocator) -> Result<PciAddress> {
             Err(Error::PciAllocationFailed)
@@ -952,6 +1031,7 @@ mod tests {
                 0xEF01,
                 0,
             ),
+            bar_accesses: Vec::new(),
         };
 
         let _ = test_dev.config_regs.add_pci_bar(
@@ -1088,6 +1168,7 @@ mod tests {
                 0xEF01,
                 0,
             ),
+            bar_accesses: Vec::new(),
         };
 
         let _ = dev.config_regs.add_pci_bar(
@@ -1132,4 +1213,58 @@ mod tests {
         // BAR2 (I/O BAR)
         assert_eq!(find_bar_and_offset(&dev, 0x800, 1), None);
     }
+
+    #[test]
+    fn bar_access_dispatch() {
+        let mut dev = TestDev {
+            config_regs: PciConfiguration::new(
+                0x1234,
+                0xABCD,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioDevice,
+                None,
+                PciHeaderType::Device,
+                0x5678,
+                0xEF01,
+                0,
+            ),
+            bar_accesses: Vec::new(),
+        };
+
+        let _ = dev.config_regs.add_pci_bar(
+            PciBarConfiguration::new(
+                0,
+                BAR0_SIZE,
+                PciBarRegionType::Memory64BitRegion,
+                PciBarPrefetchable::Prefetchable,
+            )
+            .set_address(BAR0_ADDR),
+        );
+        let _ = dev.config_regs.add_pci_bar(
+            PciBarConfiguration::new(
+                2,
+                BAR2_SIZE,
+                PciBarRegionType::Memory32BitRegion,
+                PciBarPrefetchable::NotPrefetchable,
+            )
+            .set_address(0xd0000000),
+        );
+
+        let access = |address| BusAccessInfo {
+            offset: 0,
+            address,
+            id: 0,
+        };
+        let mut data = [0u8; 4];
+        BusDevice::read(&mut dev, access(BAR0_ADDR + 0x10), &mut data);
+        BusDevice::write(&mut dev, access(0xd0000004), &data);
+        BusDevice::read(&mut dev, access(0xd0000000 + BAR2_SIZE - 1), &mut data[..1]);
+        // Crosses the end of BAR2, so it is not dispatched.
+        BusDevice::write(&mut dev, access(0xd0000000 + BAR2_SIZE - 2), &data);
+
+        assert_eq!(
+            dev.bar_accesses,
+            vec![(0, 0x10, 4), (2, 0x4, 4), (2, BAR2_SIZE - 1, 1)]
+        );
+    }
 }
@@ -156,6 +156,8 @@ pub struct PciRoot {
     root_configuration: PciRootConfiguration,
     /// Devices attached to this bridge.
     devices: BTreeMap<PciAddress, Arc<Mutex<dyn BusDevice>>>,
+    /// GSIs reserved for a single hot-plugged device, released when that device is removed.
+    owned_irqs: BTreeMap<PciAddress, u32>,
     /// pcie enhanced configuration access mmio base
     pcie_cfg_mmio: Option<u64>,
     pci_mmio_state: PciRootMmioState,
@@ -196,7 +198,7 @@ impl PciRoot {
         Ok(root)
     }
 
-    fn create_for_test(
+    pub(crate) fn create_for_test(
         mmio_bus: Weak<Bus>,
         mmio_base: GuestAddress,
         mmio_register_bit_num: usize,
@@ -221,6 +223,7 @@ impl PciRoot {
                 ),
             },
             devices: BTreeMap::new(),
+            owned_irqs: BTreeMap::new(),
             pcie_cfg_mmio: None,
             pci_mmio_state: PciRootMmioState {
                 mappings: BTreeMap::new(),
@@ -323,6 +326,24 @@ impl PciRoot {
         self.root_bus.lock().add_child_bus(bridge_bus)
     }
 
+    /// Records that `gsi` was reserved for the hot-plugged device at `address` alone, so it is
+    /// released with the device. GSIs that are not claimed, such as one that another function of
+    /// the same host device reserved first, stay allocated.
+    pub fn claim_device_irq(&mut self, address: PciAddress, gsi: u32) {
+        self.owned_irqs.insert(address, gsi);
+    }
+
+    /// Tears down the interrupts of the device at `address`, which is being hot-removed, and
+    /// returns the GSI it claimed with `claim_device_irq`, if any, to `resources`.
+    pub fn release_device_irq(&mut self, address: PciAddress, resources: &mut SystemAllocator) {
+        if let Some(d) = self.devices.get(&address) {
+            d.lock().release_irq();
+        }
+        if let Some(gsi) = self.owned_irqs.remove(&address) {
+            resources.release_irq(gsi);
+        }
+    }
+
     pub fn remove_device(&mut self, address: PciAddress) {
         if let Some(d) = self.devices.remove(&address) {
             for (range, bus_type) in d.lock().get_ranges() {
@@ -990,8 +1011,12 @@ impl Suspendable for PciVirtualConfigMmio {
 #[cfg(test)]
 mod tests {
     use base::Tube;
+    use resources::AddressRange;
+    use resources::SystemAllocatorConfig;
 
     use super::*;
+    use crate::pci::PciInterruptPin;
+    use crate::pci::PreferredIrq;
     use crate::suspendable_tests;
 
     fn create_pci_root() -> Arc<Mutex<PciRoot>> {
@@ -1084,4 +1109,100 @@ mod tests {
         );
         assert_eq!(config_address, [0x44, 0x33, 0xAA, 0x55]);
     }
+
+    // A passthrough function with a GSI fixed by the host, relying on the default `release_irq`.
+    struct FixedIrqDevice {
+        gsi: u32,
+    }
+
+    impl PciDevice for FixedIrqDevice {
+        fn debug_label(&self) -> String {
+            "fixed irq".to_owned()
+        }
+
+        fn allocate_address(
+            &mut self,
+            _resources: &mut SystemAllocator,
+        ) -> Result<PciAddress, Error> {
+            Err(Error::PciAllocationFailed)
+        }
+
+        fn keep_rds(&self) -> Vec<RawDescriptor> {
+            Vec::new()
+        }
+
+        fn preferred_irq(&self) -> PreferredIrq {
+            PreferredIrq::Fixed {
+                pin: PciInterruptPin::IntA,
+                gsi: self.gsi,
+            }
+        }
+
+        fn read_config_register(&self, _reg_idx: usize) -> u32 {
+            0
+        }
+
+        fn write_config_register(&mut self, _reg_idx: usize, _offset: u64, _data: &[u8]) {}
+
+        fn read_bar(&mut self, _bar_index: PciBarIndex, _offset: u64, _data: &mut [u8]) {}
+
+        fn write_bar(&mut self, _bar_index: PciBarIndex, _offset: u64, _data: &[u8]) {}
+
+        fn get_bar_configuration(&self, _bar_num: usize) -> Option<PciBarConfiguration> {
+            None
+        }
+    }
+
+    impl Suspendable for FixedIrqDevice {}
+
+    #[test]
+    fn release_shared_fixed_irq() {
+        let mut allocator = SystemAllocator::new(
+            SystemAllocatorConfig {
+                io: None,
+                low_mmio: AddressRange {
+                    start: 0x2000_0000,
+                    end: 0x2fff_ffff,
+                },
+                high_mmio: AddressRange {
+                    start: 0x1_0000_0000,
+                    end: 0x1_0fff_ffff,
+                },
+                platform_mmio: None,
+                first_irq: 5,
+            },
+            None,
+            &[],
+        )
+        .unwrap();
+        let pci_root = create_pci_root();
+        let mut root = pci_root.lock();
+        let gsi = 10;
+
+        // Two functions of the same host device share a GSI, plugged the way
+        // `arch::configure_pci_device` does it.
+        let addresses = [
+            PciAddress::new(0, 1, 0, 0).unwrap(),
+            PciAddress::new(0, 1, 0, 1).unwrap(),
+        ];
+        for address in addresses {
+            let device = FixedIrqDevice { gsi };
+            if let PreferredIrq::Fixed { gsi, .. } = device.preferred_irq() {
+                if allocator.reserve_irq(gsi) {
+                    root.claim_device_irq(address, gsi);
+                }
+            }
+            root.devices.insert(address, Arc::new(Mutex::new(device)));
+        }
+
+        // The second function didn't reserve the GSI, so removing it leaves it allocated.
+        root.release_device_irq(addresses[1], &mut allocator);
+        root.remove_device(addresses[1]);
+        assert!(!allocator.reserve_irq(gsi));
+
+        // The function that reserved the GSI releases it.
+        root.release_device_irq(addresses[0], &mut allocator);
+        root.remove_device(addresses[0]);
+        assert!(allocator.reserve_irq(gsi));
+    }
 }
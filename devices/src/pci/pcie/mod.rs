@@ -9,6 +9,8 @@ mod pcie_port;
 mod pcie_rp;
 mod pcie_switch;
 
+pub use pci_bridge::BridgeInterrupt;
+pub use pci_bridge::BridgeWindows;
 pub use pci_bridge::PciBridge;
 pub use pcie_host::PcieHostPort;
 pub use pcie_rp::PcieRootPort;
@@ -40,6 +42,7 @@ const PCIE_DEVCAP_RBER: u32 = 0x0000_8000;
 const PCIE_LINK_X1: u16 = 0x10;
 const PCIE_LINK_2_5GT: u16 = 0x01;
 
+const PCIE_SLTCAP_OFFSET: usize = 0x14;
 const PCIE_SLTCAP_ABP: u32 = 0x01; // Attention Button Present
 const PCIE_SLTCAP_AIP: u32 = 0x08; // Attention Indicator Present
 const PCIE_SLTCAP_PIP: u32 = 0x10; // Power Indicator Present
@@ -6,10 +6,16 @@ use std::cmp::max;
 use std::cmp::min;
 use std::sync::Arc;
 
+use base::error;
 use base::warn;
-use base::AsRawDescriptors;
+use base::Descriptor;
+use base::Event;
+use base::EventToken;
 use base::RawDescriptor;
+use base::RawDescriptorSet;
 use base::Tube;
+use base::WaitContext;
+use base::WorkerThread;
 use resources::Alloc;
 use resources::AllocOptions;
 use resources::SystemAllocator;
@@ -18,7 +24,14 @@ use sync::Mutex;
 use crate::pci::msi::MsiCap;
 use crate::pci::msi::MsiConfig;
 use crate::pci::pci_configuration::PciBridgeSubclass;
+use crate::pci::pci_configuration::PciCapabilityID;
+use crate::pci::pci_configuration::COMMAND_REG;
+use crate::pci::pci_configuration::COMMAND_REG_IO_SPACE_MASK;
+use crate::pci::pci_configuration::COMMAND_REG_MEMORY_SPACE_MASK;
+use crate::pci::pci_configuration::NUM_CONFIGURATION_REGISTERS;
 use crate::pci::pcie::pcie_device::PcieDevice;
+use crate::pci::pcie::pcie_device::PcieSlotCap;
+use crate::pci::pcie::PCIE_SLTCAP_OFFSET;
 use crate::pci::BarRange;
 use crate::pci::PciAddress;
 use crate::pci::PciBarConfiguration;
@@ -29,6 +42,7 @@ use crate::pci::PciConfiguration;
 use crate::pci::PciDevice;
 use crate::pci::PciDeviceError;
 use crate::pci::PciHeaderType;
+use crate::pci::PreferredIrq;
 use crate::pci::PCI_VENDOR_ID_INTEL;
 use crate::IrqLevelEvent;
 use crate::PciInterruptPin;
@@ -36,6 +50,13 @@ use crate::Suspendable;
 
 pub const BR_BUS_NUMBER_REG: usize = 0x6;
 pub const BR_BUS_SUBORDINATE_OFFSET: usize = 0x2;
+pub const BR_IO_REG: usize = 0x7;
+// bit[7:4] is I/O base[15:12], bit[15:12] is I/O limit[15:12], alignment to 4KB
+pub const BR_IO_BASE_LIMIT_MASK: u32 = 0xF0F0;
+pub const BR_IO_WINDOW_ALIGNMENT: u64 = 0x1000;
+pub const BR_IO_WINDOW_MASK: u64 = !(BR_IO_WINDOW_ALIGNMENT - 1);
+// Only 16-bit I/O addressing is supported
+pub const BR_IO_MAX: u64 = 0x1_0000;
 pub const BR_MEM_REG: usize = 0x8;
 // bit[15:4] is memory base[31:20] and alignment to 1MB
 pub const BR_MEM_BASE_MASK: u32 = 0xFFF0;
@@ -45,25 +66,138 @@ pub const BR_MEM_LIMIT_MASK: u32 = 0xFFF0_0000;
 pub const BR_PREF_MEM_LOW_REG: usize = 0x9;
 // bit[0] and bit[16] is 64bit memory flag
 pub const BR_PREF_MEM_64BIT: u32 = 0x001_0001;
+// base above limit, which disables the prefetchable window
+pub const BR_PREF_MEM_DISABLED: u32 = 0x0000_FFF0;
 pub const BR_PREF_MEM_BASE_HIGH_REG: usize = 0xa;
 pub const BR_PREF_MEM_LIMIT_HIGH_REG: usize = 0xb;
 pub const BR_WINDOW_ALIGNMENT: u64 = 0x10_0000;
 pub const BR_WINDOW_MASK: u64 = !(BR_WINDOW_ALIGNMENT - 1);
 // Kernel allocate at least 2MB mmio for each bridge memory window
 pub const BR_MEM_MINIMUM: u64 = 0x20_0000;
+// The non-prefetchable memory window only has 32-bit base and limit registers
+pub const BR_MEM_MAX: u64 = 0x1_0000_0000;
+// Bridge control register, in the upper half of the interrupt line/pin register.
+pub const BR_CONTROL_REG: usize = 0xf;
+pub const BR_CONTROL_SEC_BUS_RESET: u32 = 0x0040_0000;
 
 /// Holds the bus range for a pci bridge
 ///
 /// * primary - primary bus number
 /// * secondary - secondary bus number
 /// * subordinate - subordinate bus number
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct PciBridgeBusRange {
     pub primary: u8,
     pub secondary: u8,
     pub subordinate: u8,
 }
 
+impl PciBridgeBusRange {
+    /// Checks that the secondary bus is behind the primary bus and that the subordinate bus is
+    /// not below the secondary bus, as PCI requires.
+    pub fn check(&self) -> std::result::Result<(), PciDeviceError> {
+        if self.primary < self.secondary && self.secondary <= self.subordinate {
+            Ok(())
+        } else {
+            Err(PciDeviceError::InvalidBusRange(
+                self.primary,
+                self.secondary,
+                self.subordinate,
+            ))
+        }
+    }
+}
+
+/// Holds the memory windows programmed into a bridge's configuration space, each as an inclusive
+/// (base, limit) pair. A window whose base is above its limit is disabled and reported as `None`.
+///
+/// * mem - non-prefetchable memory window
+/// * pref_mem - prefetchable memory window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BridgeWindows {
+    pub mem: Option<(u64, u64)>,
+    pub pref_mem: Option<(u64, u64)>,
+}
+
+/// The interrupt of a pci bridge, shared with the `PcieDevice` behind it so that the device's
+/// hotplug and PME events reach the guest through MSI or, when MSI is disabled, through the INTx
+/// line assigned to the bridge.
+pub struct BridgeInterrupt {
+    msi_config: Arc<Mutex<MsiConfig>>,
+    interrupt_evt: Option<IrqLevelEvent>,
+}
+
+impl BridgeInterrupt {
+    fn new(msi_config: Arc<Mutex<MsiConfig>>) -> Self {
+        BridgeInterrupt {
+            msi_config,
+            interrupt_evt: None,
+        }
+    }
+
+    /// Signals an interrupt, through MSI when the guest has enabled it and through the legacy
+    /// INTx line otherwise.
+    pub fn do_interrupt(&self) {
+        let msi_config = self.msi_config.lock();
+        if msi_config.is_msi_enabled() {
+            msi_config.trigger();
+        } else if let Some(interrupt_evt) = &self.interrupt_evt {
+            if let Err(e) = interrupt_evt.trigger() {
+                error!("failed to trigger bridge INTx: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    Resample,
+    Kill,
+}
+
+// Re-asserts the INTx line of a bridge after the guest's EOI for as long as the device behind it
+// still has an interrupt pending, so that a level interrupt is only deasserted once the guest has
+// consumed the hotplug or PME event.
+fn resample_worker(
+    device: Arc<Mutex<dyn PcieDevice>>,
+    interrupt: Arc<Mutex<BridgeInterrupt>>,
+    interrupt_evt: IrqLevelEvent,
+    kill_evt: Event,
+) {
+    let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+        (interrupt_evt.get_resample(), Token::Resample),
+        (&kill_evt, Token::Kill),
+    ]) {
+        Ok(wait_ctx) => wait_ctx,
+        Err(e) => {
+            error!("failed to build WaitContext: {}", e);
+            return;
+        }
+    };
+
+    'wait: loop {
+        let events = match wait_ctx.wait() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("error while waiting for events: {}", e);
+                break;
+            }
+        };
+
+        for event in events.iter().filter(|e| e.is_readable) {
+            match event.token {
+                Token::Resample => {
+                    interrupt_evt.clear_resample();
+                    if device.lock().interrupt_pending() {
+                        interrupt.lock().do_interrupt();
+                    }
+                }
+                Token::Kill => break 'wait,
+            }
+        }
+    }
+}
+
 pub struct PciBridge {
     device: Arc<Mutex<dyn PcieDevice>>,
     config: PciConfiguration,
@@ -71,11 +205,25 @@ pub struct PciBridge {
     pci_bus: Arc<Mutex<PciBus>>,
     bus_range: PciBridgeBusRange,
     msi_config: Arc<Mutex<MsiConfig>>,
-    interrupt_evt: Option<IrqLevelEvent>,
+    interrupt: Arc<Mutex<BridgeInterrupt>>,
+    resample_thread: Option<WorkerThread<()>>,
+    // Whether the prefetchable window decodes 64-bit addresses
+    pref_mem_64bit: bool,
+    // Whether the bridge decodes a prefetchable window at all
+    prefetchable_enabled: bool,
+    // Whether guest writes to the bus number register update bus_range
+    allow_guest_bus_renumber: bool,
+    // Size reserved for a memory window that no child device needs
+    min_window_size: u64,
+    // Signaled whenever a guest config write changes a register
+    config_write_sink: Option<Event>,
 }
 
 impl PciBridge {
-    pub fn new(device: Arc<Mutex<dyn PcieDevice>>, msi_device_tube: Tube) -> Self {
+    pub fn new(
+        device: Arc<Mutex<dyn PcieDevice>>,
+        msi_device_tube: Tube,
+    ) -> std::result::Result<Self, PciDeviceError> {
         let device_id = device.lock().get_device_id();
         let msi_config = Arc::new(Mutex::new(MsiConfig::new(
             true,
@@ -99,12 +247,12 @@ impl PciBridge {
         let msi_cap = MsiCap::new(true, false);
         config
             .add_capability(&msi_cap, Some(Box::new(msi_config.clone())))
-            .map_err(PciDeviceError::CapabilitiesSetup)
-            .unwrap();
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
         let bus_range = device
             .lock()
             .get_bus_range()
-            .expect("PciBridge's backend device must implement get_bus_range()");
+            .ok_or(PciDeviceError::MissingBusRange)?;
+        bus_range.check()?;
 
         let data = [
             bus_range.primary,
@@ -113,64 +261,257 @@ impl PciBridge {
             0,
         ];
         config.write_reg(BR_BUS_NUMBER_REG, 0, &data[..]);
+        // Forward accesses to the windows until the guest turns forwarding off.
+        let forwarding = COMMAND_REG_MEMORY_SPACE_MASK | COMMAND_REG_IO_SPACE_MASK;
+        config.set_reg(COMMAND_REG, forwarding, forwarding);
         let pci_bus = Arc::new(Mutex::new(PciBus::new(
             bus_range.secondary,
             bus_range.primary,
             device.lock().hotplug_implemented(),
         )));
 
-        PciBridge {
+        Ok(PciBridge {
             device,
             config,
             pci_address: None,
             pci_bus,
             bus_range,
+            interrupt: Arc::new(Mutex::new(BridgeInterrupt::new(msi_config.clone()))),
             msi_config,
-            interrupt_evt: None,
+            resample_thread: None,
+            pref_mem_64bit: true,
+            prefetchable_enabled: true,
+            allow_guest_bus_renumber: false,
+            min_window_size: BR_MEM_MINIMUM,
+            config_write_sink: None,
+        })
+    }
+
+    /// Sets whether the prefetchable memory window decodes 64-bit addresses. Bridges are 64-bit
+    /// by default. A 32-bit bridge reports so in its config space, gets its prefetchable window
+    /// allocated below 4GB and leaves the upper 32 bits of the window registers zero.
+    pub fn set_pref_mem_64bit(&mut self, pref_mem_64bit: bool) {
+        self.pref_mem_64bit = pref_mem_64bit;
+        let value = if pref_mem_64bit { BR_PREF_MEM_64BIT } else { 0 };
+        self.config
+            .set_reg(BR_PREF_MEM_LOW_REG, value, BR_PREF_MEM_64BIT);
+        if !pref_mem_64bit {
+            self.config.set_reg(BR_PREF_MEM_BASE_HIGH_REG, 0, u32::MAX);
+            self.config.set_reg(BR_PREF_MEM_LIMIT_HIGH_REG, 0, u32::MAX);
+        }
+    }
+
+    /// Sets whether the bridge decodes a prefetchable memory window, which it does by default.
+    /// Without one, the prefetchable base and limit registers hold the disabled encoding (base
+    /// above limit), no prefetchable window is allocated, and prefetchable BARs of child devices
+    /// are placed in the memory window instead.
+    pub fn set_prefetchable_enabled(&mut self, enabled: bool) {
+        self.prefetchable_enabled = enabled;
+        let value = if enabled { 0 } else { BR_PREF_MEM_DISABLED };
+        self.config
+            .set_reg(BR_PREF_MEM_LOW_REG, value, !BR_PREF_MEM_64BIT);
+        self.config.set_reg(BR_PREF_MEM_BASE_HIGH_REG, 0, u32::MAX);
+        self.config.set_reg(BR_PREF_MEM_LIMIT_HIGH_REG, 0, u32::MAX);
+    }
+
+    /// Sets whether bus numbers programmed by the guest are adopted by the bridge. By default they
+    /// are only logged, and `get_secondary_num`/`get_subordinate_num` keep reporting the bus
+    /// numbers the bridge was created with.
+    pub fn set_allow_guest_bus_renumber(&mut self, allow: bool) {
+        self.allow_guest_bus_renumber = allow;
+    }
+
+    /// Sets the size reserved for a memory or prefetchable window that no child device needs,
+    /// which is `BR_MEM_MINIMUM` by default. The size is rounded up to a multiple of
+    /// `BR_WINDOW_ALIGNMENT`, and a window is never smaller than `BR_WINDOW_ALIGNMENT`.
+    pub fn set_min_window_size(&mut self, min_window_size: u64) {
+        let aligned = (min_window_size.max(1) + BR_WINDOW_ALIGNMENT - 1) & BR_WINDOW_MASK;
+        if aligned != min_window_size {
+            warn!(
+                "{} bridge minimum window size {:#x} rounded up to {:#x}",
+                self.device.lock().debug_label(),
+                min_window_size,
+                aligned
+            );
         }
+        self.min_window_size = aligned;
     }
 
+    /// Returns the INTx# pin the bridge requests, rotated by its device and function numbers with
+    /// `PciInterruptPin::from_address` so that bridges in different slots spread their interrupts
+    /// across the pins. Falls back to INTA# before the bridge has an address.
+    pub fn interrupt_pin(&self) -> PciInterruptPin {
+        self.pci_address
+            .map_or(PciInterruptPin::IntA, PciInterruptPin::from_address)
+    }
+
+    /// Signals `sink` whenever `write_config_register` changes the value of a config register, so
+    /// that a reader such as a live migration thread only needs to re-read the config space after
+    /// a change. Writes that leave every register unchanged don't signal it. Replaces any
+    /// previously subscribed event.
+    pub fn subscribe_config_writes(&mut self, sink: Event) {
+        self.config_write_sink = Some(sink);
+    }
+
+    /// Signals an interrupt from the bridge, through MSI when the guest has enabled it and
+    /// through the legacy INTx line assigned with `assign_irq` otherwise. The device behind the
+    /// bridge raises its hotplug and PME events the same way.
+    pub fn do_interrupt(&self) {
+        self.interrupt.lock().do_interrupt()
+    }
+
+    /// Replaces the read-only bits of a guest config write with the current register contents, so
+    /// the backing device never sees a write to a field such as the class code or header type.
+    /// Each byte is masked by the register it lands in, so an unaligned write is masked too.
+    /// Extended config space has no write mask in `config` and is left to the backing device.
+    fn mask_config_write(&self, reg_idx: usize, offset: u64, data: &[u8]) -> Vec<u8> {
+        let start = reg_idx * 4 + offset as usize;
+        data.iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                let (reg_idx, shift) = ((start + i) / 4, (start + i) % 4 * 8);
+                if reg_idx >= NUM_CONFIGURATION_REGISTERS {
+                    return byte;
+                }
+                let current = (self.read_config_register(reg_idx) >> shift) as u8;
+                let mask = (self.config_register_write_mask(reg_idx) >> shift) as u8;
+                (current & !mask) | (byte & mask)
+            })
+            .collect()
+    }
+
+    /// Returns the slot capabilities the bridge's PCIe capability advertises. A hotplug slot is
+    /// only advertised if the backing device implements hotplug and the bridge reserves a memory
+    /// window for the devices plugged into it. A static bridge always reserves at least the
+    /// minimum window size, a hotplugged one only the window size its device asks for.
+    pub fn build_slot_capabilities(&self) -> PcieSlotCap {
+        let device = self.device.lock();
+        let (window_size, pref_window_size) = device.get_bridge_window_size();
+        let mut reserved = window_size.saturating_add(pref_window_size);
+        if !device.hotplugged() {
+            reserved = max(reserved, self.min_window_size);
+        }
+        PcieSlotCap::new(device.hotplug_implemented() && reserved != 0)
+    }
+
+    /// Programs the memory and prefetchable memory windows. A window with a zero size is left
+    /// unprogrammed, otherwise its base and size must both be aligned to 1MB.
     fn write_bridge_window(
         &mut self,
         window_base: u32,
         window_size: u32,
         pref_window_base: u64,
         pref_window_size: u64,
-    ) {
-        // both window_base and window_size should be aligned to 1M
-        if window_base & (BR_WINDOW_ALIGNMENT as u32 - 1) == 0
-            && window_size != 0
-            && window_size & (BR_WINDOW_ALIGNMENT as u32 - 1) == 0
-        {
+    ) -> std::result::Result<(), PciDeviceError> {
+        // Check both windows before writing either, so a bad window doesn't leave the bridge
+        // half programmed.
+        check_window_alignment(window_base as u64, window_size as u64)?;
+        check_window_alignment(pref_window_base, pref_window_size)?;
+
+        if window_size != 0 {
             // the top of memory will be one less than a 1MB boundary
             let limit = window_base + window_size - BR_WINDOW_ALIGNMENT as u32;
             let value = (window_base >> BR_MEM_BASE_SHIFT) | limit;
             self.write_config_register(BR_MEM_REG, 0, &value.to_le_bytes());
         }
 
-        // both pref_window_base and pref_window_size should be aligned to 1M
-        if pref_window_base & (BR_WINDOW_ALIGNMENT - 1) == 0
-            && pref_window_size != 0
-            && pref_window_size & (BR_WINDOW_ALIGNMENT - 1) == 0
-        {
+        if pref_window_size != 0 {
             // the top of memory will be one less than a 1MB boundary
             let limit = pref_window_base + pref_window_size - BR_WINDOW_ALIGNMENT;
-            let low_value = ((pref_window_base as u32) >> BR_MEM_BASE_SHIFT)
-                | (limit as u32)
-                | BR_PREF_MEM_64BIT;
-            self.write_config_register(BR_PREF_MEM_LOW_REG, 0, &low_value.to_le_bytes());
-            let high_base_value = (pref_window_base >> 32) as u32;
-            self.write_config_register(
-                BR_PREF_MEM_BASE_HIGH_REG,
-                0,
-                &high_base_value.to_le_bytes(),
-            );
-            let high_top_value = (limit >> 32) as u32;
-            self.write_config_register(
-                BR_PREF_MEM_LIMIT_HIGH_REG,
-                0,
-                &high_top_value.to_le_bytes(),
+            let low_value = ((pref_window_base as u32) >> BR_MEM_BASE_SHIFT) | (limit as u32);
+            if self.pref_mem_64bit {
+                let low_value = low_value | BR_PREF_MEM_64BIT;
+                self.write_config_register(BR_PREF_MEM_LOW_REG, 0, &low_value.to_le_bytes());
+                let high_base_value = (pref_window_base >> 32) as u32;
+                self.write_config_register(
+                    BR_PREF_MEM_BASE_HIGH_REG,
+                    0,
+                    &high_base_value.to_le_bytes(),
+                );
+                let high_top_value = (limit >> 32) as u32;
+                self.write_config_register(
+                    BR_PREF_MEM_LIMIT_HIGH_REG,
+                    0,
+                    &high_top_value.to_le_bytes(),
+                );
+            } else if limit > u32::MAX as u64 {
+                warn!(
+                    "prefetchable window {:#x}-{:#x} is above 4GB on a 32-bit bridge",
+                    pref_window_base, limit
+                );
+            } else {
+                self.write_config_register(BR_PREF_MEM_LOW_REG, 0, &low_value.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bridge_io_window(&mut self, io_window_base: u64, io_window_size: u64) {
+        // both io_window_base and io_window_size should be aligned to 4K
+        if io_window_base & (BR_IO_WINDOW_ALIGNMENT - 1) != 0
+            || io_window_size == 0
+            || io_window_size & (BR_IO_WINDOW_ALIGNMENT - 1) != 0
+        {
+            return;
+        }
+        if io_window_base + io_window_size > BR_IO_MAX {
+            warn!(
+                "PCI bridge I/O window {:#x}+{:#x} needs 32-bit I/O addressing",
+                io_window_base, io_window_size
             );
+            return;
+        }
+        // the top of I/O will be one less than a 4KB boundary
+        let limit = (io_window_base + io_window_size - BR_IO_WINDOW_ALIGNMENT) as u32;
+        let value = ((io_window_base as u32) >> 8) | limit;
+        // The I/O window is read-only to the guest, so set it directly.
+        self.config.set_reg(BR_IO_REG, value, BR_IO_BASE_LIMIT_MASK);
+    }
+
+    /// Returns whether the bridge forwards accesses to its (memory, I/O) windows, as set by the
+    /// Memory Space and I/O Space enable bits of its command register. Both are enabled when the
+    /// bridge is created.
+    pub fn forwarding_enabled(&self) -> (bool, bool) {
+        let command = self.config.read_reg(COMMAND_REG);
+        (
+            command & COMMAND_REG_MEMORY_SPACE_MASK != 0,
+            command & COMMAND_REG_IO_SPACE_MASK != 0,
+        )
+    }
+
+    /// Decodes the memory windows currently programmed into the bridge's configuration space.
+    /// Both windows are reported as `None` while memory forwarding is disabled.
+    pub fn get_windows(&self) -> BridgeWindows {
+        let (mem_enabled, _) = self.forwarding_enabled();
+        if !mem_enabled {
+            return BridgeWindows {
+                mem: None,
+                pref_mem: None,
+            };
+        }
+
+        let mem_reg = self.config.read_reg(BR_MEM_REG);
+        let mem_base = u64::from(mem_reg & BR_MEM_BASE_MASK) << BR_MEM_BASE_SHIFT;
+        let mem_limit = u64::from(mem_reg & BR_MEM_LIMIT_MASK) | !BR_WINDOW_MASK;
+
+        let pref_reg = self.config.read_reg(BR_PREF_MEM_LOW_REG);
+        let mut pref_base = u64::from(pref_reg & BR_MEM_BASE_MASK) << BR_MEM_BASE_SHIFT;
+        let mut pref_limit = u64::from(pref_reg & BR_MEM_LIMIT_MASK) | !BR_WINDOW_MASK;
+        if pref_reg & BR_PREF_MEM_64BIT != 0 {
+            pref_base |= u64::from(self.config.read_reg(BR_PREF_MEM_BASE_HIGH_REG)) << 32;
+            pref_limit |= u64::from(self.config.read_reg(BR_PREF_MEM_LIMIT_HIGH_REG)) << 32;
+        }
+
+        let window = |base, limit| {
+            if base <= limit {
+                Some((base, limit))
+            } else {
+                None
+            }
+        };
+        BridgeWindows {
+            mem: window(mem_base, mem_limit),
+            pref_mem: window(pref_base, pref_limit),
         }
     }
 
@@ -183,16 +524,44 @@ impl PciBridge {
     }
 }
 
+/// Returns an error naming the first two of `ranges` that overlap, if any do.
+fn check_bar_overlap<'a>(
+    ranges: impl Iterator<Item = &'a BarRange>,
+) -> std::result::Result<(), PciDeviceError> {
+    let mut ranges: Vec<&BarRange> = ranges.filter(|r| r.size != 0).collect();
+    ranges.sort_by_key(|r| r.addr);
+    for pair in ranges.windows(2) {
+        if pair[0].addr.saturating_add(pair[0].size) > pair[1].addr {
+            return Err(PciDeviceError::BridgeChildBarsOverlap(
+                pair[0].addr,
+                pair[0].size,
+                pair[1].addr,
+                pair[1].size,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_window_alignment(base: u64, size: u64) -> std::result::Result<(), PciDeviceError> {
+    if size != 0 && (base | size) & (BR_WINDOW_ALIGNMENT - 1) != 0 {
+        return Err(PciDeviceError::BridgeWindowUnaligned(base, size));
+    }
+    Ok(())
+}
+
 fn finalize_window(
     resources: &mut SystemAllocator,
     prefetchable: bool,
+    max_address: u64,
     alloc: Alloc,
     mut base: u64,
     mut size: u64,
+    min_size: u64,
 ) -> std::result::Result<(u64, u64), PciDeviceError> {
     if size == 0 {
-        // Allocate at least 2MB bridge winodw
-        size = BR_MEM_MINIMUM;
+        // Allocate at least min_size bridge window
+        size = min_size;
     }
     // if base isn't set, allocate a new one
     if base == u64::MAX {
@@ -206,6 +575,7 @@ fn finalize_window(
             "pci_bridge_window".to_string(),
             AllocOptions::new()
                 .prefetchable(prefetchable)
+                .max_address(max_address)
                 .align(BR_WINDOW_ALIGNMENT),
         ) {
             Ok(addr) => Ok((addr, size)),
@@ -228,6 +598,46 @@ fn finalize_window(
     }
 }
 
+fn finalize_io_window(
+    resources: &mut SystemAllocator,
+    alloc: Alloc,
+    mut base: u64,
+    mut size: u64,
+) -> std::result::Result<(u64, u64), PciDeviceError> {
+    if size == 0 {
+        size = BR_IO_WINDOW_ALIGNMENT;
+    }
+    // if base isn't set, allocate a new one
+    if base == u64::MAX {
+        // align size to 4KB
+        size = (size + BR_IO_WINDOW_ALIGNMENT - 1) & BR_IO_WINDOW_MASK;
+        let io_allocator = resources.io_allocator().ok_or_else(|| {
+            PciDeviceError::PciBusWindowAllocationFailure(
+                "no I/O space for bridge I/O window".to_string(),
+            )
+        })?;
+        match io_allocator.allocate_with_align(
+            size,
+            alloc,
+            "pci_bridge_io_window".to_string(),
+            BR_IO_WINDOW_ALIGNMENT,
+        ) {
+            Ok(addr) => Ok((addr, size)),
+            Err(e) => Err(PciDeviceError::PciBusWindowAllocationFailure(format!(
+                "failed to allocate bridge I/O window: {}",
+                e
+            ))),
+        }
+    } else {
+        // align base to 4KB
+        size += base - (base & BR_IO_WINDOW_MASK);
+        base &= BR_IO_WINDOW_MASK;
+        // align size to 4KB
+        size = (size + BR_IO_WINDOW_ALIGNMENT - 1) & BR_IO_WINDOW_MASK;
+        Ok((base, size))
+    }
+}
+
 impl PciDevice for PciBridge {
     fn debug_label(&self) -> String {
         self.device.lock().debug_label()
@@ -247,22 +657,56 @@ impl PciDevice for PciBridge {
     }
 
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        let mut rds = Vec::new();
-        if let Some(interrupt_evt) = &self.interrupt_evt {
-            rds.extend(interrupt_evt.as_raw_descriptors());
+        let mut rds = RawDescriptorSet::new();
+        if let Some(interrupt_evt) = &self.interrupt.lock().interrupt_evt {
+            rds = rds.add(interrupt_evt);
+        }
+        if let Some(sink) = &self.config_write_sink {
+            rds = rds.add(sink);
+        }
+        rds.add(&Descriptor(self.msi_config.lock().get_msi_socket()))
+            .into_vec()
+    }
+
+    fn preferred_irq(&self) -> PreferredIrq {
+        PreferredIrq::AnyWithPin {
+            pin: self.interrupt_pin(),
         }
-        let descriptor = self.msi_config.lock().get_msi_socket();
-        rds.push(descriptor);
-        rds
     }
 
     fn assign_irq(&mut self, irq_evt: IrqLevelEvent, pin: PciInterruptPin, irq_num: u32) {
-        self.interrupt_evt = Some(irq_evt);
-        let msi_config_clone = self.msi_config.clone();
-        self.device.lock().clone_interrupt(msi_config_clone);
+        self.interrupt.lock().interrupt_evt = Some(irq_evt);
+        self.device.lock().clone_interrupt(self.interrupt.clone());
         self.config.set_irq(irq_num as u8, pin);
     }
 
+    fn release_irq(&mut self) {
+        // The INTx GSI of a bridge comes from the pool shared by the cold-plugged devices, so it
+        // is never the bridge's alone to release.
+        self.resample_thread = None;
+        self.msi_config.lock().destroy();
+        self.interrupt.lock().interrupt_evt = None;
+    }
+
+    fn on_device_sandboxed(&mut self) {
+        let interrupt_evt = match &self.interrupt.lock().interrupt_evt {
+            Some(interrupt_evt) => match interrupt_evt.try_clone() {
+                Ok(interrupt_evt) => interrupt_evt,
+                Err(e) => {
+                    error!("failed to clone bridge INTx event: {}", e);
+                    return;
+                }
+            },
+            None => return,
+        };
+        let device = self.device.clone();
+        let interrupt = self.interrupt.clone();
+        self.resample_thread = Some(WorkerThread::start(
+            format!("{} resample", self.debug_label()),
+            move |kill_evt| resample_worker(device, interrupt, interrupt_evt, kill_evt),
+        ));
+    }
+
     fn get_bar_configuration(&self, bar_num: usize) -> Option<PciBarConfiguration> {
         self.config.get_bar_configuration(bar_num)
     }
@@ -275,6 +719,18 @@ impl PciDevice for PciBridge {
                 .map_err(PciDeviceError::CapabilitiesSetup)?;
         }
 
+        // The device's PCIe capability may claim a hotplug slot that the bridge has no window for.
+        if let Some(offset) = self
+            .config
+            .find_capability(PciCapabilityID::PciExpress as u8)
+        {
+            self.config.set_reg(
+                (offset + PCIE_SLTCAP_OFFSET) / 4,
+                self.build_slot_capabilities().bits(),
+                u32::MAX,
+            );
+        }
+
         Ok(())
     }
 
@@ -284,10 +740,52 @@ impl PciDevice for PciBridge {
         data
     }
 
+    fn read_config_registers(&self, start: usize, count: usize) -> Vec<u32> {
+        // Take the device lock once for the whole range rather than once per register.
+        let device = self.device.lock();
+        (start..start + count)
+            .map(|reg_idx| {
+                let mut data: u32 = self.config.read_reg(reg_idx);
+                device.read_config(reg_idx, &mut data);
+                data
+            })
+            .collect()
+    }
+
+    fn config_register_write_mask(&self, reg_idx: usize) -> u32 {
+        self.config.get_writable_bits(reg_idx)
+    }
+
+    fn find_capability(&self, cap_id: u8) -> Option<usize> {
+        self.config.find_capability(cap_id)
+    }
+
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        let data = &self.mask_config_write(reg_idx, offset, data);
+
         // Suppose kernel won't modify primary/secondary/subordinate bus number,
         // if it indeed modify, print a warning
         if reg_idx == BR_BUS_NUMBER_REG {
+            let mut buses = self.config.read_reg(BR_BUS_NUMBER_REG).to_le_bytes();
+            for (i, bus) in data.iter().enumerate() {
+                if let Some(old) = buses.get_mut(offset as usize + i) {
+                    *old = *bus;
+                }
+            }
+            let new_range = PciBridgeBusRange {
+                primary: buses[0],
+                secondary: buses[1],
+                subordinate: buses[BR_BUS_SUBORDINATE_OFFSET],
+            };
+            if let Err(e) = new_range.check() {
+                warn!(
+                    "{} ignoring bus number write: {}",
+                    self.device.lock().debug_label(),
+                    e
+                );
+                return;
+            }
+
             let len = data.len();
             if offset == 0 && len == 1 && data[0] != self.bus_range.primary {
                 warn!(
@@ -318,13 +816,46 @@ impl PciDevice for PciBridge {
                     self.bus_range.subordinate, data[0]
                 );
             }
+
+            if self.allow_guest_bus_renumber && new_range != self.bus_range {
+                self.bus_range = new_range;
+                // Keep the secondary bus and the hotplug bus behind the bridge in step, so that
+                // hotplug lookups use the bus numbers the guest programmed.
+                self.pci_bus
+                    .lock()
+                    .set_bus_num(new_range.secondary, new_range.primary);
+                self.device.lock().set_bus_range(new_range);
+            }
         }
 
+        let was_in_reset = self.config.read_reg(BR_CONTROL_REG) & BR_CONTROL_SEC_BUS_RESET != 0;
+        let old_value = self
+            .config_write_sink
+            .as_ref()
+            .map(|_| self.config.read_reg(reg_idx));
+
         self.device.lock().write_config(reg_idx, offset, data);
 
         if let Some(res) = self.config.write_reg(reg_idx, offset, data) {
             self.device.lock().handle_cap_write_result(res);
         }
+
+        if let (Some(sink), Some(old_value)) = (&self.config_write_sink, old_value) {
+            if self.config.read_reg(reg_idx) != old_value {
+                if let Err(e) = sink.signal() {
+                    error!(
+                        "{} failed to signal config write: {}",
+                        self.device.lock().debug_label(),
+                        e
+                    );
+                }
+            }
+        }
+
+        // Only setting the bit resets the secondary bus, holding or clearing it does not.
+        if !was_in_reset && self.config.read_reg(BR_CONTROL_REG) & BR_CONTROL_SEC_BUS_RESET != 0 {
+            self.device.lock().on_secondary_bus_reset();
+        }
     }
 
     fn read_bar(&mut self, _bar_index: PciBarIndex, _offset: u64, _data: &mut [u8]) {}
@@ -348,13 +879,13 @@ impl PciDevice for PciBridge {
         resources: &mut SystemAllocator,
         bar_ranges: &[BarRange],
     ) -> std::result::Result<Vec<BarRange>, PciDeviceError> {
-        let address = self
-            .pci_address
-            .expect("allocate_address must be called prior to configure_bridge_window");
+        let address = self.pci_address.ok_or(PciDeviceError::PciAddressMissing)?;
         let mut window_base: u64 = u64::MAX;
         let mut window_size: u64 = 0;
         let mut pref_window_base: u64 = u64::MAX;
         let mut pref_window_size: u64 = 0;
+        let mut io_window_base: u64 = u64::MAX;
+        let mut io_window_size: u64 = 0;
         let hotplug_implemented = self.device.lock().hotplug_implemented();
         let hotplugged = self.device.lock().hotplugged();
 
@@ -366,18 +897,33 @@ impl PciDevice for PciBridge {
             let (win_size, pref_win_size) = self.device.lock().get_bridge_window_size();
             window_size = win_size;
             pref_window_size = pref_win_size;
+            if !self.prefetchable_enabled {
+                window_size += pref_window_size;
+                pref_window_size = 0;
+            }
         } else {
-            // Bridge has children connected, get bridge window size from children
+            // Bridge has children connected, get bridge window size from children. BARs that
+            // share a window must not overlap, or the window would hide the conflict.
+            let pref_window = |r: &BarRange| !r.io && r.prefetchable && self.prefetchable_enabled;
+            check_bar_overlap(bar_ranges.iter().filter(|r| r.io))?;
+            check_bar_overlap(bar_ranges.iter().filter(|r| pref_window(r)))?;
+            check_bar_overlap(bar_ranges.iter().filter(|r| !r.io && !pref_window(r)))?;
+
             let mut window_end: u64 = 0;
             let mut pref_window_end: u64 = 0;
+            let mut io_window_end: u64 = 0;
 
             for &BarRange {
                 addr,
                 size,
                 prefetchable,
+                io,
             } in bar_ranges.iter()
             {
-                if prefetchable {
+                if io {
+                    io_window_base = min(io_window_base, addr);
+                    io_window_end = max(io_window_end, addr + size);
+                } else if prefetchable && self.prefetchable_enabled {
                     pref_window_base = min(pref_window_base, addr);
                     pref_window_end = max(pref_window_end, addr + size);
                 } else {
@@ -391,6 +937,9 @@ impl PciDevice for PciBridge {
             if pref_window_end > 0 {
                 pref_window_size = pref_window_end - pref_window_base;
             }
+            if io_window_end > 0 {
+                io_window_size = io_window_end - io_window_base;
+            }
         }
 
         if !hotplugged {
@@ -399,6 +948,7 @@ impl PciDevice for PciBridge {
             let window = finalize_window(
                 resources,
                 false, // prefetchable
+                u64::MAX,
                 Alloc::PciBridgeWindow {
                     bus: address.bus,
                     dev: address.dev,
@@ -406,27 +956,59 @@ impl PciDevice for PciBridge {
                 },
                 window_base,
                 window_size,
+                self.min_window_size,
             )?;
             window_base = window.0;
             window_size = window.1;
 
-            match finalize_window(
+            // A disabled prefetchable window is left unallocated, its registers already hold the
+            // disabled encoding.
+            if self.prefetchable_enabled {
+                match finalize_window(
+                    resources,
+                    true, // prefetchable
+                    if self.pref_mem_64bit {
+                        u64::MAX
+                    } else {
+                        u32::MAX as u64
+                    },
+                    Alloc::PciBridgePrefetchWindow {
+                        bus: address.bus,
+                        dev: address.dev,
+                        func: address.func,
+                    },
+                    pref_window_base,
+                    pref_window_size,
+                    self.min_window_size,
+                ) {
+                    Ok(pref_window) => {
+                        pref_window_base = pref_window.0;
+                        pref_window_size = pref_window.1;
+                    }
+                    Err(e) => {
+                        warn!("failed to allocate PCI bridge prefetchable window: {}", e);
+                        pref_window_size = 0;
+                    }
+                }
+            }
+
+            match finalize_io_window(
                 resources,
-                true, // prefetchable
-                Alloc::PciBridgePrefetchWindow {
+                Alloc::PciBridgeIoWindow {
                     bus: address.bus,
                     dev: address.dev,
                     func: address.func,
                 },
-                pref_window_base,
-                pref_window_size,
+                io_window_base,
+                io_window_size,
             ) {
-                Ok(pref_window) => {
-                    pref_window_base = pref_window.0;
-                    pref_window_size = pref_window.1;
+                Ok(io_window) => {
+                    io_window_base = io_window.0;
+                    io_window_size = io_window.1;
                 }
                 Err(e) => {
-                    warn!("failed to allocate PCI bridge prefetchable window: {}", e);
+                    warn!("failed to allocate PCI bridge I/O window: {}", e);
+                    io_window_size = 0;
                 }
             }
         } else {
@@ -439,12 +1021,28 @@ impl PciDevice for PciBridge {
             }
         }
 
+        // Large BARs can only be placed above 4GB through the prefetchable window, which is where
+        // BARs reported as prefetchable go. Refuse a non-prefetchable window that the 32-bit
+        // memory base and limit registers can't describe instead of truncating it. An empty
+        // window has no base to check.
+        if window_size > 0
+            && window_base
+                .checked_add(window_size)
+                .map_or(true, |end| end > BR_MEM_MAX)
+        {
+            return Err(PciDeviceError::BridgeWindowAbove4G(
+                window_base,
+                window_size,
+            ));
+        }
+
         self.write_bridge_window(
             window_base as u32,
             window_size as u32,
             pref_window_base,
             pref_window_size,
-        );
+        )?;
+        self.write_bridge_io_window(io_window_base, io_window_size);
 
         let mut windows = Vec::new();
         if window_size > 0 {
@@ -452,6 +1050,7 @@ impl PciDevice for PciBridge {
                 addr: window_base,
                 size: window_size,
                 prefetchable: false,
+                io: false,
             })
         }
         if pref_window_size > 0 {
@@ -459,6 +1058,15 @@ impl PciDevice for PciBridge {
                 addr: pref_window_base,
                 size: pref_window_size,
                 prefetchable: true,
+                io: false,
+            })
+        }
+        if io_window_size > 0 {
+            windows.push(BarRange {
+                addr: io_window_base,
+                size: io_window_size,
+                prefetchable: false,
+                io: true,
             })
         }
         Ok(windows)
@@ -482,3 +1090,965 @@ impl PciDevice for PciBridge {
 }
 
 impl Suspendable for PciBridge {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use base::EventWaitResult;
+    use base::SharedMemory;
+    use resources::AddressRange;
+    use resources::MmioType;
+    use resources::SystemAllocatorConfig;
+    use vm_control::VmIrqRequest;
+    use vm_control::VmIrqResponse;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::pci::msi::PCI_MSI_FLAGS;
+    use crate::pci::pci_configuration::PciCapConfig;
+    use crate::pci::pci_configuration::PciCapConfigWriteResult;
+    use crate::pci::pcie::pcie_device::PcieCap;
+    use crate::pci::pcie::PcieDevicePortType;
+    use crate::pci::PciCapability;
+    use crate::pci::PciMmioMapper;
+    use crate::pci::PciRoot;
+    use crate::Bus;
+    use crate::BusType;
+
+    struct TestBridgeDevice {
+        bus_range: Option<PciBridgeBusRange>,
+        hotplug: bool,
+        secondary_bus_resets: Arc<AtomicUsize>,
+        hotplugged: bool,
+        bridge_window_size: (u64, u64),
+        interrupt_pending: Arc<AtomicBool>,
+    }
+
+    impl PcieDevice for TestBridgeDevice {
+        fn get_device_id(&self) -> u16 {
+            0x1234
+        }
+
+        fn debug_label(&self) -> String {
+            "test bridge".to_string()
+        }
+
+        fn allocate_address(
+            &mut self,
+            _resources: &mut SystemAllocator,
+        ) -> std::result::Result<PciAddress, PciDeviceError> {
+            Ok(PciAddress {
+                bus: 0,
+                dev: 1,
+                func: 0,
+            })
+        }
+
+        fn read_config(&self, _reg_idx: usize, _data: &mut u32) {}
+
+        fn write_config(&mut self, _reg_idx: usize, _offset: u64, _data: &[u8]) {}
+
+        fn handle_cap_write_result(&mut self, _res: Box<dyn PciCapConfigWriteResult>) {}
+
+        fn clone_interrupt(&mut self, _interrupt: Arc<Mutex<BridgeInterrupt>>) {}
+
+        fn interrupt_pending(&self) -> bool {
+            self.interrupt_pending.load(Ordering::SeqCst)
+        }
+
+        fn get_caps(&self) -> Vec<(Box<dyn PciCapability>, Option<Box<dyn PciCapConfig>>)> {
+            vec![(
+                Box::new(PcieCap::new(PcieDevicePortType::RootPort, self.hotplug, 0)),
+                None,
+            )]
+        }
+
+        fn get_bus_range(&self) -> Option<PciBridgeBusRange> {
+            self.bus_range
+        }
+
+        fn set_bus_range(&mut self, bus_range: PciBridgeBusRange) {
+            self.bus_range = Some(bus_range);
+        }
+
+        fn get_removed_devices(&self) -> Vec<PciAddress> {
+            Vec::new()
+        }
+
+        fn hotplug_implemented(&self) -> bool {
+            self.hotplug
+        }
+
+        fn hotplugged(&self) -> bool {
+            self.hotplugged
+        }
+
+        fn get_bridge_window_size(&self) -> (u64, u64) {
+            self.bridge_window_size
+        }
+
+        fn on_secondary_bus_reset(&mut self) {
+            self.secondary_bus_resets.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn new_test_bridge() -> PciBridge {
+        new_test_bridge_with_hotplug(false)
+    }
+
+    fn new_test_bridge_with_hotplug(hotplug: bool) -> PciBridge {
+        let device = TestBridgeDevice {
+            bus_range: Some(PciBridgeBusRange {
+                primary: 0,
+                secondary: 1,
+                subordinate: 1,
+            }),
+            hotplug,
+            secondary_bus_resets: Arc::new(AtomicUsize::new(0)),
+            hotplugged: false,
+            bridge_window_size: (0, 0),
+            interrupt_pending: Arc::new(AtomicBool::new(false)),
+        };
+        let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube).unwrap()
+    }
+
+    fn new_test_allocator() -> SystemAllocator {
+        SystemAllocator::new(
+            SystemAllocatorConfig {
+                io: Some(AddressRange {
+                    start: 0x1000,
+                    end: 0xffff,
+                }),
+                low_mmio: AddressRange {
+                    start: 0x2000_0000,
+                    end: 0x2fff_ffff,
+                },
+                high_mmio: AddressRange {
+                    start: 0x1_0000_0000,
+                    end: 0x1_0fff_ffff,
+                },
+                platform_mmio: None,
+                first_irq: 5,
+            },
+            None,
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_bus_range() {
+        let device = TestBridgeDevice {
+            bus_range: Some(PciBridgeBusRange {
+                primary: 1,
+                secondary: 1,
+                subordinate: 2,
+            }),
+            hotplug: false,
+            secondary_bus_resets: Arc::new(AtomicUsize::new(0)),
+            hotplugged: false,
+            bridge_window_size: (0, 0),
+            interrupt_pending: Arc::new(AtomicBool::new(false)),
+        };
+        let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        assert!(matches!(
+            PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube),
+            Err(PciDeviceError::InvalidBusRange(1, 1, 2))
+        ));
+    }
+
+    #[test]
+    fn invalid_guest_bus_numbers() {
+        let mut bridge = new_test_bridge();
+        bridge.set_allow_guest_bus_renumber(true);
+
+        // A subordinate bus below the secondary bus is rejected.
+        bridge.write_config_register(BR_BUS_NUMBER_REG, 0, &[0, 2, 1, 0]);
+        assert_eq!(
+            bridge.read_config_register(BR_BUS_NUMBER_REG) & 0xff_ffff,
+            0x01_0100
+        );
+        assert_eq!(bridge.get_secondary_num(), 1);
+
+        // Widening the subordinate range is fine.
+        bridge.write_config_register(BR_BUS_NUMBER_REG, 2, &[3]);
+        assert_eq!(
+            bridge.read_config_register(BR_BUS_NUMBER_REG) & 0xff_ffff,
+            0x03_0100
+        );
+        assert_eq!(bridge.get_subordinate_num(), 3);
+    }
+
+    #[test]
+    fn secondary_bus_reset() {
+        let secondary_bus_resets = Arc::new(AtomicUsize::new(0));
+        let device = TestBridgeDevice {
+            bus_range: Some(PciBridgeBusRange {
+                primary: 0,
+                secondary: 1,
+                subordinate: 1,
+            }),
+            hotplug: false,
+            secondary_bus_resets: secondary_bus_resets.clone(),
+            hotplugged: false,
+            bridge_window_size: (0, 0),
+            interrupt_pending: Arc::new(AtomicBool::new(false)),
+        };
+        let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        let mut bridge = PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube).unwrap();
+        // Secondary Bus Reset is bit 6 of the bridge control register at byte 2.
+        let reset = [0x40, 0x00];
+
+        bridge.write_config_register(BR_CONTROL_REG, 2, &reset);
+        assert_eq!(secondary_bus_resets.load(Ordering::SeqCst), 1);
+        // Writing the bit again while it is set doesn't reset the bus again.
+        bridge.write_config_register(BR_CONTROL_REG, 2, &reset);
+        assert_eq!(secondary_bus_resets.load(Ordering::SeqCst), 1);
+        // Neither does clearing it.
+        bridge.write_config_register(BR_CONTROL_REG, 2, &[0x00, 0x00]);
+        assert_eq!(secondary_bus_resets.load(Ordering::SeqCst), 1);
+
+        bridge.write_config_register(BR_CONTROL_REG, 0, &0x0040_0000u32.to_le_bytes());
+        assert_eq!(secondary_bus_resets.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn subscribe_config_writes() {
+        let mut bridge = new_test_bridge();
+        let sink = Event::new().unwrap();
+        bridge.subscribe_config_writes(sink.try_clone().unwrap());
+
+        bridge.write_config_register(BR_CONTROL_REG, 2, &[0x40, 0x00]);
+        assert_eq!(
+            sink.wait_timeout(Duration::from_millis(0)).unwrap(),
+            EventWaitResult::Signaled
+        );
+        // Writing the same value again changes nothing, so the sink stays quiet.
+        bridge.write_config_register(BR_CONTROL_REG, 2, &[0x40, 0x00]);
+        assert_eq!(
+            sink.wait_timeout(Duration::from_millis(0)).unwrap(),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn interrupt_pin_rotation() {
+        let mut bridge = new_test_bridge();
+        assert!(bridge.interrupt_pin() == PciInterruptPin::IntA);
+
+        for (dev, func, expected) in [
+            (0, 0, PciInterruptPin::IntA),
+            (1, 0, PciInterruptPin::IntB),
+            (2, 0, PciInterruptPin::IntC),
+            (3, 0, PciInterruptPin::IntD),
+            (4, 0, PciInterruptPin::IntA),
+            (5, 0, PciInterruptPin::IntB),
+            (1, 2, PciInterruptPin::IntD),
+            (2, 3, PciInterruptPin::IntB),
+        ] {
+            bridge.pci_address = Some(PciAddress { bus: 0, dev, func });
+            match bridge.preferred_irq() {
+                PreferredIrq::AnyWithPin { pin } => assert!(pin == expected),
+                _ => panic!("bridge should request a pin"),
+            }
+            bridge.assign_irq(IrqLevelEvent::new().unwrap(), expected, 5);
+            // The interrupt pin register is 1-based, starting with INTA#.
+            let pin_reg = (bridge.read_config_register(0xf) >> 8) & 0xff;
+            assert_eq!(pin_reg, expected.to_mask() + 1);
+        }
+    }
+
+    struct TestMmioMapper;
+
+    impl PciMmioMapper for TestMmioMapper {
+        fn supports_readonly_mapping(&self) -> bool {
+            false
+        }
+
+        fn add_mapping(
+            &mut self,
+            _addr: GuestAddress,
+            _shmem: &SharedMemory,
+        ) -> anyhow::Result<u32> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn release_irq_hotplug_cycles() {
+        let mut allocator = new_test_allocator();
+        let io_bus = Arc::new(Bus::new(BusType::Io));
+        let mmio_bus = Arc::new(Bus::new(BusType::Mmio));
+        let mut pci_root = PciRoot::create_for_test(
+            Arc::downgrade(&mmio_bus),
+            GuestAddress(0),
+            0,
+            Arc::downgrade(&io_bus),
+            Arc::new(Mutex::new(PciBus::new(0, 0, false))),
+        );
+        // A GSI from the pool that cold-plugged devices share.
+        let shared_gsi = allocator.allocate_irq().unwrap();
+
+        for _ in 0..100 {
+            let mut bridge = new_test_bridge();
+            let address = bridge.allocate_address(&mut allocator).unwrap();
+            bridge.assign_irq(
+                IrqLevelEvent::new().unwrap(),
+                PciInterruptPin::IntA,
+                shared_gsi,
+            );
+            let pci_bus = bridge.get_new_pci_bus().unwrap();
+            let bridge = Arc::new(Mutex::new(bridge));
+            pci_root
+                .add_device(address, bridge.clone(), &mut TestMmioMapper)
+                .unwrap();
+            pci_root.add_bridge(pci_bus).unwrap();
+
+            pci_root.release_device_irq(address, &mut allocator);
+            pci_root.remove_device(address);
+            assert!(bridge.lock().interrupt.lock().interrupt_evt.is_none());
+        }
+        // The shared GSI stays allocated and no other GSI leaked.
+        assert_eq!(allocator.allocate_irq(), Some(shared_gsi + 1));
+    }
+
+    fn new_test_bridge_with_pending(interrupt_pending: Arc<AtomicBool>) -> (PciBridge, Tube) {
+        let device = TestBridgeDevice {
+            bus_range: Some(PciBridgeBusRange {
+                primary: 0,
+                secondary: 1,
+                subordinate: 1,
+            }),
+            hotplug: false,
+            secondary_bus_resets: Arc::new(AtomicUsize::new(0)),
+            hotplugged: false,
+            bridge_window_size: (0, 0),
+            interrupt_pending,
+        };
+        let (msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        let bridge = PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube).unwrap();
+        (bridge, msi_host_tube)
+    }
+
+    #[test]
+    fn do_interrupt_path() {
+        let (mut bridge, msi_host_tube) =
+            new_test_bridge_with_pending(Arc::new(AtomicBool::new(false)));
+        let irq_evt = IrqLevelEvent::new().unwrap();
+        bridge.assign_irq(irq_evt.try_clone().unwrap(), PciInterruptPin::IntA, 5);
+
+        // MSI is disabled, so the legacy interrupt is used.
+        bridge.do_interrupt();
+        assert_eq!(
+            irq_evt
+                .get_trigger()
+                .wait_timeout(Duration::from_millis(0))
+                .unwrap(),
+            EventWaitResult::Signaled
+        );
+
+        // Answer the requests MSI makes to the VM when the guest enables it.
+        let host = std::thread::spawn(move || {
+            let _: VmIrqRequest = msi_host_tube.recv().unwrap();
+            msi_host_tube
+                .send(&VmIrqResponse::AllocateOneMsi { gsi: 5 })
+                .unwrap();
+            let _: VmIrqRequest = msi_host_tube.recv().unwrap();
+            msi_host_tube.send(&VmIrqResponse::Ok).unwrap();
+        });
+        bridge
+            .msi_config
+            .lock()
+            .write_msi_capability(PCI_MSI_FLAGS, &[1, 0]);
+        host.join().unwrap();
+
+        bridge.do_interrupt();
+        assert_eq!(
+            irq_evt
+                .get_trigger()
+                .wait_timeout(Duration::from_millis(0))
+                .unwrap(),
+            EventWaitResult::TimedOut
+        );
+        assert_eq!(
+            bridge
+                .msi_config
+                .lock()
+                .get_irqfd()
+                .unwrap()
+                .wait_timeout(Duration::from_millis(0))
+                .unwrap(),
+            EventWaitResult::Signaled
+        );
+    }
+
+    #[test]
+    fn resample_reasserts_pending_interrupt() {
+        let interrupt_pending = Arc::new(AtomicBool::new(true));
+        let (mut bridge, _msi_host_tube) = new_test_bridge_with_pending(interrupt_pending.clone());
+        let irq_evt = IrqLevelEvent::new().unwrap();
+        bridge.assign_irq(irq_evt.try_clone().unwrap(), PciInterruptPin::IntA, 5);
+        bridge.on_device_sandboxed();
+
+        // The guest hasn't consumed the event yet, so the line is asserted again after the EOI.
+        irq_evt.get_resample().signal().unwrap();
+        assert_eq!(
+            irq_evt
+                .get_trigger()
+                .wait_timeout(Duration::from_secs(5))
+                .unwrap(),
+            EventWaitResult::Signaled
+        );
+
+        // Once it is consumed, the line stays deasserted.
+        interrupt_pending.store(false, Ordering::SeqCst);
+        irq_evt.get_resample().signal().unwrap();
+        assert_eq!(
+            irq_evt
+                .get_trigger()
+                .wait_timeout(Duration::from_millis(100))
+                .unwrap(),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn configure_min_window_size() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        // Not a multiple of the window alignment, so it is rounded up.
+        bridge.set_min_window_size(0x8_0000);
+        assert_eq!(bridge.min_window_size, BR_WINDOW_ALIGNMENT);
+
+        // Neither memory window is needed by a child, so both get the minimum size.
+        let windows = bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        let mem_window = windows.iter().find(|w| !w.prefetchable && !w.io).unwrap();
+        assert_eq!(mem_window.size, BR_WINDOW_ALIGNMENT);
+        let pref_window = windows.iter().find(|w| w.prefetchable).unwrap();
+        assert_eq!(pref_window.size, BR_WINDOW_ALIGNMENT);
+    }
+
+    #[test]
+    fn get_windows() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let windows = bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        let mem_window = windows.iter().find(|w| !w.prefetchable && !w.io).unwrap();
+        let pref_window = windows.iter().find(|w| w.prefetchable).unwrap();
+        assert_eq!(
+            bridge.get_windows(),
+            BridgeWindows {
+                mem: Some((mem_window.addr, mem_window.addr + mem_window.size - 1)),
+                pref_mem: Some((pref_window.addr, pref_window.addr + pref_window.size - 1)),
+            }
+        );
+
+        // A base above the limit disables the window.
+        bridge.config.set_reg(BR_MEM_REG, 0x0010_0020, u32::MAX);
+        assert_eq!(bridge.get_windows().mem, None);
+    }
+
+    #[test]
+    fn disable_memory_forwarding() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+        bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        let windows = bridge.get_windows();
+        assert_eq!(bridge.forwarding_enabled(), (true, true));
+
+        // Clear Memory Space Enable and leave I/O Space Enable set.
+        bridge.write_config_register(COMMAND_REG, 0, &[0x01, 0x00]);
+        assert_eq!(bridge.forwarding_enabled(), (false, true));
+        assert_eq!(
+            bridge.get_windows(),
+            BridgeWindows {
+                mem: None,
+                pref_mem: None,
+            }
+        );
+
+        // The programmed windows come back once forwarding is enabled again.
+        bridge.write_config_register(COMMAND_REG, 0, &[0x03, 0x00]);
+        assert_eq!(bridge.forwarding_enabled(), (true, true));
+        assert_eq!(bridge.get_windows(), windows);
+    }
+
+    #[test]
+    fn write_bridge_window_aligned() {
+        let mut bridge = new_test_bridge();
+        bridge
+            .write_bridge_window(0x1000_0000, 0x20_0000, 0x2_0000_0000, 0x10_0000)
+            .unwrap();
+        assert_eq!(
+            bridge.get_windows(),
+            BridgeWindows {
+                mem: Some((0x1000_0000, 0x101f_ffff)),
+                pref_mem: Some((0x2_0000_0000, 0x2_000f_ffff)),
+            }
+        );
+    }
+
+    #[test]
+    fn write_bridge_window_unaligned() {
+        let mut bridge = new_test_bridge();
+        let windows = bridge.get_windows();
+
+        let err = bridge
+            .write_bridge_window(0x1000_0000, 0x20_0000, 0x2_0000_0000, 0x1000)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PciDeviceError::BridgeWindowUnaligned(0x2_0000_0000, 0x1000)
+        ));
+        let err = bridge
+            .write_bridge_window(0x1000_8000, 0x20_0000, 0, 0)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PciDeviceError::BridgeWindowUnaligned(0x1000_8000, 0x20_0000)
+        ));
+
+        // Neither window is programmed when one of them is rejected.
+        assert_eq!(bridge.get_windows(), windows);
+    }
+
+    #[test]
+    fn configure_overlapping_child_bars() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let bar_ranges = [
+            BarRange {
+                addr: 0x2340_0000,
+                size: 0x2000,
+                prefetchable: false,
+                io: false,
+            },
+            BarRange {
+                addr: 0x2340_1000,
+                size: 0x1000,
+                prefetchable: false,
+                io: false,
+            },
+            // In the prefetchable window, so it doesn't conflict with the BARs above.
+            BarRange {
+                addr: 0x2340_0000,
+                size: 0x1000,
+                prefetchable: true,
+                io: false,
+            },
+        ];
+        let err = bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PciDeviceError::BridgeChildBarsOverlap(0x2340_0000, 0x2000, 0x2340_1000, 0x1000)
+        ));
+
+        // Adjacent BARs don't overlap.
+        let bar_ranges = [
+            BarRange {
+                addr: 0x2340_0000,
+                size: 0x1000,
+                prefetchable: false,
+                io: false,
+            },
+            BarRange {
+                addr: 0x2340_1000,
+                size: 0x1000,
+                prefetchable: false,
+                io: false,
+            },
+        ];
+        bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+    }
+
+    #[test]
+    fn configure_disabled_pref_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.set_prefetchable_enabled(false);
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let bar_ranges = [BarRange {
+            addr: 0x2348_0000,
+            size: 0x1000,
+            prefetchable: true,
+            io: false,
+        }];
+        let windows = bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+
+        // The prefetchable BAR is covered by the memory window instead.
+        assert!(windows.iter().all(|w| !w.prefetchable));
+        let mem_window = windows.iter().find(|w| !w.io).unwrap();
+        assert_eq!(mem_window.addr, 0x2340_0000);
+        assert_eq!(
+            bridge.read_config_register(BR_PREF_MEM_LOW_REG),
+            BR_PREF_MEM_DISABLED | BR_PREF_MEM_64BIT
+        );
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_BASE_HIGH_REG), 0);
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_LIMIT_HIGH_REG), 0);
+        assert_eq!(bridge.get_windows().pref_mem, None);
+
+        // None of the high MMIO region was allocated.
+        let alloc = allocator.get_anon_alloc();
+        allocator
+            .mmio_allocator(MmioType::High)
+            .allocate(0x1000_0000, alloc, "test".to_string())
+            .expect("high MMIO was consumed");
+    }
+
+    #[test]
+    fn configure_unaligned_pref_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let bar_ranges = [BarRange {
+            addr: 0x1_2348_0000,
+            size: 0x1000,
+            prefetchable: true,
+            io: false,
+        }];
+        let windows = bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+
+        // The prefetchable window is aligned down to 1MB and still covers the BAR.
+        let pref_window = windows.iter().find(|w| w.prefetchable).unwrap();
+        assert_eq!(pref_window.addr, 0x1_2340_0000);
+        assert_eq!(pref_window.size, 0x10_0000);
+
+        assert_eq!(
+            bridge.read_config_register(BR_PREF_MEM_LOW_REG),
+            0x2340_2340 | BR_PREF_MEM_64BIT
+        );
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_BASE_HIGH_REG), 0x1);
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_LIMIT_HIGH_REG), 0x1);
+        assert_eq!(
+            bridge.get_windows().pref_mem,
+            Some((0x1_2340_0000, 0x1_234f_ffff))
+        );
+    }
+
+    #[test]
+    fn configure_32bit_pref_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.set_pref_mem_64bit(false);
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let bar_ranges = [BarRange {
+            addr: 0x2348_0000,
+            size: 0x1000,
+            prefetchable: true,
+            io: false,
+        }];
+        bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+
+        assert_eq!(
+            bridge.read_config_register(BR_PREF_MEM_LOW_REG),
+            0x2340_2340
+        );
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_BASE_HIGH_REG), 0);
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_LIMIT_HIGH_REG), 0);
+        assert_eq!(
+            bridge.get_windows().pref_mem,
+            Some((0x2340_0000, 0x234f_ffff))
+        );
+    }
+
+    #[test]
+    fn configure_io_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        let bar_ranges = [BarRange {
+            addr: 0x2100,
+            size: 0x100,
+            prefetchable: false,
+            io: true,
+        }];
+        let windows = bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+
+        // The I/O window is aligned to 4KB and covers the child's port I/O BAR.
+        let io_window = windows.iter().find(|w| w.io).unwrap();
+        assert_eq!(io_window.addr, 0x2000);
+        assert_eq!(io_window.size, 0x1000);
+        assert_eq!(
+            bridge.read_config_register(BR_IO_REG) & BR_IO_BASE_LIMIT_MASK,
+            0x2020
+        );
+    }
+
+    #[test]
+    fn allocate_io_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        // With no children the I/O window is allocated from the I/O region.
+        let windows = bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        let io_window = windows.iter().find(|w| w.io).unwrap();
+        assert_eq!(io_window.addr, 0x1000);
+        assert_eq!(io_window.size, 0x1000);
+        assert_eq!(
+            bridge.read_config_register(BR_IO_REG) & BR_IO_BASE_LIMIT_MASK,
+            0x1010
+        );
+    }
+
+    #[test]
+    fn allocate_32bit_pref_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.set_pref_mem_64bit(false);
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        // With no children the prefetchable window is allocated below 4GB.
+        let windows = bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        let pref_window = windows.iter().find(|w| w.prefetchable).unwrap();
+        assert!(pref_window.addr + pref_window.size <= u32::MAX as u64 + 1);
+        assert_eq!(
+            bridge.read_config_register(BR_PREF_MEM_LOW_REG) & BR_PREF_MEM_64BIT,
+            0
+        );
+    }
+
+    #[test]
+    fn read_config_registers_matches_single_reads() {
+        let bridge = new_test_bridge();
+        let regs = bridge.read_config_registers(0, 16);
+        assert_eq!(regs.len(), 16);
+        for (reg_idx, data) in regs.iter().enumerate() {
+            assert_eq!(*data, bridge.read_config_register(reg_idx));
+        }
+    }
+
+    #[test]
+    fn find_capability() {
+        let mut bridge = new_test_bridge();
+        let msi_id = PciCapabilityID::MessageSignalledInterrupts as u8;
+        let pcie_id = PciCapabilityID::PciExpress as u8;
+
+        // The MSI capability is added when the bridge is created, the PCIe one when the device's
+        // capabilities are registered.
+        let msi_offset = bridge.find_capability(msi_id).expect("no MSI capability");
+        assert_eq!(
+            bridge.read_config_register(msi_offset / 4) & 0xff,
+            msi_id as u32
+        );
+        assert_eq!(bridge.find_capability(pcie_id), None);
+
+        bridge.register_device_capabilities().unwrap();
+        let pcie_offset = bridge.find_capability(pcie_id).expect("no PCIe capability");
+        assert_ne!(pcie_offset, msi_offset);
+        // The walk over the config space the guest sees lands on the same offsets.
+        let read_reg = |reg_idx| bridge.read_config_register(reg_idx);
+        assert_eq!(
+            crate::pci::pci_configuration::find_capability(read_reg, pcie_id),
+            Some(pcie_offset)
+        );
+        assert_eq!(
+            bridge.find_capability(PciCapabilityID::VendorSpecific as u8),
+            None
+        );
+    }
+
+    #[test]
+    fn read_only_config_write() {
+        let mut bridge = new_test_bridge();
+        let class_reg = bridge.read_config_register(2);
+        let header_reg = bridge.read_config_register(3);
+
+        bridge.write_config_register(2, 0, &[0xff; 4]);
+        assert_eq!(bridge.read_config_register(2), class_reg);
+
+        // Only the cache line size byte of the header type register is writable.
+        bridge.write_config_register(3, 0, &[0x10, 0xff, 0xff, 0xff]);
+        assert_eq!(bridge.read_config_register(3), (header_reg & !0xff) | 0x10);
+        assert_eq!(
+            bridge.mask_config_write(3, 2, &[0xff]),
+            vec![(header_reg >> 16) as u8]
+        );
+
+        // A write crossing from the class code into the cache line size is masked per register.
+        assert_eq!(
+            bridge.mask_config_write(2, 3, &[0xff, 0x20]),
+            vec![(class_reg >> 24) as u8, 0x20]
+        );
+
+        // Extended config space is passed through to the backing device.
+        let data = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(
+            bridge.mask_config_write(NUM_CONFIGURATION_REGISTERS, 0, &data),
+            data
+        );
+        assert_eq!(bridge.mask_config_write(0x100, 2, &data[..2]), data[..2]);
+    }
+
+    #[test]
+    fn guest_bus_renumber() {
+        let mut bridge = new_test_bridge();
+
+        // Bus number writes are ignored by default.
+        bridge.write_config_register(BR_BUS_NUMBER_REG, 0, &[0, 2, 3, 0]);
+        assert_eq!(bridge.get_secondary_num(), 1);
+        assert_eq!(bridge.get_subordinate_num(), 1);
+
+        bridge.set_allow_guest_bus_renumber(true);
+        bridge.write_config_register(BR_BUS_NUMBER_REG, 0, &[0, 2, 3, 0]);
+        assert_eq!(bridge.get_secondary_num(), 2);
+        assert_eq!(bridge.get_subordinate_num(), 3);
+
+        bridge.write_config_register(BR_BUS_NUMBER_REG, BR_BUS_SUBORDINATE_OFFSET as u64, &[5]);
+        assert_eq!(bridge.get_secondary_num(), 2);
+        assert_eq!(bridge.get_subordinate_num(), 5);
+
+        // The secondary bus and the backing device follow the new bus numbers.
+        assert_eq!(bridge.get_new_pci_bus().unwrap().lock().get_bus_num(), 2);
+        assert_eq!(
+            bridge.device.lock().get_bus_range(),
+            Some(PciBridgeBusRange {
+                primary: 0,
+                secondary: 2,
+                subordinate: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn configure_large_window() {
+        let mut allocator = new_test_allocator();
+        let mut bridge = new_test_bridge();
+        bridge.allocate_address(&mut allocator).unwrap();
+
+        // A 6GB non-prefetchable window can't be encoded in the 32-bit memory window.
+        let bar_ranges = [BarRange {
+            addr: 0x1_0000_0000,
+            size: 0x1_8000_0000,
+            prefetchable: false,
+            io: false,
+        }];
+        assert!(matches!(
+            bridge.configure_bridge_window(&mut allocator, &bar_ranges),
+            Err(PciDeviceError::BridgeWindowAbove4G(
+                0x1_0000_0000,
+                0x1_8000_0000
+            ))
+        ));
+
+        // The same BAR reported as prefetchable uses the 64-bit prefetchable window.
+        let bar_ranges = [BarRange {
+            addr: 0x1_0000_0000,
+            size: 0x1_8000_0000,
+            prefetchable: true,
+            io: false,
+        }];
+        let windows = bridge
+            .configure_bridge_window(&mut allocator, &bar_ranges)
+            .unwrap();
+        let pref_window = windows.iter().find(|w| w.prefetchable).unwrap();
+        assert_eq!(pref_window.addr, 0x1_0000_0000);
+        assert_eq!(pref_window.size, 0x1_8000_0000);
+        assert_eq!(
+            bridge.read_config_register(BR_PREF_MEM_LOW_REG),
+            0x7ff0_0000 | BR_PREF_MEM_64BIT
+        );
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_BASE_HIGH_REG), 0x1);
+        assert_eq!(bridge.read_config_register(BR_PREF_MEM_LIMIT_HIGH_REG), 0x2);
+
+        // A hotplugged bridge that only asks for a prefetchable window has no non-prefetchable
+        // window to place.
+        let device = TestBridgeDevice {
+            bus_range: Some(PciBridgeBusRange {
+                primary: 0,
+                secondary: 1,
+                subordinate: 1,
+            }),
+            hotplug: false,
+            secondary_bus_resets: Arc::new(AtomicUsize::new(0)),
+            hotplugged: true,
+            bridge_window_size: (0, 0x20_0000),
+            interrupt_pending: Arc::new(AtomicBool::new(false)),
+        };
+        let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+        let mut bridge = PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube).unwrap();
+        bridge.allocate_address(&mut allocator).unwrap();
+        let windows = bridge.configure_bridge_window(&mut allocator, &[]).unwrap();
+        assert_eq!(windows.len(), 1);
+        assert!(windows[0].prefetchable && !windows[0].io);
+        assert_eq!((windows[0].addr, windows[0].size), (0, 0x20_0000));
+    }
+
+    fn read_slot_cap(bridge: &PciBridge) -> PcieSlotCap {
+        let cap_offset = bridge
+            .find_capability(PciCapabilityID::PciExpress as u8)
+            .expect("no PCIe capability");
+        PcieSlotCap::from_bits(bridge.read_config_register((cap_offset + PCIE_SLTCAP_OFFSET) / 4))
+    }
+
+    #[test]
+    fn slot_capabilities() {
+        let mut bridge = new_test_bridge_with_hotplug(true);
+        bridge.register_device_capabilities().unwrap();
+        let slot_cap = read_slot_cap(&bridge);
+        assert_eq!(slot_cap, bridge.build_slot_capabilities());
+        assert!(slot_cap.attention_button_present());
+        assert!(slot_cap.attention_indicator_present());
+        assert!(slot_cap.power_indicator_present());
+        assert!(slot_cap.hotplug_surprise());
+        assert!(slot_cap.hotplug_capable());
+
+        let mut bridge = new_test_bridge();
+        bridge.register_device_capabilities().unwrap();
+        let slot_cap = read_slot_cap(&bridge);
+        assert_eq!(slot_cap, bridge.build_slot_capabilities());
+        assert_eq!(slot_cap.bits(), 0);
+
+        // A hotplugged bridge only gets the window its device asks for, and without one it has
+        // nowhere to place hotplugged devices.
+        for (bridge_window_size, hotplug_capable) in [((0, 0), false), ((0x20_0000, 0), true)] {
+            let device = TestBridgeDevice {
+                bus_range: Some(PciBridgeBusRange {
+                    primary: 0,
+                    secondary: 1,
+                    subordinate: 1,
+                }),
+                hotplug: true,
+                secondary_bus_resets: Arc::new(AtomicUsize::new(0)),
+                hotplugged: true,
+                bridge_window_size,
+                interrupt_pending: Arc::new(AtomicBool::new(false)),
+            };
+            let (_msi_host_tube, msi_device_tube) = Tube::pair().unwrap();
+            let mut bridge = PciBridge::new(Arc::new(Mutex::new(device)), msi_device_tube).unwrap();
+            bridge.register_device_capabilities().unwrap();
+            let slot_cap = read_slot_cap(&bridge);
+            assert_eq!(slot_cap, bridge.build_slot_capabilities());
+            assert_eq!(slot_cap.hotplug_capable(), hotplug_capable);
+            assert_eq!(slot_cap.attention_button_present(), hotplug_capable);
+        }
+    }
+}
@@ -11,9 +11,9 @@ use zerocopy::AsBytes;
 use crate::pci::pci_configuration::PciCapConfig;
 use crate::pci::pci_configuration::PciCapConfigWriteResult;
 use crate::pci::pci_configuration::PciCapabilityID;
+use crate::pci::pcie::pci_bridge::BridgeInterrupt;
 use crate::pci::pcie::pci_bridge::PciBridgeBusRange;
 use crate::pci::pcie::*;
-use crate::pci::MsiConfig;
 use crate::pci::PciAddress;
 use crate::pci::PciCapability;
 use crate::pci::PciDeviceError;
@@ -31,11 +31,18 @@ pub trait PcieDevice: Send {
     fn read_config(&self, reg_idx: usize, data: &mut u32);
     fn write_config(&mut self, reg_idx: usize, offset: u64, data: &[u8]);
     fn handle_cap_write_result(&mut self, res: Box<dyn PciCapConfigWriteResult>);
-    fn clone_interrupt(&mut self, msi_config: Arc<Mutex<MsiConfig>>);
+    fn clone_interrupt(&mut self, interrupt: Arc<Mutex<BridgeInterrupt>>);
+    /// Whether an interrupt the device raised through `clone_interrupt` is still waiting for the
+    /// guest to consume it. The bridge re-asserts its INTx line on resample while this is true.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
     fn get_caps(&self) -> Vec<(Box<dyn PciCapability>, Option<Box<dyn PciCapConfig>>)>;
     fn get_bus_range(&self) -> Option<PciBridgeBusRange> {
         None
     }
+    /// Called when the guest renumbers the buses behind the bridge backed by this device.
+    fn set_bus_range(&mut self, _bus_range: PciBridgeBusRange) {}
     fn get_removed_devices(&self) -> Vec<PciAddress>;
 
     /// Hotplug capability is implemented on this bridge or not.
@@ -49,6 +56,61 @@ pub trait PcieDevice: Send {
     /// Get bridge window size to cover children's mmio size
     /// (u64, u64) -> (non_prefetchable window size, prefetchable_window_size)
     fn get_bridge_window_size(&self) -> (u64, u64);
+
+    /// Called when the guest sets the Secondary Bus Reset bit in the bridge control register of
+    /// the bridge backed by this device, so the devices behind it can be reset.
+    fn on_secondary_bus_reset(&mut self) {}
+}
+
+/// Contents of the Slot Capabilities register of a PCIe port.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PcieSlotCap(u32);
+
+impl PcieSlotCap {
+    /// Slot capabilities for a port with a slot (`slot` is true) or without one.
+    /// A slot advertises an attention button, attention and power indicators and
+    /// hot-plug surprise, so that guests may remove devices without prior notice.
+    pub fn new(slot: bool) -> Self {
+        if slot {
+            PcieSlotCap(
+                PCIE_SLTCAP_ABP
+                    | PCIE_SLTCAP_AIP
+                    | PCIE_SLTCAP_PIP
+                    | PCIE_SLTCAP_HPS
+                    | PCIE_SLTCAP_HPC,
+            )
+        } else {
+            PcieSlotCap(0)
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        PcieSlotCap(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn attention_button_present(&self) -> bool {
+        self.0 & PCIE_SLTCAP_ABP != 0
+    }
+
+    pub fn attention_indicator_present(&self) -> bool {
+        self.0 & PCIE_SLTCAP_AIP != 0
+    }
+
+    pub fn power_indicator_present(&self) -> bool {
+        self.0 & PCIE_SLTCAP_PIP != 0
+    }
+
+    pub fn hotplug_surprise(&self) -> bool {
+        self.0 & PCIE_SLTCAP_HPS != 0
+    }
+
+    pub fn hotplug_capable(&self) -> bool {
+        self.0 & PCIE_SLTCAP_HPC != 0
+    }
 }
 
 #[repr(C)]
@@ -123,14 +185,9 @@ impl PcieCap {
         let link_cap = (PCIE_LINK_X1 | PCIE_LINK_2_5GT) as u32;
         let link_status = PCIE_LINK_X1 | PCIE_LINK_2_5GT;
 
-        let mut slot_cap: u32 = 0;
+        let slot_cap = PcieSlotCap::new(slot).bits();
         let mut slot_control: u16 = 0;
         if slot {
-            slot_cap = PCIE_SLTCAP_ABP
-                | PCIE_SLTCAP_AIP
-                | PCIE_SLTCAP_PIP
-                | PCIE_SLTCAP_HPS
-                | PCIE_SLTCAP_HPC;
             slot_control = PCIE_SLTCTL_PIC_OFF | PCIE_SLTCTL_AIC_OFF;
         }
 
@@ -16,6 +16,7 @@ use crate::pci::pci_configuration::PciCapConfig;
 use crate::pci::pci_configuration::PciCapConfigWriteResult;
 use crate::pci::pci_configuration::PciCapMapping;
 use crate::pci::pci_configuration::PciCapability;
+use crate::pci::pcie::pci_bridge::BridgeInterrupt;
 use crate::pci::pcie::pci_bridge::PciBridgeBusRange;
 use crate::pci::pcie::pcie_device::PcieCap;
 use crate::pci::pcie::pcie_device::PcieDevice;
@@ -25,7 +26,6 @@ use crate::pci::pm::PciDevicePower;
 use crate::pci::pm::PciPmCap;
 use crate::pci::pm::PmConfig;
 use crate::pci::pm::PmStatusChange;
-use crate::pci::MsiConfig;
 use crate::pci::PciAddress;
 use crate::pci::PciDeviceError;
 
@@ -34,12 +34,9 @@ const PCIE_BR_MEM_SIZE: u64 = 0x80_0000;
 // reserve 64MB prefetch window
 const PCIE_BR_PREF_MEM_SIZE: u64 = 0x400_0000;
 
-fn trigger_interrupt(msi: &Option<Arc<Mutex<MsiConfig>>>) {
-    if let Some(msi_config) = msi {
-        let msi_config = msi_config.lock();
-        if msi_config.is_msi_enabled() {
-            msi_config.trigger()
-        }
+fn trigger_interrupt(interrupt: &Option<Arc<Mutex<BridgeInterrupt>>>) {
+    if let Some(interrupt) = interrupt {
+        interrupt.lock().do_interrupt()
     }
 }
 
@@ -51,7 +48,7 @@ struct PcieRootCap {
     status: u32,
     pme_pending_requester_id: Option<u16>,
 
-    msi_config: Option<Arc<Mutex<MsiConfig>>>,
+    interrupt: Option<Arc<Mutex<BridgeInterrupt>>>,
 }
 
 impl PcieRootCap {
@@ -62,19 +59,22 @@ impl PcieRootCap {
             control: 0,
             status: 0,
             pme_pending_requester_id: None,
-            msi_config: None,
+            interrupt: None,
         }
     }
 
-    fn clone_interrupt(&mut self, msi_config: Arc<Mutex<MsiConfig>>) {
-        self.msi_config = Some(msi_config);
+    fn clone_interrupt(&mut self, interrupt: Arc<Mutex<BridgeInterrupt>>) {
+        self.interrupt = Some(interrupt);
     }
 
-    fn trigger_pme_interrupt(&self) {
-        if (self.control & PCIE_ROOTCTL_PME_ENABLE) != 0
+    fn pme_interrupt_pending(&self) -> bool {
+        (self.control & PCIE_ROOTCTL_PME_ENABLE) != 0
             && (self.status & PCIE_ROOTSTA_PME_STATUS) != 0
-        {
-            trigger_interrupt(&self.msi_config)
+    }
+
+    fn trigger_pme_interrupt(&self) {
+        if self.pme_interrupt_pending() {
+            trigger_interrupt(&self.interrupt)
         }
     }
 }
@@ -108,7 +108,7 @@ pub struct PciePort {
     pcie_config: Arc<Mutex<PcieConfig>>,
     pm_config: Arc<Mutex<PmConfig>>,
 
-    msi_config: Option<Arc<Mutex<MsiConfig>>>,
+    interrupt: Option<Arc<Mutex<BridgeInterrupt>>>,
 
     // For PcieRootPort, root_cap point to itself
     // For PcieDownstreamPort or PciDownstreamPort, root_cap point to PcieRootPort its behind.
@@ -152,7 +152,7 @@ impl PciePort {
             pci_address: None,
             bus_range,
             pcie_host: None,
-            msi_config: None,
+            interrupt: None,
             pcie_config: Arc::new(Mutex::new(PcieConfig::new(
                 root_cap.clone(),
                 slot_implemented,
@@ -193,7 +193,7 @@ impl PciePort {
             pci_address: None,
             bus_range,
             pcie_host: Some(pcie_host),
-            msi_config: None,
+            interrupt: None,
             pcie_config: Arc::new(Mutex::new(PcieConfig::new(
                 root_cap.clone(),
                 slot_implemented,
@@ -301,6 +301,15 @@ impl PciePort {
         Some(self.bus_range)
     }
 
+    pub fn set_bus_range(&mut self, bus_range: PciBridgeBusRange) {
+        self.bus_range = bus_range;
+        if self.port_type == PcieDevicePortType::RootPort {
+            let mut root_cap = self.root_cap.lock();
+            root_cap.secondary_bus_num = bus_range.secondary;
+            root_cap.subordinate_bus_num = bus_range.subordinate;
+        }
+    }
+
     pub fn get_bridge_window_size(&self) -> (u64, u64) {
         if let Some(host) = &self.pcie_host {
             host.get_bridge_window_size()
@@ -309,12 +318,19 @@ impl PciePort {
         }
     }
 
-    pub fn clone_interrupt(&mut self, msi_config: Arc<Mutex<MsiConfig>>) {
+    pub fn clone_interrupt(&mut self, interrupt: Arc<Mutex<BridgeInterrupt>>) {
         if self.port_type == PcieDevicePortType::RootPort {
-            self.root_cap.lock().clone_interrupt(msi_config.clone());
+            self.root_cap.lock().clone_interrupt(interrupt.clone());
         }
-        self.pcie_config.lock().msi_config = Some(msi_config.clone());
-        self.msi_config = Some(msi_config);
+        self.pcie_config.lock().interrupt = Some(interrupt.clone());
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Whether a hotplug or PME event the guest has enabled is still waiting to be consumed.
+    pub fn interrupt_pending(&self) -> bool {
+        self.pcie_config.lock().interrupt_pending()
+            || (self.port_type == PcieDevicePortType::RootPort
+                && self.root_cap.lock().pme_interrupt_pending())
     }
 
     pub fn hotplug_implemented(&self) -> bool {
@@ -390,7 +406,7 @@ impl PciePort {
 }
 
 pub struct PcieConfig {
-    msi_config: Option<Arc<Mutex<MsiConfig>>>,
+    interrupt: Option<Arc<Mutex<BridgeInterrupt>>>,
 
     slot_control: Option<u16>,
     slot_status: u16,
@@ -414,7 +430,7 @@ impl PcieConfig {
         port_type: PcieDevicePortType,
     ) -> Self {
         PcieConfig {
-            msi_config: None,
+            interrupt: None,
 
             slot_control: if slot_implemented {
                 Some(PCIE_SLTCTL_PIC_OFF | PCIE_SLTCTL_AIC_OFF)
@@ -569,20 +585,32 @@ impl PcieConfig {
         0
     }
 
-    fn trigger_cc_interrupt(&self) {
-        if (self.get_slot_control() & PCIE_SLTCTL_CCIE) != 0
+    fn cc_event_pending(&self) -> bool {
+        (self.get_slot_control() & PCIE_SLTCTL_CCIE) != 0
             && (self.slot_status & PCIE_SLTSTA_CC) != 0
-        {
-            trigger_interrupt(&self.msi_config)
+    }
+
+    fn slot_event_pending(&self) -> bool {
+        let slot_control = self.get_slot_control();
+        (slot_control & PCIE_SLTCTL_HPIE) != 0
+            && (self.slot_status & slot_control & (PCIE_SLTCTL_ABPE | PCIE_SLTCTL_PDCE)) != 0
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.cc_event_pending() || self.slot_event_pending()
+    }
+
+    fn trigger_cc_interrupt(&self) {
+        if self.cc_event_pending() {
+            trigger_interrupt(&self.interrupt)
         }
     }
 
     fn trigger_hp_interrupt(&mut self) {
-        let slot_control = self.get_slot_control();
-        if (slot_control & PCIE_SLTCTL_HPIE) != 0 {
+        if (self.get_slot_control() & PCIE_SLTCTL_HPIE) != 0 {
             self.set_slot_status(PCIE_SLTSTA_PDC);
-            if (self.slot_status & slot_control & (PCIE_SLTCTL_ABPE | PCIE_SLTCTL_PDCE)) != 0 {
-                trigger_interrupt(&self.msi_config)
+            if self.slot_event_pending() {
+                trigger_interrupt(&self.interrupt)
             }
         }
     }
@@ -669,8 +697,12 @@ impl<T: PciePortVariant> PcieDevice for T {
         self.get_pcie_port_mut().allocate_address(resources)
     }
 
-    fn clone_interrupt(&mut self, msi_config: Arc<Mutex<MsiConfig>>) {
-        self.get_pcie_port_mut().clone_interrupt(msi_config);
+    fn clone_interrupt(&mut self, interrupt: Arc<Mutex<BridgeInterrupt>>) {
+        self.get_pcie_port_mut().clone_interrupt(interrupt);
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.get_pcie_port().interrupt_pending()
     }
 
     fn read_config(&self, reg_idx: usize, data: &mut u32) {
@@ -693,6 +725,10 @@ impl<T: PciePortVariant> PcieDevice for T {
         self.get_pcie_port().get_bus_range()
     }
 
+    fn set_bus_range(&mut self, bus_range: PciBridgeBusRange) {
+        self.get_pcie_port_mut().set_bus_range(bus_range)
+    }
+
     fn get_removed_devices(&self) -> Vec<PciAddress> {
         self.get_removed_devices_impl()
     }
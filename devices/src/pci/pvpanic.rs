@@ -164,6 +164,7 @@ impl PciDevice for PvPanicPciDevice {
             addr: pvpanic_reg_addr,
             size: PVPANIC_REG_SIZE,
             prefetchable: false,
+            io: false,
         });
 
         Ok(ranges)
@@ -1452,6 +1452,7 @@ impl VfioPciDevice {
                     addr: bar_addr,
                     size: bar_size,
                     prefetchable: mem_bar.is_prefetchable(),
+                    io: false,
                 });
             }
             self.configure_barmem(mem_bar, bar_addr);
@@ -1576,6 +1577,7 @@ impl VfioPciDevice {
                         addr: bar_addr,
                         size: mem_info.size(),
                         prefetchable: mem_info.is_prefetchable(),
+                        io: false,
                     });
                 }
             }
@@ -1760,6 +1762,7 @@ impl PciDevice for VfioPciDevice {
                 addr: bar_addr,
                 size,
                 prefetchable: false,
+                io: false,
             });
             self.device_data = Some(DeviceData::IntelGfxData {
                 opregion_index: index,
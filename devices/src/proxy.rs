@@ -80,6 +80,7 @@ enum Command {
         value: u32,
     },
     DestroyDevice,
+    ReleaseIrq,
     Shutdown,
     GetRanges,
     Snapshot,
@@ -191,6 +192,10 @@ fn child_proc<D: BusDevice>(tube: Tube, mut device: D) {
                 device.destroy_device();
                 Ok(())
             }
+            Command::ReleaseIrq => {
+                device.release_irq();
+                tube.send(&CommandResult::Ok)
+            }
             Command::Shutdown => {
                 // Explicitly drop the device so that its Drop implementation has a chance to run
                 // before sending the `Command::Shutdown` response.
@@ -512,6 +517,11 @@ impl BusDevice for ProxyDevice {
     fn destroy_device(&mut self) {
         self.send_no_result(&Command::DestroyDevice);
     }
+
+    fn release_irq(&mut self) {
+        // Wait for the device to tear down its interrupts before its GSI can be handed out again.
+        self.sync_send(&Command::ReleaseIrq);
+    }
 }
 
 impl Suspendable for ProxyDevice {
@@ -273,6 +273,7 @@ impl PciDevice for XhciController {
             addr: bar0_addr,
             size: XHCI_BAR0_SIZE,
             prefetchable: false,
+            io: false,
         }])
     }
 
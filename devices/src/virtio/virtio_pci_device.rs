@@ -968,6 +968,7 @@ where
         addr: settings_config_addr,
         size: CAPABILITY_BAR_SIZE,
         prefetchable: false,
+        io: false,
     }])
 }
 
@@ -1046,6 +1047,7 @@ where
             addr: device_addr,
             size: config.size(),
             prefetchable: false,
+            io: false,
         });
     }
 
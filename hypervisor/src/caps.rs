@@ -56,4 +56,66 @@ pub enum VmCap {
     ReadOnlyMemoryRegion,
     /// VM can set guest memory cache noncoherent DMA flag
     MemNoncoherentDma,
+    /// Supports `Vm::register_ioevent`, whether delivered by the kernel or emulated in userspace.
+    IoEvent,
+}
+
+impl VmCap {
+    /// Every `VmCap` variant, in declaration order.
+    pub const ALL: &'static [VmCap] = &[
+        VmCap::DirtyLog,
+        VmCap::PvClock,
+        VmCap::Protected,
+        VmCap::EarlyInitCpuid,
+        #[cfg(target_arch = "x86_64")]
+        VmCap::BusLockDetect,
+        VmCap::ReadOnlyMemoryRegion,
+        VmCap::MemNoncoherentDma,
+        VmCap::IoEvent,
+    ];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A set of `VmCap`s, stored as a bitset.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VmCapSet(u32);
+
+impl VmCapSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `cap` to the set.
+    pub fn insert(&mut self, cap: VmCap) {
+        self.0 |= cap.bit();
+    }
+
+    /// Returns true if `cap` is in the set.
+    pub fn contains(&self, cap: VmCap) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    /// Returns true if the set has no capabilities.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterates over the capabilities in the set, in `VmCap` declaration order.
+    pub fn iter(&self) -> impl Iterator<Item = VmCap> + '_ {
+        VmCap::ALL.iter().copied().filter(|cap| self.contains(*cap))
+    }
+}
+
+impl FromIterator<VmCap> for VmCapSet {
+    fn from_iter<I: IntoIterator<Item = VmCap>>(iter: I) -> Self {
+        let mut set = VmCapSet::new();
+        for cap in iter {
+            set.insert(cap);
+        }
+        set
+    }
 }
@@ -603,7 +603,8 @@ pub struct GeniezoneVm {
     geniezone: Geniezone,
     vm: SafeDescriptor,
     guest_mem: GuestMemory,
-    mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
+    // Each region's guest address and host mapping.
+    mem_regions: Arc<Mutex<BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>)>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
 }
@@ -914,6 +915,7 @@ impl Vm for GeniezoneVm {
             VmCap::EarlyInitCpuid => false,
             VmCap::ReadOnlyMemoryRegion => false,
             VmCap::MemNoncoherentDma => false,
+            VmCap::IoEvent => true,
         }
     }
 
@@ -974,13 +976,13 @@ impl Vm for GeniezoneVm {
             gaps.push(Reverse(slot));
             return Err(e);
         }
-        regions.insert(slot, mem);
+        regions.insert(slot, (guest_addr, mem));
         Ok(slot)
     }
 
     fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let mem = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let (_, mem) = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
 
         mem.msync(offset, size).map_err(|err| match err {
             MmapError::InvalidAddress => Error::new(EFAULT),
@@ -1002,7 +1004,19 @@ impl Vm for GeniezoneVm {
         }
         self.mem_slot_gaps.lock().push(Reverse(slot));
         // This remove will always succeed because of the contains_key check above.
-        Ok(regions.remove(&slot).unwrap())
+        Ok(regions.remove(&slot).unwrap().1)
+    }
+
+    fn for_each_memory_region(
+        &self,
+        f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        // Hold the lock for the whole walk so the regions can't change under `f`.
+        let regions = self.mem_regions.lock();
+        for (slot, (guest_addr, mem)) in regions.iter() {
+            f(*slot, *guest_addr, mem.as_ref());
+        }
+        Ok(())
     }
 
     fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
@@ -1055,7 +1069,7 @@ impl Vm for GeniezoneVm {
         prot: Protection,
     ) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
         match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
             Ok(()) => Ok(()),
@@ -1066,7 +1080,7 @@ impl Vm for GeniezoneVm {
 
     fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
         match region.remove_mapping(offset, size) {
             Ok(()) => Ok(()),
@@ -439,6 +439,7 @@ impl Vm for GunyahVm {
             VmCap::BusLockDetect => false,
             VmCap::ReadOnlyMemoryRegion => false,
             VmCap::MemNoncoherentDma => false,
+            VmCap::IoEvent => true,
         }
     }
 
@@ -512,6 +513,18 @@ impl Vm for GunyahVm {
         unimplemented!()
     }
 
+    fn for_each_memory_region(
+        &self,
+        f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        // Hold the lock for the whole walk so the regions can't change under `f`.
+        let regions = self.mem_regions.lock();
+        for (slot, (mem, guest_addr)) in regions.iter() {
+            f(*slot, *guest_addr, mem.as_ref());
+        }
+        Ok(())
+    }
+
     fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
         unimplemented!()
     }
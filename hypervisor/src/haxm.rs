@@ -7,10 +7,15 @@ use std::arch::x86_64::__cpuid;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
+use base::errno_result;
+use base::ioctl_with_mut_ref;
+use base::ioctl_with_ref;
 use base::AsRawDescriptor;
+use base::Error;
 use base::RawDescriptor;
 use base::Result;
 use base::SafeDescriptor;
+use libc::ENOTSUP;
 
 use crate::CpuId;
 use crate::CpuIdEntry;
@@ -69,6 +74,40 @@ impl Haxm {
             haxm: open_haxm_device(get_use_ghaxm())?,
         })
     }
+
+    /// Sets the driver's limit on the guest RAM mapped by a VM to `quota` bytes, rounded up to a
+    /// whole megabyte, or removes the limit if `quota` is zero. The limit is global to the driver
+    /// and only applies to VMs created after it is set. Returns ENOTSUP if the driver does not
+    /// support a memory quota.
+    pub fn set_memory_quota(&self, quota: u64) -> Result<()> {
+        if capability_info(&self.haxm)?.wstatus as u32 & HAX_CAP_MEMQUOTA == 0 {
+            return Err(Error::new(ENOTSUP));
+        }
+        let memlimit = hax_set_memlimit {
+            enable_memlimit: (quota != 0) as u8,
+            memory_limit: quota.div_ceil(1 << 20),
+            ..Default::default()
+        };
+        // SAFETY:
+        // Safe because we know that our file is a HAXM fd and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, HAX_IOCTL_SET_MEMLIMIT(), &memlimit) };
+        if ret != 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+}
+
+/// Queries the capabilities of the HAXM driver behind `haxm`.
+fn capability_info(haxm: &SafeDescriptor) -> Result<hax_capabilityinfo> {
+    let mut capability_info = hax_capabilityinfo::default();
+    // SAFETY:
+    // Safe because we know that our file is a HAXM fd and we verify the return result.
+    let ret = unsafe { ioctl_with_mut_ref(haxm, HAX_IOCTL_CAPABILITY(), &mut capability_info) };
+    if ret != 0 {
+        return errno_result();
+    }
+    Ok(capability_info)
 }
 
 impl Hypervisor for Haxm {
@@ -312,6 +351,21 @@ mod tests {
         assert!(!haxm.check_capability(HypervisorCap::ImmediateExit));
     }
 
+    #[test]
+    fn set_memory_quota() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let info = capability_info(&haxm.haxm).expect("failed to query HAXM capabilities");
+        if info.wstatus as u32 & HAX_CAP_MEMQUOTA == 0 {
+            assert_eq!(haxm.set_memory_quota(1 << 30), Err(Error::new(ENOTSUP)));
+            return;
+        }
+        haxm.set_memory_quota(1 << 30)
+            .expect("failed to set memory quota");
+        // Remove the limit again so it doesn't apply to VMs created by other tests.
+        haxm.set_memory_quota(0)
+            .expect("failed to clear memory quota");
+    }
+
     #[test]
     fn check_supported_cpuid() {
         let haxm = Haxm::new().expect("failed to instantiate HAXM");
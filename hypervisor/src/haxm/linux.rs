@@ -10,12 +10,16 @@ use base::errno_result;
 use base::Error;
 use base::FromRawDescriptor;
 use base::MappedRegion;
+use base::MmapError;
 use base::Result;
 use base::SafeDescriptor;
 use libc::open64;
 use libc::EBUSY;
+use libc::EIO;
 use libc::O_CLOEXEC;
 use libc::O_RDWR;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
 
 use super::haxm_sys::hax_tunnel;
 use super::HaxmVcpu;
@@ -61,3 +65,26 @@ pub(super) fn open_haxm_vcpu_device(
     // Safe because we verify that ret is valid and we own the fd.
     unsafe { SafeDescriptor::from_raw_descriptor(ret) };
 }
+
+/// Releases the host memory backing a range of guest RAM that the guest gave up to the balloon.
+pub(super) fn release_guest_mem_range(
+    guest_mem: &GuestMemory,
+    guest_address: GuestAddress,
+    size: u64,
+) -> Result<()> {
+    match guest_mem.remove_range(guest_address, size) {
+        Ok(_) => Ok(()),
+        Err(vm_memory::Error::MemoryAccess(_, MmapError::SystemCallFailed(e))) => Err(e),
+        Err(_) => Err(Error::new(EIO)),
+    }
+}
+
+/// Returns a range of guest RAM released with `release_guest_mem_range` to the guest.
+pub(super) fn reclaim_guest_mem_range(
+    _guest_mem: &GuestMemory,
+    _guest_address: GuestAddress,
+    _size: u64,
+) -> Result<()> {
+    // No-op, the removed pages read back as zeroes when the guest accesses them again.
+    Ok(())
+}
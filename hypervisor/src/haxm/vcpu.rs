@@ -2,13 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use core::ffi::c_void;
 use std::arch::x86_64::CpuidResult;
 use std::cmp::min;
 use std::intrinsics::copy_nonoverlapping;
 use std::mem::size_of;
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
 
 use base::errno_result;
+use base::error;
 use base::ioctl;
 use base::ioctl_with_mut_ref;
 use base::ioctl_with_ptr_sized;
@@ -24,6 +26,7 @@ use libc::EINVAL;
 use libc::ENOENT;
 use libc::ENXIO;
 use libc::EOPNOTSUPP;
+use sync::Mutex;
 use vm_memory::GuestAddress;
 
 use super::*;
@@ -34,6 +37,8 @@ use crate::CpuId;
 use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::DescriptorTable;
+use crate::FaultHandler;
+use crate::FaultResolution;
 use crate::Fpu;
 use crate::HypervHypercall;
 use crate::IoOperation;
@@ -81,11 +86,146 @@ const HAX_EXIT_DIRECTION_PIO_OUT: u32 = 0;
 const HAX_EXIT_DIRECTION_MMIO_READ: u8 = 0;
 const HAX_EXIT_DIRECTION_MMIO_WRITE: u8 = 1;
 
+// The io buffer HAXM maps for each vcpu is a single page.
+const HAX_IO_BUF_SIZE: usize = 0x1000;
+
+/// The tunnel and io buffer that `HAX_VCPU_IOCTL_SETUP_TUNNEL` maps into this process, through
+/// which HAXM reports vcpu exits.
+///
+/// Both mappings are owned by the HAXM driver and stay valid until the vcpu is destroyed. They must
+/// never be unmapped or freed from userspace, which is why this type is `Copy`, has no `Drop`, and
+/// is shared by every clone of the vcpu.
+#[derive(Clone, Copy)]
+pub(super) struct VcpuTunnel {
+    tunnel: *mut hax_tunnel,
+    tunnel_size: usize,
+    io_buffer: *mut u8,
+}
+
+impl VcpuTunnel {
+    /// Wraps the mappings described by `tunnel_info`.
+    ///
+    /// # Safety
+    ///
+    /// `tunnel_info` must have been filled in by `HAX_VCPU_IOCTL_SETUP_TUNNEL` and accepted by
+    /// `check_tunnel_info`, and the tunnel must not be used after its vcpu is destroyed.
+    pub(super) unsafe fn new(tunnel_info: &hax_tunnel_info) -> Self {
+        VcpuTunnel {
+            tunnel: tunnel_info.va as *mut hax_tunnel,
+            tunnel_size: tunnel_info.size as usize,
+            io_buffer: tunnel_info.io_va as *mut u8,
+        }
+    }
+
+    fn tunnel(&self) -> *mut hax_tunnel {
+        debug_assert!(size_of::<hax_tunnel>() <= self.tunnel_size);
+        self.tunnel
+    }
+
+    fn fast_mmio_ptr(&self) -> *mut hax_fastmmio {
+        debug_assert!(size_of::<hax_fastmmio>() <= HAX_IO_BUF_SIZE);
+        self.io_buffer as *mut hax_fastmmio
+    }
+
+    /// Returns the reason HAXM gave for the last exit.
+    pub(super) fn exit_status(&self) -> u32 {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu.
+        unsafe { (*self.tunnel())._exit_status }
+    }
+
+    /// Sets the exit reason checked by HAXM on the next entry.
+    pub(super) fn set_exit_reason(&self, reason: u32) {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu.
+        unsafe { (*self.tunnel())._exit_reason = reason }
+    }
+
+    pub(super) fn set_request_interrupt_window(&self, requested: bool) {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu.
+        unsafe { (*self.tunnel()).request_interrupt_window = i32::from(requested) }
+    }
+
+    pub(super) fn ready_for_interrupt_injection(&self) -> bool {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu.
+        unsafe { (*self.tunnel()).ready_for_interrupt_injection != 0 }
+    }
+
+    /// Returns the port io request. Only meaningful after a `HAX_EXIT_IO` exit.
+    pub(super) fn io(&self) -> hax_tunnel__bindgen_ty_1__bindgen_ty_1 {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu, and
+        // every variant of the union is plain data.
+        unsafe { (*self.tunnel()).__bindgen_anon_1.io }
+    }
+
+    /// Returns the page fault. Only meaningful after a `HAX_EXIT_PAGEFAULT` exit.
+    pub(super) fn pagefault(&self) -> hax_tunnel__bindgen_ty_1__bindgen_ty_3 {
+        // SAFETY:
+        // Safe because the driver keeps a hax_tunnel sized mapping for the life of the vcpu, and
+        // every variant of the union is plain data.
+        unsafe { (*self.tunnel()).__bindgen_anon_1.pagefault }
+    }
+
+    /// Returns the mmio request. Only meaningful after a `HAX_EXIT_FAST_MMIO` exit.
+    pub(super) fn fast_mmio(&self) -> hax_fastmmio {
+        // SAFETY:
+        // Safe because the io buffer is a page, which holds a hax_fastmmio, and is mapped for the
+        // life of the vcpu.
+        unsafe { *self.fast_mmio_ptr() }
+    }
+
+    /// Sets the data returned to the guest for a fast mmio read.
+    pub(super) fn set_fast_mmio_value(&self, value: u64) {
+        // SAFETY:
+        // Safe because the io buffer is a page, which holds a hax_fastmmio, and is mapped for the
+        // life of the vcpu.
+        unsafe { (*self.fast_mmio_ptr()).__bindgen_anon_1.value = value }
+    }
+
+    /// Copies the start of the io buffer into `data`.
+    pub(super) fn read_io_buffer(&self, data: &mut [u8]) {
+        debug_assert!(data.len() <= HAX_IO_BUF_SIZE);
+        // SAFETY:
+        // Safe because the io buffer is a page mapped for the life of the vcpu and the copy is no
+        // longer than that.
+        unsafe {
+            copy_nonoverlapping(
+                self.io_buffer as *const u8,
+                data.as_mut_ptr(),
+                min(data.len(), HAX_IO_BUF_SIZE),
+            )
+        }
+    }
+
+    /// Copies `data` to the start of the io buffer.
+    pub(super) fn write_io_buffer(&self, data: &[u8]) {
+        debug_assert!(data.len() <= HAX_IO_BUF_SIZE);
+        // SAFETY:
+        // Safe because the io buffer is a page mapped for the life of the vcpu and the copy is no
+        // longer than that.
+        unsafe {
+            copy_nonoverlapping(
+                data.as_ptr(),
+                self.io_buffer,
+                min(data.len(), HAX_IO_BUF_SIZE),
+            )
+        }
+    }
+}
+
 pub struct HaxmVcpu {
     pub(super) descriptor: SafeDescriptor,
     pub(super) id: usize,
-    pub(super) tunnel: *mut hax_tunnel,
-    pub(super) io_buffer: *mut c_void,
+    pub(super) tunnel: VcpuTunnel,
+    // Shared with the VM so it can report the guest physical address width set through cpuid.
+    pub(super) guest_phys_addr_bits: Arc<AtomicU8>,
+    // The VM's descriptor, used to make guard pages accessible when the fault handler asks for it.
+    pub(super) vm_descriptor: SafeDescriptor,
+    pub(super) fault_handler: Arc<Mutex<Option<FaultHandler>>>,
+    pub(super) exit_logger: Option<Arc<dyn Fn(u32) + Send + Sync>>,
 }
 
 // TODO(b/315998194): Add safety comment
@@ -138,6 +278,35 @@ impl HaxmVcpu {
 
         self.set_msrs(&efer)
     }
+
+    /// Passes the guard region fault behind the last exit to the VM's fault handler. Returns true
+    /// if the handler had the faulting page made accessible, so the guest can simply be resumed.
+    fn resolve_fault(&self) -> bool {
+        let handler = self.fault_handler.lock();
+        let handler = match handler.as_ref() {
+            Some(handler) => handler,
+            None => return false,
+        };
+        let gpa = self.tunnel.pagefault().gpa;
+        match handler(GuestAddress(gpa)) {
+            FaultResolution::MapPage => {
+                let page = gpa & !(HAXM_PAGE_SIZE - 1);
+                match set_ram_protection(
+                    &self.vm_descriptor,
+                    page,
+                    HAXM_PAGE_SIZE,
+                    HAX_RAM_PERM_RWX,
+                ) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("failed to map guest page {:#x} on demand: {}", page, e);
+                        false
+                    }
+                }
+            }
+            FaultResolution::Unhandled => false,
+        }
+    }
 }
 
 impl Vcpu for HaxmVcpu {
@@ -147,7 +316,10 @@ impl Vcpu for HaxmVcpu {
             descriptor: self.descriptor.try_clone()?,
             id: self.id,
             tunnel: self.tunnel,
-            io_buffer: self.io_buffer,
+            guest_phys_addr_bits: self.guest_phys_addr_bits.clone(),
+            vm_descriptor: self.vm_descriptor.try_clone()?,
+            fault_handler: self.fault_handler.clone(),
+            exit_logger: self.exit_logger.clone(),
         })
     }
 
@@ -162,13 +334,10 @@ impl Vcpu for HaxmVcpu {
 
     /// Sets the bit that requests an immediate exit.
     fn set_immediate_exit(&self, exit: bool) {
-        // SAFETY:
-        // Safe because we know the tunnel is a pointer to a hax_tunnel and we know its size.
         // Crosvm's HAXM implementation does not use the _exit_reason, so it's fine if we
         // overwrite it.
-        unsafe {
-            (*self.tunnel)._exit_reason = if exit { HAX_EXIT_PAUSED } else { 0 };
-        }
+        self.tunnel
+            .set_exit_reason(if exit { HAX_EXIT_PAUSED } else { 0 });
     }
 
     /// Signals to the hypervisor that this guest is being paused by userspace.
@@ -189,19 +358,10 @@ impl Vcpu for HaxmVcpu {
     /// exit, call `handle_fn` with the respective IoOperation to perform the mmio read or
     /// write, and set the return data in the vcpu so that the vcpu can resume running.
     fn handle_mmio(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
-        // SAFETY:
-        // Safe because we know we mapped enough memory to hold the hax_tunnel struct because the
-        // kernel told us how large it was.
         // Verify that the handler is called for mmio context only.
-        unsafe {
-            assert!((*self.tunnel)._exit_status == HAX_EXIT_FAST_MMIO);
-        }
-        let mmio = self.io_buffer as *mut hax_fastmmio;
-        let (address, size, direction) =
-            // SAFETY:
-            // Safe because the exit_reason (which comes from the kernel) told us which
-            // union field to use.
-            unsafe { ((*mmio).gpa, (*mmio).size as usize, (*mmio).direction) };
+        assert!(self.tunnel.exit_status() == HAX_EXIT_FAST_MMIO);
+        let mmio = self.tunnel.fast_mmio();
+        let (address, size, direction) = (mmio.gpa, mmio.size as usize, mmio.direction);
 
         match direction {
             HAX_EXIT_DIRECTION_MMIO_READ => {
@@ -210,20 +370,14 @@ impl Vcpu for HaxmVcpu {
                     size,
                     operation: IoOperation::Read,
                 }) {
-                    let data = u64::from_ne_bytes(data);
-                    // SAFETY:
-                    // Safe because we know this is an mmio read, so we need to put data into the
-                    // "value" field of the hax_fastmmio.
-                    unsafe {
-                        (*mmio).__bindgen_anon_1.value = data;
-                    }
+                    self.tunnel.set_fast_mmio_value(u64::from_ne_bytes(data));
                 }
                 Ok(())
             }
             HAX_EXIT_DIRECTION_MMIO_WRITE => {
                 // SAFETY:
                 // safe because we trust haxm to fill in the union properly.
-                let data = unsafe { (*mmio).__bindgen_anon_1.value };
+                let data = unsafe { mmio.__bindgen_anon_1.value };
                 handle_fn(IoParams {
                     address,
                     size,
@@ -242,19 +396,10 @@ impl Vcpu for HaxmVcpu {
     /// Once called, it will determine whether an io in or io out was the reason for the io exit,
     /// call `handle_fn` with the respective IoOperation to perform the io in or io out,
     /// and set the return data in the vcpu so that the vcpu can resume running.
-    #[allow(clippy::cast_ptr_alignment)]
     fn handle_io(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
-        // SAFETY:
-        // Safe because we know we mapped enough memory to hold the hax_tunnel struct because the
-        // kernel told us how large it was.
         // Verify that the handler is called for io context only.
-        unsafe {
-            assert!((*self.tunnel)._exit_status == HAX_EXIT_IO);
-        }
-        // SAFETY:
-        // Safe because the exit_reason (which comes from the kernel) told us which
-        // union field to use.
-        let io = unsafe { (*self.tunnel).__bindgen_anon_1.io };
+        assert!(self.tunnel.exit_status() == HAX_EXIT_IO);
+        let io = self.tunnel.io();
         let address = io._port.into();
         let size = (io._count as usize) * (io._size as usize);
         match io._direction as u32 {
@@ -264,26 +409,13 @@ impl Vcpu for HaxmVcpu {
                     size,
                     operation: IoOperation::Read,
                 }) {
-                    // SAFETY:
-                    // Safe because the exit_reason (which comes from the kernel) told us that
-                    // this is port io, where the iobuf can be treated as a *u8
-                    unsafe {
-                        copy_nonoverlapping(data.as_ptr(), self.io_buffer as *mut u8, size);
-                    }
+                    self.tunnel.write_io_buffer(&data[..min(size, data.len())]);
                 }
                 Ok(())
             }
             HAX_EXIT_DIRECTION_PIO_OUT => {
                 let mut data = [0; 8];
-                // SAFETY:
-                // safe because we check the size, from what the kernel told us is the max to copy.
-                unsafe {
-                    copy_nonoverlapping(
-                        self.io_buffer as *const u8,
-                        data.as_mut_ptr(),
-                        min(size, data.len()),
-                    );
-                }
+                self.tunnel.read_io_buffer(&mut data[..min(size, 8)]);
                 handle_fn(IoParams {
                     address,
                     size,
@@ -315,21 +447,24 @@ impl Vcpu for HaxmVcpu {
         // TODO(b/233766326): Implement.
     }
 
-    #[allow(clippy::cast_ptr_alignment)]
-    // The pointer is page aligned so casting to a different type is well defined, hence the clippy
-    // allow attribute.
     fn run(&mut self) -> Result<VcpuExit> {
-        // TODO(b/315998194): Add safety comment
-        #[allow(clippy::undocumented_unsafe_blocks)]
-        let ret = unsafe { ioctl(self, HAX_VCPU_IOCTL_RUN()) };
-        if ret != 0 {
-            return errno_result();
-        }
+        let exit_status = loop {
+            // TODO(b/315998194): Add safety comment
+            #[allow(clippy::undocumented_unsafe_blocks)]
+            let ret = unsafe { ioctl(self, HAX_VCPU_IOCTL_RUN()) };
+            if ret != 0 {
+                return errno_result();
+            }
 
-        // SAFETY:
-        // Safe because we know we mapped enough memory to hold the hax_tunnel struct because the
-        // kernel told us how large it was.
-        let exit_status = unsafe { (*self.tunnel)._exit_status };
+            let exit_status = self.tunnel.exit_status();
+            if let Some(exit_logger) = &self.exit_logger {
+                exit_logger(exit_status);
+            }
+            // A fault the handler resolved is invisible to the caller.
+            if exit_status != HAX_EXIT_PAGEFAULT || !self.resolve_fault() {
+                break exit_status;
+            }
+        };
 
         match exit_status {
             HAX_EXIT_IO => Ok(VcpuExit::Io),
@@ -350,20 +485,12 @@ impl VcpuX86_64 for HaxmVcpu {
     /// Sets or clears the flag that requests the VCPU to exit when it becomes possible to inject
     /// interrupts into the guest.
     fn set_interrupt_window_requested(&self, requested: bool) {
-        // SAFETY:
-        // Safe because we know we mapped enough memory to hold the hax_tunnel struct because the
-        // kernel told us how large it was.
-        unsafe {
-            (*self.tunnel).request_interrupt_window = i32::from(requested);
-        }
+        self.tunnel.set_request_interrupt_window(requested);
     }
 
     /// Checks if we can inject an interrupt into the VCPU.
     fn ready_for_interrupt(&self) -> bool {
-        // SAFETY:
-        // Safe because we know we mapped enough memory to hold the hax_tunnel struct because the
-        // kernel told us how large it was.
-        unsafe { (*self.tunnel).ready_for_interrupt_injection != 0 }
+        self.tunnel.ready_for_interrupt_injection()
     }
 
     /// Injects interrupt vector `irq` into the VCPU.
@@ -584,6 +711,16 @@ impl VcpuX86_64 for HaxmVcpu {
         if ret != 0 {
             return errno_result();
         }
+
+        // Low 8 bits of the 0x80000008 leaf: guest physical address size in bits.
+        if let Some(entry) = cpuid
+            .cpu_id_entries
+            .iter()
+            .find(|e| e.function == 0x80000008)
+        {
+            self.guest_phys_addr_bits
+                .store(entry.cpuid.eax as u8, Ordering::Relaxed);
+        }
         Ok(())
     }
 
@@ -1030,6 +1167,8 @@ mod tests {
     use vm_memory::GuestMemory;
 
     use super::*;
+    use crate::host_phys_addr_bits;
+    use crate::Vm;
     use crate::VmX86_64;
 
     // EFER Bits
@@ -1123,6 +1262,36 @@ mod tests {
         vcpu.set_cpuid(&cpuid).expect("failed to set cpuid");
     }
 
+    #[test]
+    fn set_cpuid_guest_phys_addr_bits() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest memory");
+        let vm = HaxmVm::new(&haxm, mem).expect("failed to create vm");
+        let vcpu = vm.create_vcpu(0).expect("failed to create vcpu");
+        // Without a cpuid the VM reports the host's width.
+        assert_eq!(vm.get_guest_phys_addr_bits(), host_phys_addr_bits());
+
+        let mut cpuid = haxm
+            .get_supported_cpuid()
+            .expect("failed to get supported cpuids");
+        cpuid.cpu_id_entries.retain(|e| e.function != 0x80000008);
+        cpuid.cpu_id_entries.push(CpuIdEntry {
+            function: 0x80000008,
+            index: 0,
+            flags: 0,
+            cpuid: CpuidResult {
+                eax: 36,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            },
+        });
+
+        vcpu.set_cpuid(&cpuid).expect("failed to set cpuid");
+        assert_eq!(vm.get_guest_phys_addr_bits(), 36);
+    }
+
     #[test]
     fn set_efer() {
         // HAXM efer setting requires some extra code, so we have this test specifically
@@ -1165,4 +1334,33 @@ mod tests {
         vcpu.get_msrs(&mut efer_reg).expect("failed to get msrs");
         assert_eq!(efer_reg[0].value, EFER_SCE | EFER_LME | EFER_LMA);
     }
+
+    #[test]
+    fn vcpu_tunnel_accessors() {
+        // Stand-ins for the two pages the driver maps.
+        let mut tunnel_page = vec![0u64; HAX_IO_BUF_SIZE / 8];
+        let mut io_page = vec![0u64; HAX_IO_BUF_SIZE / 8];
+        let tunnel_info = hax_tunnel_info {
+            va: tunnel_page.as_mut_ptr() as u64,
+            io_va: io_page.as_mut_ptr() as u64,
+            size: HAX_IO_BUF_SIZE as u16,
+            ..Default::default()
+        };
+        // SAFETY: both pages outlive the tunnel.
+        let tunnel = unsafe { VcpuTunnel::new(&tunnel_info) };
+
+        tunnel.set_exit_reason(HAX_EXIT_PAUSED);
+        assert_eq!(tunnel_page[0] as u32, HAX_EXIT_PAUSED);
+        assert_eq!(tunnel.exit_status(), 0);
+        assert!(!tunnel.ready_for_interrupt_injection());
+
+        tunnel.write_io_buffer(&[1, 2, 3, 4]);
+        let mut data = [0u8; 4];
+        tunnel.read_io_buffer(&mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        tunnel.set_fast_mmio_value(0x1234);
+        // SAFETY: the value was just written.
+        assert_eq!(unsafe { tunnel.fast_mmio().__bindgen_anon_1.value }, 0x1234);
+    }
 }
@@ -2,11 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use core::ffi::c_void;
 use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
+use std::sync::atomic::AtomicU8;
 use std::sync::Arc;
+use std::sync::Once;
+use std::time::Instant;
 
 use base::errno_result;
 use base::error;
@@ -16,22 +18,28 @@ use base::warn;
 use base::AsRawDescriptor;
 use base::Error;
 use base::Event;
+use base::ExternalMapping;
 use base::MappedRegion;
+use base::MemoryMappingBuilder;
 use base::MmapError;
 use base::Protection;
 use base::RawDescriptor;
 use base::Result;
 use base::SafeDescriptor;
+use base::SharedMemory;
+use base::VolatileSlice;
 use fnv::FnvHashMap;
+use fnv::FnvHashSet;
 use libc::E2BIG;
 use libc::EEXIST;
 use libc::EFAULT;
 use libc::EINVAL;
 use libc::EIO;
+use libc::EMFILE;
 use libc::ENOENT;
 use libc::ENOSPC;
-use libc::ENOTSUP;
 use libc::EOVERFLOW;
+use libc::EPERM;
 use sync::Mutex;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -40,35 +48,122 @@ use win_util::win32_wide_string;
 
 use super::*;
 use crate::host_phys_addr_bits;
+use crate::BalloonEvent;
 use crate::ClockState;
 use crate::Datamatch;
 use crate::DeviceKind;
+use crate::FaultHandler;
 use crate::Hypervisor;
 use crate::IoEventAddress;
 use crate::MemCacheType;
 use crate::MemSlot;
+use crate::VcpuConfig;
 use crate::VcpuX86_64;
 use crate::Vm;
 use crate::VmCap;
 use crate::VmX86_64;
 
+/// HAXM does not report a limit on memory slots, so use the same conservative limit as KVM's
+/// default.
+const HAXM_MAX_MEMORY_SLOTS: usize = 509;
+
+/// HAXM maps guest memory in 4KB pages and rejects regions that are not aligned to them.
+pub(super) const HAXM_PAGE_SIZE: u64 = 0x1000;
+
+/// Size of the buffer that memory region contents are copied through when they are snapshotted
+/// or restored.
+const SNAPSHOT_CHUNK_SIZE: usize = 0x10000;
+
+/// The first VMM version for which HAXM reports MMIO through HAX_EXIT_FAST_MMIO.
+const FAST_MMIO_VERSION: u32 = 2;
+
+/// The capabilities reported by the HAXM driver.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct HaxmCapabilities {
+    /// HAXM is able to run VMs on this host.
+    pub working: bool,
+    /// Extended page tables are supported.
+    pub ept: bool,
+    /// MMIO can be emulated through the vcpu io buffer.
+    pub fast_mmio: bool,
+    /// Unrestricted guest mode is supported.
+    pub ug: bool,
+    /// RAM blocks larger than 4GB are supported.
+    pub ramblock_64bit: bool,
+    /// SET_RAM2 with 64-bit sizes is supported.
+    pub set_ram_64bit: bool,
+    /// The vcpu tunnel is a full page.
+    pub tunnel_page: bool,
+    /// Guest RAM can be protected with HAX_VM_IOCTL_PROTECT_RAM.
+    pub ram_protection: bool,
+    /// Guest debugging is supported.
+    pub debug: bool,
+    /// RAM blocks are created implicitly by SET_RAM2.
+    pub implicit_ramblock: bool,
+    /// Guest CPUID can be set.
+    pub cpuid: bool,
+    /// Per-VM log files are supported (internal fork of HAXM only).
+    pub vm_log: bool,
+    /// The memory quota reported by the driver, if any.
+    pub mem_quota: Option<u64>,
+}
+
+impl From<&hax_capabilityinfo> for HaxmCapabilities {
+    fn from(info: &hax_capabilityinfo) -> Self {
+        let status = info.wstatus as u32;
+        let winfo = info.winfo as u32;
+        HaxmCapabilities {
+            working: status & HAX_CAP_STATUS_WORKING != 0,
+            ept: winfo & HAX_CAP_EPT != 0,
+            fast_mmio: winfo & HAX_CAP_FASTMMIO != 0,
+            ug: winfo & HAX_CAP_UG != 0,
+            ramblock_64bit: winfo & HAX_CAP_64BIT_RAMBLOCK != 0,
+            set_ram_64bit: winfo & HAX_CAP_64BIT_SETRAM != 0,
+            tunnel_page: winfo & HAX_CAP_TUNNEL_PAGE != 0,
+            ram_protection: winfo & HAX_CAP_RAM_PROTECTION != 0,
+            debug: winfo & HAX_CAP_DEBUG != 0,
+            implicit_ramblock: winfo & HAX_CAP_IMPLICIT_RAMBLOCK != 0,
+            cpuid: winfo & HAX_CAP_CPUID != 0,
+            vm_log: winfo & HAX_CAP_VM_LOG != 0,
+            mem_quota: if status & HAX_CAP_MEMQUOTA != 0 {
+                Some(info.mem_quota)
+            } else {
+                None
+            },
+        }
+    }
+}
+
 /// A wrapper around creating and using a HAXM VM.
 pub struct HaxmVm {
     haxm: Haxm,
     vm_id: u32,
     descriptor: SafeDescriptor,
     guest_mem: GuestMemory,
-    mem_regions: Arc<Mutex<BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>)>>>,
+    // Each region's guest address, host mapping and optional diagnostic label.
+    mem_regions:
+        Arc<Mutex<BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>, Option<String>)>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// The set of MemSlots that are currently read-only for the guest
+    read_only_slots: Arc<Mutex<FnvHashSet<MemSlot>>>,
+    /// Software emulation of the paravirtual clock, shared by all clones of this VM
+    pvclock: Arc<Mutex<SoftPvClock>>,
+    /// The guest physical address width advertised by the cpuid last set on a vcpu, or 0 if no
+    /// cpuid has been set. Shared with the vcpus.
+    guest_phys_addr_bits: Arc<AtomicU8>,
+    /// Called with the exit status of every vcpu exit, for debugging. Handed to the vcpus when they
+    /// are created.
+    exit_logger: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+    /// Handler for guard region faults, shared with the vcpus and clones of this VM.
+    fault_handler: Arc<Mutex<Option<FaultHandler>>>,
     // HAXM's implementation of ioevents makes several assumptions about how crosvm uses ioevents:
     //   1. All ioevents are registered during device setup, and thus can be cloned when the vm is
     //      cloned instead of locked in an Arc<Mutex<>>. This will make handling ioevents in each
     //      vcpu thread easier because no locks will need to be acquired.
-    //   2. All ioevents use Datamatch::AnyLength. We don't bother checking the datamatch, which
-    //      will make this faster.
-    //   3. We only ever register one eventfd to each address. This simplifies our data structure.
-    ioevents: FnvHashMap<IoEventAddress, Event>,
+    //   2. Only a handful of ioevents are registered to each address, so the datamatch of each
+    //      one is checked in software with a linear scan when the address is written.
+    ioevents: FnvHashMap<IoEventAddress, Vec<(Datamatch, Event)>>,
 }
 
 impl HaxmVm {
@@ -107,28 +202,477 @@ impl HaxmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            read_only_slots: Arc::new(Mutex::new(FnvHashSet::default())),
+            pvclock: Arc::new(Mutex::new(SoftPvClock::new())),
+            guest_phys_addr_bits: Arc::new(AtomicU8::new(0)),
+            exit_logger: None,
+            fault_handler: Arc::new(Mutex::new(None)),
             ioevents: FnvHashMap::default(),
         })
     }
 
+    fn capability_info(&self) -> Result<hax_capabilityinfo> {
+        capability_info(&self.haxm)
+    }
+
     pub fn check_raw_capability(&self, cap: u32) -> bool {
-        let mut capability_info = hax_capabilityinfo::default();
-        let ret =
-            // SAFETY:
-            // Safe because we know that our file is a VM fd and we verify the return result.
-            unsafe { ioctl_with_mut_ref(&self.haxm, HAX_IOCTL_CAPABILITY(), &mut capability_info) };
+        match self.capability_info() {
+            Ok(capability_info) => (cap & capability_info.winfo as u32) != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Tears the VM down: removes every memory region added with `add_memory_region`, reporting
+    /// the first remove ioctl that fails instead of leaving it to drop, then drops the ioevents and
+    /// the VM descriptor. Clones of the VM share its memory regions and descriptor, so HAXM only
+    /// destroys the VM once every clone is gone.
+    pub fn destroy(mut self) -> Result<()> {
+        let mappings = self.clear_memory_regions()?;
+        // Unmap only after HAXM has stopped using the memory.
+        drop(mappings);
+        self.ioevents.clear();
+        Ok(())
+    }
+
+    /// Enables fast MMIO exits, where HAXM decodes the faulting instruction itself and reports
+    /// only the access through `hax_fastmmio`. The driver turns this on for VMMs that report at
+    /// least `FAST_MMIO_VERSION` through HAX_VM_IOCTL_NOTIFY_QEMU_VERSION.
+    ///
+    /// Returns false without touching the VM if the driver lacks HAX_CAP_FASTMMIO. MMIO accesses
+    /// still exit to crosvm either way: HAXM has no in-kernel ioevent delivery, so ioevents keep
+    /// being signalled by `handle_io_events`.
+    pub fn enable_fast_mmio(&self) -> Result<bool> {
+        if !self.check_raw_capability(HAX_CAP_FASTMMIO) {
+            return Ok(false);
+        }
 
+        let version = hax_qemu_version {
+            cur_version: FAST_MMIO_VERSION,
+            least_version: FAST_MMIO_VERSION,
+        };
+        // SAFETY:
+        // Safe because we know that our file is a VM fd and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, HAX_VM_IOCTL_NOTIFY_QEMU_VERSION(), &version) };
         if ret != 0 {
-            return false;
+            return errno_result();
+        }
+        Ok(true)
+    }
+
+    /// Queries the capabilities of the HAXM driver. If the query fails, every capability is
+    /// reported as unavailable.
+    pub fn capabilities(&self) -> HaxmCapabilities {
+        match self.capability_info() {
+            Ok(info) => HaxmCapabilities::from(&info),
+            Err(e) => {
+                warn!("failed to query HAXM capabilities: {}", e);
+                HaxmCapabilities::default()
+            }
+        }
+    }
+
+    /// Returns the id the HAXM driver assigned to this VM, which identifies it in the driver's
+    /// logs.
+    pub fn vm_id(&self) -> u32 {
+        self.vm_id
+    }
+
+    /// Returns the address and datamatch of every registered ioevent, in no particular order.
+    pub fn registered_ioevents(&self) -> Vec<(IoEventAddress, Datamatch)> {
+        self.ioevents
+            .iter()
+            .flat_map(|(addr, evts)| evts.iter().map(|(datamatch, _)| (*addr, *datamatch)))
+            .collect()
+    }
+
+    /// Returns the driver's limit on the guest RAM mapped by a VM, in bytes. Returns ENXIO if the
+    /// driver does not enforce a limit.
+    pub fn get_memory_quota(&self) -> Result<u64> {
+        HaxmCapabilities::from(&self.capability_info()?)
+            .mem_quota
+            .ok_or(Error::new(libc::ENXIO))
+    }
+
+    /// Sets a logger that every vcpu calls with the `hax_tunnel` exit status of each exit, including
+    /// exits `run` handles itself, to trace what a hung guest is doing. Only vcpus created after
+    /// this call use the logger; without one the only cost is checking an `Option`.
+    pub fn set_exit_logger(&mut self, logger: Box<dyn Fn(u32) + Send + Sync>) {
+        self.exit_logger = Some(Arc::from(logger));
+    }
+
+    // Checks that mapping `size` more bytes keeps the VM within the driver's memory quota, if it
+    // enforces one. Without this check HAXM fails the mapping with the same error as an overlap.
+    fn check_memory_quota(
+        &self,
+        regions: &BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>, Option<String>)>,
+        size: u64,
+    ) -> Result<()> {
+        let quota = match self.get_memory_quota() {
+            Ok(quota) => quota,
+            Err(_) => return Ok(()),
+        };
+        check_memory_quota(quota, self.mapped_bytes(regions), size)
+    }
+
+    // Sums the guest memory and the `regions` added on top of it.
+    fn mapped_bytes(
+        &self,
+        regions: &BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>, Option<String>)>,
+    ) -> u64 {
+        self.guest_mem.memory_size()
+            + regions
+                .values()
+                .map(|(_, mem, _)| mem.size() as u64)
+                .sum::<u64>()
+    }
+
+    /// Changes whether the memory region at `slot` is read-only for the guest, without removing
+    /// it from the guest's address space. The region keeps its existing host mapping.
+    ///
+    /// Returns ENOENT if `slot` is unknown. On failure the region is left unchanged.
+    pub fn set_memory_region_readonly(&mut self, slot: MemSlot, read_only: bool) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let (guest_addr, mem, _) = regions.get(&slot).ok_or(Error::new(ENOENT))?;
+
+        // SAFETY:
+        // Safe because the slot is checked against the list of memory slots, and the region
+        // keeps the same host mapping that is still owned by `mem_regions`.
+        unsafe {
+            set_user_memory_region(
+                &self.descriptor,
+                read_only,
+                guest_addr.offset(),
+                mem.size() as u64,
+                MemoryRegionOp::Add(mem.as_ptr() as u64),
+            )
+        }?;
+        let mut read_only_slots = self.read_only_slots.lock();
+        if read_only {
+            read_only_slots.insert(slot);
+        } else {
+            read_only_slots.remove(&slot);
+        }
+        Ok(())
+    }
+
+    /// Points the memory region at `slot` to the host mapping at `new_host_addr`, for when the
+    /// backing memory has been remapped on the host. The region is re-added in place with the same
+    /// guest address, size and flags, so it stays mapped in the guest while vcpus are running.
+    ///
+    /// On success the region is recorded as an `ExternalMapping` of the new address and the
+    /// previous mapping is returned, so the caller can decide whether it still needs to be
+    /// unmapped. Returns ENOENT if `slot` is unknown. On failure the region is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// `new_host_addr` must point to a mapping at least as large as the region that stays valid
+    /// until the region is removed from the VM. The caller keeps ownership of that mapping and is
+    /// responsible for unmapping it once the returned or later removed `ExternalMapping` is no
+    /// longer in use.
+    pub unsafe fn update_memory_region_va(
+        &mut self,
+        slot: MemSlot,
+        new_host_addr: *mut u8,
+    ) -> Result<Box<dyn MappedRegion>> {
+        let mut regions = self.mem_regions.lock();
+        let (guest_addr, mem, _) = regions.get_mut(&slot).ok_or(Error::new(ENOENT))?;
+        let size = mem.size();
+        let read_only = self.read_only_slots.lock().contains(&slot);
+
+        // SAFETY:
+        // Safe because the slot is checked against the list of memory slots, and the caller
+        // guarantees that `new_host_addr` maps at least `size` bytes for as long as the region is
+        // part of the VM.
+        unsafe {
+            set_user_memory_region(
+                &self.descriptor,
+                read_only,
+                guest_addr.offset(),
+                size as u64,
+                MemoryRegionOp::Add(new_host_addr as u64),
+            )
+        }?;
+        Ok(std::mem::replace(
+            mem,
+            Box::new(ExternalMapping {
+                ptr: new_host_addr as u64,
+                size,
+            }),
+        ))
+    }
+
+    /// Returns the maximum number of memory slots, including those used by guest memory. Adding a
+    /// memory region fails with EMFILE once every slot is in use.
+    pub fn max_memory_slots(&self) -> usize {
+        HAXM_MAX_MEMORY_SLOTS
+    }
+
+    /// Adds several memory regions at once. Each entry is the guest address, the host mapping and
+    /// whether the region is read-only. Returns the slots of the new regions in the same order.
+    ///
+    /// All regions are checked for overlap with guest memory and with each other before any of
+    /// them are added. If adding a region fails, the regions added so far are removed again and
+    /// their slots are released.
+    pub fn add_memory_regions(
+        &mut self,
+        regions: Vec<(GuestAddress, Box<dyn MappedRegion>, bool)>,
+    ) -> Result<Vec<MemSlot>> {
+        let mut ranges = Vec::with_capacity(regions.len());
+        for (guest_addr, mem, _) in &regions {
+            let end_addr = guest_addr
+                .checked_add(mem.size() as u64)
+                .ok_or(Error::new(EOVERFLOW))?;
+            if self.guest_mem.range_overlap(*guest_addr, end_addr) {
+                return Err(Error::new(ENOSPC));
+            }
+            ranges.push((*guest_addr, end_addr));
+        }
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|w| w[1].0 < w[0].1) {
+            return Err(Error::new(ENOSPC));
+        }
+        for (guest_addr, end_addr) in &ranges {
+            check_page_aligned(*guest_addr, end_addr.offset() - guest_addr.offset())?;
+        }
+
+        let mut mem_regions = self.mem_regions.lock();
+        let used_slots = mem_regions.len() + self.guest_mem.num_regions() as usize;
+        if used_slots + regions.len() > self.max_memory_slots() {
+            return Err(Error::new(EMFILE));
+        }
+        let size = ranges
+            .iter()
+            .map(|(guest_addr, end_addr)| end_addr.offset() - guest_addr.offset())
+            .sum();
+        self.check_memory_quota(&mem_regions, size)?;
+        let mut gaps = self.mem_slot_gaps.lock();
+        let mut read_only_slots = self.read_only_slots.lock();
+        let mut slots = Vec::with_capacity(regions.len());
+        for (guest_addr, mem, read_only) in regions {
+            let slot = match gaps.pop() {
+                Some(gap) => gap.0,
+                None => (mem_regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+            };
+
+            // SAFETY:
+            // Safe because we check that the given guest address is valid and has no overlaps. We
+            // also know that the pointer and size are correct because the MemoryMapping interface
+            // ensures this. We take ownership of the memory mapping so that it won't be unmapped
+            // until the slot is removed.
+            let res = unsafe {
+                set_user_memory_region(
+                    &self.descriptor,
+                    read_only,
+                    guest_addr.offset(),
+                    mem.size() as u64,
+                    MemoryRegionOp::Add(mem.as_ptr() as u64),
+                )
+            };
+
+            if let Err(e) = res {
+                gaps.push(Reverse(slot));
+                for slot in slots.into_iter().rev() {
+                    let (guest_addr, mem, _) = mem_regions.remove(&slot).unwrap();
+                    // SAFETY:
+                    // Safe because the slot was added above and is still in the list of memory
+                    // slots.
+                    let res = unsafe {
+                        set_user_memory_region(
+                            &self.descriptor,
+                            false,
+                            guest_addr.offset(),
+                            mem.size() as u64,
+                            MemoryRegionOp::Remove,
+                        )
+                    };
+                    if let Err(e) = res {
+                        error!("failed to roll back memory slot {}: {}", slot, e);
+                        // Leak the mapping rather than unmapping memory HAXM may still use.
+                        std::mem::forget(mem);
+                    }
+                    read_only_slots.remove(&slot);
+                    gaps.push(Reverse(slot));
+                }
+                return Err(e);
+            }
+            mem_regions.insert(slot, (guest_addr, mem, None));
+            if read_only {
+                read_only_slots.insert(slot);
+            }
+            slots.push(slot);
+        }
+        Ok(slots)
+    }
+
+    /// Like `Vm::add_memory_region`, but records `label` with the region so it can be identified
+    /// in `describe_memory_regions`, e.g. "bios" or "pstore".
+    pub fn add_memory_region_labeled(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        read_only: bool,
+        _log_dirty_pages: bool,
+        label: Option<String>,
+    ) -> Result<MemSlot> {
+        let size = mem.size() as u64;
+        let end_addr = guest_addr.checked_add(size).ok_or(Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        check_page_aligned(guest_addr, size)?;
+        let mut regions = self.mem_regions.lock();
+        if regions.len() + self.guest_mem.num_regions() as usize >= self.max_memory_slots() {
+            return Err(Error::new(EMFILE));
+        }
+        self.check_memory_quota(&regions, size)?;
+        let mut gaps = self.mem_slot_gaps.lock();
+        let slot = match gaps.pop() {
+            Some(gap) => gap.0,
+            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+        };
+
+        // SAFETY:
+        // Safe because we check that the given guest address is valid and has no overlaps. We also
+        // know that the pointer and size are correct because the MemoryMapping interface ensures
+        // this. We take ownership of the memory mapping so that it won't be unmapped until the slot
+        // is removed.
+        let res = unsafe {
+            set_user_memory_region(
+                &self.descriptor,
+                read_only,
+                guest_addr.offset(),
+                size,
+                MemoryRegionOp::Add(mem.as_ptr() as u64),
+            )
+        };
+
+        if let Err(e) = res {
+            gaps.push(Reverse(slot));
+            return Err(e);
+        }
+        regions.insert(slot, (guest_addr, mem, label));
+        if read_only {
+            self.read_only_slots.lock().insert(slot);
+        }
+        Ok(slot)
+    }
+
+    /// Returns the slot, guest address, size and label of each memory region added to the VM,
+    /// ordered by slot. Guest memory regions are not included.
+    pub fn describe_memory_regions(&self) -> Vec<(MemSlot, GuestAddress, u64, Option<String>)> {
+        self.mem_regions
+            .lock()
+            .iter()
+            .map(|(slot, (guest_addr, mem, label))| {
+                (*slot, *guest_addr, mem.size() as u64, label.clone())
+            })
+            .collect()
+    }
+
+    /// Translates the `len` bytes of guest physical memory at `addr` to the host address they are
+    /// mapped at, looking in guest memory and in the regions added to the VM. Returns EFAULT if
+    /// `addr` isn't mapped or if the range crosses the end of the region containing it, even into
+    /// an adjacent region, since the host mappings of two regions aren't contiguous.
+    ///
+    /// The pointer is only valid while the region stays in the VM: removing the region or
+    /// remapping it with `update_memory_region_va` leaves it dangling. Callers that dereference
+    /// it must make sure that can't happen in the meantime.
+    pub fn guest_to_host(&self, addr: GuestAddress, len: u64) -> Result<*mut u8> {
+        let translate = |region_addr: GuestAddress, region_size: u64, host_addr: *mut u8| {
+            let offset = addr.offset().checked_sub(region_addr.offset())?;
+            if offset >= region_size {
+                return None;
+            }
+            if len > region_size - offset {
+                return Some(Err(Error::new(EFAULT)));
+            }
+            // SAFETY:
+            // Safe because `offset` is within the region's mapping.
+            Some(Ok(unsafe { host_addr.add(offset as usize) }))
+        };
+
+        let from_guest_mem = self.guest_mem.regions().find_map(|region| {
+            translate(
+                region.guest_addr,
+                region.size as u64,
+                region.host_addr as *mut u8,
+            )
+        });
+        if let Some(result) = from_guest_mem {
+            return result;
+        }
+        let regions = self.mem_regions.lock();
+        regions
+            .values()
+            .find_map(|(guest_addr, mem, _)| {
+                translate(*guest_addr, mem.size() as u64, mem.as_ptr())
+            })
+            .unwrap_or(Err(Error::new(EFAULT)))
+    }
+
+    /// Finds the memory region that fully contains `[guest_address, guest_address + size)`.
+    /// Returns the slot and the offset of `guest_address` into it, or EINVAL if no region
+    /// contains the range.
+    fn find_region_range(
+        &self,
+        guest_address: GuestAddress,
+        size: u64,
+    ) -> Result<(MemSlot, usize)> {
+        let end = guest_address.checked_add(size).ok_or(Error::new(EINVAL))?;
+        let regions = self.mem_regions.lock();
+        regions
+            .iter()
+            .find(|(_, (addr, mem, _))| {
+                *addr <= guest_address
+                    && addr
+                        .checked_add(mem.size() as u64)
+                        .map_or(false, |region_end| end <= region_end)
+            })
+            .map(|(slot, (addr, _, _))| (*slot, (guest_address.offset() - addr.offset()) as usize))
+            .ok_or(Error::new(EINVAL))
+    }
+
+    /// Releases the host memory backing a range the guest gave up to the balloon.
+    fn handle_inflate(&mut self, guest_address: GuestAddress, size: u64) -> Result<()> {
+        if self.guest_mem.is_valid_range(guest_address, size) {
+            return release_guest_mem_range(&self.guest_mem, guest_address, size);
         }
+        let (slot, offset) = self.find_region_range(guest_address, size)?;
+        self.remove_mapping(slot, offset, size as usize)
+    }
 
-        (cap & capability_info.winfo as u32) != 0
+    /// Backs a range the balloon returned to the guest with fresh, zeroed host memory.
+    fn handle_deflate(&mut self, guest_address: GuestAddress, size: u64) -> Result<()> {
+        if self.guest_mem.is_valid_range(guest_address, size) {
+            return reclaim_guest_mem_range(&self.guest_mem, guest_address, size);
+        }
+        let (slot, offset) = self.find_region_range(guest_address, size)?;
+        let shm = SharedMemory::new("haxm_balloon_deflate", size)?;
+        // The new mapping keeps the shared memory alive after `shm` is dropped.
+        self.add_fd_mapping(
+            slot,
+            offset,
+            size as usize,
+            &shm,
+            0,
+            Protection::read_write(),
+        )
     }
 
-    pub fn register_log_file(&self, path: &str) -> Result<()> {
+    /// Asks the driver to write this VM's log to `path`. Every `{vm_id}` in `path` is replaced with
+    /// `vm_id()`, so VMs sharing a log path template get one file each.
+    ///
+    /// Returns whether the log is written, and if not, why. Returns E2BIG if the path is too long
+    /// for the driver.
+    pub fn register_log_file(&self, path: &str) -> Result<LogFileStatus> {
         // The IOCTL here is only avilable on internal fork of HAXM and only works on Windows.
         #[cfg(windows)]
         if get_use_ghaxm() {
+            if !self.check_raw_capability(HAX_CAP_VM_LOG) {
+                return Ok(LogFileStatus::CapabilityMissing);
+            }
+
+            let path = path.replace("{vm_id}", &self.vm_id.to_string());
             let mut log_file = hax_log_file::default();
 
             // Although it would be more efficient to do this check prior to allocating the log_file
@@ -139,7 +683,7 @@ impl HaxmVm {
                 return Err(Error::new(E2BIG));
             }
 
-            let wstring = &win32_wide_string(path);
+            let wstring = &win32_wide_string(&path);
             log_file.path[..wstring.len()].clone_from_slice(wstring);
 
             // SAFETY:
@@ -149,17 +693,73 @@ impl HaxmVm {
             if ret != 0 {
                 return errno_result();
             }
+            return Ok(LogFileStatus::Enabled(path));
         }
-        Ok(())
+        Ok(LogFileStatus::UnsupportedPlatform)
     }
 }
 
+/// Whether `HaxmVm::register_log_file` turned on the driver's log file for a VM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogFileStatus {
+    /// The driver writes the VM's log to this path, with `{vm_id}` already replaced.
+    Enabled(String),
+    /// Log files are only supported by the internal fork of HAXM on Windows.
+    UnsupportedPlatform,
+    /// The driver does not report `HAX_CAP_VM_LOG`.
+    CapabilityMissing,
+}
+
 impl AsRawDescriptor for HaxmVm {
     fn as_raw_descriptor(&self) -> RawDescriptor {
         self.descriptor.as_raw_descriptor()
     }
 }
 
+impl std::fmt::Debug for HaxmVm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HaxmVm")
+            .field("vm_id", &self.vm_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// HAXM has no in-kernel paravirtual clock, so the guest's view of the clock is emulated in
+/// software as an offset from the host monotonic clock. Restoring a previously read `ClockState`
+/// with `set_pvclock` (e.g. across a suspend/resume) recomputes the offset so the guest clock
+/// continues from the saved value instead of jumping by the time spent suspended.
+struct SoftPvClock {
+    epoch: Instant,
+    offset_ns: u64,
+    flags: u32,
+}
+
+impl SoftPvClock {
+    fn new() -> Self {
+        SoftPvClock {
+            epoch: Instant::now(),
+            offset_ns: 0,
+            flags: 0,
+        }
+    }
+
+    fn host_ns(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    fn get(&self) -> ClockState {
+        ClockState {
+            clock: self.host_ns().wrapping_add(self.offset_ns),
+            flags: self.flags,
+        }
+    }
+
+    fn set(&mut self, state: &ClockState) {
+        self.offset_ns = state.clock.wrapping_sub(self.host_ns());
+        self.flags = state.flags;
+    }
+}
+
 enum MemoryRegionOp {
     // Map a memory region for the given host address.
     Add(u64),
@@ -201,12 +801,187 @@ unsafe fn set_user_memory_region(
     Ok(())
 }
 
+/// Sets the guest's access permissions to the `size` bytes of RAM at `guest_addr` to `flags`, one of
+/// the `HAX_RAM_PERM_*` values.
+pub(super) fn set_ram_protection(
+    descriptor: &SafeDescriptor,
+    guest_addr: u64,
+    size: u64,
+    flags: u32,
+) -> Result<()> {
+    let protect = hax_protect_ram_info {
+        pa_start: guest_addr,
+        size,
+        flags,
+        ..Default::default()
+    };
+    // SAFETY:
+    // Safe because we know that our file is a VM fd and we verify the return result.
+    let ret = unsafe { ioctl_with_ref(descriptor, HAX_VM_IOCTL_PROTECT_RAM(), &protect) };
+    if ret != 0 {
+        return errno_result();
+    }
+    Ok(())
+}
+
+/// Label of the memory regions added by `add_guard_region`.
+pub const GUARD_REGION_LABEL: &str = "guard";
+
+/// Checks that a memory region starts and ends on a HAXM page boundary, so a misaligned region is
+/// reported with its address and size instead of an opaque error from the driver.
+fn check_page_aligned(guest_addr: GuestAddress, size: u64) -> Result<()> {
+    if guest_addr.offset() % HAXM_PAGE_SIZE != 0 {
+        error!(
+            "memory region guest address {:#x} is not aligned to {:#x}",
+            guest_addr.offset(),
+            HAXM_PAGE_SIZE
+        );
+        return Err(Error::new(EINVAL));
+    }
+    if size % HAXM_PAGE_SIZE != 0 {
+        error!(
+            "memory region size {:#x} at guest address {:#x} is not aligned to {:#x}",
+            size,
+            guest_addr.offset(),
+            HAXM_PAGE_SIZE
+        );
+        return Err(Error::new(EINVAL));
+    }
+    Ok(())
+}
+
+/// Checks an address passed to one of the 16-bit guest setup calls that HAXM ignores. An unaligned
+/// address is rejected so the misconfiguration surfaces early; otherwise a warning that the value
+/// is ignored is logged the first time through `warned`.
+fn check_ignored_addr(
+    name: &str,
+    addr: GuestAddress,
+    guest_mem: &GuestMemory,
+    warned: &Once,
+) -> Result<()> {
+    if addr.offset() % HAXM_PAGE_SIZE != 0 {
+        error!(
+            "{} address {:#x} is not aligned to {:#x}",
+            name,
+            addr.offset(),
+            HAXM_PAGE_SIZE
+        );
+        return Err(Error::new(EINVAL));
+    }
+    warned.call_once(|| {
+        if guest_mem.address_in_range(addr) {
+            warn!("HAXM ignores the {} address {:#x}", name, addr.offset());
+        } else {
+            warn!(
+                "HAXM ignores the {} address {:#x}, which is outside guest memory",
+                name,
+                addr.offset()
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Checks that mapping `size` more bytes on top of the `mapped` bytes already mapped stays within
+/// `quota`, naming the quota in the log when it does not.
+fn check_memory_quota(quota: u64, mapped: u64, size: u64) -> Result<()> {
+    if mapped.saturating_add(size) > quota {
+        error!(
+            "mapping {:#x} bytes on top of {:#x} exceeds the HAXM memory quota of {:#x} bytes",
+            size, mapped, quota
+        );
+        return Err(Error::new(E2BIG));
+    }
+    Ok(())
+}
+
+/// Checks the tunnel returned by `HAX_VCPU_IOCTL_SETUP_TUNNEL`, so that a driver returning a short
+/// or unmapped tunnel fails vcpu creation instead of letting later accesses overrun it.
+fn check_tunnel_info(tunnel_info: &hax_tunnel_info) -> Result<()> {
+    // hax_tunnel_info is packed, so copy the fields out before formatting them.
+    let (va, io_va, size) = (tunnel_info.va, tunnel_info.io_va, tunnel_info.size);
+    if (size as usize) < std::mem::size_of::<hax_tunnel>() {
+        error!(
+            "haxm tunnel size {:#x} is smaller than hax_tunnel ({:#x})",
+            size,
+            std::mem::size_of::<hax_tunnel>()
+        );
+        return Err(Error::new(EIO));
+    }
+    if va == 0 || io_va == 0 {
+        error!(
+            "haxm tunnel is not mapped: va {:#x}, io_va {:#x}",
+            va, io_va
+        );
+        return Err(Error::new(EIO));
+    }
+    Ok(())
+}
+
+/// Converts an I/O error into an errno, using EIO for errors that don't come from the OS.
+fn io_to_errno(e: std::io::Error) -> Error {
+    Error::new(e.raw_os_error().unwrap_or(EIO))
+}
+
+/// Returns whether a guest write of `data` should signal an ioevent registered with `datamatch`.
+fn datamatch_matches(datamatch: &Datamatch, data: &[u8]) -> bool {
+    match *datamatch {
+        Datamatch::AnyLength => true,
+        Datamatch::U8(v) => match data.try_into() {
+            Ok(bytes) => v.map_or(true, |v| u8::from_le_bytes(bytes) == v),
+            Err(_) => false,
+        },
+        Datamatch::U16(v) => match data.try_into() {
+            Ok(bytes) => v.map_or(true, |v| u16::from_le_bytes(bytes) == v),
+            Err(_) => false,
+        },
+        Datamatch::U32(v) => match data.try_into() {
+            Ok(bytes) => v.map_or(true, |v| u32::from_le_bytes(bytes) == v),
+            Err(_) => false,
+        },
+        Datamatch::U64(v) => match data.try_into() {
+            Ok(bytes) => v.map_or(true, |v| u64::from_le_bytes(bytes) == v),
+            Err(_) => false,
+        },
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // The number of ioevent clones allowed on this thread before `clone_ioevent` fails, or `None`
+    // for no limit. Lets tests fail `HaxmVm::try_clone` partway through.
+    static IOEVENT_CLONE_BUDGET: std::cell::Cell<Option<usize>> = std::cell::Cell::new(None);
+}
+
+fn clone_ioevent(evt: &Event) -> Result<Event> {
+    #[cfg(test)]
+    IOEVENT_CLONE_BUDGET.with(|budget| match budget.get() {
+        Some(0) => Err(Error::new(EMFILE)),
+        Some(remaining) => {
+            budget.set(Some(remaining - 1));
+            Ok(())
+        }
+        None => Ok(()),
+    })?;
+    evt.try_clone()
+}
+
 impl Vm for HaxmVm {
     fn try_clone(&self) -> Result<Self> {
-        let mut ioevents = FnvHashMap::default();
-        for (addr, evt) in self.ioevents.iter() {
-            ioevents.insert(*addr, evt.try_clone()?);
-        }
+        // HAXM ioevents are only dispatched by `handle_io_events`, so the cloned events are not
+        // registered with the driver and any already cloned are simply closed if a later clone
+        // fails. The ioevents are cloned before anything else so a failure leaves no partial VM.
+        let ioevents = self
+            .ioevents
+            .iter()
+            .map(|(addr, evts)| {
+                let cloned = evts
+                    .iter()
+                    .map(|(datamatch, evt)| Ok((*datamatch, clone_ioevent(evt)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((*addr, cloned))
+            })
+            .collect::<Result<FnvHashMap<_, _>>>()?;
         Ok(HaxmVm {
             vm_id: self.vm_id,
             haxm: self.haxm.try_clone()?,
@@ -214,6 +989,11 @@ impl Vm for HaxmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            read_only_slots: self.read_only_slots.clone(),
+            pvclock: self.pvclock.clone(),
+            guest_phys_addr_bits: self.guest_phys_addr_bits.clone(),
+            exit_logger: self.exit_logger.clone(),
+            fault_handler: self.fault_handler.clone(),
             ioevents,
         })
     }
@@ -221,12 +1001,13 @@ impl Vm for HaxmVm {
     fn check_capability(&self, c: VmCap) -> bool {
         match c {
             VmCap::DirtyLog => false,
-            VmCap::PvClock => false,
+            VmCap::PvClock => true,
             VmCap::Protected => false,
             VmCap::EarlyInitCpuid => false,
             VmCap::BusLockDetect => false,
             VmCap::ReadOnlyMemoryRegion => false,
             VmCap::MemNoncoherentDma => false,
+            VmCap::IoEvent => true,
         }
     }
 
@@ -239,47 +1020,15 @@ impl Vm for HaxmVm {
         guest_addr: GuestAddress,
         mem: Box<dyn MappedRegion>,
         read_only: bool,
-        _log_dirty_pages: bool,
+        log_dirty_pages: bool,
         _cache: MemCacheType,
     ) -> Result<MemSlot> {
-        let size = mem.size() as u64;
-        let end_addr = guest_addr.checked_add(size).ok_or(Error::new(EOVERFLOW))?;
-        if self.guest_mem.range_overlap(guest_addr, end_addr) {
-            return Err(Error::new(ENOSPC));
-        }
-        let mut regions = self.mem_regions.lock();
-        let mut gaps = self.mem_slot_gaps.lock();
-        let slot = match gaps.pop() {
-            Some(gap) => gap.0,
-            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
-        };
+        self.add_memory_region_labeled(guest_addr, mem, read_only, log_dirty_pages, None)
+    }
 
-        // SAFETY:
-        // Safe because we check that the given guest address is valid and has no overlaps. We also
-        // know that the pointer and size are correct because the MemoryMapping interface ensures
-        // this. We take ownership of the memory mapping so that it won't be unmapped until the slot
-        // is removed.
-        let res = unsafe {
-            set_user_memory_region(
-                &self.descriptor,
-                read_only,
-                guest_addr.offset(),
-                size,
-                MemoryRegionOp::Add(mem.as_ptr() as u64),
-            )
-        };
-
-        if let Err(e) = res {
-            gaps.push(Reverse(slot));
-            return Err(e);
-        }
-        regions.insert(slot, (guest_addr, mem));
-        Ok(slot)
-    }
-
-    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
-        let mut regions = self.mem_regions.lock();
-        let (_, mem) = regions.get_mut(&slot).ok_or(Error::new(ENOENT))?;
+    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let (_, mem, _) = regions.get_mut(&slot).ok_or(Error::new(ENOENT))?;
 
         mem.msync(offset, size).map_err(|err| match err {
             MmapError::InvalidAddress => Error::new(EFAULT),
@@ -292,7 +1041,7 @@ impl Vm for HaxmVm {
     fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
         let mut regions = self.mem_regions.lock();
 
-        if let Some((guest_addr, mem)) = regions.get(&slot) {
+        if let Some((guest_addr, mem, _)) = regions.get(&slot) {
             // SAFETY:
             // Safe because the slot is checked against the list of memory slots.
             unsafe {
@@ -304,6 +1053,7 @@ impl Vm for HaxmVm {
                     MemoryRegionOp::Remove,
                 )?;
             }
+            self.read_only_slots.lock().remove(&slot);
             self.mem_slot_gaps.lock().push(Reverse(slot));
             Ok(regions.remove(&slot).unwrap().1)
         } else {
@@ -311,12 +1061,259 @@ impl Vm for HaxmVm {
         }
     }
 
-    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
-        // Haxm does not support in-kernel devices
-        Err(Error::new(libc::ENXIO))
+    fn zero_memory_region(&mut self, slot: MemSlot) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let (_, mem, _) = regions.get(&slot).ok_or(Error::new(ENOENT))?;
+        // A read-only slot may be backed by a mapping the host can't write either.
+        if self.read_only_slots.lock().contains(&slot) {
+            return Err(Error::new(EPERM));
+        }
+        // SAFETY:
+        // Safe because the mapping is owned by `mem_regions`, which stays locked while it is
+        // written, and `as_ptr` and `size` describe exactly the mapped range. The guest may still
+        // access it, so it is only written through a volatile slice.
+        let contents = unsafe { VolatileSlice::from_raw_parts(mem.as_ptr(), mem.size()) };
+        contents.write_bytes(0);
+        Ok(())
+    }
+
+    /// Guards are backed by zeroed memory that is mapped with HAX_RAM_PERM_NONE through
+    /// HAX_VM_IOCTL_PROTECT_RAM, so that any access exits to the VMM with HAX_EXIT_PAGEFAULT,
+    /// which `run` reports as `VcpuExit::Exception`. Requires the `ram_protection` capability.
+    fn add_guard_region(&mut self, guest_addr: GuestAddress, size: u64) -> Result<MemSlot> {
+        if !self.capabilities().ram_protection {
+            warn!("HAXM does not support RAM protection, can't add a guard region");
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into());
+        }
+        let shm = SharedMemory::new("haxm_guard", size)?;
+        let mem = MemoryMappingBuilder::new(size as usize)
+            .from_shared_memory(&shm)
+            .build()
+            .map_err(|e| match e {
+                MmapError::SystemCallFailed(e) => e,
+                _ => Error::new(EINVAL),
+            })?;
+        let slot = self.add_memory_region_labeled(
+            guest_addr,
+            Box::new(mem),
+            false,
+            false,
+            Some(GUARD_REGION_LABEL.to_string()),
+        )?;
+
+        if let Err(e) = set_ram_protection(
+            &self.descriptor,
+            guest_addr.offset(),
+            size,
+            HAX_RAM_PERM_NONE,
+        ) {
+            if let Err(e) = self.remove_memory_region(slot) {
+                error!("failed to remove unprotected guard region: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(slot)
+    }
+
+    /// Faults are delivered when a vcpu touches a guard region added with `add_guard_region`.
+    /// `FaultResolution::MapPage` makes the faulting page of the guard accessible, backed by the
+    /// guard's zeroed memory, and resumes the guest; `FaultResolution::Unhandled` leaves `run` to
+    /// report `VcpuExit::Exception` as before. The handler runs with the VM's handler lock held,
+    /// so it must not call `set_fault_handler` itself.
+    fn set_fault_handler(&mut self, handler: FaultHandler) {
+        *self.fault_handler.lock() = Some(handler);
+    }
+
+    /// Each region, guest memory included, is re-added in place with SET_RAM2 and the ROM flag
+    /// toggled, skipping regions that are already in the requested state. HAXM doesn't yet report
+    /// a guest write to a ROM region as a fault the VMM can resolve, so until it does this only
+    /// write-protects the guest and can't be used to copy pages on write.
+    fn set_all_regions_readonly(&mut self, read_only: bool) -> Result<()> {
+        let regions = self.mem_regions.lock();
+        let mut read_only_slots = self.read_only_slots.lock();
+        // Guest memory takes the first slots, in index order, followed by the added regions.
+        let all_regions: Vec<(MemSlot, u64, u64, u64)> = self
+            .guest_mem
+            .regions()
+            .map(|region| {
+                (
+                    region.index as MemSlot,
+                    region.guest_addr.offset(),
+                    region.size as u64,
+                    region.host_addr as u64,
+                )
+            })
+            .chain(regions.iter().map(|(slot, (guest_addr, mem, _))| {
+                (
+                    *slot,
+                    guest_addr.offset(),
+                    mem.size() as u64,
+                    mem.as_ptr() as u64,
+                )
+            }))
+            .filter(|(slot, ..)| read_only_slots.contains(slot) != read_only)
+            .collect();
+
+        let set_region = |(slot, guest_addr, size, va): (MemSlot, u64, u64, u64), read_only| {
+            // SAFETY:
+            // Safe because the region is re-added with the same guest address, size and host
+            // mapping it already has, and that mapping is still owned by `guest_mem` or
+            // `mem_regions`.
+            unsafe {
+                set_user_memory_region(
+                    &self.descriptor,
+                    read_only,
+                    guest_addr,
+                    size,
+                    MemoryRegionOp::Add(va),
+                )
+            }
+        };
+
+        for (changed, region) in all_regions.iter().enumerate() {
+            if let Err(e) = set_region(*region, read_only) {
+                // Restore the regions already changed, and record any that stay changed.
+                for region in &all_regions[..changed] {
+                    let slot = region.0;
+                    if let Err(e) = set_region(*region, !read_only) {
+                        error!("failed to restore memory slot {}: {}", slot, e);
+                        if read_only {
+                            read_only_slots.insert(slot);
+                        } else {
+                            read_only_slots.remove(&slot);
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        }
+        for (slot, ..) in all_regions {
+            if read_only {
+                read_only_slots.insert(slot);
+            } else {
+                read_only_slots.remove(&slot);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_memory_regions(&mut self) -> Result<Vec<Box<dyn MappedRegion>>> {
+        let mut regions = self.mem_regions.lock();
+        let mut read_only_slots = self.read_only_slots.lock();
+        let mut gaps = self.mem_slot_gaps.lock();
+        let mut removed = Vec::with_capacity(regions.len());
+        let mut result = Ok(());
+
+        // Attempt to remove every slot even if one fails. A slot HAXM fails to remove stays in
+        // `mem_regions` so its mapping is not unmapped while HAXM may still use it.
+        for (slot, (guest_addr, mem, _)) in regions.iter() {
+            // SAFETY:
+            // Safe because the slot is taken from the list of memory slots.
+            let res = unsafe {
+                set_user_memory_region(
+                    &self.descriptor,
+                    false,
+                    guest_addr.offset(),
+                    mem.size() as u64,
+                    MemoryRegionOp::Remove,
+                )
+            };
+            match res {
+                Ok(()) => removed.push(*slot),
+                Err(e) => {
+                    error!("failed to remove memory slot {}: {}", slot, e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut mappings = Vec::with_capacity(removed.len());
+        for slot in removed {
+            read_only_slots.remove(&slot);
+            gaps.push(Reverse(slot));
+            mappings.push(regions.remove(&slot).unwrap().1);
+        }
+        if regions.is_empty() {
+            // Every slot is free, so they are handed out from the start again.
+            gaps.clear();
+        }
+        result.map(|_| mappings)
+    }
+
+    fn for_each_memory_region(
+        &self,
+        f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        // Hold the lock for the whole walk so the regions can't change under `f`.
+        let regions = self.mem_regions.lock();
+        for (slot, (guest_addr, mem, _)) in regions.iter() {
+            f(*slot, *guest_addr, mem.as_ref());
+        }
+        Ok(())
+    }
+
+    fn total_mapped_bytes(&self) -> Result<u64> {
+        Ok(self.mapped_bytes(&self.mem_regions.lock()))
+    }
+
+    fn snapshot_memory(&self, slot: MemSlot, out: &mut dyn std::io::Write) -> Result<usize> {
+        let regions = self.mem_regions.lock();
+        let (_, mem, _) = regions.get(&slot).ok_or(Error::new(ENOENT))?;
+        // SAFETY:
+        // Safe because the mapping is owned by `mem_regions`, which stays locked while the slice
+        // is alive, and `as_ptr` and `size` describe the whole mapping. The guest may write to it
+        // concurrently, so it is only accessed through volatile copies.
+        let contents = unsafe { VolatileSlice::from_raw_parts(mem.as_ptr(), mem.size()) };
+        let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE.min(contents.size())];
+        let mut offset = 0;
+        while offset < contents.size() {
+            let len = buf.len().min(contents.size() - offset);
+            contents
+                .sub_slice(offset, len)
+                .map_err(|_| Error::new(EFAULT))?
+                .copy_to(&mut buf[..len]);
+            out.write_all(&buf[..len]).map_err(io_to_errno)?;
+            offset += len;
+        }
+        Ok(offset)
     }
 
-    fn get_dirty_log(&self, _slot: u32, _dirty_log: &mut [u8]) -> Result<()> {
+    fn restore_memory(&mut self, slot: MemSlot, input: &mut dyn std::io::Read) -> Result<usize> {
+        let regions = self.mem_regions.lock();
+        let (_, mem, _) = regions.get(&slot).ok_or(Error::new(ENOENT))?;
+        // SAFETY:
+        // Safe because the mapping is owned by `mem_regions`, which stays locked while the slice
+        // is alive, and `as_ptr` and `size` describe the whole mapping. The guest may access it
+        // concurrently, so it is only accessed through volatile copies.
+        let contents = unsafe { VolatileSlice::from_raw_parts(mem.as_ptr(), mem.size()) };
+        let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE.min(contents.size())];
+        let mut offset = 0;
+        while offset < contents.size() {
+            let len = buf.len().min(contents.size() - offset);
+            input.read_exact(&mut buf[..len]).map_err(io_to_errno)?;
+            contents
+                .sub_slice(offset, len)
+                .map_err(|_| Error::new(EFAULT))?
+                .copy_from(&buf[..len]);
+            offset += len;
+        }
+        Ok(offset)
+    }
+
+    /// HAXM has no in-kernel devices. For `DeviceKind::Irqchip` this returns an `Event` standing
+    /// in for the interrupt controller, which HAXM VMs always emulate in userspace, so device setup
+    /// can treat HAXM like other hypervisors. The descriptor is not backed by the driver and does
+    /// nothing by itself. Every other kind fails with ENXIO.
+    fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor> {
+        match kind {
+            DeviceKind::Irqchip => Ok(Event::new()?.into()),
+            _ => Err(Error::new(libc::ENXIO)),
+        }
+    }
+
+    fn get_dirty_log(&self, _slot: MemSlot, _dirty_log: &mut [u8]) -> Result<()> {
         // Haxm does not support VmCap::DirtyLog
         Err(Error::new(libc::ENXIO))
     }
@@ -327,17 +1324,13 @@ impl Vm for HaxmVm {
         addr: IoEventAddress,
         datamatch: Datamatch,
     ) -> Result<()> {
-        if datamatch != Datamatch::AnyLength {
-            error!("HAXM currently only supports Datamatch::AnyLength");
-            return Err(Error::new(ENOTSUP));
-        }
-
-        if self.ioevents.contains_key(&addr) {
-            error!("HAXM does not support multiple ioevents for the same address");
+        let evts = self.ioevents.entry(addr).or_default();
+        if evts.iter().any(|(existing, _)| *existing == datamatch) {
+            error!("HAXM does not support multiple ioevents for the same address and datamatch");
             return Err(Error::new(EEXIST));
         }
 
-        self.ioevents.insert(addr, evt.try_clone()?);
+        evts.push((datamatch, evt.try_clone()?));
 
         Ok(())
     }
@@ -348,44 +1341,50 @@ impl Vm for HaxmVm {
         addr: IoEventAddress,
         datamatch: Datamatch,
     ) -> Result<()> {
-        if datamatch != Datamatch::AnyLength {
-            error!("HAXM only supports Datamatch::AnyLength");
-            return Err(Error::new(ENOTSUP));
+        let evts = self.ioevents.get_mut(&addr).ok_or(Error::new(ENOENT))?;
+        // evt should match the existing evt associated with addr and datamatch
+        let index = evts
+            .iter()
+            .position(|(existing_datamatch, existing_evt)| {
+                *existing_datamatch == datamatch && existing_evt == evt
+            })
+            .ok_or(Error::new(ENOENT))?;
+        evts.remove(index);
+        if evts.is_empty() {
+            self.ioevents.remove(&addr);
         }
+        Ok(())
+    }
 
-        match self.ioevents.get(&addr) {
-            Some(existing_evt) => {
-                // evt should match the existing evt associated with addr
-                if evt != existing_evt {
-                    return Err(Error::new(ENOENT));
-                }
-                self.ioevents.remove(&addr);
-            }
-
-            None => {
-                return Err(Error::new(ENOENT));
-            }
-        };
+    fn relocate_ioevent(&mut self, old: IoEventAddress, new: IoEventAddress) -> Result<()> {
+        if self.ioevents.contains_key(&new) {
+            return Err(Error::new(EEXIST));
+        }
+        let evts = self.ioevents.remove(&old).ok_or(Error::new(ENOENT))?;
+        self.ioevents.insert(new, evts);
         Ok(())
     }
 
-    /// Trigger any io events based on the memory mapped IO at `addr`.  If the hypervisor does
-    /// in-kernel IO event delivery, this is a no-op.
-    fn handle_io_events(&self, addr: IoEventAddress, _data: &[u8]) -> Result<()> {
-        if let Some(evt) = self.ioevents.get(&addr) {
-            evt.signal()?;
+    /// Trigger any io events based on the memory mapped IO at `addr`. HAXM has no in-kernel IO
+    /// event delivery, even with fast MMIO enabled, so every ioevent is signalled from here.
+    fn handle_io_events(&self, addr: IoEventAddress, data: &[u8]) -> Result<()> {
+        if let Some(evts) = self.ioevents.get(&addr) {
+            for (datamatch, evt) in evts.iter() {
+                if datamatch_matches(datamatch, data) {
+                    evt.signal()?;
+                }
+            }
         }
         Ok(())
     }
 
     fn get_pvclock(&self) -> Result<ClockState> {
-        // Haxm does not support VmCap::PvClock
-        Err(Error::new(libc::ENXIO))
+        Ok(self.pvclock.lock().get())
     }
 
-    fn set_pvclock(&self, _state: &ClockState) -> Result<()> {
-        // Haxm does not support VmCap::PvClock
-        Err(Error::new(libc::ENXIO))
+    fn set_pvclock(&self, state: &ClockState) -> Result<()> {
+        self.pvclock.lock().set(state);
+        Ok(())
     }
 
     fn add_fd_mapping(
@@ -398,7 +1397,7 @@ impl Vm for HaxmVm {
         prot: Protection,
     ) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let (_, region) = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
+        let (_, region, _) = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
 
         match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
             Ok(()) => Ok(()),
@@ -409,7 +1408,7 @@ impl Vm for HaxmVm {
 
     fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let (_, region) = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
+        let (_, region, _) = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
 
         match region.remove_mapping(offset, size) {
             Ok(()) => Ok(()),
@@ -418,16 +1417,21 @@ impl Vm for HaxmVm {
         }
     }
 
-    fn handle_balloon_event(&mut self, _event: crate::BalloonEvent) -> Result<()> {
-        // TODO(b/233773610): implement ballooning support in haxm
-        warn!("Memory ballooning attempted but not supported on haxm hypervisor");
-        // no-op
-        Ok(())
+    fn handle_balloon_event(&mut self, event: BalloonEvent) -> Result<()> {
+        match event {
+            BalloonEvent::Inflate(m) => self.handle_inflate(m.guest_address, m.size),
+            BalloonEvent::Deflate(m) => self.handle_deflate(m.guest_address, m.size),
+            BalloonEvent::BalloonTargetReached(_) => Ok(()),
+        }
     }
 
     fn get_guest_phys_addr_bits(&self) -> u8 {
-        // Assume the guest physical address size is the same as the host.
-        host_phys_addr_bits()
+        // Use the width from the cpuid given to the vcpus, and otherwise assume the guest physical
+        // address size is the same as the host.
+        match self.guest_phys_addr_bits.load(Ordering::Relaxed) {
+            0 => host_phys_addr_bits(),
+            bits => bits,
+        }
     }
 }
 
@@ -437,6 +1441,10 @@ impl VmX86_64 for HaxmVm {
     }
 
     fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>> {
+        self.create_vcpu_with_config(id, VcpuConfig::default())
+    }
+
+    fn create_vcpu_with_config(&self, id: usize, cfg: VcpuConfig) -> Result<Box<dyn VcpuX86_64>> {
         // SAFETY:
         // Safe because we know that our file is a VM fd and we verify the return result.
         let fd = unsafe { ioctl_with_ref(self, HAX_VM_IOCTL_VCPU_CREATE(), &(id as u32)) };
@@ -446,6 +1454,8 @@ impl VmX86_64 for HaxmVm {
 
         let descriptor =
             open_haxm_vcpu_device(USE_GHAXM.load(Ordering::Relaxed), self.vm_id, id as u32)?;
+        // HAXM vcpus are created on the thread that runs them.
+        cfg.apply()?;
 
         let mut tunnel_info = hax_tunnel_info::default();
 
@@ -458,25 +1468,38 @@ impl VmX86_64 for HaxmVm {
         if ret != 0 {
             return errno_result();
         }
+        check_tunnel_info(&tunnel_info)?;
+        // SAFETY:
+        // Safe because the driver just set up tunnel_info for this vcpu and it passed the check
+        // above. The tunnel is only reachable through the vcpu, which keeps the driver's mapping
+        // alive.
+        let tunnel = unsafe { VcpuTunnel::new(&tunnel_info) };
 
         Ok(Box::new(HaxmVcpu {
             descriptor,
             id,
-            tunnel: tunnel_info.va as *mut hax_tunnel,
-            io_buffer: tunnel_info.io_va as *mut c_void,
+            tunnel,
+            guest_phys_addr_bits: self.guest_phys_addr_bits.clone(),
+            vm_descriptor: self.descriptor.try_clone()?,
+            fault_handler: self.fault_handler.clone(),
+            exit_logger: self.exit_logger.clone(),
         }))
     }
 
     /// Sets the address of the three-page region in the VM's address space.
-    /// This function is only necessary for 16 bit guests, which we do not support for HAXM.
-    fn set_tss_addr(&self, _addr: GuestAddress) -> Result<()> {
-        Ok(())
+    /// This function is only necessary for 16 bit guests, which we do not support for HAXM, so the
+    /// address is only validated and then ignored.
+    fn set_tss_addr(&self, addr: GuestAddress) -> Result<()> {
+        static WARNED: Once = Once::new();
+        check_ignored_addr("TSS", addr, &self.guest_mem, &WARNED)
     }
 
     /// Sets the address of a one-page region in the VM's address space.
-    /// This function is only necessary for 16 bit guests, which we do not support for HAXM.
-    fn set_identity_map_addr(&self, _addr: GuestAddress) -> Result<()> {
-        Ok(())
+    /// This function is only necessary for 16 bit guests, which we do not support for HAXM, so the
+    /// address is only validated and then ignored.
+    fn set_identity_map_addr(&self, addr: GuestAddress) -> Result<()> {
+        static WARNED: Once = Once::new();
+        check_ignored_addr("identity map", addr, &self.guest_mem, &WARNED)
     }
 }
 
@@ -488,9 +1511,13 @@ mod tests {
 
     use base::EventWaitResult;
     use base::MemoryMappingBuilder;
-    use base::SharedMemory;
+    use base::VolatileMemory;
 
     use super::*;
+    use crate::MemRegion;
+    use crate::Regs;
+    use crate::Vcpu;
+    use crate::VcpuExit;
 
     #[test]
     fn create_vm() {
@@ -500,6 +1527,57 @@ mod tests {
         HaxmVm::new(&haxm, mem).expect("failed to create vm");
     }
 
+    #[test]
+    fn capabilities() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest memory");
+        let vm = HaxmVm::new(&haxm, mem).expect("failed to create vm");
+        let caps = vm.capabilities();
+        assert!(caps.working);
+        assert!(caps.ept);
+        assert_eq!(caps.vm_log, vm.check_raw_capability(HAX_CAP_VM_LOG));
+    }
+
+    #[test]
+    fn capabilities_from_info() {
+        let info = hax_capabilityinfo {
+            wstatus: (HAX_CAP_STATUS_WORKING | HAX_CAP_MEMQUOTA) as u16,
+            winfo: (HAX_CAP_EPT | HAX_CAP_FASTMMIO) as u16,
+            win_refcount: 0,
+            mem_quota: 0x1000,
+        };
+        let caps = HaxmCapabilities::from(&info);
+        assert!(caps.working);
+        assert!(caps.ept);
+        assert!(caps.fast_mmio);
+        assert!(!caps.ug);
+        assert_eq!(caps.mem_quota, Some(0x1000));
+    }
+
+    #[test]
+    fn create_device() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        vm.create_device(DeviceKind::Irqchip)
+            .expect("failed to create irqchip");
+        let err = vm
+            .create_device(DeviceKind::Vfio)
+            .expect_err("created an in-kernel vfio device");
+        assert_eq!(err.errno(), libc::ENXIO);
+    }
+
+    #[test]
+    fn enable_fast_mmio() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest memory");
+        let vm = HaxmVm::new(&haxm, mem).expect("failed to create vm");
+        let enabled = vm.enable_fast_mmio().expect("failed to enable fast mmio");
+        assert_eq!(enabled, vm.capabilities().fast_mmio);
+    }
+
     #[test]
     fn create_vcpu() {
         let haxm = Haxm::new().expect("failed to instantiate HAXM");
@@ -509,6 +1587,57 @@ mod tests {
         vm.create_vcpu(0).expect("failed to create vcpu");
     }
 
+    #[test]
+    fn create_vcpu_with_config() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest memory");
+        let vm = HaxmVm::new(&haxm, mem).expect("failed to create vm");
+        let cfg = VcpuConfig {
+            affinity_mask: Some(1),
+            priority: Some(winapi::um::winbase::THREAD_PRIORITY_ABOVE_NORMAL as i32),
+        };
+        vm.create_vcpu_with_config(0, cfg)
+            .expect("failed to create vcpu");
+    }
+
+    #[test]
+    fn exit_logger() {
+        let haxm = Haxm::new().expect("failed to instantiate HAXM");
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest memory");
+        // hlt
+        mem.write_at_addr(&[0xf4], GuestAddress(0))
+            .expect("failed to write code");
+        let mut vm = HaxmVm::new(&haxm, mem).expect("failed to create vm");
+        let exits = Arc::new(Mutex::new(Vec::new()));
+        let logged_exits = exits.clone();
+        vm.set_exit_logger(Box::new(move |exit| logged_exits.lock().push(exit)));
+
+        let mut vcpu = vm.create_vcpu(0).expect("failed to create vcpu");
+        let mut sregs = vcpu.get_sregs().expect("failed to get sregs");
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).expect("failed to set sregs");
+        vcpu.set_regs(&Regs {
+            rip: 0,
+            rflags: 2,
+            ..Default::default()
+        })
+        .expect("failed to set regs");
+        loop {
+            match vcpu.run().expect("run failed") {
+                VcpuExit::Intr => continue,
+                VcpuExit::Hlt => break,
+                r => panic!("unexpected exit reason: {:?}", r),
+            }
+        }
+
+        let exits = exits.lock();
+        // Any interrupt exits come first, then HAX_EXIT_HLT.
+        assert_eq!(exits.last(), Some(&6));
+    }
+
     #[test]
     fn register_ioevent() {
         let haxm = Haxm::new().expect("failed to create haxm");
@@ -526,25 +1655,44 @@ mod tests {
             IoEventAddress::Mmio(0x1000),
             Datamatch::AnyLength,
         )
-        .expect_err("HAXM should not allow you to register two events for the same address");
+        .expect_err("HAXM should not allow you to register two events with the same datamatch");
 
+        vm.register_ioevent(&otherevt, IoEventAddress::Mmio(0x1000), Datamatch::U8(None))
+            .unwrap();
         vm.register_ioevent(
             &otherevt,
             IoEventAddress::Mmio(0x1000),
-            Datamatch::U8(None),
+            Datamatch::U32(Some(0xf6)),
         )
-        .expect_err(
-            "HAXM should not allow you to register ioevents with Datamatches other than AnyLength",
-        );
+        .unwrap();
+        let registered = vm.registered_ioevents();
+        assert_eq!(registered.len(), 4);
+        for ioevent in [
+            (IoEventAddress::Pio(0xf4), Datamatch::AnyLength),
+            (IoEventAddress::Mmio(0x1000), Datamatch::AnyLength),
+            (IoEventAddress::Mmio(0x1000), Datamatch::U8(None)),
+            (IoEventAddress::Mmio(0x1000), Datamatch::U32(Some(0xf6))),
+        ] {
+            assert!(registered.contains(&ioevent));
+        }
 
-        vm.register_ioevent(
+        vm.unregister_ioevent(
+            &otherevt,
+            IoEventAddress::Mmio(0x1000),
+            Datamatch::U32(Some(0xf7)),
+        )
+        .expect_err("unregistering with a different datamatch should fail");
+        vm.unregister_ioevent(&otherevt, IoEventAddress::Mmio(0x1000), Datamatch::U8(None))
+            .unwrap();
+        vm.unregister_ioevent(
             &otherevt,
             IoEventAddress::Mmio(0x1000),
             Datamatch::U32(Some(0xf6)),
         )
-        .expect_err(
-            "HAXM should not allow you to register ioevents with Datamatches other than AnyLength",
-        );
+        .unwrap();
+        let registered = vm.registered_ioevents();
+        assert_eq!(registered.len(), 2);
+        assert!(!registered.contains(&(IoEventAddress::Mmio(0x1000), Datamatch::U8(None))));
 
         vm.unregister_ioevent(&otherevt, IoEventAddress::Pio(0xf4), Datamatch::AnyLength)
             .expect_err("unregistering an unknown event should fail");
@@ -558,6 +1706,54 @@ mod tests {
             .unwrap();
         vm.unregister_ioevent(&evt, IoEventAddress::Mmio(0x1000), Datamatch::AnyLength)
             .unwrap();
+        assert!(vm.registered_ioevents().is_empty());
+    }
+
+    #[test]
+    fn relocate_ioevent() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        let evt = Event::new().expect("failed to create event");
+        vm.register_ioevent(
+            &evt,
+            IoEventAddress::Mmio(0x1000),
+            Datamatch::U32(Some(0xf6)),
+        )
+        .unwrap();
+        vm.register_ioevent(&evt, IoEventAddress::Mmio(0x2000), Datamatch::AnyLength)
+            .unwrap();
+
+        let err = vm
+            .relocate_ioevent(IoEventAddress::Mmio(0x3000), IoEventAddress::Mmio(0x4000))
+            .expect_err("relocating an unknown address should fail");
+        assert_eq!(err.errno(), ENOENT);
+        let err = vm
+            .relocate_ioevent(IoEventAddress::Mmio(0x1000), IoEventAddress::Mmio(0x2000))
+            .expect_err("relocating onto a registered address should fail");
+        assert_eq!(err.errno(), EEXIST);
+
+        vm.relocate_ioevent(IoEventAddress::Mmio(0x1000), IoEventAddress::Mmio(0x3000))
+            .unwrap();
+        let registered = vm.registered_ioevents();
+        assert_eq!(registered.len(), 2);
+        assert!(registered.contains(&(IoEventAddress::Mmio(0x3000), Datamatch::U32(Some(0xf6)))));
+
+        // Writes to the old address no longer signal the event, writes to the new one do.
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0xf6u32.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_eq!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        vm.handle_io_events(IoEventAddress::Mmio(0x3000), &0xf6u32.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_ne!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
     }
 
     #[test]
@@ -615,55 +1811,1153 @@ mod tests {
     }
 
     #[test]
-    fn remove_memory() {
-        let haxm = Haxm::new().unwrap();
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
-        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
-        let mem_size = 0x1000;
-        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
-        let mem = MemoryMappingBuilder::new(mem_size)
-            .from_shared_memory(&shm)
-            .build()
-            .unwrap();
-        let mem_ptr = mem.as_ptr();
-        let slot = vm
-            .add_memory_region(
-                GuestAddress(0x1000),
-                Box::new(mem),
-                false,
-                false,
-                MemCacheType::CacheCoherent,
+    fn try_clone_ioevents_failure() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        let evts: Vec<Event> = (0..4)
+            .map(|_| Event::new().expect("failed to create event"))
+            .collect();
+        for (i, evt) in evts.iter().enumerate() {
+            vm.register_ioevent(
+                evt,
+                IoEventAddress::Pio(0x1000 + i as u64),
+                Datamatch::AnyLength,
             )
             .unwrap();
-        let removed_mem = vm.remove_memory_region(slot).unwrap();
-        assert_eq!(removed_mem.size(), mem_size);
-        assert_eq!(removed_mem.as_ptr(), mem_ptr);
-    }
-
-    #[cfg(windows)]
-    #[test]
-    fn register_log_file() {
-        let haxm = Haxm::new().unwrap();
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
-        let vm = HaxmVm::new(&haxm, gm).unwrap();
-
-        if !vm.check_raw_capability(HAX_CAP_VM_LOG) {
-            return;
         }
 
-        let dir = tempfile::TempDir::new().unwrap();
-        let mut file_path = dir.path().to_owned();
-        file_path.push("test");
+        // The third ioevent clone fails, dropping the two made before it.
+        IOEVENT_CLONE_BUDGET.with(|budget| budget.set(Some(2)));
+        assert_eq!(vm.try_clone().err(), Some(Error::new(EMFILE)));
+        assert_eq!(IOEVENT_CLONE_BUDGET.with(|budget| budget.get()), Some(0));
+        IOEVENT_CLONE_BUDGET.with(|budget| budget.set(None));
+
+        // The original VM is untouched and still signals every ioevent.
+        assert_eq!(vm.ioevents.len(), evts.len());
+        for (i, evt) in evts.iter().enumerate() {
+            vm.handle_io_events(IoEventAddress::Pio(0x1000 + i as u64), &[])
+                .expect("failed to handle_io_events");
+            assert_eq!(
+                evt.wait_timeout(Duration::from_millis(10))
+                    .expect("failed to read event"),
+                EventWaitResult::Signaled
+            );
+        }
 
-        vm.register_log_file(file_path.to_str().unwrap())
-            .expect("failed to register log file");
+        let cloned = vm.try_clone().expect("failed to clone vm");
+        assert_eq!(cloned.ioevents.len(), evts.len());
+    }
 
-        let vcpu = vm.create_vcpu(0).expect("failed to create vcpu");
+    #[test]
+    fn try_clone_ioevents() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        let evt = Event::new().expect("failed to create event");
+        vm.register_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::AnyLength)
+            .unwrap();
+        vm.register_ioevent(&evt, IoEventAddress::Mmio(0x1000), Datamatch::AnyLength)
+            .unwrap();
 
-        // Setting cpuid will force some logs
-        let cpuid = haxm.get_supported_cpuid().unwrap();
-        vcpu.set_cpuid(&cpuid).expect("failed to set cpuid");
+        let mut cloned = vm.try_clone().expect("failed to clone vm");
+        cloned
+            .handle_io_events(IoEventAddress::Pio(0x1000), &[])
+            .expect("failed to handle_io_events");
+        assert_ne!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
 
-        assert!(file_path.exists());
+        // The clone's ioevents are independent of the original's.
+        cloned
+            .unregister_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::AnyLength)
+            .unwrap();
+        vm.handle_io_events(IoEventAddress::Pio(0x1000), &[])
+            .expect("failed to handle_io_events");
+        assert_ne!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn handle_io_events_datamatch() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        let evt = Event::new().expect("failed to create event");
+        let evt2 = Event::new().expect("failed to create event");
+        let any = Event::new().expect("failed to create event");
+        vm.register_ioevent(&evt, IoEventAddress::Mmio(0x1000), Datamatch::U16(Some(1)))
+            .unwrap();
+        vm.register_ioevent(&evt2, IoEventAddress::Mmio(0x1000), Datamatch::U16(Some(2)))
+            .unwrap();
+        vm.register_ioevent(&any, IoEventAddress::Mmio(0x1000), Datamatch::AnyLength)
+            .unwrap();
+
+        // Only the event whose value matches, plus the AnyLength event, should be signaled.
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &2u16.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_eq!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_ne!(
+            evt2.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_ne!(
+            any.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+
+        // A write of the wrong length does not match a sized datamatch.
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &1u32.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_eq!(
+            evt.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_ne!(
+            any.wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn handle_io_events_length() {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).expect("failed to create vm");
+        let evt16 = Event::new().expect("failed to create event");
+        let evt32 = Event::new().expect("failed to create event");
+        vm.register_ioevent(&evt16, IoEventAddress::Mmio(0x1000), Datamatch::U16(None))
+            .unwrap();
+        vm.register_ioevent(&evt32, IoEventAddress::Mmio(0x1000), Datamatch::U32(None))
+            .unwrap();
+
+        // Any value matches, but only writes of the registered length.
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0xabcdu16.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_ne!(
+            evt16
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_eq!(
+            evt32
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0x1234_5678u32.to_le_bytes())
+            .expect("failed to handle_io_events");
+        assert_eq!(
+            evt16
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_ne!(
+            evt32
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+
+        // A single byte write matches neither.
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &[1])
+            .expect("failed to handle_io_events");
+        assert_eq!(
+            evt16
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+        assert_eq!(
+            evt32
+                .wait_timeout(Duration::from_millis(10))
+                .expect("failed to read event"),
+            EventWaitResult::TimedOut
+        );
+    }
+
+    #[test]
+    fn capabilities_bitset() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vm = HaxmVm::new(&haxm, gm).unwrap();
+        let caps = vm.capabilities_bitset();
+
+        assert!(caps.contains(VmCap::PvClock));
+        assert!(caps.contains(VmCap::IoEvent));
+        assert!(!caps.contains(VmCap::Protected));
+        assert!(!caps.contains(VmCap::DirtyLog));
+        for cap in VmCap::ALL {
+            assert_eq!(caps.contains(*cap), vm.check_capability(*cap));
+        }
+    }
+
+    #[test]
+    fn pvclock() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vm = HaxmVm::new(&haxm, gm).unwrap();
+        assert!(vm.check_capability(VmCap::PvClock));
+
+        let state = ClockState {
+            clock: 1_000_000_000,
+            flags: 0,
+        };
+        vm.set_pvclock(&state).expect("failed to set pvclock");
+        let clock = vm.get_pvclock().expect("failed to get pvclock").clock;
+        assert!(clock >= state.clock);
+        assert!(clock - state.clock < Duration::from_secs(10).as_nanos() as u64);
+
+        // Clones of the vm share the same clock.
+        let cloned = vm.try_clone().unwrap();
+        assert!(cloned.get_pvclock().unwrap().clock >= clock);
+    }
+
+    #[test]
+    fn remove_memory() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let mem_ptr = mem.as_ptr();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        let removed_mem = vm.remove_memory_region(slot).unwrap();
+        assert_eq!(removed_mem.size(), mem_size);
+        assert_eq!(removed_mem.as_ptr(), mem_ptr);
+    }
+
+    #[test]
+    fn add_memory_regions() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let new_region = |name| -> Box<dyn MappedRegion> {
+            let shm = SharedMemory::new(name, mem_size as u64).unwrap();
+            Box::new(
+                MemoryMappingBuilder::new(mem_size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        // Overlapping regions are rejected before anything is added.
+        vm.add_memory_regions(vec![
+            (GuestAddress(0x1000), new_region("a"), false),
+            (GuestAddress(0x1800), new_region("b"), false),
+        ])
+        .unwrap_err();
+        vm.add_memory_regions(vec![(GuestAddress(0), new_region("a"), false)])
+            .unwrap_err();
+
+        let slots = vm
+            .add_memory_regions(vec![
+                (GuestAddress(0x1000), new_region("a"), false),
+                (GuestAddress(0x2000), new_region("b"), true),
+            ])
+            .unwrap();
+        assert_eq!(slots, vec![1, 2]);
+        vm.remove_memory_region(slots[0]).unwrap();
+        vm.remove_memory_region(slots[1]).unwrap();
+
+        // Removed slots are reused so slot numbers stay dense.
+        let slots = vm
+            .add_memory_regions(vec![(GuestAddress(0x1000), new_region("a"), false)])
+            .unwrap();
+        assert_eq!(slots, vec![1]);
+    }
+
+    #[test]
+    fn check_tunnel_info_rejects_bad_tunnel() {
+        // What a broken driver's setup-tunnel ioctl would leave behind.
+        let zeroed = hax_tunnel_info::default();
+        assert_eq!(check_tunnel_info(&zeroed), Err(Error::new(EIO)));
+
+        let valid = hax_tunnel_info {
+            va: 0x1000,
+            io_va: 0x2000,
+            size: std::mem::size_of::<hax_tunnel>() as u16,
+            ..Default::default()
+        };
+        check_tunnel_info(&valid).expect("valid tunnel rejected");
+
+        let short = hax_tunnel_info {
+            size: valid.size - 1,
+            ..valid
+        };
+        assert_eq!(check_tunnel_info(&short), Err(Error::new(EIO)));
+
+        let unmapped_io = hax_tunnel_info { io_va: 0, ..valid };
+        assert_eq!(check_tunnel_info(&unmapped_io), Err(Error::new(EIO)));
+    }
+
+    #[test]
+    fn add_memory_region_unaligned() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let new_region = |size| -> Box<dyn MappedRegion> {
+            let shm = SharedMemory::new("test", size as u64).unwrap();
+            Box::new(
+                MemoryMappingBuilder::new(size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(
+            vm.add_memory_region(
+                GuestAddress(0x1800),
+                new_region(0x1000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            ),
+            Err(Error::new(EINVAL))
+        );
+        assert_eq!(
+            vm.add_memory_region(
+                GuestAddress(0x1000),
+                new_region(0x800),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            ),
+            Err(Error::new(EINVAL))
+        );
+        // Overlap is reported ahead of alignment.
+        assert_eq!(
+            vm.add_memory_region(
+                GuestAddress(0x800),
+                new_region(0x1000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            ),
+            Err(Error::new(ENOSPC))
+        );
+        vm.add_memory_region(
+            GuestAddress(0x1000),
+            new_region(0x1000),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn describe_memory_regions() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let new_region = || -> Box<dyn MappedRegion> {
+            let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+            Box::new(
+                MemoryMappingBuilder::new(mem_size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let bios = vm
+            .add_memory_region_labeled(
+                GuestAddress(0x1000),
+                new_region(),
+                true,
+                false,
+                Some("bios".to_string()),
+            )
+            .unwrap();
+        let unlabeled = vm
+            .add_memory_region(
+                GuestAddress(0x2000),
+                new_region(),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        assert_eq!(
+            vm.describe_memory_regions(),
+            vec![
+                (bios, GuestAddress(0x1000), 0x1000, Some("bios".to_string())),
+                (unlabeled, GuestAddress(0x2000), 0x1000, None),
+            ]
+        );
+
+        vm.remove_memory_region(bios).unwrap();
+        assert_eq!(
+            vm.describe_memory_regions(),
+            vec![(unlabeled, GuestAddress(0x2000), 0x1000, None)]
+        );
+    }
+
+    #[test]
+    fn total_mapped_bytes() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        assert_eq!(vm.total_mapped_bytes(), Ok(0x1000));
+
+        for (guest_addr, size) in [(0x4000, 0x2000), (0x8000, 0x3000)] {
+            let shm = SharedMemory::new("test", size).unwrap();
+            let mem = MemoryMappingBuilder::new(size as usize)
+                .from_shared_memory(&shm)
+                .build()
+                .unwrap();
+            vm.add_memory_region(
+                GuestAddress(guest_addr),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        }
+        assert_eq!(vm.total_mapped_bytes(), Ok(0x1000 + 0x2000 + 0x3000));
+    }
+
+    #[test]
+    fn memory_region_available() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let shm = SharedMemory::new("test", 0x2000).unwrap();
+        let mem = MemoryMappingBuilder::new(0x2000)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        vm.add_memory_region(
+            GuestAddress(0x4000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .unwrap();
+
+        // Overlaps the guest memory.
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0), 0x1000),
+            Ok(false)
+        );
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0x800), 0x1000),
+            Ok(false)
+        );
+        // Overlaps the added region.
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0x3000), 0x2000),
+            Ok(false)
+        );
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0x5000), 0x1000),
+            Ok(false)
+        );
+        // Fits in the gaps around them.
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0x1000), 0x3000),
+            Ok(true)
+        );
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(0x6000), 0x1000),
+            Ok(true)
+        );
+        assert_eq!(
+            vm.memory_region_available(GuestAddress(u64::MAX), 0x1000),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn destroy() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let shm = SharedMemory::new("test", 0x1000).unwrap();
+        let mem = MemoryMappingBuilder::new(0x1000)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        vm.add_memory_region(
+            GuestAddress(0x1000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .unwrap();
+        let evt = Event::new().unwrap();
+        vm.register_ioevent(&evt, IoEventAddress::Mmio(0x2000), Datamatch::AnyLength)
+            .unwrap();
+
+        vm.destroy().expect("failed to destroy vm");
+    }
+
+    #[test]
+    fn zero_memory_region() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let shm = SharedMemory::new("test", 0x2000).unwrap();
+        let mem = MemoryMappingBuilder::new(0x2000)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        // A second mapping of the same memory to check the region's contents through.
+        let view = MemoryMappingBuilder::new(0x2000)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        view.get_slice(0, 0x2000).unwrap().write_bytes(0xa5);
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x4000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        vm.zero_memory_region(slot).unwrap();
+        assert!(view.get_slice(0, 0x2000).unwrap().is_all_zero());
+        assert_eq!(vm.zero_memory_region(slot + 1), Err(Error::new(ENOENT)));
+
+        // Read-only regions are left untouched.
+        view.get_slice(0, 0x2000).unwrap().write_bytes(0xa5);
+        vm.set_memory_region_readonly(slot, true).unwrap();
+        assert_eq!(vm.zero_memory_region(slot), Err(Error::new(EPERM)));
+        let mut byte = [0u8; 1];
+        view.get_slice(0x1fff, 1).unwrap().copy_to(&mut byte);
+        assert_eq!(byte, [0xa5]);
+    }
+
+    #[test]
+    fn for_each_memory_region() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let new_region = |size: usize| -> Box<dyn MappedRegion> {
+            let shm = SharedMemory::new("test", size as u64).unwrap();
+            Box::new(
+                MemoryMappingBuilder::new(size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let first = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                new_region(0x1000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        let second = vm
+            .add_memory_region(
+                GuestAddress(0x4000),
+                new_region(0x2000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        let mut regions = Vec::new();
+        vm.for_each_memory_region(&mut |slot, guest_addr, mem| {
+            regions.push((slot, guest_addr, mem.size()))
+        })
+        .unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                (first, GuestAddress(0x1000), 0x1000),
+                (second, GuestAddress(0x4000), 0x2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn msync_all() {
+        use std::io::Read;
+        use std::io::Seek;
+        use std::io::SeekFrom;
+
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(0x1000).unwrap();
+        let mem = MemoryMappingBuilder::new(0x1000)
+            .from_file(&file)
+            .build()
+            .unwrap();
+        mem.write_slice(b"snapshot", 0x10).unwrap();
+        vm.add_memory_region(
+            GuestAddress(0x1000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .unwrap();
+
+        vm.msync_all().unwrap();
+        let mut buf = [0u8; 8];
+        file.seek(SeekFrom::Start(0x10)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"snapshot");
+    }
+
+    #[test]
+    fn find_memory_region() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let shm = SharedMemory::new("test", 0x2000).unwrap();
+        let mem = MemoryMappingBuilder::new(0x2000)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x4000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        assert_eq!(
+            vm.find_memory_region(GuestAddress(0x10)),
+            Ok(Some((0, GuestAddress(0), 0x10)))
+        );
+        assert_eq!(
+            vm.find_memory_region(GuestAddress(0x5008)),
+            Ok(Some((slot, GuestAddress(0x4000), 0x1008)))
+        );
+        assert_eq!(vm.find_memory_region(GuestAddress(0x6000)), Ok(None));
+        assert_eq!(vm.find_memory_region(GuestAddress(0x2000)), Ok(None));
+    }
+
+    #[test]
+    fn add_guard_region() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+
+        let res = vm.add_guard_region(GuestAddress(0x10_0000), 0x2000);
+        if !vm.capabilities().ram_protection {
+            assert!(res.is_err());
+            return;
+        }
+        let slot = res.expect("failed to add guard region");
+        assert_eq!(
+            vm.describe_memory_regions(),
+            vec![(
+                slot,
+                GuestAddress(0x10_0000),
+                0x2000,
+                Some(GUARD_REGION_LABEL.to_string())
+            )]
+        );
+        let mem = vm.remove_memory_region(slot).unwrap();
+        assert_eq!(mem.size(), 0x2000);
+    }
+
+    #[test]
+    fn clear_memory_regions() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let new_region = |size: usize| -> Box<dyn MappedRegion> {
+            let shm = SharedMemory::new("test", size as u64).unwrap();
+            Box::new(
+                MemoryMappingBuilder::new(size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let first = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                new_region(0x1000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        let second = vm
+            .add_memory_region(
+                GuestAddress(0x4000),
+                new_region(0x2000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        vm.remove_memory_region(first).unwrap();
+
+        let mappings = vm.clear_memory_regions().unwrap();
+        assert_eq!(
+            mappings.iter().map(|m| m.size()).collect::<Vec<_>>(),
+            vec![0x2000]
+        );
+        assert!(vm.describe_memory_regions().is_empty());
+        assert!(vm.get_memory().address_in_range(GuestAddress(0)));
+
+        // Slot numbers are handed out from the start again.
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                new_region(0x1000),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        assert_eq!(slot, first);
+        assert_ne!(slot, second);
+    }
+
+    #[test]
+    fn snapshot_restore_memory() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        // Larger than a single copy chunk, so the contents are copied in several parts.
+        let mem_size = SNAPSHOT_CHUNK_SIZE + 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        mem.write_obj(0x1234u32, 0x10).unwrap();
+        mem.write_obj(0x9abcu32, SNAPSHOT_CHUNK_SIZE + 0x10)
+            .unwrap();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        let mut snapshot = Vec::new();
+        assert_eq!(vm.snapshot_memory(slot, &mut snapshot).unwrap(), mem_size);
+        assert_eq!(snapshot.len(), mem_size);
+        assert_eq!(snapshot[0x10..0x14], 0x1234u32.to_le_bytes());
+        let last_chunk = SNAPSHOT_CHUNK_SIZE + 0x10;
+        assert_eq!(
+            snapshot[last_chunk..last_chunk + 4],
+            0x9abcu32.to_le_bytes()
+        );
+
+        snapshot[0x10..0x14].copy_from_slice(&0x5678u32.to_le_bytes());
+        snapshot[last_chunk..last_chunk + 4].copy_from_slice(&0xdef0u32.to_le_bytes());
+        assert_eq!(
+            vm.restore_memory(slot, &mut snapshot.as_slice()).unwrap(),
+            mem_size
+        );
+        let mut restored = Vec::new();
+        vm.snapshot_memory(slot, &mut restored).unwrap();
+        assert_eq!(restored, snapshot);
+
+        // Short input and unknown slots are rejected.
+        vm.restore_memory(slot, &mut &snapshot[..0x10]).unwrap_err();
+        assert_eq!(
+            vm.snapshot_memory(slot + 1, &mut Vec::new()).unwrap_err(),
+            Error::new(ENOENT)
+        );
+    }
+
+    #[test]
+    fn balloon_guest_memory() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x4000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm.clone()).unwrap();
+        gm.write_obj_at_addr(0x1234u32, GuestAddress(0x1010))
+            .unwrap();
+
+        vm.handle_balloon_event(BalloonEvent::Inflate(MemRegion {
+            guest_address: GuestAddress(0x1000),
+            size: 0x1000,
+        }))
+        .unwrap();
+        vm.handle_balloon_event(BalloonEvent::Deflate(MemRegion {
+            guest_address: GuestAddress(0x1000),
+            size: 0x1000,
+        }))
+        .unwrap();
+        // The page comes back zeroed.
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(0x1010)).unwrap(),
+            0
+        );
+
+        // A range that runs past the end of guest memory is rejected.
+        let event = BalloonEvent::Inflate(MemRegion {
+            guest_address: GuestAddress(0x3000),
+            size: 0x2000,
+        });
+        assert_eq!(
+            vm.handle_balloon_event(event).unwrap_err(),
+            Error::new(EINVAL)
+        );
+    }
+
+    #[test]
+    fn balloon_unknown_region() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let event = BalloonEvent::Inflate(MemRegion {
+            guest_address: GuestAddress(0x10_0000),
+            size: 0x1000,
+        });
+        assert_eq!(
+            vm.handle_balloon_event(event).unwrap_err(),
+            Error::new(EINVAL)
+        );
+    }
+
+    #[test]
+    fn max_memory_slots() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let new_region = || -> Box<dyn MappedRegion> {
+            Box::new(
+                MemoryMappingBuilder::new(mem_size)
+                    .from_shared_memory(&shm)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        // Guest memory uses the first slot.
+        for i in 1..vm.max_memory_slots() {
+            vm.add_memory_region(
+                GuestAddress((i * mem_size) as u64),
+                new_region(),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        }
+        let next_addr = GuestAddress((vm.max_memory_slots() * mem_size) as u64);
+        assert_eq!(
+            vm.add_memory_region(
+                next_addr,
+                new_region(),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap_err(),
+            Error::new(EMFILE)
+        );
+        assert_eq!(
+            vm.add_memory_regions(vec![(next_addr, new_region(), false)])
+                .unwrap_err(),
+            Error::new(EMFILE)
+        );
+    }
+
+    #[test]
+    fn set_memory_region_readonly() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+        vm.set_memory_region_readonly(slot, true)
+            .expect("failed to make region read-only");
+        vm.set_memory_region_readonly(slot, false)
+            .expect("failed to make region writable");
+        vm.set_memory_region_readonly(slot + 1, true)
+            .expect_err("unknown slot should fail");
+    }
+
+    #[test]
+    fn guest_to_host() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let guest_mem_host = gm.get_host_address(GuestAddress(0)).unwrap() as *mut u8;
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let region_host = mem.as_ptr();
+        vm.add_memory_region(
+            GuestAddress(0x1000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vm.guest_to_host(GuestAddress(0x10), 0x100).unwrap(),
+            guest_mem_host.wrapping_add(0x10)
+        );
+        assert_eq!(
+            vm.guest_to_host(GuestAddress(0x1800), 0x800).unwrap(),
+            region_host.wrapping_add(0x800)
+        );
+        // The two regions are adjacent in the guest but not on the host.
+        assert_eq!(
+            vm.guest_to_host(GuestAddress(0xf00), 0x200)
+                .unwrap_err()
+                .errno(),
+            EFAULT
+        );
+        assert_eq!(
+            vm.guest_to_host(GuestAddress(0x2000), 1)
+                .unwrap_err()
+                .errno(),
+            EFAULT
+        );
+    }
+
+    #[test]
+    fn set_all_regions_readonly() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                false,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        vm.set_all_regions_readonly(true)
+            .expect("failed to make regions read-only");
+        {
+            let read_only_slots = vm.read_only_slots.lock();
+            assert!(read_only_slots.contains(&0));
+            assert!(read_only_slots.contains(&slot));
+        }
+        // A region that is already read-only is left alone.
+        vm.set_all_regions_readonly(true)
+            .expect("failed to make regions read-only");
+
+        vm.set_all_regions_readonly(false)
+            .expect("failed to make regions writable");
+        assert!(vm.read_only_slots.lock().is_empty());
+    }
+
+    #[test]
+    fn update_memory_region_va() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        let mem_size = 0x1000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let old_addr = mem.as_ptr();
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                true,
+                false,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        let new_mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        // SAFETY:
+        // Safe because `new_mem` is as large as the region and outlives it, which is removed below.
+        let old_mem = unsafe { vm.update_memory_region_va(slot, new_mem.as_ptr()) }
+            .expect("failed to update region address");
+        assert_eq!(old_mem.as_ptr(), old_addr);
+        vm.for_each_memory_region(&mut |_, _, mem| {
+            assert_eq!(mem.as_ptr(), new_mem.as_ptr());
+            assert_eq!(mem.size(), mem_size);
+        })
+        .unwrap();
+
+        // SAFETY:
+        // Safe because the slot is unknown, so nothing is mapped.
+        unsafe { vm.update_memory_region_va(slot + 1, new_mem.as_ptr()) }
+            .err()
+            .expect("unknown slot should fail");
+        vm.remove_memory_region(slot).unwrap();
+    }
+
+    #[test]
+    fn dirty_log() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = HaxmVm::new(&haxm, gm).unwrap();
+        assert!(!vm.check_capability(VmCap::DirtyLog));
+
+        let mem_size = 0x4000;
+        let shm = SharedMemory::new("test", mem_size as u64).unwrap();
+        let mem = MemoryMappingBuilder::new(mem_size)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        // Asking for dirty page tracking doesn't fail the region, it's ignored.
+        let slot = vm
+            .add_memory_region(
+                GuestAddress(0x1000),
+                Box::new(mem),
+                false,
+                true,
+                MemCacheType::CacheCoherent,
+            )
+            .unwrap();
+
+        let mut dirty_log = [0u8; 1];
+        assert_eq!(
+            vm.get_dirty_log(slot, &mut dirty_log),
+            Err(Error::new(libc::ENXIO))
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn register_log_file() {
+        let haxm = Haxm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vm = HaxmVm::new(&haxm, gm).unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut file_path = dir.path().to_owned();
+        file_path.push("test-{vm_id}");
+
+        let status = vm
+            .register_log_file(file_path.to_str().unwrap())
+            .expect("failed to register log file");
+        if !get_use_ghaxm() {
+            assert_eq!(status, LogFileStatus::UnsupportedPlatform);
+            return;
+        }
+        if !vm.check_raw_capability(HAX_CAP_VM_LOG) {
+            assert_eq!(status, LogFileStatus::CapabilityMissing);
+            return;
+        }
+        let file_path = dir.path().join(format!("test-{}", vm.vm_id()));
+        assert_eq!(
+            status,
+            LogFileStatus::Enabled(file_path.to_str().unwrap().to_string())
+        );
+
+        let vcpu = vm.create_vcpu(0).expect("failed to create vcpu");
+
+        // Setting cpuid will force some logs
+        let cpuid = haxm.get_supported_cpuid().unwrap();
+        vcpu.set_cpuid(&cpuid).expect("failed to set cpuid");
+
+        assert!(file_path.exists());
+    }
+
+    #[test]
+    fn check_memory_quota_limit() {
+        check_memory_quota(0x4000, 0x2000, 0x2000).unwrap();
+        assert_eq!(
+            check_memory_quota(0x4000, 0x2000, 0x3000),
+            Err(Error::new(E2BIG))
+        );
+        assert_eq!(
+            check_memory_quota(0x4000, u64::MAX, 0x1000),
+            Err(Error::new(E2BIG))
+        );
+    }
+
+    #[test]
+    fn check_ignored_addr_alignment() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x2000)]).unwrap();
+        let warned = Once::new();
+        assert_eq!(
+            check_ignored_addr("test", GuestAddress(0x1001), &gm, &warned),
+            Err(Error::new(EINVAL))
+        );
+        assert!(!warned.is_completed());
+        check_ignored_addr("test", GuestAddress(0x1000), &gm, &warned).unwrap();
+        assert!(warned.is_completed());
+        // Addresses outside guest memory are still accepted, since HAXM ignores them.
+        check_ignored_addr("test", GuestAddress(0x10000), &gm, &warned).unwrap();
     }
 }
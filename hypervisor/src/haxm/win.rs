@@ -2,16 +2,26 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::ffi::c_void;
 use std::ptr::null_mut;
 
 use base::errno_result;
+use base::Error;
 use base::FromRawDescriptor;
 use base::Result;
 use base::SafeDescriptor;
+use libc::EINVAL;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
 use win_util::win32_wide_string;
+use winapi::shared::winerror::ERROR_BUSY;
+use winapi::shared::winerror::ERROR_SUCCESS;
 use winapi::um::fileapi::CreateFileW;
 use winapi::um::fileapi::CREATE_ALWAYS;
 use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::memoryapi::OfferVirtualMemory;
+use winapi::um::memoryapi::ReclaimVirtualMemory;
+use winapi::um::memoryapi::VmOfferPriorityBelowNormal;
 use winapi::um::winnt::FILE_ATTRIBUTE_NORMAL;
 use winapi::um::winnt::GENERIC_READ;
 use winapi::um::winnt::GENERIC_WRITE;
@@ -106,3 +116,45 @@ pub(super) fn open_haxm_vcpu_device(
     // Safe because we verify that ret is valid and we own the fd.
     Ok(unsafe { SafeDescriptor::from_raw_descriptor(ret) })
 }
+
+/// Offers the host memory backing a range of guest RAM that the guest gave up to the balloon back
+/// to Windows.
+pub(super) fn release_guest_mem_range(
+    guest_mem: &GuestMemory,
+    guest_address: GuestAddress,
+    size: u64,
+) -> Result<()> {
+    let host_address = guest_mem
+        .get_host_address(guest_address)
+        .map_err(|_| Error::new(EINVAL))? as *mut c_void;
+    // SAFETY:
+    // Safe because the range is guest RAM owned by `guest_mem`, which the guest no longer uses,
+    // and OfferVirtualMemory checks its parameters.
+    let result =
+        unsafe { OfferVirtualMemory(host_address, size as usize, VmOfferPriorityBelowNormal) };
+    if result != ERROR_SUCCESS {
+        return Err(Error::new(result));
+    }
+    Ok(())
+}
+
+/// Reclaims a range of guest RAM released with `release_guest_mem_range` and zeroes it, as its
+/// contents are undefined after the reclaim.
+pub(super) fn reclaim_guest_mem_range(
+    guest_mem: &GuestMemory,
+    guest_address: GuestAddress,
+    size: u64,
+) -> Result<()> {
+    let slice = guest_mem
+        .get_slice_at_addr(guest_address, size as usize)
+        .map_err(|_| Error::new(EINVAL))?;
+    // SAFETY:
+    // Safe because the range is guest RAM owned by `guest_mem`, and ReclaimVirtualMemory fails
+    // for ranges that were not offered.
+    let result = unsafe { ReclaimVirtualMemory(slice.as_ptr() as *const c_void, slice.size()) };
+    if result != ERROR_SUCCESS && result != ERROR_BUSY {
+        return Err(Error::new(result));
+    }
+    slice.write_bytes(0);
+    Ok(())
+}
@@ -227,7 +227,8 @@ pub struct KvmVm {
     kvm: Kvm,
     vm: SafeDescriptor,
     guest_mem: GuestMemory,
-    mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
+    // Each region's guest address and host mapping.
+    mem_regions: Arc<Mutex<BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>)>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
 }
@@ -575,6 +576,7 @@ impl Vm for KvmVm {
                 cfg!(feature = "noncoherent-dma")
                     && self.check_raw_capability(KvmCap::MemNoncoherentDma)
             }
+            VmCap::IoEvent => true,
         }
     }
 
@@ -657,13 +659,13 @@ impl Vm for KvmVm {
             gaps.push(Reverse(slot));
             return Err(e);
         }
-        regions.insert(slot, mem);
+        regions.insert(slot, (guest_addr, mem));
         Ok(slot)
     }
 
     fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let mem = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let (_, mem) = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
 
         mem.msync(offset, size).map_err(|err| match err {
             MmapError::InvalidAddress => Error::new(EFAULT),
@@ -694,7 +696,19 @@ impl Vm for KvmVm {
         }
         self.mem_slot_gaps.lock().push(Reverse(slot));
         // This remove will always succeed because of the contains_key check above.
-        Ok(regions.remove(&slot).unwrap())
+        Ok(regions.remove(&slot).unwrap().1)
+    }
+
+    fn for_each_memory_region(
+        &self,
+        f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        // Hold the lock for the whole walk so the regions can't change under `f`.
+        let regions = self.mem_regions.lock();
+        for (slot, (guest_addr, mem)) in regions.iter() {
+            f(*slot, *guest_addr, mem.as_ref());
+        }
+        Ok(())
     }
 
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor> {
@@ -708,8 +722,8 @@ impl Vm for KvmVm {
                     flags: 0,
                 },
 
-                // ARM and risc-v have additional DeviceKinds, so it needs the catch-all pattern
-                #[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64"))]
+                // The remaining DeviceKinds are either arch specific and handled above, or have no
+                // KVM device, like the x86 irqchip that is created with KVM_CREATE_IRQCHIP.
                 _ => return Err(Error::new(libc::ENXIO)),
             }
         };
@@ -731,7 +745,7 @@ impl Vm for KvmVm {
 
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
-        let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let (_, mmap) = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
         // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
         if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
             return Err(Error::new(EINVAL));
@@ -794,7 +808,7 @@ impl Vm for KvmVm {
         prot: Protection,
     ) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
         match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
             Ok(()) => Ok(()),
@@ -805,7 +819,7 @@ impl Vm for KvmVm {
 
     fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
         let mut regions = self.mem_regions.lock();
-        let region = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
 
         match region.remove_mapping(offset, size) {
             Ok(()) => Ok(()),
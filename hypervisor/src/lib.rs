@@ -28,9 +28,13 @@ pub mod x86_64;
 #[cfg(all(unix, feature = "geniezone"))]
 pub mod geniezone;
 
+use base::error;
 use base::AsRawDescriptor;
+use base::Error;
 use base::Event;
 use base::MappedRegion;
+use base::MmapError;
+use base::MmapResult;
 use base::Protection;
 use base::Result;
 use base::SafeDescriptor;
@@ -77,6 +81,20 @@ pub enum BalloonEvent {
     BalloonTargetReached(u64),
 }
 
+/// What a handler registered with `Vm::set_fault_handler` wants done about a guest access to
+/// memory that is not backed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// Back the page holding the faulting address and resume the guest, which retries the access.
+    MapPage,
+    /// Leave the page unbacked. The access is reported to the vcpu's run loop as it would be
+    /// without a handler.
+    Unhandled,
+}
+
+/// Handler called with the faulting guest address, see `Vm::set_fault_handler`.
+pub type FaultHandler = Box<dyn Fn(GuestAddress) -> FaultResolution + Send>;
+
 /// A trait for checking hypervisor capabilities.
 pub trait Hypervisor: Send {
     /// Makes a shallow clone of this `Hypervisor`.
@@ -102,6 +120,15 @@ pub trait Vm: Send {
     /// reflects the usable capabilities.
     fn check_capability(&self, c: VmCap) -> bool;
 
+    /// Returns the set of every `VmCap` for which `check_capability` returns true.
+    fn capabilities_bitset(&self) -> VmCapSet {
+        VmCap::ALL
+            .iter()
+            .copied()
+            .filter(|cap| self.check_capability(*cap))
+            .collect()
+    }
+
     /// Enable the VM capabilities.
     fn enable_capability(&self, _capability: VmCap, _flags: u32) -> Result<bool> {
         Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
@@ -146,9 +173,277 @@ pub trait Vm: Send {
     /// `offset` from the start of the region.  `offset` must be page aligned.
     fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()>;
 
+    /// Does a synchronous msync of every region added with `add_memory_region`, so that
+    /// file-backed regions are consistent on disk before a snapshot is taken. A failure on one
+    /// region doesn't stop the others from being synced; the first error is returned. Returns
+    /// ENOTSUP without syncing anything if the regions can't be listed.
+    fn msync_all(&mut self) -> Result<()> {
+        let mut regions = Vec::new();
+        self.for_each_memory_region(&mut |slot, _, region| regions.push((slot, region.size())))?;
+        let mut result = Ok(());
+        for (slot, size) in regions {
+            if let Err(e) = self.msync_memory_region(slot, 0, size) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
     /// Removes and drops the `UserMemoryRegion` that was previously added at the given slot.
     fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>>;
 
+    /// Zeroes the memory region at `slot` in place, keeping it mapped at the same slot, for example
+    /// to clear a framebuffer after a guest reboot. The region's host mapping must be writable and
+    /// the guest should not be running. Returns ENOENT if `slot` is unknown and EPERM if the region
+    /// is read-only.
+    fn zero_memory_region(&mut self, _slot: MemSlot) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Calls `f` with the slot, guest address and mapping of each memory region added with
+    /// `add_memory_region`, ordered by slot. Regions can't be added or removed until the walk
+    /// finishes, so `f` must not call back into the VM to change its memory regions.
+    ///
+    /// Returns ENOTSUP if the hypervisor can't list its memory regions, which fails the provided
+    /// methods built on this one with the same error.
+    fn for_each_memory_region(
+        &self,
+        _f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        Err(Error::new(libc::ENOTSUP))
+    }
+
+    /// Returns whether `[guest_addr, guest_addr + size)` is clear of both the guest memory the VM
+    /// was created with and every region added with `add_memory_region`, so a caller can check a
+    /// range before building a mapping for it. Returns the error from `for_each_memory_region` if
+    /// the regions can't be listed, rather than an answer that only covers the guest memory.
+    fn memory_region_available(&self, guest_addr: GuestAddress, size: u64) -> Result<bool> {
+        let end_addr = match guest_addr.checked_add(size) {
+            Some(end_addr) => end_addr,
+            None => return Ok(false),
+        };
+        if self.get_memory().range_overlap(guest_addr, end_addr) {
+            return Ok(false);
+        }
+        let mut available = true;
+        self.for_each_memory_region(&mut |_, region_addr, region| {
+            let region_end = region_addr.unchecked_add(region.size() as u64);
+            if guest_addr < region_end && region_addr < end_addr {
+                available = false;
+            }
+        })?;
+        Ok(available)
+    }
+
+    /// Returns the number of bytes of guest memory mapped into the VM: the guest memory it was
+    /// created with plus every region added with `add_memory_region`. Returns the error from
+    /// `for_each_memory_region` if the regions can't be listed, rather than undercounting.
+    fn total_mapped_bytes(&self) -> Result<u64> {
+        let mut total = self.get_memory().memory_size();
+        self.for_each_memory_region(&mut |_, _, region| total += region.size() as u64)?;
+        Ok(total)
+    }
+
+    /// Finds the memory region containing `addr`, such as the target of a DMA or a faulting
+    /// access, and returns its slot, its base address and the offset of `addr` within it. Both the
+    /// guest memory the VM was created with and the regions added with `add_memory_region` are
+    /// searched. Returns the error from `for_each_memory_region` if `addr` is outside the guest
+    /// memory and the regions can't be listed, rather than reporting that nothing maps it.
+    fn find_memory_region(
+        &self,
+        addr: GuestAddress,
+    ) -> Result<Option<(MemSlot, GuestAddress, usize)>> {
+        // The guest memory regions take the first slots, in order.
+        let guest_region = self.get_memory().regions().find(|region| {
+            region.guest_addr <= addr && addr < region.guest_addr.unchecked_add(region.size as u64)
+        });
+        if let Some(region) = guest_region {
+            return Ok(Some((
+                region.index as MemSlot,
+                region.guest_addr,
+                addr.offset_from(region.guest_addr) as usize,
+            )));
+        }
+        let mut found = None;
+        self.for_each_memory_region(&mut |slot, region_addr, region| {
+            if region_addr <= addr && addr < region_addr.unchecked_add(region.size() as u64) {
+                found = Some((slot, region_addr, addr.offset_from(region_addr) as usize));
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Locks the host memory backing the region at `slot` in RAM, so the host never pages it out
+    /// and the guest doesn't take the latency of a host page fault. Subject to the host's limit on
+    /// locked memory, which is `RLIMIT_MEMLOCK` on Linux and the working set size on Windows.
+    /// Returns ENOENT if `slot` is unknown.
+    fn lock_memory_region(&mut self, slot: MemSlot) -> Result<()> {
+        with_memory_region(self, slot, |region| region.lock())
+    }
+
+    /// Unlocks the host memory backing the region at `slot` after `lock_memory_region`, so the
+    /// host may page it out again. Returns ENOENT if `slot` is unknown.
+    fn unlock_memory_region(&mut self, slot: MemSlot) -> Result<()> {
+        with_memory_region(self, slot, |region| region.unlock())
+    }
+
+    /// Maps a guard region of `size` bytes at `guest_addr` which traps every guest access
+    /// instead of silently reading zeros or dropping writes, to catch stray accesses such as
+    /// misdirected DMA. The guard is removed with `remove_memory_region` like any other region.
+    ///
+    /// How a hit guard is reported depends on the hypervisor; see the implementations.
+    fn add_guard_region(&mut self, _guest_addr: GuestAddress, _size: u64) -> Result<MemSlot> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Registers `handler` to be called from the vcpu thread when the guest touches memory that is
+    /// not backed, for example to page guest memory in on demand. Replaces any previous handler.
+    ///
+    /// Only HAXM delivers faults, and only for regions added with `add_guard_region`. Other
+    /// hypervisors ignore the handler and keep reporting such accesses as they did before.
+    fn set_fault_handler(&mut self, _handler: FaultHandler) {}
+
+    /// Makes every memory region, including the guest memory the VM was created with where the
+    /// hypervisor allows it, read-only for the guest or writable again, without changing the host
+    /// mappings. Meant as a building block for forking a VM, where guest writes are trapped so the
+    /// written pages can be copied on demand.
+    ///
+    /// Either every region is changed or, on failure, the regions are restored to their previous
+    /// state as far as possible and the error is returned.
+    fn set_all_regions_readonly(&mut self, _read_only: bool) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Removes every memory region added with `add_memory_region` and returns their mappings,
+    /// ordered by slot, so the caller decides when they are unmapped. The guest memory the VM was
+    /// created with is not affected.
+    fn clear_memory_regions(&mut self) -> Result<Vec<Box<dyn MappedRegion>>> {
+        let mut slots = Vec::new();
+        self.for_each_memory_region(&mut |slot, _, _| slots.push(slot))?;
+        slots
+            .into_iter()
+            .map(|slot| self.remove_memory_region(slot))
+            .collect()
+    }
+
+    /// Writes the contents of the memory region at `slot` to `out`. The VM's vcpus should be
+    /// stopped so the contents don't change while they are copied.
+    ///
+    /// Returns the number of bytes written.
+    fn snapshot_memory(&self, _slot: MemSlot, _out: &mut dyn std::io::Write) -> Result<usize> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Replaces the contents of the memory region at `slot` with the contents read from `input`,
+    /// which must hold at least as many bytes as the region. The VM's vcpus should be stopped.
+    ///
+    /// Returns the number of bytes read.
+    fn restore_memory(&mut self, _slot: MemSlot, _input: &mut dyn std::io::Read) -> Result<usize> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Writes `buf` to guest memory at `addr`. The range may span adjacent guest memory regions.
+    ///
+    /// Returns EFAULT without writing anything if part of the range is not guest memory.
+    fn write_guest(&self, addr: GuestAddress, buf: &[u8]) -> Result<()> {
+        check_guest_range(self.get_memory(), addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            match self
+                .get_memory()
+                .write_at_addr(&buf[done..], addr.unchecked_add(done as u64))
+            {
+                Ok(n) if n > 0 => done += n,
+                _ => return Err(Error::new(libc::EFAULT)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills `len` bytes of guest memory at `addr` with `byte`, for example to clear a framebuffer
+    /// or scratch area. The range may span adjacent guest memory regions, and the part in each
+    /// region is filled with a single memset.
+    ///
+    /// Returns EFAULT without writing anything if part of the range is not guest memory.
+    fn fill_guest(&self, addr: GuestAddress, byte: u8, len: u64) -> Result<()> {
+        let mem = self.get_memory();
+        check_guest_range(
+            mem,
+            addr,
+            usize::try_from(len).map_err(|_| Error::new(libc::EFAULT))?,
+        )?;
+        let end = addr.unchecked_add(len);
+        let mut cur = addr;
+        while cur < end {
+            let region_end = mem
+                .regions()
+                .map(|region| {
+                    (
+                        region.guest_addr,
+                        region.guest_addr.unchecked_add(region.size as u64),
+                    )
+                })
+                .find(|(start, region_end)| *start <= cur && cur < *region_end)
+                .map(|(_, region_end)| region_end)
+                .ok_or(Error::new(libc::EFAULT))?;
+            let chunk = std::cmp::min(end, region_end).offset_from(cur);
+            mem.get_slice_at_addr(cur, chunk as usize)
+                .map_err(|_| Error::new(libc::EFAULT))?
+                .write_bytes(byte);
+            cur = cur.unchecked_add(chunk);
+        }
+        Ok(())
+    }
+
+    /// Reads guest memory at `addr` into `buf`. The range may span adjacent guest memory regions.
+    ///
+    /// Returns EFAULT if part of the range is not guest memory.
+    fn read_guest(&self, addr: GuestAddress, buf: &mut [u8]) -> Result<()> {
+        check_guest_range(self.get_memory(), addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            match self
+                .get_memory()
+                .read_at_addr(&mut buf[done..], addr.unchecked_add(done as u64))
+            {
+                Ok(n) if n > 0 => done += n,
+                _ => return Err(Error::new(libc::EFAULT)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Gathers the guest memory fragments in `iovs`, each a guest address and a length, into `out`
+    /// in order, for example to read a buffer a DMA descriptor chain points at. A fragment may span
+    /// adjacent guest memory regions. Fragments past the end of `out` are truncated. Returns the
+    /// number of bytes read.
+    ///
+    /// Every fragment is checked before anything is read. Returns EFAULT, and logs the index of the
+    /// fragment, if the first bad fragment is not entirely guest memory.
+    fn read_guest_iovec(&self, iovs: &[(GuestAddress, usize)], out: &mut [u8]) -> Result<usize> {
+        for (i, (addr, len)) in iovs.iter().enumerate() {
+            if let Err(e) = check_guest_range(self.get_memory(), *addr, *len) {
+                error!(
+                    "guest iovec fragment {} ({:#x} bytes at {}) is not guest memory",
+                    i, len, addr
+                );
+                return Err(e);
+            }
+        }
+        let mut done = 0;
+        for (addr, len) in iovs {
+            if done == out.len() {
+                break;
+            }
+            let len = std::cmp::min(*len, out.len() - done);
+            self.read_guest(*addr, &mut out[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+
     /// Creates an emulated device.
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor>;
 
@@ -186,6 +481,17 @@ pub trait Vm: Send {
         datamatch: Datamatch,
     ) -> Result<()>;
 
+    /// Moves every event registered with `register_ioevent` at `old` to `new`, keeping their
+    /// datamatches, for when the guest relocates the BAR the events belong to. Unlike
+    /// unregistering and registering the events again, there is no window in which a write to
+    /// either address is missed.
+    ///
+    /// Returns ENOENT if nothing is registered at `old` and EEXIST if something already is at
+    /// `new`.
+    fn relocate_ioevent(&mut self, _old: IoEventAddress, _new: IoEventAddress) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
     /// Trigger any matching registered io events based on an MMIO or PIO write at `addr`. The
     /// `data` slice represents the contents and length of the write, which is used to compare with
     /// the registered io events' Datamatch values. If the hypervisor does in-kernel IO event
@@ -227,6 +533,48 @@ pub trait Vm: Send {
     fn handle_balloon_event(&mut self, event: BalloonEvent) -> Result<()>;
 }
 
+/// Checks that `[addr, addr + len)` is entirely backed by guest memory, possibly spread across
+/// several adjacent regions.
+fn check_guest_range(mem: &GuestMemory, addr: GuestAddress, len: usize) -> Result<()> {
+    let end = addr
+        .checked_add(len as u64)
+        .ok_or(Error::new(libc::EFAULT))?;
+    let mut cur = addr;
+    while cur < end {
+        cur = mem
+            .regions()
+            .map(|region| {
+                (
+                    region.guest_addr,
+                    region.guest_addr.unchecked_add(region.size as u64),
+                )
+            })
+            .find(|(start, region_end)| *start <= cur && cur < *region_end)
+            .map(|(_, region_end)| region_end)
+            .ok_or(Error::new(libc::EFAULT))?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with the mapping of the memory region at `slot` and returns its result, or ENOENT if
+/// `vm` has no such region.
+fn with_memory_region<V: Vm + ?Sized>(
+    vm: &V,
+    slot: MemSlot,
+    f: impl Fn(&dyn MappedRegion) -> MmapResult<()>,
+) -> Result<()> {
+    let mut result = Err(Error::new(libc::ENOENT));
+    vm.for_each_memory_region(&mut |region_slot, _, region| {
+        if region_slot == slot {
+            result = f(region).map_err(|e| match e {
+                MmapError::SystemCallFailed(e) => e,
+                _ => Error::new(libc::EIO),
+            });
+        }
+    })?;
+    result
+}
+
 /// Operation for Io and Mmio
 #[derive(Copy, Clone, Debug)]
 pub enum IoOperation {
@@ -491,6 +839,11 @@ pub enum DeviceKind {
     /// RiscV AIA in-kernel emulation
     #[cfg(target_arch = "riscv64")]
     RiscvAia,
+    /// x86 interrupt controller for hypervisors without an in-kernel one. This is not an in-kernel
+    /// device: the interrupt controller is emulated in userspace and the descriptor is only a
+    /// handle to it.
+    #[cfg(target_arch = "x86_64")]
+    Irqchip,
 }
 
 /// The source chip of an `IrqSource`
@@ -518,6 +518,7 @@ impl Vm for WhpxVm {
             VmCap::BusLockDetect => false,
             VmCap::ReadOnlyMemoryRegion => true,
             VmCap::MemNoncoherentDma => false,
+            VmCap::IoEvent => true,
         }
     }
 
@@ -601,6 +602,18 @@ impl Vm for WhpxVm {
         }
     }
 
+    fn for_each_memory_region(
+        &self,
+        f: &mut dyn FnMut(MemSlot, GuestAddress, &dyn MappedRegion),
+    ) -> Result<()> {
+        // Hold the lock for the whole walk so the regions can't change under `f`.
+        let regions = self.mem_regions.lock();
+        for (slot, (guest_addr, mem)) in regions.iter() {
+            f(*slot, *guest_addr, mem.as_ref());
+        }
+        Ok(())
+    }
+
     fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
         // Whpx does not support in-kernel devices
         Err(Error::new(libc::ENXIO))
@@ -64,6 +64,14 @@ pub trait VmX86_64: Vm {
     /// Create a Vcpu with the specified Vcpu ID.
     fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>>;
 
+    /// Create a Vcpu with the specified Vcpu ID and apply the scheduling hints in `cfg` to the
+    /// calling thread, which should be the thread that will run the Vcpu.
+    fn create_vcpu_with_config(&self, id: usize, cfg: VcpuConfig) -> Result<Box<dyn VcpuX86_64>> {
+        let vcpu = self.create_vcpu(id)?;
+        cfg.apply()?;
+        Ok(vcpu)
+    }
+
     /// Sets the address of the three-page region in the VM's address space.
     fn set_tss_addr(&self, addr: GuestAddress) -> Result<()>;
 
@@ -71,6 +79,36 @@ pub trait VmX86_64: Vm {
     fn set_identity_map_addr(&self, addr: GuestAddress) -> Result<()>;
 }
 
+/// Scheduling hints for the host thread that runs a VCPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VcpuConfig {
+    /// Mask of the host cores the thread may run on. `None` leaves the affinity unchanged.
+    pub affinity_mask: Option<usize>,
+    /// Priority of the thread, e.g. `THREAD_PRIORITY_TIME_CRITICAL`. Only supported on Windows.
+    /// `None` leaves the priority unchanged.
+    pub priority: Option<i32>,
+}
+
+impl VcpuConfig {
+    /// Applies the hints to the calling thread.
+    pub fn apply(&self) -> Result<()> {
+        if let Some(mask) = self.affinity_mask {
+            let cpus = (0..usize::BITS as usize).filter(|cpu| mask & (1 << cpu) != 0);
+            base::set_cpu_affinity(cpus)?;
+        }
+        if let Some(priority) = self.priority {
+            #[cfg(windows)]
+            base::set_thread_priority(priority)?;
+            #[cfg(not(windows))]
+            warn!(
+                "ignoring vcpu thread priority {}, unsupported on this platform",
+                priority
+            );
+        }
+        Ok(())
+    }
+}
+
 /// A wrapper around creating and using a VCPU on x86_64.
 pub trait VcpuX86_64: Vcpu {
     /// Sets or clears the flag that requests the VCPU to exit when it becomes possible to inject
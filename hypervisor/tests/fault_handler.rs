@@ -0,0 +1,86 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Only HAXM delivers faults to the handler.
+#![cfg(all(windows, feature = "haxm"))]
+#![cfg(target_arch = "x86_64")]
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use hypervisor::*;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+fn test_haxm_fault_handler() {
+    use hypervisor::haxm::*;
+    test_fault_handler(|guest_mem| {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let vm = HaxmVm::new(&haxm, guest_mem).expect("failed to create vm");
+        (haxm, vm)
+    });
+}
+
+fn test_fault_handler<CreateVm, HypervisorT, VmT>(create_vm: CreateVm)
+where
+    CreateVm: FnOnce(GuestMemory) -> (HypervisorT, VmT),
+    HypervisorT: Hypervisor,
+    VmT: VmX86_64,
+{
+    /*
+    0x0000000000001000:  A0 10 30    mov al, byte ptr [0x3010]
+    0x0000000000001003:  F4          hlt
+    */
+    let code = [0xa0, 0x10, 0x30, 0xf4];
+    let load_addr = GuestAddress(0x1000);
+
+    let guest_mem =
+        GuestMemory::new(&[(GuestAddress(0), 0x2000)]).expect("failed to create guest mem");
+    guest_mem
+        .write_at_addr(&code, load_addr)
+        .expect("failed to write code to guest memory");
+
+    let (_, mut vm) = create_vm(guest_mem);
+    if vm.add_guard_region(GuestAddress(0x3000), 0x1000).is_err() {
+        // The driver has no RAM protection, so there are no faults to deliver.
+        return;
+    }
+    let fault_addr = Arc::new(AtomicU64::new(0));
+    let handler_fault_addr = fault_addr.clone();
+    vm.set_fault_handler(Box::new(move |addr| {
+        handler_fault_addr.store(addr.offset(), Ordering::SeqCst);
+        FaultResolution::MapPage
+    }));
+
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    vcpu_sregs.cs.base = 0;
+    vcpu_sregs.cs.selector = 0;
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+
+    let vcpu_regs = Regs {
+        rip: load_addr.offset(),
+        rax: 0xff,
+        rflags: 2,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+
+    // The handler maps the guard page, so the load completes without the run loop seeing a fault.
+    loop {
+        match vcpu.run().expect("run failed") {
+            // Continue on external interrupt or signal
+            VcpuExit::Intr => continue,
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    assert_eq!(fault_addr.load(Ordering::SeqCst), 0x3010);
+    // The page is backed by the guard's zeroed memory.
+    let regs = vcpu.get_regs().expect("failed to get regs");
+    assert_eq!(regs.rax, 0);
+}
@@ -0,0 +1,120 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![cfg(target_arch = "x86_64")]
+
+use hypervisor::*;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_kvm_guest_memory_access() {
+    use hypervisor::kvm::*;
+    test_guest_memory_access(|guest_mem| {
+        let kvm = Kvm::new().expect("failed to create kvm");
+        let vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+        (kvm, vm)
+    });
+}
+
+#[test]
+#[cfg(all(windows, feature = "haxm"))]
+fn test_haxm_guest_memory_access() {
+    use hypervisor::haxm::*;
+    test_guest_memory_access(|guest_mem| {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let vm = HaxmVm::new(&haxm, guest_mem).expect("failed to create vm");
+        (haxm, vm)
+    });
+}
+
+fn test_guest_memory_access<CreateVm, HypervisorT, VmT>(create_vm: CreateVm)
+where
+    CreateVm: FnOnce(GuestMemory) -> (HypervisorT, VmT),
+    HypervisorT: Hypervisor,
+    VmT: Vm,
+{
+    // Two adjacent regions followed by a hole.
+    let guest_mem = GuestMemory::new(&[
+        (GuestAddress(0x1000), 0x1000),
+        (GuestAddress(0x2000), 0x1000),
+        (GuestAddress(0x4000), 0x1000),
+    ])
+    .expect("failed to create guest mem");
+    let (_, vm) = create_vm(guest_mem);
+
+    // A write that crosses from one region into the next.
+    let data = [0xaa_u8; 0x20];
+    vm.write_guest(GuestAddress(0x1ff0), &data)
+        .expect("failed to write guest memory");
+    let mut buf = [0u8; 0x20];
+    vm.read_guest(GuestAddress(0x1ff0), &mut buf)
+        .expect("failed to read guest memory");
+    assert_eq!(buf, data);
+
+    // A range that runs into the hole fails without writing anything.
+    let err = vm
+        .write_guest(GuestAddress(0x2ff0), &data)
+        .expect_err("write into a hole succeeded");
+    assert_eq!(err.errno(), libc::EFAULT);
+    let mut buf = [0u8; 0x10];
+    vm.read_guest(GuestAddress(0x2ff0), &mut buf)
+        .expect("failed to read guest memory");
+    assert_eq!(buf, [0u8; 0x10]);
+
+    let err = vm
+        .read_guest(GuestAddress(0x3000), &mut buf)
+        .expect_err("read from a hole succeeded");
+    assert_eq!(err.errno(), libc::EFAULT);
+
+    // A fill that crosses from one region into the next.
+    vm.fill_guest(GuestAddress(0x1f00), 0x55, 0x200)
+        .expect("failed to fill guest memory");
+    let mut buf = [0u8; 0x202];
+    vm.read_guest(GuestAddress(0x1eff), &mut buf)
+        .expect("failed to read guest memory");
+    assert_eq!(buf[0], 0);
+    assert!(buf[1..0x201].iter().all(|&b| b == 0x55));
+    assert_eq!(buf[0x201], 0);
+
+    // A fill that runs into the hole fails without writing anything.
+    let err = vm
+        .fill_guest(GuestAddress(0x2f00), 0x55, 0x200)
+        .expect_err("fill into a hole succeeded");
+    assert_eq!(err.errno(), libc::EFAULT);
+    let mut buf = [0u8; 0x100];
+    vm.read_guest(GuestAddress(0x2f00), &mut buf)
+        .expect("failed to read guest memory");
+    assert_eq!(buf, [0u8; 0x100]);
+
+    // Scatter-gather reads, including a fragment that crosses into the next region.
+    vm.write_guest(GuestAddress(0x4000), &[0x11_u8; 0x10])
+        .expect("failed to write guest memory");
+    let iovs = [
+        (GuestAddress(0x4000), 0x8),
+        (GuestAddress(0x1ffc), 0x8),
+        (GuestAddress(0x1000), 0),
+    ];
+    let mut buf = [0u8; 0x20];
+    assert_eq!(vm.read_guest_iovec(&iovs, &mut buf).unwrap(), 0x10);
+    assert_eq!(buf[..0x8], [0x11_u8; 0x8]);
+    // Filled above.
+    assert_eq!(buf[0x8..0x10], [0x55_u8; 0x8]);
+    assert_eq!(buf[0x10..], [0u8; 0x10]);
+
+    // Fragments past the end of the output are truncated.
+    let mut buf = [0u8; 0xc];
+    assert_eq!(vm.read_guest_iovec(&iovs, &mut buf).unwrap(), 0xc);
+    assert_eq!(buf[..0x8], [0x11_u8; 0x8]);
+
+    // A fragment that runs into the hole fails the whole read.
+    let err = vm
+        .read_guest_iovec(
+            &[(GuestAddress(0x4000), 0x8), (GuestAddress(0x2ff0), 0x20)],
+            &mut buf,
+        )
+        .expect_err("gather from a hole succeeded");
+    assert_eq!(err.errno(), libc::EFAULT);
+}
@@ -19,6 +19,7 @@ use base::IntoRawDescriptor;
 use base::MappedRegion;
 use base::MemoryMappingArena;
 use base::MemoryMappingBuilder;
+use base::SharedMemory;
 use hypervisor::kvm::dirty_log_bitmap_size;
 use hypervisor::kvm::Kvm;
 use hypervisor::kvm::KvmVm;
@@ -185,6 +186,172 @@ fn remove_memory() {
     assert_eq!(removed_mem.as_ptr(), mem_ptr);
 }
 
+#[test]
+fn for_each_memory_region() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = pagesize();
+    let first = vm
+        .add_memory_region(
+            GuestAddress(pagesize() as u64),
+            Box::new(MemoryMappingBuilder::new(mem_size).build().unwrap()),
+            false,
+            false,
+            CacheCoherent,
+        )
+        .unwrap();
+    let second = vm
+        .add_memory_region(
+            GuestAddress(4 * pagesize() as u64),
+            Box::new(MemoryMappingBuilder::new(2 * mem_size).build().unwrap()),
+            false,
+            false,
+            CacheCoherent,
+        )
+        .unwrap();
+
+    let mut regions = Vec::new();
+    vm.for_each_memory_region(&mut |slot, guest_addr, mem| {
+        regions.push((slot, guest_addr, mem.size()))
+    })
+    .unwrap();
+    assert_eq!(
+        regions,
+        vec![
+            (first, GuestAddress(pagesize() as u64), mem_size),
+            (second, GuestAddress(4 * pagesize() as u64), 2 * mem_size),
+        ]
+    );
+
+    vm.remove_memory_region(first).unwrap();
+    let mut slots = Vec::new();
+    vm.for_each_memory_region(&mut |slot, _, _| slots.push(slot))
+        .unwrap();
+    assert_eq!(slots, vec![second]);
+}
+
+#[test]
+fn clear_memory_regions() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mem_size = pagesize();
+    let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+    let mem_ptr = mem.as_ptr();
+    vm.add_memory_region(
+        GuestAddress(pagesize() as u64),
+        Box::new(mem),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+    vm.add_memory_region(
+        GuestAddress(4 * pagesize() as u64),
+        Box::new(MemoryMappingBuilder::new(2 * mem_size).build().unwrap()),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+
+    let removed = vm.clear_memory_regions().unwrap();
+    assert_eq!(removed.len(), 2);
+    assert_eq!(removed[0].as_ptr(), mem_ptr);
+    assert_eq!(removed[1].size(), 2 * mem_size);
+    assert!(vm.clear_memory_regions().unwrap().is_empty());
+    // The freed range can be mapped again.
+    vm.add_memory_region(
+        GuestAddress(pagesize() as u64),
+        Box::new(MemoryMappingBuilder::new(mem_size).build().unwrap()),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+}
+
+#[test]
+fn memory_region_available() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let page = pagesize() as u64;
+    vm.add_memory_region(
+        GuestAddress(4 * page),
+        Box::new(MemoryMappingBuilder::new(2 * pagesize()).build().unwrap()),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+
+    assert_eq!(vm.memory_region_available(GuestAddress(0), page), Ok(false));
+    assert_eq!(
+        vm.memory_region_available(GuestAddress(3 * page), 2 * page),
+        Ok(false)
+    );
+    assert_eq!(
+        vm.memory_region_available(GuestAddress(5 * page), page),
+        Ok(false)
+    );
+    assert_eq!(
+        vm.memory_region_available(GuestAddress(page), 3 * page),
+        Ok(true)
+    );
+    assert_eq!(
+        vm.memory_region_available(GuestAddress(6 * page), page),
+        Ok(true)
+    );
+}
+
+#[test]
+fn total_mapped_bytes() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    assert_eq!(vm.total_mapped_bytes(), Ok(pagesize() as u64));
+
+    vm.add_memory_region(
+        GuestAddress(4 * pagesize() as u64),
+        Box::new(MemoryMappingBuilder::new(2 * pagesize()).build().unwrap()),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+    assert_eq!(vm.total_mapped_bytes(), Ok(3 * pagesize() as u64));
+}
+
+#[test]
+fn find_memory_region() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let page = pagesize() as u64;
+    let slot = vm
+        .add_memory_region(
+            GuestAddress(4 * page),
+            Box::new(MemoryMappingBuilder::new(2 * pagesize()).build().unwrap()),
+            false,
+            false,
+            CacheCoherent,
+        )
+        .unwrap();
+
+    assert_eq!(
+        vm.find_memory_region(GuestAddress(0x10)),
+        Ok(Some((0, GuestAddress(0), 0x10)))
+    );
+    assert_eq!(
+        vm.find_memory_region(GuestAddress(5 * page + 8)),
+        Ok(Some((slot, GuestAddress(4 * page), page as usize + 8)))
+    );
+    assert_eq!(vm.find_memory_region(GuestAddress(6 * page)), Ok(None));
+    assert_eq!(vm.find_memory_region(GuestAddress(2 * page)), Ok(None));
+}
+
 #[test]
 fn remove_invalid_memory() {
     let kvm = Kvm::new().unwrap();
@@ -236,6 +403,39 @@ fn sync_memory() {
     assert!(vm.msync_memory_region(slot + 1, mem_size, 0).is_err());
 }
 
+#[test]
+fn msync_all() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), pagesize() as u64)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    // Nothing to sync yet.
+    vm.msync_all().unwrap();
+
+    let shm = SharedMemory::new("test", pagesize() as u64).unwrap();
+    vm.add_memory_region(
+        GuestAddress(pagesize() as u64),
+        Box::new(
+            MemoryMappingBuilder::new(pagesize())
+                .from_shared_memory(&shm)
+                .build()
+                .unwrap(),
+        ),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+    vm.add_memory_region(
+        GuestAddress(4 * pagesize() as u64),
+        Box::new(MemoryMappingArena::new(pagesize()).unwrap()),
+        false,
+        false,
+        CacheCoherent,
+    )
+    .unwrap();
+    vm.msync_all().unwrap();
+}
+
 #[test]
 fn register_irqfd() {
     let kvm = Kvm::new().unwrap();
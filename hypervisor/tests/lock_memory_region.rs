@@ -0,0 +1,80 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![cfg(target_arch = "x86_64")]
+
+use base::MemoryMappingBuilder;
+use base::SharedMemory;
+use hypervisor::*;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_kvm_lock_memory_region() {
+    use hypervisor::kvm::*;
+    test_lock_memory_region(|guest_mem| {
+        let kvm = Kvm::new().expect("failed to create kvm");
+        let vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+        (kvm, vm)
+    });
+}
+
+#[test]
+#[cfg(all(windows, feature = "haxm"))]
+fn test_haxm_lock_memory_region() {
+    use hypervisor::haxm::*;
+    test_lock_memory_region(|guest_mem| {
+        let haxm = Haxm::new().expect("failed to create haxm");
+        let vm = HaxmVm::new(&haxm, guest_mem).expect("failed to create vm");
+        (haxm, vm)
+    });
+}
+
+fn test_lock_memory_region<CreateVm, HypervisorT, VmT>(create_vm: CreateVm)
+where
+    CreateVm: FnOnce(GuestMemory) -> (HypervisorT, VmT),
+    HypervisorT: Hypervisor,
+    VmT: Vm,
+{
+    let guest_mem =
+        GuestMemory::new(&[(GuestAddress(0), 0x1000)]).expect("failed to create guest mem");
+    let (_, mut vm) = create_vm(guest_mem);
+
+    let mem_size = 0x1000;
+    let shm = SharedMemory::new("test", mem_size as u64).expect("failed to create shm");
+    let mem = MemoryMappingBuilder::new(mem_size)
+        .from_shared_memory(&shm)
+        .build()
+        .expect("failed to create mapping");
+    let slot = vm
+        .add_memory_region(
+            GuestAddress(0x1000),
+            Box::new(mem),
+            false,
+            false,
+            MemCacheType::CacheCoherent,
+        )
+        .expect("failed to add memory region");
+
+    // The slot is looked up before anything is locked, so this doesn't depend on the memory
+    // lock limit.
+    let err = vm
+        .lock_memory_region(slot + 1)
+        .expect_err("locking an unknown slot succeeded");
+    assert_eq!(err.errno(), libc::ENOENT);
+    let err = vm
+        .unlock_memory_region(slot + 1)
+        .expect_err("unlocking an unknown slot succeeded");
+    assert_eq!(err.errno(), libc::ENOENT);
+
+    match vm.lock_memory_region(slot) {
+        Ok(()) => {}
+        // The process may not be allowed to lock any memory.
+        Err(e) if e.errno() == libc::ENOMEM || e.errno() == libc::EPERM => return,
+        Err(e) => panic!("failed to lock memory region: {}", e),
+    }
+    vm.unlock_memory_region(slot)
+        .expect("failed to unlock memory region");
+}
@@ -1500,7 +1500,8 @@ pub struct RunCommand {
     #[argh(option, arg_name = "PATH")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
-    /// forward hypervisor kernel driver logs for this VM to a file.
+    /// forward hypervisor kernel driver logs for this VM to a file. With HAXM, "{vm_id}" in the
+    /// path is replaced with the driver's id for the VM.
     pub kernel_log_file: Option<String>,
 
     #[argh(option, arg_name = "PATH")]
@@ -1854,11 +1855,17 @@ pub struct RunCommand {
     /// (EXPERIMENTAL) prevent host access to guest memory, but don't use protected VM firmware
     protected_vm_without_firmware: Option<bool>,
 
-    #[argh(option, arg_name = "path=PATH,size=SIZE")]
+    #[argh(option, arg_name = "[path=PATH,]size=SIZE")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
-    /// path to pstore buffer backend file followed by size
-    ///     [--pstore <path=PATH,size=SIZE>]
+    /// path to pstore buffer backend file followed by size. Without
+    /// a path the buffer is kept in memory and lost on exit.
+    ///     [--pstore <[path=PATH,]size=SIZE>]
+    /// Optional sizes of the ramoops sections:
+    ///     [,record-size=SIZE,console-size=SIZE,ftrace-size=SIZE,
+    ///      pmsg-size=SIZE]
+    /// Optional bytes of ECC per ramoops block:
+    ///     [,ecc=SIZE]
     pub pstore: Option<Pstore>,
 
     #[argh(switch)]
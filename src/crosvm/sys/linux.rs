@@ -1070,7 +1070,10 @@ fn create_pure_virtual_pcie_root_port(
             .insert(i, pcie_root_port.clone() as Arc<Mutex<dyn PmeNotify>>);
         let (msi_host_tube, msi_device_tube) = Tube::pair().context("failed to create tube")?;
         irq_control_tubes.push(msi_host_tube);
-        let pci_bridge = Box::new(PciBridge::new(pcie_root_port.clone(), msi_device_tube));
+        let pci_bridge = Box::new(
+            PciBridge::new(pcie_root_port.clone(), msi_device_tube)
+                .context("failed to create pci bridge")?,
+        );
         // no ipc is used if the root port disables hotplug
         devices.push((pci_bridge, None));
     }
@@ -1088,7 +1091,10 @@ fn create_pure_virtual_pcie_root_port(
         );
         let (msi_host_tube, msi_device_tube) = Tube::pair().context("failed to create tube")?;
         irq_control_tubes.push(msi_host_tube);
-        let pci_bridge = Box::new(PciBridge::new(pcie_root_port.clone(), msi_device_tube));
+        let pci_bridge = Box::new(
+            PciBridge::new(pcie_root_port.clone(), msi_device_tube)
+                .context("failed to create pci bridge")?,
+        );
 
         hp_stub.iommu_bus_ranges.push(RangeInclusive::new(
             PciAddress {
@@ -2317,8 +2323,10 @@ fn add_hotplug_device<V: VmArch, Vcpu: VcpuArch>(
                     let pcie_upstream_port = Arc::new(Mutex::new(PcieUpstreamPort::new_from_host(
                         pcie_host, true,
                     )?));
-                    let pci_bridge =
-                        Box::new(PciBridge::new(pcie_upstream_port.clone(), msi_device_tube));
+                    let pci_bridge = Box::new(
+                        PciBridge::new(pcie_upstream_port.clone(), msi_device_tube)
+                            .context("failed to create pci bridge")?,
+                    );
                     linux
                         .hotplug_bus
                         .insert(pci_bridge.get_secondary_num(), pcie_upstream_port);
@@ -2329,10 +2337,10 @@ fn add_hotplug_device<V: VmArch, Vcpu: VcpuArch>(
                     let pcie_downstream_port = Arc::new(Mutex::new(
                         PcieDownstreamPort::new_from_host(pcie_host, true)?,
                     ));
-                    let pci_bridge = Box::new(PciBridge::new(
-                        pcie_downstream_port.clone(),
-                        msi_device_tube,
-                    ));
+                    let pci_bridge = Box::new(
+                        PciBridge::new(pcie_downstream_port.clone(), msi_device_tube)
+                            .context("failed to create pci bridge")?,
+                    );
                     linux
                         .hotplug_bus
                         .insert(pci_bridge.get_secondary_num(), pcie_downstream_port);
@@ -2540,6 +2548,10 @@ fn remove_hotplug_bridge<V: VmArch, Vcpu: VcpuArch>(
     for (bus_num, hp_bus) in linux.hotplug_bus.iter() {
         let mut hp_bus_lock = hp_bus.lock();
         if let Some(pci_addr) = hp_bus_lock.get_hotplug_device(hotplug_key) {
+            linux
+                .root_config
+                .lock()
+                .release_device_irq(pci_addr, sys_allocator);
             sys_allocator.release_pci(pci_addr.bus, pci_addr.dev, pci_addr.func);
             hp_bus_lock.hot_unplug(pci_addr)?;
             buses_to_remove.push(child_bus);
@@ -2630,6 +2642,10 @@ fn remove_hotplug_device<V: VmArch, Vcpu: VcpuArch>(
                 hp_bus_lock.hot_unplug(pci_addr)?;
             }
 
+            linux
+                .root_config
+                .lock()
+                .release_device_irq(pci_addr, sys_allocator);
             sys_allocator.release_pci(pci_addr.bus, pci_addr.dev, pci_addr.func);
             if empty_simbling || hp_bus_lock.is_empty() {
                 if let Some(hotplug_key) = hp_bus_lock.get_hotplug_key() {
@@ -2739,6 +2755,19 @@ fn send_pvclock_cmd(tube: &Tube, command: PvClockCommand) -> Result<()> {
     Ok(())
 }
 
+// The guest may have renumbered the buses behind a hotplug bridge since it was added, so key the
+// hotplug buses by the secondary bus numbers they currently report.
+#[cfg(target_arch = "x86_64")]
+fn rekey_hotplug_buses<V: VmArch, Vcpu: VcpuArch>(linux: &mut RunnableLinuxVm<V, Vcpu>) {
+    linux.hotplug_bus = std::mem::take(&mut linux.hotplug_bus)
+        .into_iter()
+        .map(|(bus_num, hp_bus)| {
+            let bus_num = hp_bus.lock().get_secondary_bus_number().unwrap_or(bus_num);
+            (bus_num, hp_bus)
+        })
+        .collect();
+}
+
 #[cfg(target_arch = "x86_64")]
 fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
     linux: &mut RunnableLinuxVm<V, Vcpu>,
@@ -2759,6 +2788,7 @@ fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
         None
     };
 
+    rekey_hotplug_buses(linux);
     let ret = if add {
         add_hotplug_device(
             linux,
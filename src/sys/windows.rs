@@ -1846,25 +1846,26 @@ fn create_haxm_vm(
     kernel_log_file: &Option<String>,
 ) -> Result<HaxmVm> {
     let vm = HaxmVm::new(&haxm, mem)?;
+    info!("created HAXM VM {}", vm.vm_id());
     if let Some(path) = kernel_log_file {
-        use hypervisor::haxm::HAX_CAP_VM_LOG;
-        if vm.check_raw_capability(HAX_CAP_VM_LOG) {
-            match vm.register_log_file(path) {
-                Ok(_) => {}
-                Err(e) => match e.errno() {
-                    libc::E2BIG => {
-                        error!(
-                            "kernel_log_file path is too long, kernel log file will not be written"
-                        );
-                    }
-                    _ => return Err(e.into()),
-                },
+        use hypervisor::haxm::LogFileStatus;
+        match vm.register_log_file(path) {
+            Ok(LogFileStatus::Enabled(path)) => info!("writing kernel log to {}", path),
+            Ok(LogFileStatus::UnsupportedPlatform) => {
+                warn!("kernel_log_file specified but kernel log files need the GHAXM driver");
             }
-        } else {
-            warn!(
-                "kernel_log_file specified but this version of HAXM does not support kernel log \
-                  files"
-            );
+            Ok(LogFileStatus::CapabilityMissing) => {
+                warn!(
+                    "kernel_log_file specified but this version of HAXM does not support kernel \
+                     log files"
+                );
+            }
+            Err(e) => match e.errno() {
+                libc::E2BIG => {
+                    error!("kernel_log_file path is too long, kernel log file will not be written");
+                }
+                _ => return Err(e.into()),
+            },
         }
     }
     Ok(vm)